@@ -4,21 +4,10 @@ use evunit::prelude::*;
 use std::process::exit;
 
 fn main() {
-	let mut tests = Vec::new();
-
-	for a in 0..=128 {
-		for b in 0..=127 {
-			let mut test = TestConfig::new(format!("Test {a} + {b}"));
-
-			// Initial state
-			test.initial = Registers::new().with_a(a).with_b(b);
-
-			// Expected state
-			test.result = Some(Registers::new().with_a(a + b));
-
-			tests.push(test);
-		}
-	}
+	let tests = TruthTable::new(TestConfig::new(String::from("Test")))
+		.with_inputs([Location::A, Location::B])
+		.with_outputs([Location::A])
+		.tests((0..=128).flat_map(|a| (0..=127).map(move |b| ([a, b], [a + b]))));
 
 	let result = run_tests("test/test.gb", &tests, SilenceLevel::Passing);
 
@@ -0,0 +1,12 @@
+#![no_main]
+
+use evunit::fuzz;
+use libfuzzer_sys::fuzz_target;
+
+// Enough instructions to reach most loops, while keeping each run fast.
+const INSTRUCTIONS: usize = 4096;
+
+fuzz_target!(|data: &[u8]| {
+	let _ = fuzz::execute_image(data, INSTRUCTIONS);
+	let _ = fuzz::execute_rom(&data.to_vec(), INSTRUCTIONS);
+});
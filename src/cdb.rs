@@ -0,0 +1,453 @@
+//! C-level names and types from the `.cdb` debug files written by SDCC, such as those of GBDK projects.
+//!
+//! Only a few kinds of records are read; the rest are ignored:
+//!
+//! ```not_rust
+//! S:G$player$0_0$0({4}STplayer_t:S),E,0,0
+//! T:Fmain$player_t[({0}S:S$hp$0_0$0({1}SC:U),Z,0,0)({1}S:S$x$0_0$0({2}SI:S),Z,0,0)({3}S:S$y$0_0$0({1}SC:U),Z,0,0)]
+//! L:G$player$0_0$0:C0A0
+//! ```
+//!
+//! Symbol records (`S:`) give the type of each global or static variable, type records (`T:`) the members of each struct,
+//! and linker records (`L:`) the address of each variable and function.
+
+use crate::config::Symbols;
+use std::collections::HashMap;
+
+#[derive(Debug, thiserror::Error)]
+#[error("line {line}: {message}")]
+pub struct CdbError {
+	pub line: usize,
+	pub message: String,
+}
+
+/// The type of a C variable or struct member.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CType {
+	/// A `char`, `int`, or `long`, of 1, 2, or 4 bytes.
+	Integer {
+		size: u16,
+		signed: bool,
+	},
+	Pointer,
+	Array {
+		element: Box<CType>,
+		count: u16,
+	},
+	Struct {
+		name: String,
+		size: u16,
+	},
+	/// Anything else, such as a float or bitfield, which is only known by its size.
+	Other(u16),
+}
+
+impl CType {
+	#[must_use]
+	pub fn size(&self) -> u16 {
+		match self {
+			CType::Integer { size, .. } | CType::Struct { size, .. } | CType::Other(size) => *size,
+			CType::Pointer => 2,
+			CType::Array { element, count } => element.size().saturating_mul(*count),
+		}
+	}
+}
+
+/// A member of a struct, at `offset` bytes from its start.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Member {
+	pub name: String,
+	pub offset: u16,
+	pub ty: CType,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DebugInfo {
+	/// The type of each global and static variable.
+	pub variables: HashMap<String, CType>,
+	/// The members of each struct, in order.
+	pub structs: HashMap<String, Vec<Member>>,
+	/// The bank and address of each variable and function.
+	pub addresses: HashMap<String, (u32, u16)>,
+}
+
+impl DebugInfo {
+	/// Reads the variables, structs, and addresses in a `.cdb` file.
+	///
+	/// # Errors
+	///
+	/// Returns every record which could not be read.
+	pub fn parse(text: &str) -> Result<Self, Vec<CdbError>> {
+		let mut info = Self::default();
+		let mut errors = Vec::new();
+		for (index, line) in text.lines().enumerate() {
+			let result = match line.trim().split_once(':') {
+				Some(("S", record)) => info.read_symbol(record),
+				Some(("T", record)) => info.read_struct(record),
+				Some(("L", record)) => info.read_address(record),
+				_ => Ok(()),
+			};
+			if let Err(message) = result {
+				errors.push(CdbError {
+					line: index + 1,
+					message,
+				});
+			}
+		}
+		if errors.is_empty() {
+			Ok(info)
+		} else {
+			Err(errors)
+		}
+	}
+
+	/// Reads a symbol record such as `G$player$0_0$0({4}STplayer_t:S),E,0,0`.
+	/// Local variables are skipped, since they have no fixed address.
+	fn read_symbol(&mut self, record: &str) -> Result<(), String> {
+		let Some(name) = scoped_name(record) else {
+			return Ok(());
+		};
+		let ty = parse_type(type_chain(record)?)?;
+		self.variables.insert(String::from(name), ty);
+		Ok(())
+	}
+
+	/// Reads a type record such as `Fmain$player_t[({0}S:S$hp$0_0$0({1}SC:U),Z,0,0)]`.
+	fn read_struct(&mut self, record: &str) -> Result<(), String> {
+		let malformed = || format!("Malformed type record `{record}`");
+		let (_, rest) = record.split_once('$').ok_or_else(malformed)?;
+		let (name, members) = rest.split_once('[').ok_or_else(malformed)?;
+		let members = members.strip_suffix(']').ok_or_else(malformed)?;
+		let members = groups(members)
+			.into_iter()
+			.map(|member| {
+				let (offset, symbol) = member
+					.strip_prefix('{')
+					.and_then(|member| member.split_once('}'))
+					.ok_or_else(malformed)?;
+				let offset = offset.parse().map_err(|_| malformed())?;
+				let symbol = symbol.strip_prefix("S:S$").ok_or_else(malformed)?;
+				let (name, _) = symbol.split_once('$').ok_or_else(malformed)?;
+				Ok(Member {
+					name: String::from(name),
+					offset,
+					ty: parse_type(type_chain(symbol)?)?,
+				})
+			})
+			.collect::<Result<_, String>>()?;
+		self.structs.insert(String::from(name), members);
+		Ok(())
+	}
+
+	/// Reads a linker record such as `G$player$0_0$0:C0A0`, which gives a variable or function's address.
+	/// Banked addresses hold their bank above the lower 16 bits, like in `.noi` files.
+	fn read_address(&mut self, record: &str) -> Result<(), String> {
+		let Some(name) = scoped_name(record) else {
+			return Ok(());
+		};
+		let (_, address) = record
+			.rsplit_once(':')
+			.ok_or_else(|| format!("Malformed linker record `{record}`"))?;
+		let address = u32::from_str_radix(address, 16)
+			.map_err(|_| format!("Invalid address `{address}` in linker record"))?;
+		let [low, high, ..] = address.to_le_bytes();
+		self.addresses.insert(
+			String::from(name),
+			(address >> 16, u16::from_le_bytes([low, high])),
+		);
+		Ok(())
+	}
+
+	/// Finds the address and type of a variable, member, or array element, such as `player.hp` or `enemies[2].x`.
+	///
+	/// Returns `None` if `path` does not start with a variable's name.
+	///
+	/// # Errors
+	///
+	/// Returns an error if a member or index does not exist.
+	#[must_use]
+	pub fn resolve(&self, path: &str) -> Option<Result<(u32, u16, CType), String>> {
+		let end = path.find(['.', '[']).unwrap_or(path.len());
+		let (name, mut rest) = path.split_at(end);
+		let (bank, mut address) = *self.addresses.get(name)?;
+		let mut ty = self.variables.get(name)?.clone();
+		while !rest.is_empty() {
+			let step = if let Some(after) = rest.strip_prefix('.') {
+				let end = after.find(['.', '[']).unwrap_or(after.len());
+				let (member, after) = after.split_at(end);
+				rest = after;
+				self.member(&ty, member)
+			} else if let Some((index, after)) =
+				rest.strip_prefix('[').and_then(|rest| rest.split_once(']'))
+			{
+				rest = after;
+				element(&ty, index)
+			} else {
+				Err(format!("Expected `.member` or `[index]` in `{path}`"))
+			};
+			match step {
+				Ok((offset, inner)) => {
+					address = address.wrapping_add(offset);
+					ty = inner;
+				}
+				Err(message) => return Some(Err(message)),
+			}
+		}
+		Some(Ok((bank, address, ty)))
+	}
+
+	fn member(&self, ty: &CType, member: &str) -> Result<(u16, CType), String> {
+		let CType::Struct { name, .. } = ty else {
+			return Err(format!("Cannot take member `{member}` of a non-struct"));
+		};
+		self.structs
+			.get(name)
+			.and_then(|members| members.iter().find(|candidate| candidate.name == member))
+			.map(|member| (member.offset, member.ty.clone()))
+			.ok_or_else(|| format!("Struct `{name}` has no member `{member}`"))
+	}
+
+	/// Every variable and function, along with each member of every struct variable, such as `player.hp`,
+	/// for looking up addresses and naming them in failures.
+	#[must_use]
+	pub fn symbols(&self) -> Symbols {
+		let mut symbols = Symbols::new();
+		for (name, (bank, address)) in &self.addresses {
+			symbols.insert(name.clone(), (*bank, *address));
+			if let Some(ty) = self.variables.get(name) {
+				self.insert_members(&mut symbols, name, *bank, *address, ty);
+			}
+		}
+		symbols
+	}
+
+	fn insert_members(
+		&self,
+		symbols: &mut Symbols,
+		path: &str,
+		bank: u32,
+		address: u16,
+		ty: &CType,
+	) {
+		let CType::Struct { name, .. } = ty else {
+			return;
+		};
+		for member in self.structs.get(name).into_iter().flatten() {
+			let path = format!("{path}.{}", member.name);
+			let address = address.wrapping_add(member.offset);
+			symbols.insert(path.clone(), (bank, address));
+			self.insert_members(symbols, &path, bank, address, &member.ty);
+		}
+	}
+}
+
+fn element(ty: &CType, index: &str) -> Result<(u16, CType), String> {
+	let CType::Array { element, count } = ty else {
+		return Err(format!("Cannot index a non-array with `[{index}]`"));
+	};
+	let index = index
+		.trim()
+		.parse::<u16>()
+		.map_err(|_| format!("Invalid index `{index}`"))?;
+	if index >= *count {
+		return Err(format!(
+			"Index {index} is past the end of an array of {count}"
+		));
+	}
+	Ok((element.size().wrapping_mul(index), (**element).clone()))
+}
+
+/// Returns the name of a global (`G$name$...`) or file-level static (`Ffile$name$...`) symbol.
+fn scoped_name(record: &str) -> Option<&str> {
+	let mut parts = record.split('$');
+	let scope = parts.next()?;
+	if scope == "G" || scope.starts_with('F') {
+		parts.next()
+	} else {
+		None
+	}
+}
+
+/// Returns the type chain between the first parentheses of a symbol, such as `{4}STplayer_t:S`.
+fn type_chain(symbol: &str) -> Result<&str, String> {
+	symbol
+		.split_once('(')
+		.and_then(|(_, rest)| rest.split_once(')'))
+		.map(|(chain, _)| chain)
+		.ok_or_else(|| format!("Missing type in `{symbol}`"))
+}
+
+/// Reads a type chain such as `{6}DA3d,SI:U`: a size, declarators such as arrays and pointers, then a base type and sign.
+fn parse_type(chain: &str) -> Result<CType, String> {
+	let malformed = || format!("Malformed type `{chain}`");
+	let (size, rest) = chain
+		.strip_prefix('{')
+		.and_then(|chain| chain.split_once('}'))
+		.ok_or_else(malformed)?;
+	let size = size.parse::<u16>().map_err(|_| malformed())?;
+	let (rest, sign) = rest.rsplit_once(':').ok_or_else(malformed)?;
+	let parts = rest.split(',').collect::<Vec<_>>();
+	build_type(&parts, size, sign == "S").ok_or_else(malformed)
+}
+
+fn build_type(parts: &[&str], size: u16, signed: bool) -> Option<CType> {
+	let (first, rest) = parts.split_first()?;
+	if let Some(count) = first
+		.strip_prefix("DA")
+		.and_then(|count| count.strip_suffix('d'))
+	{
+		let count = count.parse::<u16>().ok()?;
+		let element = build_type(rest, size.checked_div(count).unwrap_or(0), signed)?;
+		return Some(CType::Array {
+			element: Box::new(element),
+			count,
+		});
+	}
+	Some(match *first {
+		"DF" => CType::Other(size),
+		declarator if declarator.starts_with('D') => CType::Pointer,
+		"SC" | "SS" | "SI" | "SL" => CType::Integer { size, signed },
+		base => match base.strip_prefix("ST") {
+			Some(name) => CType::Struct {
+				name: String::from(name),
+				size,
+			},
+			None => CType::Other(size),
+		},
+	})
+}
+
+/// Splits a list of parenthesized groups, such as `(a)(b(c))`, into the contents of each.
+fn groups(list: &str) -> Vec<&str> {
+	let mut groups = Vec::new();
+	let mut depth = 0;
+	let mut start = 0;
+	for (index, char) in list.char_indices() {
+		match char {
+			'(' => {
+				if depth == 0 {
+					start = index + 1;
+				}
+				depth += 1;
+			}
+			')' => {
+				depth -= 1;
+				if depth == 0 {
+					groups.push(&list[start..index]);
+				}
+			}
+			_ => {}
+		}
+	}
+	groups
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const FIXTURE: &str = "\
+M:main
+F:G$main$0_0$0({2}DF,SV:S),C,0,0,0,0,0
+S:G$main$0_0$0({2}DF,SV:S),C,0,0
+T:Fmain$vec_t[({0}S:S$x$0_0$0({1}SC:S),Z,0,0)({1}S:S$y$0_0$0({1}SC:S),Z,0,0)]
+T:Fmain$player_t[({0}S:S$hp$0_0$0({1}SC:U),Z,0,0)({1}S:S$score$0_0$0({2}SI:U),Z,0,0)({3}S:S$pos$0_0$0({2}STvec_t:S),Z,0,0)({5}S:S$big$0_0$0({4}SL:U),Z,0,0)]
+S:G$player$0_0$0({9}STplayer_t:S),E,0,0
+S:G$enemies$0_0$0({27}DA3d,STplayer_t:S),E,0,0
+S:Fmain$counter$0_0$0({1}SC:U),E,0,0
+S:Lmain$local$1_0$1({1}SC:U),R,0,0,[a]
+L:G$main$0_0$0:100
+L:XG$main$0_0$0:101
+L:G$player$0_0$0:C000
+L:G$enemies$0_0$0:C010
+L:Fmain$counter$0_0$0:C040
+L:G$banked$0_0$0:24000
+";
+
+	fn player() -> CType {
+		CType::Struct {
+			name: String::from("player_t"),
+			size: 9,
+		}
+	}
+
+	#[test]
+	fn records() {
+		let info = DebugInfo::parse(FIXTURE).unwrap();
+		assert_eq!(info.variables["player"], player());
+		assert_eq!(
+			info.variables["enemies"],
+			CType::Array {
+				element: Box::new(player()),
+				count: 3,
+			}
+		);
+		assert_eq!(
+			info.variables["counter"],
+			CType::Integer {
+				size: 1,
+				signed: false,
+			}
+		);
+		assert!(!info.variables.contains_key("local"));
+		assert_eq!(info.structs["player_t"].len(), 4);
+		assert_eq!(info.addresses["main"], (0, 0x0100));
+		assert_eq!(info.addresses["counter"], (0, 0xC040));
+		assert_eq!(info.addresses["banked"], (2, 0x4000));
+	}
+
+	#[test]
+	fn resolve() {
+		let info = DebugInfo::parse(FIXTURE).unwrap();
+		let signed_char = CType::Integer {
+			size: 1,
+			signed: true,
+		};
+		assert_eq!(
+			info.resolve("player.pos.y"),
+			Some(Ok((0, 0xC004, signed_char)))
+		);
+		let int = CType::Integer {
+			size: 2,
+			signed: false,
+		};
+		assert_eq!(info.resolve("enemies[2].score"), Some(Ok((0, 0xC023, int))));
+		assert_eq!(
+			info.resolve("enemies[3]"),
+			Some(Err(String::from(
+				"Index 3 is past the end of an array of 3"
+			)))
+		);
+		assert_eq!(
+			info.resolve("player.mp"),
+			Some(Err(String::from("Struct `player_t` has no member `mp`")))
+		);
+		assert_eq!(info.resolve("wPlayer"), None);
+	}
+
+	#[test]
+	fn member_symbols() {
+		let symbols = DebugInfo::parse(FIXTURE).unwrap().symbols();
+		assert_eq!(symbols["player"], (0, 0xC000));
+		assert_eq!(symbols["player.pos.x"], (0, 0xC003));
+		assert_eq!(symbols["player.big"], (0, 0xC005));
+		// Array elements are found with `resolve` instead.
+		assert!(!symbols.contains_key("enemies.hp"));
+	}
+
+	#[test]
+	fn malformed_records() {
+		let errors = DebugInfo::parse("M:main\nS:G$x$0_0$0,E,0,0\nL:G$y$0_0$0:C0ZZ\n").unwrap_err();
+		let errors = errors
+			.iter()
+			.map(ToString::to_string)
+			.collect::<Vec<String>>();
+		assert_eq!(
+			errors,
+			[
+				"line 2: Missing type in `G$x$0_0$0,E,0,0`",
+				"line 3: Invalid address `C0ZZ` in linker record",
+			]
+		);
+	}
+}
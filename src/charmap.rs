@@ -0,0 +1,192 @@
+//! Character maps, which give the bytes each character of a game's text is stored as.
+//!
+//! RGBDS charmaps are read from the `charmap` directives which define them:
+//!
+//! ```not_rust
+//! charmap "A", $80
+//! charmap "<HERO>", $F0, $01
+//! charmap "<END>", $FF ; Comments are ignored
+//! ```
+//!
+//! Other directives, such as `newcharmap` and `setcharmap`, are skipped, so a file should only contain one charmap.
+
+#[derive(Debug, thiserror::Error)]
+#[error("line {line}: {message}")]
+pub struct CharmapError {
+	pub line: usize,
+	pub message: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Charmap {
+	/// Each character, or sequence of characters, along with the bytes it is stored as, in the order they were defined.
+	pub entries: Vec<(String, Vec<u8>)>,
+}
+
+impl Charmap {
+	/// Reads every `charmap` directive in an RGBDS source file.
+	///
+	/// # Errors
+	///
+	/// Returns every directive which could not be read.
+	pub fn parse(text: &str) -> Result<Self, Vec<CharmapError>> {
+		let mut charmap = Self::default();
+		let mut errors = Vec::new();
+		for (index, line) in text.lines().enumerate() {
+			match parse_line(line) {
+				Some(Ok((name, bytes))) => charmap.insert(name, bytes),
+				Some(Err(message)) => errors.push(CharmapError {
+					line: index + 1,
+					message,
+				}),
+				None => {}
+			}
+		}
+		if errors.is_empty() {
+			Ok(charmap)
+		} else {
+			Err(errors)
+		}
+	}
+
+	/// Maps `name` to `bytes`, replacing any earlier mapping of it, as redefining a character does in RGBDS.
+	pub fn insert(&mut self, name: String, bytes: Vec<u8>) {
+		match self.entries.iter_mut().find(|(entry, _)| *entry == name) {
+			Some((_, entry)) => *entry = bytes,
+			None => self.entries.push((name, bytes)),
+		}
+	}
+
+	/// Converts text to bytes, matching the longest character sequence at each point like RGBASM does.
+	/// ASCII characters which aren't in the map are stored as themselves.
+	///
+	/// # Errors
+	///
+	/// Returns an error naming the first character which is neither mapped nor ASCII.
+	pub fn encode(&self, text: &str) -> Result<Vec<u8>, String> {
+		let mut bytes = Vec::new();
+		let mut rest = text;
+		while let Some(char) = rest.chars().next() {
+			let longest = self
+				.entries
+				.iter()
+				.filter(|(name, _)| !name.is_empty() && rest.starts_with(name.as_str()))
+				.max_by_key(|(name, _)| name.len());
+			if let Some((name, mapped)) = longest {
+				bytes.extend(mapped);
+				rest = &rest[name.len()..];
+			} else if char.is_ascii() {
+				bytes.push(char as u8);
+				rest = &rest[1..];
+			} else {
+				return Err(format!("Character `{char}` is not in the charmap"));
+			}
+		}
+		Ok(bytes)
+	}
+
+	/// Converts bytes back to text, preferring the characters with the longest encodings.
+	/// Bytes which no character maps to are shown as printable ASCII, or otherwise escaped like `\xFF`.
+	#[must_use]
+	pub fn decode(&self, bytes: &[u8]) -> String {
+		let mut text = String::new();
+		let mut rest = bytes;
+		while let Some((byte, after)) = rest.split_first() {
+			// The earliest definition wins between characters with the same encoding.
+			let longest = self
+				.entries
+				.iter()
+				.filter(|(_, mapped)| !mapped.is_empty() && rest.starts_with(mapped))
+				.rev()
+				.max_by_key(|(_, mapped)| mapped.len());
+			if let Some((name, mapped)) = longest {
+				text += name;
+				rest = &rest[mapped.len()..];
+			} else {
+				text += &escape(*byte);
+				rest = after;
+			}
+		}
+		text
+	}
+}
+
+/// Shows a byte as printable ASCII, or otherwise escaped like `\xFF`.
+pub(crate) fn escape(byte: u8) -> String {
+	match byte {
+		b' '..=b'~' => char::from(byte).to_string(),
+		_ => format!("\\x{byte:02X}"),
+	}
+}
+
+/// Reads a directive such as `charmap "A", $80`, returning `None` if the line isn't one.
+fn parse_line(line: &str) -> Option<Result<(String, Vec<u8>), String>> {
+	let line = line.trim_start();
+	let directive = line.get(..7)?;
+	if !directive.eq_ignore_ascii_case("charmap") || !line[7..].starts_with(char::is_whitespace) {
+		return None;
+	}
+	let Some((name, rest)) = parse_string(line[7..].trim_start()) else {
+		return Some(Err(format!("Malformed charmap `{}`", line.trim())));
+	};
+	let rest = rest.split_once(';').map_or(rest, |(rest, _)| rest);
+	let bytes = rest
+		.split(',')
+		.skip(1)
+		.map(|value| parse_number(value.trim()))
+		.collect::<Result<Vec<u8>, String>>();
+	Some(match bytes {
+		Ok(bytes) if bytes.is_empty() || !rest.trim_start().starts_with(',') => {
+			Err(format!("Missing a value for charmap \"{name}\""))
+		}
+		Ok(bytes) => Ok((name, bytes)),
+		Err(message) => Err(message),
+	})
+}
+
+/// Reads a quoted string with RGBDS's escapes, returning it and the text after its closing quote.
+fn parse_string(text: &str) -> Option<(String, &str)> {
+	let mut chars = text.strip_prefix('"')?.char_indices();
+	let mut string = String::new();
+	while let Some((index, char)) = chars.next() {
+		match char {
+			'"' => return Some((string, &text[index + 2..])),
+			'\\' => string.push(match chars.next()?.1 {
+				'n' => '\n',
+				'r' => '\r',
+				't' => '\t',
+				'0' => '\0',
+				escaped => escaped,
+			}),
+			char => string.push(char),
+		}
+	}
+	None
+}
+
+/// Reads an 8-bit number in any of RGBDS's bases: `$FF`, `0xFF`, `%1010`, `0b1010`, `&17`, `0o17`, or decimal.
+fn parse_number(value: &str) -> Result<u8, String> {
+	let (digits, radix) = if let Some(digits) = value
+		.strip_prefix('$')
+		.or_else(|| value.strip_prefix("0x"))
+		.or_else(|| value.strip_prefix("0X"))
+	{
+		(digits, 16)
+	} else if let Some(digits) = value
+		.strip_prefix('%')
+		.or_else(|| value.strip_prefix("0b"))
+		.or_else(|| value.strip_prefix("0B"))
+	{
+		(digits, 2)
+	} else if let Some(digits) = value
+		.strip_prefix('&')
+		.or_else(|| value.strip_prefix("0o"))
+		.or_else(|| value.strip_prefix("0O"))
+	{
+		(digits, 8)
+	} else {
+		(value, 10)
+	};
+	u8::from_str_radix(&digits.replace('_', ""), radix)
+		.map_err(|_| format!("Invalid charmap value `{value}`"))
+}
@@ -0,0 +1,23 @@
+//! Custom pass/fail criteria for tests, beyond comparing registers and memory.
+
+use crate::memory::{Access, Peek};
+use crate::registers::Registers;
+use std::fmt;
+
+/// A check performed once a test has finished, such as "OAM entries are sorted by Y".
+/// Failed conditions are reported alongside mismatched registers and memory.
+pub trait Condition: fmt::Debug + Send + Sync {
+	/// Checks the final state of a test.
+	///
+	/// `accesses` lists every read and write the test made, in order.
+	///
+	/// # Errors
+	///
+	/// Returns a message describing the problem if the condition does not hold.
+	fn check(
+		&self,
+		registers: &Registers,
+		memory: &dyn Peek,
+		accesses: &[Access],
+	) -> Result<(), String>;
+}
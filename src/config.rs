@@ -0,0 +1,2696 @@
+//! Reading tests from TOML, JSON, or YAML configuration files.
+//!
+//! Every problem found in a file is collected as a [`Diagnostic`], rather than stopping at the first,
+//! so that they can all be fixed at once.
+
+use crate::cdb::{CType, DebugInfo};
+use crate::charmap::Charmap;
+use crate::convention::{Arg, CallingConvention};
+use crate::expression::{self, Expression};
+use crate::invariant::{Comparison, Invariant};
+use crate::mapfile::MapFile;
+use crate::memory::{self, RamFill};
+use crate::reg::HwReg;
+use crate::registers::{Model, Registers, Typed};
+use crate::savestate::{self, LoadedState};
+#[cfg(feature = "scripts")]
+use crate::script::Script;
+use crate::table::Location;
+use crate::test::{Dump, Policy, TestConfig, TestStep};
+use serde::de::DeserializeOwned;
+use serde::de::{self, Deserializer, IntoDeserializer, SeqAccess, Unexpected, Visitor};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::iter;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+use std::sync::Arc;
+use std::{env, fmt, fs};
+
+/// Maps symbol names to their bank and address, as returned by [`crate::open_symfile`].
+pub type Symbols = HashMap<String, (u32, u16)>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+	/// The file could not be read as intended, and its tests should not be run.
+	Error,
+	Warning,
+}
+
+/// The language a configuration file is written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+	#[default]
+	Toml,
+	Json,
+	Yaml,
+}
+
+impl Format {
+	/// Guesses the format of a file from its extension, if it is one of `.toml`, `.json`, `.yaml`, or `.yml`.
+	#[must_use]
+	pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+		let extension = path.as_ref().extension()?.to_str()?;
+		match extension.to_ascii_lowercase().as_str() {
+			"toml" => Some(Self::Toml),
+			"json" => Some(Self::Json),
+			"yaml" | "yml" => Some(Self::Yaml),
+			_ => None,
+		}
+	}
+}
+
+/// Settings for reading a configuration file.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+	pub format: Format,
+	/// Values for `${NAME}` references in strings, which take priority over environment variables.
+	pub defines: HashMap<String, String>,
+	/// Whether `${NAME}` may refer to an environment variable.
+	pub environment: bool,
+	/// C variables which tests may name instead of addresses, such as `"player.hp" = 3`.
+	pub debug_info: Option<Arc<DebugInfo>>,
+	/// Sections which region options may name instead of addresses, such as `forbid-write = ["Variables"]`.
+	pub map: Option<Arc<MapFile>>,
+}
+
+impl Options {
+	#[must_use]
+	pub fn with_format(mut self, format: Format) -> Self {
+		self.format = format;
+		self
+	}
+	#[must_use]
+	pub fn with_define(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+		self.defines.insert(name.into(), value.into());
+		self
+	}
+	#[must_use]
+	pub fn with_environment(mut self, environment: bool) -> Self {
+		self.environment = environment;
+		self
+	}
+	#[must_use]
+	pub fn with_debug_info(mut self, debug_info: DebugInfo) -> Self {
+		self.debug_info = Some(Arc::new(debug_info));
+		self
+	}
+	#[must_use]
+	pub fn with_map(mut self, map: MapFile) -> Self {
+		self.map = Some(Arc::new(map));
+		self
+	}
+
+	fn variable(&self, name: &str) -> Option<String> {
+		self.defines
+			.get(name)
+			.cloned()
+			.or_else(|| self.environment.then(|| env::var(name).ok()).flatten())
+	}
+}
+
+/// A problem found while reading a configuration file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+	pub severity: Severity,
+	pub message: String,
+	/// Byte range of the configuration file which the problem refers to, if known.
+	pub span: Option<Range<usize>>,
+}
+
+impl Diagnostic {
+	fn error(message: impl Into<String>, span: Option<Range<usize>>) -> Self {
+		Self {
+			severity: Severity::Error,
+			message: message.into(),
+			span,
+		}
+	}
+
+	/// Formats this diagnostic as `path:line:column: message`, using `source` to find the line.
+	#[must_use]
+	pub fn display<'a>(&'a self, path: &'a str, source: &'a str) -> impl fmt::Display + 'a {
+		DiagnosticDisplay {
+			diagnostic: self,
+			path,
+			source,
+		}
+	}
+}
+
+struct DiagnosticDisplay<'a> {
+	diagnostic: &'a Diagnostic,
+	path: &'a str,
+	source: &'a str,
+}
+
+impl fmt::Display for DiagnosticDisplay<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let severity = match self.diagnostic.severity {
+			Severity::Error => "error",
+			Severity::Warning => "warning",
+		};
+		write!(f, "{}", self.path)?;
+		if let Some(span) = &self.diagnostic.span {
+			let before = &self.source[..span.start.min(self.source.len())];
+			let line = before.matches('\n').count() + 1;
+			let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+			write!(f, ":{line}:{column}")?;
+		}
+		write!(f, ": {severity}: {}", self.diagnostic.message)
+	}
+}
+
+/// The tests read from a configuration file, along with any problems found in it.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+	pub tests: Vec<TestConfig>,
+	pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Config {
+	/// Reads every test from the TOML in `text`, looking up symbols in `symfile`.
+	///
+	/// `data` files are read relative to the current directory.
+	#[must_use]
+	pub fn parse(text: &str, symfile: &Symbols) -> Self {
+		Self::parse_with(text, &Options::default(), symfile)
+	}
+
+	/// Reads every test from `text`, looking up symbols in `symfile`.
+	///
+	/// JSON and YAML files have the same structure as TOML ones,
+	/// but only syntax errors can be located within them.
+	#[must_use]
+	pub fn parse_with(text: &str, options: &Options, symfile: &Symbols) -> Self {
+		let mut config = Self::default();
+		let parsed = match options.format {
+			Format::Toml => toml_edit::ImDocument::parse(text)
+				.map_err(|msg| Diagnostic::error(msg.message(), msg.span()))
+				.and_then(|document| {
+					let table = toml::from_str::<toml::Table>(text)
+						.map_err(|msg| Diagnostic::error(msg.message(), msg.span()))?;
+					Ok((Spans(Some(document)), table))
+				}),
+			Format::Json => serde_json::from_str::<toml::Table>(text)
+				.map_err(|msg| {
+					let offset = line_offset(text, msg.line()) + msg.column().saturating_sub(1);
+					Diagnostic::error(strip_location(&msg), Some(offset..offset))
+				})
+				.map(|table| (Spans(None), table)),
+			Format::Yaml => serde_norway::from_str::<toml::Table>(text)
+				.map_err(|msg| {
+					let span = msg
+						.location()
+						.map(|location| location.index()..location.index());
+					Diagnostic::error(strip_location(&msg), span)
+				})
+				.map(|table| (Spans(None), table)),
+		};
+		match parsed {
+			Ok((spans, table)) => config.read_table(table, &spans, options, symfile),
+			Err(diagnostic) => config.diagnostics.push(diagnostic),
+		}
+		// Problems are found in several passes, but are easiest to fix from top to bottom.
+		config
+			.diagnostics
+			.sort_by_key(|diagnostic| diagnostic.span.as_ref().map(|span| span.start));
+		config
+	}
+
+	/// Returns true if any diagnostic is an error.
+	#[must_use]
+	pub fn has_errors(&self) -> bool {
+		self.diagnostics
+			.iter()
+			.any(|diagnostic| diagnostic.severity == Severity::Error)
+	}
+
+	fn read_table(
+		&mut self,
+		mut table: toml::Table,
+		spans: &Spans<'_>,
+		options: &Options,
+		symfile: &Symbols,
+	) {
+		let mut global_config = TestConfig::new(String::from("Global"));
+		let mut overlays = HashMap::new();
+		// Where each test's overlay was named, for reporting undeclared overlays
+		let mut overlay_spans = Vec::new();
+		// Likewise for the tests named by `after`, which may come later in the file
+		let mut after_spans = Vec::new();
+		let first_test = self.tests.len();
+
+		// Settings for the whole file are kept out of the way of tests, so that every other name is free for a test
+		self.read_settings(
+			&mut table,
+			spans,
+			options,
+			symfile,
+			&mut global_config,
+			&mut overlays,
+		);
+		// Not a test; runs once, and every test starts from its final state
+		let setup = self.setup_table(&mut table, spans, options);
+
+		for (key, value) in table {
+			let locate = |inner: Option<&str>| spans.find(&key, inner);
+			let mut problems = Problems::new();
+			let value = interpolate(value, options, None, &mut problems);
+			let value = resolve_c_names(value, options, &mut problems);
+			if !problems.is_empty() {
+				for (problem_key, message) in problems {
+					self.diagnostics
+						.push(Diagnostic::error(message, locate(problem_key.as_deref())));
+				}
+				continue;
+			}
+			let mut inputs = Vec::new();
+			collect_inputs(&key, &value, &mut inputs);
+			match value {
+				toml::Value::Table(table) => {
+					let table = sections_in(table, options, symfile);
+					// A test with a `data` file is expanded into one test per row
+					let rows = match table.get("data") {
+						Some(toml::Value::String(path)) => read_data(path, &table),
+						Some(_) => Err(String::from(
+							"Value of `data` must be the path to a CSV file.",
+						)),
+						None => Ok(vec![(None, table)]),
+					};
+					let rows = match rows {
+						Ok(rows) => rows,
+						Err(message) => {
+							self.diagnostics
+								.push(Diagnostic::error(message, locate(Some("data"))));
+							continue;
+						}
+					};
+
+					for (row_name, row) in rows {
+						let mut test = global_config.clone();
+						test.inputs.extend(inputs.iter().cloned());
+						test.name = match &row_name {
+							Some(row_name) => format!("{key} {row_name}"),
+							None => key.clone(),
+						};
+						let mut problems = apply_table(&mut test, row, symfile);
+						if let Err(message) =
+							test.calling_convention.check(&test.args, test.returns)
+						{
+							problems.push((Some(String::from("args")), message));
+						}
+						for (problem_key, message) in problems {
+							// Problems in a data file are reported at the `data` key
+							let (problem_key, message) = match &row_name {
+								Some(row_name) => {
+									(Some("data"), format!("row {row_name}: {message}"))
+								}
+								None => (problem_key.as_deref(), message),
+							};
+							self.diagnostics
+								.push(Diagnostic::error(message, locate(problem_key)));
+						}
+						if test.overlay.is_some() {
+							overlay_spans.push((self.tests.len(), locate(Some("overlay"))));
+						}
+						if test.after.is_some() {
+							after_spans.push((self.tests.len(), locate(Some("after"))));
+						}
+						self.tests.push(test);
+					}
+				}
+				value => {
+					let span = locate(None);
+					let value = resolve_sections(&key, value, options, symfile);
+					let table = toml::Table::from_iter([(key.clone(), value)]);
+					global_config.inputs.append(&mut inputs);
+					for (_, message) in apply_table(&mut global_config, table, symfile) {
+						self.diagnostics
+							.push(Diagnostic::error(message, span.clone()));
+					}
+				}
+			}
+		}
+
+		// The setup is read last, since JSON and YAML configs may set global options after it.
+		// Tests written before the setup use it too.
+		let setup =
+			setup.map(|setup| self.read_setup(setup, &global_config, spans, options, symfile));
+		if let Some(setup) = setup {
+			for test in &mut self.tests[first_test..] {
+				test.setup = Some(Arc::clone(&setup));
+			}
+		}
+
+		// Overlays may be declared after the tests which use them, so they are resolved last
+		self.resolve_overlays(&overlays, overlay_spans);
+		self.check_after(first_test, after_spans);
+	}
+
+	/// Takes the `setup` table, if any, checking that it is a table and resolving its C names,
+	/// so that it can be read once the global options are known.
+	fn setup_table(
+		&mut self,
+		table: &mut toml::Table,
+		spans: &Spans<'_>,
+		options: &Options,
+	) -> Option<toml::Table> {
+		let setup = table.remove(SETUP)?;
+		let mut problems = Problems::new();
+		let setup = interpolate(setup, options, None, &mut problems);
+		let setup = match resolve_c_names(setup, options, &mut problems) {
+			toml::Value::Table(table) if problems.is_empty() => Some(table),
+			toml::Value::Table(_) => None,
+			_ => {
+				problems.push((None, format!("`{SETUP}` must be a table.")));
+				None
+			}
+		};
+		for (problem_key, message) in problems {
+			self.diagnostics.push(Diagnostic::error(
+				message,
+				spans.find(SETUP, problem_key.as_deref()),
+			));
+		}
+		setup
+	}
+
+	/// Reads the `setup` table, which runs once, starting from the file's global options.
+	fn read_setup(
+		&mut self,
+		setup: toml::Table,
+		global_config: &TestConfig,
+		spans: &Spans<'_>,
+		options: &Options,
+		symfile: &Symbols,
+	) -> Arc<TestConfig> {
+		let mut test = global_config.clone();
+		test.name = String::from(SETUP);
+		for (key, value) in &setup {
+			collect_inputs(key, value, &mut test.inputs);
+		}
+		let setup = sections_in(setup, options, symfile);
+		for (_, message) in apply_table(&mut test, setup, symfile) {
+			self.diagnostics
+				.push(Diagnostic::error(message, spans.find(SETUP, None)));
+		}
+		Arc::new(test)
+	}
+
+	/// Takes the `evunit` table, if any, which holds the `charmap` and `overlays` shared by every test in the file.
+	/// The global charmap is applied to `global_config` straight away.
+	fn read_settings(
+		&mut self,
+		table: &mut toml::Table,
+		spans: &Spans<'_>,
+		options: &Options,
+		symfile: &Symbols,
+		global_config: &mut TestConfig,
+		overlays: &mut HashMap<String, String>,
+	) {
+		let Some(settings) = table.remove(SETTINGS) else {
+			return;
+		};
+		let locate = |inner: Option<&str>| spans.find(SETTINGS, inner);
+		let mut problems = Problems::new();
+		let settings = interpolate(settings, options, None, &mut problems);
+		let toml::Value::Table(settings) = settings else {
+			let message = format!(
+				"`{SETTINGS}` is reserved for settings shared by every test, and must be a table."
+			);
+			self.diagnostics
+				.push(Diagnostic::error(message, locate(None)));
+			return;
+		};
+		for (name, value) in settings {
+			match (name.as_str(), value) {
+				("charmap", charmap) => {
+					collect_inputs(&name, &charmap, &mut global_config.inputs);
+					let table = toml::Table::from_iter([(name.clone(), charmap)]);
+					problems.extend(apply_table(global_config, table, symfile));
+				}
+				("overlays", toml::Value::Table(table)) => {
+					// Maps overlay names to the ROMs they use
+					for (overlay, path) in table {
+						if let toml::Value::String(path) = path {
+							overlays.insert(overlay, path);
+						} else {
+							let message = format!("Path of overlay `{overlay}` must be a string.");
+							problems.push((Some(name.clone()), message));
+						}
+					}
+				}
+				("overlays", _) => {
+					problems.push((Some(name), String::from("`overlays` must be a table.")));
+				}
+				_ => {
+					let message = format!(
+						"Unknown setting `{SETTINGS}.{name}`. The `{SETTINGS}` table is reserved for `charmap` and `overlays`, so it can't be the name of a test."
+					);
+					problems.push((Some(name), message));
+				}
+			}
+		}
+		for (problem_key, message) in problems {
+			self.diagnostics
+				.push(Diagnostic::error(message, locate(problem_key.as_deref())));
+		}
+	}
+
+	/// Reports tests which run `after` a test that isn't in the same file, or which depend on themselves.
+	fn check_after(&mut self, first_test: usize, after_spans: Vec<(usize, Option<Range<usize>>)>) {
+		let tests = &self.tests[first_test..];
+		let find = |name: &str| tests.iter().find(|test| test.name == name);
+		for (index, span) in after_spans {
+			let test = &self.tests[index];
+			let Some(after) = &test.after else {
+				continue;
+			};
+			let message = if find(after).is_none() {
+				format!(
+					"Test \"{after}\" used by `after` in {} is not in this file.",
+					test.name
+				)
+			} else if iter::successors(Some(test), |test| find(test.after.as_ref()?))
+				.skip(1)
+				.take(tests.len())
+				.any(|other| other.name == test.name)
+			{
+				format!("`after` in {} forms a cycle.", test.name)
+			} else {
+				continue;
+			};
+			self.diagnostics.push(Diagnostic::error(message, span));
+		}
+	}
+
+	/// Replaces the name of each test's overlay with the path it was declared with.
+	fn resolve_overlays(
+		&mut self,
+		overlays: &HashMap<String, String>,
+		overlay_spans: Vec<(usize, Option<Range<usize>>)>,
+	) {
+		for (index, span) in overlay_spans {
+			let test = &mut self.tests[index];
+			if let Some(name) = test.overlay.take() {
+				if let Some(path) = overlays.get(&name) {
+					test.overlay = Some(path.clone());
+				} else {
+					let message =
+						format!("Overlay \"{name}\" used by {} is not declared.", test.name);
+					self.diagnostics.push(Diagnostic::error(message, span));
+				}
+			}
+		}
+	}
+}
+
+/// Replaces the names of C variables in a test, its `result`, and each of its `checkpoints` and `steps`
+/// with their addresses, writing values to match the variables' widths.
+fn resolve_c_names(value: toml::Value, options: &Options, problems: &mut Problems) -> toml::Value {
+	let Some(info) = &options.debug_info else {
+		return value;
+	};
+	let toml::Value::Table(table) = value else {
+		return value;
+	};
+	let mut resolved = toml::Table::new();
+	for (key, value) in table {
+		match (key.as_str(), value) {
+			("result", toml::Value::Table(result)) => {
+				let result = c_names_in(result, "result", info, problems);
+				resolved.insert(key, toml::Value::Table(result));
+			}
+			("checkpoints", toml::Value::Table(checkpoints)) => {
+				let checkpoints = checkpoints
+					.into_iter()
+					.map(|(name, value)| match value {
+						toml::Value::Table(expected) => {
+							let expected = c_names_in(expected, "checkpoints", info, problems);
+							(name, toml::Value::Table(expected))
+						}
+						value => (name, value),
+					})
+					.collect();
+				resolved.insert(key, toml::Value::Table(checkpoints));
+			}
+			("steps", toml::Value::Array(steps)) => {
+				let steps = steps
+					.into_iter()
+					.map(|step| resolve_c_names(step, options, problems))
+					.collect();
+				resolved.insert(key, toml::Value::Array(steps));
+			}
+			(_, value) if is_known_key(&key) => {
+				resolved.insert(key, value);
+			}
+			(_, value) => resolved.extend(c_name(&key, value, &key, info, problems)),
+		}
+	}
+	toml::Value::Table(resolved)
+}
+
+/// The top-level table holding settings shared by every test in a file, rather than a test.
+const SETTINGS: &str = "evunit";
+
+/// The top-level table holding the routine which runs once before every test in a file, rather than a test.
+const SETUP: &str = "setup";
+
+/// Options which list regions of memory, and may name whole sections of a map file.
+const REGION_KEYS: [&str; 4] = ["forbid-read", "forbid-write", "forbid-exec", "snapshot"];
+
+/// Replaces the names of sections in a region option with their address and size,
+/// turning the list into a table such as `{ "0xC000" = 256 }`.
+/// Symbols take priority over sections with the same name.
+fn resolve_sections(
+	key: &str,
+	value: toml::Value,
+	options: &Options,
+	symfile: &Symbols,
+) -> toml::Value {
+	let Some(map) = &options.map else {
+		return value;
+	};
+	if !REGION_KEYS.contains(&key) {
+		return value;
+	}
+	let entries = match value {
+		toml::Value::Array(entries) => entries,
+		toml::Value::String(_) => vec![value],
+		value => return value,
+	};
+	let is_section = |name: &str| !symfile.contains_key(name) && map.named(name).next().is_some();
+	if !entries
+		.iter()
+		.any(|entry| entry.as_str().is_some_and(is_section))
+	{
+		return toml::Value::Array(entries);
+	}
+	let mut regions = toml::Table::new();
+	for entry in entries {
+		match entry {
+			toml::Value::String(name) if is_section(&name) => {
+				for section in map.named(&name) {
+					let start = format!("0x{:04X}", section.start);
+					regions.insert(start, toml::Value::Integer(section.size.into()));
+				}
+			}
+			toml::Value::String(name) => {
+				regions.insert(name, toml::Value::Integer(1));
+			}
+			entry => {
+				regions.insert(entry.to_string(), toml::Value::Integer(1));
+			}
+		}
+	}
+	toml::Value::Table(regions)
+}
+
+/// Replaces the names of sections in each of a test's region options.
+fn sections_in(table: toml::Table, options: &Options, symfile: &Symbols) -> toml::Table {
+	table
+		.into_iter()
+		.map(|(key, value)| {
+			let value = resolve_sections(&key, value, options, symfile);
+			(key, value)
+		})
+		.collect()
+}
+
+/// Replaces the names of C variables in a table of registers and memory, such as a `result`.
+fn c_names_in(
+	table: toml::Table,
+	location: &str,
+	info: &DebugInfo,
+	problems: &mut Problems,
+) -> toml::Table {
+	let mut resolved = toml::Table::new();
+	for (key, value) in table {
+		if is_known_key(&key) {
+			resolved.insert(key, value);
+		} else {
+			resolved.extend(c_name(&key, value, location, info, problems));
+		}
+	}
+	resolved
+}
+
+/// Replaces a C variable, member, or array element with its address,
+/// expanding tables of members such as `player = { hp = 3, x = 10 }`.
+/// Keys which don't name a variable are returned unchanged.
+fn c_name(
+	path: &str,
+	value: toml::Value,
+	location: &str,
+	info: &DebugInfo,
+	problems: &mut Problems,
+) -> Vec<(String, toml::Value)> {
+	let (address, ty) = match info.resolve(path) {
+		Some(Ok((_, address, ty))) => (address, ty),
+		Some(Err(message)) => {
+			problems.push((Some(String::from(location)), message));
+			return Vec::new();
+		}
+		None => return vec![(String::from(path), value)],
+	};
+	let key = format!("[0x{address:04X}]");
+	match (ty, value) {
+		(CType::Struct { .. }, toml::Value::Table(members)) => members
+			.into_iter()
+			.flat_map(|(member, value)| {
+				c_name(&format!("{path}.{member}"), value, location, info, problems)
+			})
+			.collect(),
+		(CType::Integer { size: 2, .. } | CType::Pointer, toml::Value::Integer(value)) => {
+			let value = toml::Table::from_iter([(String::from("u16le"), value.into())]);
+			vec![(key, toml::Value::Table(value))]
+		}
+		(CType::Integer { size: 4, .. }, toml::Value::Integer(value)) => {
+			let bytes = value.to_le_bytes()[..4]
+				.iter()
+				.map(|byte| toml::Value::Integer((*byte).into()))
+				.collect();
+			vec![(key, toml::Value::Array(bytes))]
+		}
+		(_, value) => vec![(key, value)],
+	}
+}
+
+/// Every key which configures a test, rather than naming memory or a C variable.
+/// Besides the fields of `RegisterOptions` and `TestOptions`, this includes the keys taken from a test, its `result`, or a step before they are read.
+const OPTION_KEYS: [&str; 63] = [
+	"a",
+	"b",
+	"c",
+	"d",
+	"e",
+	"h",
+	"l",
+	"f.z",
+	"f.n",
+	"f.h",
+	"f.c",
+	"ime",
+	"f",
+	"af",
+	"bc",
+	"de",
+	"hl",
+	"pc",
+	"sp",
+	"caller",
+	"crash",
+	"exit",
+	"enable-breakpoints",
+	"timeout",
+	"preset",
+	"initial-state",
+	"after",
+	"result",
+	"checkpoints",
+	"steps",
+	"invariants",
+	"script",
+	"watch",
+	"stack",
+	"args",
+	"calling-convention",
+	"stack-check",
+	"bank",
+	"bank-check",
+	"ram-fill",
+	"trampolines",
+	"poison",
+	"forbid-read",
+	"forbid-write",
+	"forbid-exec",
+	"snapshot",
+	"strict-rom",
+	"accurate-halt",
+	"rom",
+	"overlay",
+	"patch",
+	"tags",
+	"expect-failure",
+	"skip",
+	"dump",
+	"charmap",
+	"cycles",
+	"cycles-min",
+	"cycles-max",
+	"calls",
+	"return",
+	"name",
+	"data",
+];
+
+/// Whether `key` is an option, register, or memory address, rather than a name which may refer to a C variable.
+fn is_known_key(key: &str) -> bool {
+	key.starts_with('[') || OPTION_KEYS.contains(&key)
+}
+
+/// Returns the byte offset of the start of a 1-based line number.
+fn line_offset(text: &str, line: usize) -> usize {
+	text.split_inclusive('\n')
+		.take(line.saturating_sub(1))
+		.map(str::len)
+		.sum()
+}
+
+/// Removes the "at line L column C" suffix which JSON and YAML errors add, since diagnostics show it already.
+fn strip_location(msg: &impl fmt::Display) -> String {
+	let msg = msg.to_string();
+	match msg.rsplit_once(" at line ") {
+		Some((message, _)) => String::from(message),
+		None => msg,
+	}
+}
+
+/// Finds where keys were written in the original file, which is only known for TOML.
+struct Spans<'a>(Option<toml_edit::ImDocument<&'a str>>);
+
+impl Spans<'_> {
+	/// Returns the span of `key` within the top-level `table`, or of `table` itself if `key` is not found.
+	fn find(&self, table: &str, key: Option<&str>) -> Option<Range<usize>> {
+		let root = self.0.as_ref()?.as_table();
+		key.and_then(|key| root.get(table)?.as_table_like()?.get_key_value(key))
+			.and_then(|(key, _)| key.span())
+			.or_else(|| root.key(table)?.span())
+	}
+}
+
+/// Registers and memory, shared by a test's initial state and its `result`.
+#[derive(Default, Deserialize)]
+struct RegisterOptions {
+	#[serde(default, deserialize_with = "byte")]
+	a: Option<u8>,
+	#[serde(default, deserialize_with = "byte")]
+	b: Option<u8>,
+	#[serde(default, deserialize_with = "byte")]
+	c: Option<u8>,
+	#[serde(default, deserialize_with = "byte")]
+	d: Option<u8>,
+	#[serde(default, deserialize_with = "byte")]
+	e: Option<u8>,
+	#[serde(default, deserialize_with = "byte")]
+	h: Option<u8>,
+	#[serde(default, deserialize_with = "byte")]
+	l: Option<u8>,
+	#[serde(rename = "f.z")]
+	zf: Option<bool>,
+	#[serde(rename = "f.n")]
+	nf: Option<bool>,
+	#[serde(rename = "f.h")]
+	hf: Option<bool>,
+	#[serde(rename = "f.c")]
+	cf: Option<bool>,
+	ime: Option<bool>,
+	/// All of the flags at once, or some of them with a mask.
+	f: Option<toml::Value>,
+	af: Option<Address>,
+	bc: Option<Address>,
+	de: Option<Address>,
+	hl: Option<Address>,
+	pc: Option<Address>,
+	sp: Option<Address>,
+	/// Memory assignments such as `[wCount]`, and any unknown keys.
+	#[serde(flatten)]
+	other: toml::Table,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TestOptions {
+	#[serde(flatten)]
+	registers: RegisterOptions,
+	caller: Option<Address>,
+	#[serde(default, deserialize_with = "one_or_many")]
+	crash: Vec<Address>,
+	#[serde(default, deserialize_with = "one_or_many")]
+	exit: Vec<Address>,
+	enable_breakpoints: Option<bool>,
+	timeout: Option<u64>,
+	preset: Option<Model>,
+	initial_state: Option<String>,
+	after: Option<String>,
+	result: Option<toml::Table>,
+	checkpoints: Option<toml::Table>,
+	steps: Option<Vec<toml::Table>>,
+	#[serde(default, deserialize_with = "one_or_many")]
+	invariants: Vec<String>,
+	#[serde(default, deserialize_with = "one_or_many")]
+	script: Vec<String>,
+	#[serde(default, deserialize_with = "one_or_many")]
+	watch: Vec<Address>,
+	stack: Option<toml::Value>,
+	args: Option<Vec<ArgOption>>,
+	calling_convention: Option<ConventionOption>,
+	stack_check: Option<Policy>,
+	#[serde(default, deserialize_with = "bank")]
+	bank: Option<usize>,
+	bank_check: Option<Policy>,
+	#[serde(default, deserialize_with = "ram_fill")]
+	ram_fill: Option<RamFill>,
+	#[serde(default, deserialize_with = "one_or_many")]
+	trampolines: Vec<Address>,
+	poison: Option<toml::Table>,
+	forbid_read: Option<toml::Value>,
+	forbid_write: Option<toml::Value>,
+	forbid_exec: Option<toml::Value>,
+	snapshot: Option<toml::Value>,
+	strict_rom: Option<bool>,
+	accurate_halt: Option<bool>,
+	rom: Option<String>,
+	overlay: Option<String>,
+	#[serde(default, deserialize_with = "one_or_many")]
+	patch: Vec<String>,
+	#[serde(default, deserialize_with = "one_or_many")]
+	tags: Vec<String>,
+	expect_failure: Option<bool>,
+	skip: Option<Skip>,
+	#[serde(default, deserialize_with = "dump")]
+	dump: Option<Dump>,
+}
+
+/// A 16-bit value given as a number or the name of a symbol.
+enum Address {
+	Number(u16),
+	Symbol(String),
+}
+
+impl<'de> Deserialize<'de> for Address {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		struct AddressVisitor;
+
+		impl Visitor<'_> for AddressVisitor {
+			type Value = Address;
+
+			fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+				write!(f, "a 16-bit integer or a symbol")
+			}
+
+			fn visit_i64<E: de::Error>(self, value: i64) -> Result<Address, E> {
+				if (-0x8000..0x10000).contains(&value) {
+					Ok(Address::Number(low_word(value)))
+				} else {
+					Err(E::invalid_value(Unexpected::Signed(value), &self))
+				}
+			}
+
+			fn visit_u64<E: de::Error>(self, value: u64) -> Result<Address, E> {
+				u16::try_from(value)
+					.map(Address::Number)
+					.map_err(|_| E::invalid_value(Unexpected::Unsigned(value), &self))
+			}
+
+			fn visit_str<E: de::Error>(self, value: &str) -> Result<Address, E> {
+				Ok(Address::Symbol(String::from(value)))
+			}
+		}
+
+		deserializer.deserialize_any(AddressVisitor)
+	}
+}
+
+/// An argument or return value: a number or symbol, whose width is guessed,
+/// or a value with its width given, such as `{ u16 = 3 }`.
+#[derive(Deserialize)]
+#[serde(
+	untagged,
+	expecting = "a 16-bit integer, a symbol, or a table such as `{ u8 = 3 }`"
+)]
+enum ArgOption {
+	Inferred(Address),
+	Byte { u8: Address },
+	Word { u16: Address },
+}
+
+/// `calling-convention = "stack"`, or a custom mapping such as `{ args = ["a", "[hArg]"], return = "hl" }`.
+#[derive(Deserialize)]
+#[serde(
+	untagged,
+	expecting = "\"registers\", \"stack\", or a table of `args` and `return`"
+)]
+enum ConventionOption {
+	Name(String),
+	Custom {
+		#[serde(default)]
+		args: Vec<String>,
+		#[serde(rename = "return")]
+		returns: String,
+	},
+}
+
+/// `skip = true`, or a reason for skipping.
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "a boolean or a reason")]
+enum Skip {
+	Flag(bool),
+	Reason(String),
+}
+
+/// Truncates a value which may be negative, wrapping around like in assembly.
+fn low_byte(value: i64) -> u8 {
+	value.to_le_bytes()[0]
+}
+
+fn low_word(value: i64) -> u16 {
+	let [low, high, ..] = value.to_le_bytes();
+	u16::from_le_bytes([low, high])
+}
+
+/// Reads an integer from -128 to 255 as a byte.
+fn byte<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u8>, D::Error> {
+	let value = i64::deserialize(deserializer)?;
+	if (-0x80..0x100).contains(&value) {
+		Ok(Some(low_byte(value)))
+	} else {
+		Err(de::Error::invalid_value(
+			Unexpected::Signed(value),
+			&"an 8-bit integer",
+		))
+	}
+}
+
+fn bank<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<usize>, D::Error> {
+	let value = i64::deserialize(deserializer)?;
+	match usize::try_from(value) {
+		Ok(bank @ 0..=0x1FF) => Ok(Some(bank)),
+		_ => Err(de::Error::invalid_value(
+			Unexpected::Signed(value),
+			&"a ROM bank number",
+		)),
+	}
+}
+
+/// Reads the initial contents of RAM: a byte, `"random"`, or `{ random = SEED }`.
+/// Without a seed, one is chosen now so that it can be reported.
+fn ram_fill<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<RamFill>, D::Error> {
+	match toml::Value::deserialize(deserializer)? {
+		toml::Value::Integer(byte @ -0x80..=0xFF) => Ok(Some(RamFill::Byte(low_byte(byte)))),
+		// Seeds are kept below 2^63 so that a reported seed can be written back into a config.
+		toml::Value::String(random) if random == "random" => {
+			Ok(Some(RamFill::Random(fastrand::u64(..1 << 63))))
+		}
+		toml::Value::Table(table) if table.len() == 1 => match table.get("random") {
+			Some(toml::Value::Integer(seed @ 0..)) => {
+				Ok(Some(RamFill::Random(seed.unsigned_abs())))
+			}
+			_ => Err(de::Error::custom("the seed must be a non-negative integer")),
+		},
+		_ => Err(de::Error::custom(
+			"expected an 8-bit integer, \"random\", or a table with a `random` seed",
+		)),
+	}
+}
+
+/// Reads `dump`, which is either a boolean or `"always"`.
+fn dump<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Dump>, D::Error> {
+	match toml::Value::deserialize(deserializer)? {
+		toml::Value::Boolean(true) => Ok(Some(Dump::OnFailure)),
+		toml::Value::Boolean(false) => Ok(Some(Dump::Never)),
+		toml::Value::String(always) if always == "always" => Ok(Some(Dump::Always)),
+		_ => Err(de::Error::custom("expected true, false, or \"always\"")),
+	}
+}
+
+/// Reads either a single value or an array of them.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+	D: Deserializer<'de>,
+	T: Deserialize<'de>,
+{
+	struct OneOrMany<T>(PhantomData<T>);
+
+	impl<'de, T: Deserialize<'de>> Visitor<'de> for OneOrMany<T> {
+		type Value = Vec<T>;
+
+		fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+			write!(f, "a value or an array of values")
+		}
+
+		fn visit_i64<E: de::Error>(self, value: i64) -> Result<Vec<T>, E> {
+			T::deserialize(value.into_deserializer()).map(|value| vec![value])
+		}
+
+		fn visit_u64<E: de::Error>(self, value: u64) -> Result<Vec<T>, E> {
+			T::deserialize(value.into_deserializer()).map(|value| vec![value])
+		}
+
+		fn visit_str<E: de::Error>(self, value: &str) -> Result<Vec<T>, E> {
+			T::deserialize(value.into_deserializer()).map(|value| vec![value])
+		}
+
+		fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<T>, A::Error> {
+			let mut values = Vec::new();
+			while let Some(value) = seq.next_element()? {
+				values.push(value);
+			}
+			Ok(values)
+		}
+	}
+
+	deserializer.deserialize_any(OneOrMany(PhantomData))
+}
+
+/// Each problem found in a table, along with the key it was found in when known.
+type Problems = Vec<(Option<String>, String)>;
+
+/// Applies a table of options to `test`.
+fn apply_table(test: &mut TestConfig, mut table: toml::Table, symfile: &Symbols) -> Problems {
+	let mut problems = Vec::new();
+	// Strings are encoded as they're read, so the charmap must come first.
+	if let Some(charmap) = table.remove("charmap") {
+		if let Some(charmap) = parse_charmap(charmap, &mut problems) {
+			test.charmap = Some(Arc::new(charmap));
+		}
+	}
+	let symfile = match scope(&table, test, symfile) {
+		Some(parent) => Cow::Owned(with_locals(symfile, &parent)),
+		None => Cow::Borrowed(symfile),
+	};
+	let banked = banked_symbols(&table, &symfile);
+	let sets_bank = table.contains_key("bank");
+	for options in deserialize_each::<TestOptions>(table, None, &mut problems) {
+		apply_options(test, options, &symfile, &mut problems);
+	}
+	apply_banks(test, banked, sets_bank, &mut problems);
+	problems
+}
+
+/// The symbols named by `pc`, `exit`, and `crash` which are in a switchable ROM bank, as (key, name, bank).
+fn banked_symbols(table: &toml::Table, symfile: &Symbols) -> Vec<(&'static str, String, usize)> {
+	let mut banked = Vec::new();
+	for key in ["pc", "exit", "crash"] {
+		let names = match table.get(key) {
+			Some(toml::Value::String(name)) => vec![name.as_str()],
+			Some(toml::Value::Array(names)) => names
+				.iter()
+				.filter_map(|name| name.as_str())
+				.collect::<Vec<_>>(),
+			_ => continue,
+		};
+		for name in names {
+			// Symbols in 0x4000-0x7FFF which claim bank 0 don't say which bank they're in.
+			if let Some((bank @ 1.., 0x4000..=0x7FFF)) = symfile.get(name) {
+				if let Ok(bank) = usize::try_from(*bank) {
+					banked.push((key, String::from(name), bank));
+				}
+			}
+		}
+	}
+	banked
+}
+
+/// Maps the bank containing `pc`'s symbol when the test begins, and checks that the symbols named by `exit`
+/// and `crash` are in that bank, since only their addresses are compared.
+///
+/// A `bank` set alongside `pc` must agree with it. Tests with trampolines may switch banks,
+/// so their `exit` and `crash` symbols aren't checked.
+fn apply_banks(
+	test: &mut TestConfig,
+	banked: Vec<(&'static str, String, usize)>,
+	sets_bank: bool,
+	problems: &mut Problems,
+) {
+	for (key, name, bank) in &banked {
+		if *key != "pc" {
+			continue;
+		}
+		match test.bank {
+			Some(mapped) if sets_bank && mapped != *bank => problems.push((
+				Some(String::from("bank")),
+				format!("`pc` is set to {name}, in bank {bank}, but `bank` is {mapped}"),
+			)),
+			_ => test.bank = Some(*bank),
+		}
+	}
+	if !test.trampolines.is_empty() {
+		return;
+	}
+	let mapped = test.bank.unwrap_or(1);
+	for (key, name, bank) in banked {
+		if key != "pc" && bank != mapped {
+			problems.push((
+				Some(String::from(key)),
+				format!("`{key}` names {name}, in bank {bank}, but the test runs in bank {mapped}"),
+			));
+		}
+	}
+}
+
+/// The global label whose local labels, such as `.loop`, a test may name without their parent:
+/// the label its `pc` is set to, or the parent of a local label it is set to.
+fn scope(table: &toml::Table, test: &TestConfig, symfile: &Symbols) -> Option<String> {
+	let pc = match table.get("pc") {
+		Some(toml::Value::String(pc)) => pc.as_str(),
+		Some(_) => return None,
+		// Inherited from the global config, so only its address is known
+		None => {
+			let pc = test.initial.pc?;
+			symfile
+				.iter()
+				.filter(|(name, (_, address))| *address == pc && !name.contains('.'))
+				.map(|(name, _)| name.as_str())
+				.min()?
+		}
+	};
+	let parent = pc.split_once('.').map_or(pc, |(parent, _)| parent);
+	let prefix = format!("{parent}.");
+	symfile
+		.keys()
+		.any(|name| name.starts_with(&prefix))
+		.then(|| String::from(parent))
+}
+
+/// Adds each local label of `parent` under its short name, such as `.loop` for `Parent.loop`.
+fn with_locals(symfile: &Symbols, parent: &str) -> Symbols {
+	let mut scoped = symfile.clone();
+	for (name, location) in symfile {
+		if let Some(local) = name
+			.strip_prefix(parent)
+			.filter(|local| local.starts_with('.'))
+		{
+			scoped.entry(String::from(local)).or_insert(*location);
+		}
+	}
+	scoped
+}
+
+/// Deserializes each key of `table` on its own, so that one invalid value doesn't hide problems
+/// with the others.
+///
+/// Problems are recorded under `parent` if given, or under each key of the table otherwise.
+fn deserialize_each<T: DeserializeOwned>(
+	table: toml::Table,
+	parent: Option<&str>,
+	problems: &mut Problems,
+) -> Vec<T> {
+	table
+		.into_iter()
+		.filter_map(|(key, value)| {
+			let single = toml::Table::from_iter([(key.clone(), value)]);
+			T::deserialize(toml::Value::Table(single))
+				.map_err(|msg| {
+					let (location, name) = match parent {
+						Some(parent) => (String::from(parent), format!("{parent}.{key}")),
+						None => (key.clone(), key),
+					};
+					problems.push((
+						Some(location),
+						format!("Invalid value for `{name}`: {}", msg.message()),
+					));
+				})
+				.ok()
+		})
+		.collect()
+}
+
+/// Reads regions of memory to poison, which must be in RAM.
+fn parse_poison(
+	key: &str,
+	poison: toml::Table,
+	symfile: &Symbols,
+	problems: &mut Problems,
+) -> Vec<(u16, u16)> {
+	let regions = parse_regions(key, toml::Value::Table(poison), symfile, problems);
+	regions
+		.into_iter()
+		.filter(|(address, length)| {
+			let in_ram = (0..*length).all(|offset| memory::is_ram(address.wrapping_add(offset)));
+			if !in_ram {
+				problems.push((
+					Some(String::from(key)),
+					format!(
+						"Poisoned region at 0x{address:04x} must be in VRAM, SRAM, WRAM, or HRAM"
+					),
+				));
+			}
+			in_ram
+		})
+		.collect()
+}
+
+/// Reads regions of memory as (address, length), from either a table of lengths such as
+/// `{ wScratch = 16 }`, or a list of single addresses or labels.
+fn parse_regions(
+	key: &str,
+	value: toml::Value,
+	symfile: &Symbols,
+	problems: &mut Problems,
+) -> Vec<(u16, u16)> {
+	let mut regions = Vec::new();
+	match value {
+		toml::Value::Table(table) => {
+			for (name, length) in table {
+				let length = length
+					.as_integer()
+					.and_then(|length| u16::try_from(length).ok())
+					.filter(|length| *length != 0);
+				match (parse_address(&name, symfile), length) {
+					(Some(address), Some(length)) => regions.push((address, length)),
+					(None, _) => problems.push((
+						Some(String::from(key)),
+						format!("Address \"{name}\" is not a valid address"),
+					)),
+					(_, None) => problems.push((
+						Some(String::from(key)),
+						format!(
+							"Length of region {name} in `{key}` must be a positive 16-bit integer"
+						),
+					)),
+				}
+			}
+		}
+		value => {
+			let addresses = match value {
+				toml::Value::Array(addresses) => addresses,
+				address => vec![address],
+			};
+			for address in addresses {
+				match Address::deserialize(address) {
+					Ok(address) => {
+						if let Some(address) = resolve(key, address, symfile, problems) {
+							regions.push((address, 1));
+						}
+					}
+					Err(msg) => problems.push((
+						Some(String::from(key)),
+						format!("Invalid value for `{key}`: {}", msg.message()),
+					)),
+				}
+			}
+		}
+	}
+	regions
+}
+
+/// Looks up `address` if it is a symbol, recording a problem in `key` if it does not exist.
+fn resolve(key: &str, address: Address, symfile: &Symbols, problems: &mut Problems) -> Option<u16> {
+	match address {
+		Address::Number(address) => Some(address),
+		Address::Symbol(symbol) => {
+			let address = lookup(&symbol, symfile);
+			if address.is_none() {
+				problems.push((
+					Some(String::from(key)),
+					format!("Symbol \"{symbol}\" not found."),
+				));
+			}
+			address
+		}
+	}
+}
+
+/// Resolves an argument or return value, guessing its width if it wasn't given.
+fn resolve_arg(
+	key: &str,
+	arg: ArgOption,
+	symfile: &Symbols,
+	problems: &mut Problems,
+) -> Option<Arg> {
+	match arg {
+		ArgOption::Inferred(address) => {
+			let is_symbol = matches!(address, Address::Symbol(_));
+			resolve(key, address, symfile, problems).map(|value| Arg::infer(value, is_symbol))
+		}
+		ArgOption::Byte { u8: address } => {
+			let value = resolve(key, address, symfile, problems)?;
+			let byte = u8::try_from(value).map_err(|_| {
+				let message = format!("0x{value:X} is not an 8-bit value");
+				problems.push((Some(String::from(key)), message));
+			});
+			byte.ok().map(Arg::byte)
+		}
+		ArgOption::Word { u16: address } => resolve(key, address, symfile, problems).map(Arg::word),
+	}
+}
+
+fn apply_options(
+	test: &mut TestConfig,
+	mut options: TestOptions,
+	symfile: &Symbols,
+	problems: &mut Problems,
+) {
+	apply_args(test, &mut options, symfile, problems);
+	apply_memory_options(test, &mut options, symfile, problems);
+	let mut resolve_all = |key: &str, addresses: Vec<Address>| {
+		addresses
+			.into_iter()
+			.filter_map(|address| resolve(key, address, symfile, problems))
+			.collect::<Vec<u16>>()
+	};
+	test.crash_addresses
+		.extend(resolve_all("crash", options.crash));
+	test.exit_addresses
+		.extend(resolve_all("exit", options.exit));
+	test.trampolines
+		.extend(resolve_all("trampolines", options.trampolines));
+	test.watch.extend(resolve_all("watch", options.watch));
+	if let Some(caller) = options.caller {
+		if let Some(caller) = resolve("caller", caller, symfile, problems) {
+			test.caller_address = caller;
+		}
+	}
+
+	macro_rules! set {
+		($($option:ident => $field:ident),+) => { $(
+			if let Some(value) = options.$option {
+				test.$field = value;
+			}
+		)+ };
+	}
+	set!(
+		enable_breakpoints => enable_breakpoints,
+		timeout => timeout,
+		stack_check => stack_check,
+		bank_check => bank_check,
+		ram_fill => ram_fill,
+		strict_rom => strict_rom,
+		accurate_halt => accurate_halt,
+		expect_failure => expect_failure
+	);
+	let state = options
+		.initial_state
+		.and_then(|path| read_state(&path, problems));
+	if let Some(bank) = state.as_ref().and_then(|state| state.bank) {
+		test.bank = Some(bank);
+	}
+	// Unlike `set!`, these fields are optional themselves.
+	macro_rules! replace {
+		($($field:ident),+) => { $(
+			if options.$field.is_some() {
+				test.$field = options.$field;
+			}
+		)+ };
+	}
+	replace!(bank, rom, overlay, dump, after);
+	test.patches.extend(options.patch);
+	test.tags.extend(options.tags);
+	match options.skip {
+		Some(Skip::Flag(true)) => test.skip = Some(String::new()),
+		Some(Skip::Flag(false)) => test.skip = None,
+		Some(Skip::Reason(reason)) => test.skip = Some(reason),
+		None => {}
+	}
+
+	// A preset comes before the save state wherever either is written, since the state's registers replace it.
+	if let Some(model) = options.preset {
+		test.initial = mem::take(&mut test.initial).with_preset(model);
+	}
+	if let Some(state) = state {
+		apply_state(&mut test.initial, state.registers);
+	}
+	apply_registers(
+		&mut test.initial,
+		options.registers,
+		symfile,
+		test.charmap.as_ref(),
+		problems,
+	);
+	if let Some(result) = options.result {
+		apply_result(test, result, symfile, problems);
+	}
+	test.invariants
+		.extend(parse_invariants(options.invariants, symfile, problems));
+	apply_scripts(test, options.script, symfile, problems);
+	if let Some(checkpoints) = options.checkpoints {
+		test.checkpoints = parse_checkpoints(checkpoints, symfile, test.charmap.as_ref(), problems);
+	}
+	if let Some(steps) = options.steps {
+		test.steps = parse_steps(steps, symfile, test.charmap.as_ref(), problems);
+	}
+}
+
+/// Reads a test's `stack`, along with the options which list regions of memory.
+fn apply_memory_options(
+	test: &mut TestConfig,
+	options: &mut TestOptions,
+	symfile: &Symbols,
+	problems: &mut Problems,
+) {
+	if let Some(stack) = options.stack.take() {
+		match parse_memory("stack", &stack, test.charmap.as_ref()) {
+			Ok(data) => test.stack.extend(data),
+			Err(message) => problems.push((Some(String::from("stack")), message)),
+		}
+	}
+	if let Some(poison) = options.poison.take() {
+		test.poison
+			.extend(parse_poison("poison", poison, symfile, problems));
+	}
+	let TestOptions {
+		forbid_read,
+		forbid_write,
+		forbid_exec,
+		snapshot,
+		..
+	} = options;
+	let region_options = [
+		("forbid-read", forbid_read.take(), &mut test.forbid_read),
+		("forbid-write", forbid_write.take(), &mut test.forbid_write),
+		("forbid-exec", forbid_exec.take(), &mut test.forbid_exec),
+		("snapshot", snapshot.take(), &mut test.snapshot),
+	];
+	for (key, value, regions) in region_options {
+		if let Some(value) = value {
+			regions.extend(parse_regions(key, value, symfile, problems));
+		}
+	}
+}
+
+/// Reads a test's `invariants`, reporting any which are malformed.
+fn parse_invariants(
+	invariants: Vec<String>,
+	symfile: &Symbols,
+	problems: &mut Problems,
+) -> Vec<Invariant> {
+	invariants
+		.into_iter()
+		.filter_map(|invariant| {
+			parse_invariant(&invariant, symfile)
+				.map_err(|message| problems.push((Some(String::from("invariants")), message)))
+				.ok()
+		})
+		.collect()
+}
+
+/// Whether a `script` is the path to a `.rhai` file, rather than the script itself.
+fn is_script_path(script: &str) -> bool {
+	!script.contains('\n')
+		&& Path::new(script)
+			.extension()
+			.is_some_and(|extension| extension.eq_ignore_ascii_case("rhai"))
+}
+
+/// Adds the path of each file read by the option `key` to `inputs`, looking inside tables and arrays.
+/// These are the files which a test depends on besides its config, ROM, and patches.
+fn collect_inputs(key: &str, value: &toml::Value, inputs: &mut Vec<String>) {
+	match (key, value) {
+		("data" | "file" | "initial-state" | "charmap", toml::Value::String(path)) => {
+			inputs.push(path.clone());
+		}
+		("script", toml::Value::String(script)) if is_script_path(script) => {
+			inputs.push(script.clone());
+		}
+		(_, toml::Value::Table(table)) => {
+			for (key, value) in table {
+				collect_inputs(key, value, inputs);
+			}
+		}
+		(_, toml::Value::Array(values)) => {
+			for value in values {
+				collect_inputs(key, value, inputs);
+			}
+		}
+		_ => {}
+	}
+}
+
+/// Compiles a test's `script`s, each either the path to a `.rhai` file or the script itself,
+/// and adds them to its conditions.
+#[cfg(feature = "scripts")]
+fn apply_scripts(
+	test: &mut TestConfig,
+	scripts: Vec<String>,
+	symfile: &Symbols,
+	problems: &mut Problems,
+) {
+	let key = || Some(String::from("script"));
+	let scripts = scripts.into_iter().filter_map(|script| {
+		let (name, source) = if is_script_path(&script) {
+			let source = fs::read_to_string(&script)
+				.map_err(|msg| {
+					problems.push((key(), format!("Failed to read {script}: {msg}")));
+				})
+				.ok()?;
+			(script, source)
+		} else {
+			let name = match script.trim() {
+				line if !line.contains('\n') => format!("`{line}`"),
+				_ => String::from("script"),
+			};
+			(name, script)
+		};
+		Script::compile(name, &source, |word| parse_address(word, symfile))
+			.map_err(|message| problems.push((key(), message)))
+			.ok()
+	});
+	for script in scripts {
+		test.conditions.push(Arc::new(script));
+	}
+}
+
+#[cfg(not(feature = "scripts"))]
+// Takes the same arguments as with the feature.
+#[allow(clippy::needless_pass_by_value)]
+fn apply_scripts(
+	_test: &mut TestConfig,
+	scripts: Vec<String>,
+	_symfile: &Symbols,
+	problems: &mut Problems,
+) {
+	if !scripts.is_empty() {
+		let message = "Scripts require evunit to be built with the `scripts` feature";
+		problems.push((Some(String::from("script")), String::from(message)));
+	}
+}
+
+/// Reads `args`, and `calling-convention`, which is either the name of a built-in convention or a custom mapping.
+fn apply_args(
+	test: &mut TestConfig,
+	options: &mut TestOptions,
+	symfile: &Symbols,
+	problems: &mut Problems,
+) {
+	if let Some(args) = options.args.take() {
+		test.args = args
+			.into_iter()
+			.filter_map(|arg| resolve_arg("args", arg, symfile, problems))
+			.collect();
+	}
+	let Some(convention) = options.calling_convention.take() else {
+		return;
+	};
+	let convention = match convention {
+		ConventionOption::Name(name) => match name.as_str() {
+			"registers" => Ok(CallingConvention::registers()),
+			"stack" => Ok(CallingConvention::stack()),
+			"sdcc" => Ok(CallingConvention::Sdcc),
+			_ => Err(format!(
+				"Unknown calling convention \"{name}\"; expected \"registers\", \"stack\", \"sdcc\", or a table"
+			)),
+		},
+		ConventionOption::Custom { args, returns } => {
+			let location = |key: &String| {
+				parse_location(key, symfile)
+					.unwrap_or_else(|| Err(format!("\"{key}\" is not a register or address")))
+			};
+			args.iter()
+				.map(location)
+				.collect::<Result<_, _>>()
+				.and_then(|args| {
+					Ok(CallingConvention::Fixed {
+						args,
+						returns: location(&returns)?,
+					})
+				})
+		}
+	};
+	match convention {
+		Ok(convention) => test.calling_convention = convention,
+		Err(message) => problems.push((Some(String::from("calling-convention")), message)),
+	}
+}
+
+/// Reads the calls a test makes after its own, each setting registers and memory like a test
+/// and optionally expecting a `result`.
+fn parse_steps(
+	steps: Vec<toml::Table>,
+	symfile: &Symbols,
+	charmap: Option<&Arc<Charmap>>,
+	problems: &mut Problems,
+) -> Vec<TestStep> {
+	let location = || Some(String::from("steps"));
+	let mut parsed = Vec::new();
+	for (number, mut step) in (1..).zip(steps) {
+		let name = match step.remove("name") {
+			Some(toml::Value::String(name)) => name,
+			Some(_) => {
+				problems.push((
+					location(),
+					format!("Name of step {number} must be a string"),
+				));
+				continue;
+			}
+			None => format!("step {number}"),
+		};
+		let poison = match step.remove("poison") {
+			Some(toml::Value::Table(poison)) => parse_poison("steps", poison, symfile, problems),
+			Some(_) => {
+				problems.push((location(), format!("Poison of {name} must be a table")));
+				continue;
+			}
+			None => vec![],
+		};
+		let result = match step.remove("result") {
+			Some(toml::Value::Table(result)) => {
+				Some(parse_expected(result, "steps", symfile, charmap, problems))
+			}
+			Some(_) => {
+				problems.push((location(), format!("Result of {name} must be a table")));
+				continue;
+			}
+			None => None,
+		};
+		let mut initial = Registers::new();
+		let mut register_problems = Vec::new();
+		for options in deserialize_each::<RegisterOptions>(step, Some("steps"), problems) {
+			apply_registers(
+				&mut initial,
+				options,
+				symfile,
+				charmap,
+				&mut register_problems,
+			);
+		}
+		problems.extend(
+			register_problems
+				.into_iter()
+				.map(|(_, message)| (location(), format!("In {name}: {message}"))),
+		);
+		if initial.pc.is_none() {
+			problems.push((
+				location(),
+				format!("Missing `pc` for {name}, the routine it calls"),
+			));
+			continue;
+		}
+		parsed.push(TestStep {
+			name,
+			poison,
+			initial,
+			result,
+		});
+	}
+	parsed
+}
+
+/// Reads the states expected when a test reaches each address, such as `{ CopyDone = { a = 0 } }`.
+fn parse_checkpoints(
+	checkpoints: toml::Table,
+	symfile: &Symbols,
+	charmap: Option<&Arc<Charmap>>,
+	problems: &mut Problems,
+) -> Vec<(String, u16, Registers)> {
+	let mut parsed = Vec::new();
+	for (name, expected) in checkpoints {
+		let Some(address) = parse_address(&name, symfile) else {
+			problems.push((
+				Some(String::from("checkpoints")),
+				format!("Address \"{name}\" is not a valid address"),
+			));
+			continue;
+		};
+		let toml::Value::Table(expected) = expected else {
+			problems.push((
+				Some(String::from("checkpoints")),
+				format!("Checkpoint {name} must be a table of expected values"),
+			));
+			continue;
+		};
+		let parent = format!("checkpoints.{name}");
+		let registers = parse_expected(expected, &parent, symfile, charmap, problems);
+		parsed.push((name, address, registers));
+	}
+	parsed
+}
+
+/// Reads the registers and memory expected by a test's result or checkpoint.
+/// Problems are reported in the first part of `parent`, such as `result`.
+fn parse_expected(
+	table: toml::Table,
+	parent: &str,
+	symfile: &Symbols,
+	charmap: Option<&Arc<Charmap>>,
+	problems: &mut Problems,
+) -> Registers {
+	let location_key = parent.split('.').next().unwrap_or(parent);
+	let mut registers = Registers::new();
+	let (table, ranges) = take_ranges(table, parent, symfile, problems);
+	registers.ranges = ranges;
+	let (table, expressions) = take_expressions(table, parent, symfile, problems);
+	registers.expressions = expressions;
+	let mut register_problems = Vec::new();
+	for options in deserialize_each(table, Some(location_key), problems) {
+		apply_registers(
+			&mut registers,
+			options,
+			symfile,
+			charmap,
+			&mut register_problems,
+		);
+	}
+	problems.extend(
+		register_problems
+			.into_iter()
+			.map(|(_, message)| (Some(String::from(location_key)), message)),
+	);
+	registers
+}
+
+/// Removes `cycles`, `cycles-min`, and `cycles-max` from a test's result,
+/// returning the range of cycles the test must finish within.
+fn take_cycles(result: &mut toml::Table, problems: &mut Problems) -> Option<RangeInclusive<u64>> {
+	let mut take = |key: &str| {
+		let value = result.remove(key)?;
+		let cycles = value
+			.as_integer()
+			.and_then(|cycles| u64::try_from(cycles).ok());
+		if cycles.is_none() {
+			problems.push((
+				Some(String::from("result")),
+				format!("Value of `{key}` must be a positive integer"),
+			));
+		}
+		cycles
+	};
+	let exact = take("cycles");
+	let min = take("cycles-min");
+	let max = take("cycles-max");
+	if exact.is_none() && min.is_none() && max.is_none() {
+		return None;
+	}
+	let min = exact.or(min).unwrap_or(0);
+	let max = exact.or(max).unwrap_or(u64::MAX);
+	if min > max {
+		problems.push((
+			Some(String::from("result")),
+			format!("Minimum of {min} cycles is greater than the maximum of {max}"),
+		));
+	}
+	Some(min..=max)
+}
+
+/// Reads the number of times routines must be called, such as `{ Memcpy = 2, Panic = 0 }`.
+fn parse_calls(
+	calls: toml::Value,
+	symfile: &Symbols,
+	problems: &mut Problems,
+) -> Vec<(String, u16, u32)> {
+	let toml::Value::Table(calls) = calls else {
+		problems.push((
+			Some(String::from("result")),
+			String::from("Value of `calls` must be a table of routines and counts"),
+		));
+		return Vec::new();
+	};
+	let mut expected = Vec::new();
+	for (name, count) in calls {
+		let count = count
+			.as_integer()
+			.and_then(|count| u32::try_from(count).ok());
+		match (parse_address(&name, symfile), count) {
+			(Some(address), Some(count)) => expected.push((name, address, count)),
+			(None, _) => problems.push((
+				Some(String::from("result")),
+				format!("Address \"{name}\" is not a valid address"),
+			)),
+			(_, None) => problems.push((
+				Some(String::from("result")),
+				format!("Number of calls to {name} must be a positive integer"),
+			)),
+		}
+	}
+	expected
+}
+
+/// Reads a register, such as `a` or `hl`, or a byte of memory, such as `[wCount]`,
+/// or returns `None` if `key` is neither.
+pub(crate) fn parse_location(key: &str, symfile: &Symbols) -> Option<Result<Location, String>> {
+	Some(Ok(match key {
+		"a" => Location::A,
+		"b" => Location::B,
+		"c" => Location::C,
+		"d" => Location::D,
+		"e" => Location::E,
+		"h" => Location::H,
+		"l" => Location::L,
+		"bc" => Location::Bc,
+		"de" => Location::De,
+		"hl" => Location::Hl,
+		"pc" => Location::Pc,
+		"sp" => Location::Sp,
+		_ => {
+			let name = key.strip_prefix('[')?.strip_suffix(']')?;
+			return Some(
+				parse_address(name, symfile)
+					.map(Location::Address)
+					.ok_or_else(|| format!("Address \"{name}\" is not a valid address")),
+			);
+		}
+	}))
+}
+
+/// Reads an invariant such as `sp >= 0xDF00` or `[wLock] <= 1`.
+pub(crate) fn parse_invariant(text: &str, symfile: &Symbols) -> Result<Invariant, String> {
+	let (location, comparison, value) = Comparison::OPERATORS
+		.iter()
+		.find_map(|(operator, comparison)| {
+			let (location, value) = text.split_once(operator)?;
+			Some((location.trim(), *comparison, value.trim()))
+		})
+		.ok_or_else(|| {
+			format!("Invariant `{text}` must compare a register or memory with a value, such as `a <= 10`")
+		})?;
+	let location = parse_location(location, symfile).unwrap_or_else(|| {
+		Err(format!(
+			"`{location}` in invariant `{text}` is not a register or memory"
+		))
+	})?;
+	let number = parse_address(value, symfile)
+		.ok_or_else(|| format!("`{value}` in invariant `{text}` is not a number or symbol"))?;
+	if !location.is_word() && number > 0xFF {
+		return Err(format!(
+			"`{value}` in invariant `{text}` must be an 8-bit integer"
+		));
+	}
+	Ok(Invariant {
+		location,
+		comparison,
+		value: number,
+	})
+}
+
+/// Separates comparisons such as `a = { min = 1, max = 10 }` from the rest of a test's result or checkpoint,
+/// returning the remaining entries along with the ranges.
+fn take_ranges(
+	result: toml::Table,
+	parent: &str,
+	symfile: &Symbols,
+	problems: &mut Problems,
+) -> (toml::Table, Vec<(Location, RangeInclusive<u16>)>) {
+	let location_key = parent.split('.').next().unwrap_or(parent);
+	let mut remaining = toml::Table::new();
+	let mut ranges = Vec::new();
+	for (key, value) in result {
+		let table = match value {
+			toml::Value::Table(table) if table.contains_key("min") || table.contains_key("max") => {
+				table
+			}
+			value => {
+				remaining.insert(key, value);
+				continue;
+			}
+		};
+		let mut problem = |message: String| {
+			problems.push((Some(String::from(location_key)), message));
+		};
+
+		let location = match parse_location(&key, symfile) {
+			Some(Ok(location)) => location,
+			Some(Err(message)) => {
+				problem(message);
+				continue;
+			}
+			None => {
+				problem(format!("A range can't be used for `{parent}.{key}`"));
+				continue;
+			}
+		};
+		if let Some(other) = table
+			.keys()
+			.find(|other| *other != "min" && *other != "max")
+		{
+			problem(format!(
+				"Unknown key `{other}` in range for `{parent}.{key}`"
+			));
+			continue;
+		}
+
+		let limit = if location.is_word() { 0xFFFF } else { 0xFF };
+		let mut bound = |name: &str, default: u16| match table.get(name) {
+			None => Some(default),
+			Some(toml::Value::Integer(value @ -128..=255)) if !location.is_word() => {
+				Some(u16::from(low_byte(*value)))
+			}
+			Some(value) if location.is_word() => match Address::deserialize(value.clone()) {
+				Ok(address) => resolve(location_key, address, symfile, problems),
+				Err(msg) => {
+					let message = format!(
+						"Invalid value for `{parent}.{key}.{name}`: {}",
+						msg.message()
+					);
+					problems.push((Some(String::from(location_key)), message));
+					None
+				}
+			},
+			Some(_) => {
+				let message = format!("Value of `{parent}.{key}.{name}` must be an 8-bit integer");
+				problems.push((Some(String::from(location_key)), message));
+				None
+			}
+		};
+		let (Some(min), Some(max)) = (bound("min", 0), bound("max", limit)) else {
+			continue;
+		};
+		if min > max {
+			problems.push((
+				Some(String::from(location_key)),
+				format!("The range for `{parent}.{key}` has a minimum larger than its maximum"),
+			));
+			continue;
+		}
+		ranges.push((location, min..=max));
+	}
+	(remaining, ranges)
+}
+
+/// Separates expressions such as `a = "initial.a + 1"` from the rest of a test's result or checkpoint,
+/// returning the remaining entries along with the expressions.
+fn take_expressions(
+	result: toml::Table,
+	parent: &str,
+	symfile: &Symbols,
+	problems: &mut Problems,
+) -> (toml::Table, Vec<(Location, Expression)>) {
+	let location_key = parent.split('.').next().unwrap_or(parent);
+	let mut remaining = toml::Table::new();
+	let mut expressions = Vec::new();
+	for (key, value) in result {
+		let text = match value {
+			toml::Value::String(text) if expression::is_expression(&text, symfile) => text,
+			value => {
+				remaining.insert(key, value);
+				continue;
+			}
+		};
+		let expression = match parse_location(&key, symfile) {
+			Some(Ok(location)) => expression::parse(&text, symfile)
+				.map(|expression| (location, expression))
+				.map_err(|message| format!("Invalid expression for `{parent}.{key}`: {message}")),
+			Some(Err(message)) => Err(message),
+			None => Err(format!("An expression can't be used for `{parent}.{key}`")),
+		};
+		match expression {
+			Ok(expression) => expressions.push(expression),
+			Err(message) => problems.push((Some(String::from(location_key)), message)),
+		}
+	}
+	(remaining, expressions)
+}
+
+fn apply_registers(
+	registers: &mut Registers,
+	options: RegisterOptions,
+	symfile: &Symbols,
+	charmap: Option<&Arc<Charmap>>,
+	problems: &mut Problems,
+) {
+	macro_rules! set {
+		($($reg:ident),+) => { $(
+			if options.$reg.is_some() {
+				registers.$reg = options.$reg;
+			}
+		)+ };
+	}
+	set!(a, b, c, d, e, h, l);
+	if let Some(toml::Value::Integer(f)) = options.f {
+		match f {
+			-128..=255 => registers.f = Some(low_byte(f)),
+			_ => problems.push((
+				Some(String::from("f")),
+				String::from("Value of `f` must be an 8-bit integer"),
+			)),
+		}
+	} else if let Some(f) = options.f {
+		match parse_flags(&f) {
+			Ok(flags) => {
+				let [zf, nf, hf, cf] = flags;
+				let flags = [
+					(&mut registers.zf, zf),
+					(&mut registers.nf, nf),
+					(&mut registers.hf, hf),
+					(&mut registers.cf, cf),
+				];
+				for (register, flag) in flags {
+					if flag.is_some() {
+						*register = flag;
+					}
+				}
+			}
+			Err(message) => problems.push((Some(String::from("f")), message)),
+		}
+	}
+	set!(zf, nf, hf, cf, ime);
+
+	macro_rules! set_address {
+		($($reg:ident),+) => { $(
+			if let Some(address) = options.$reg {
+				if let Some(value) = resolve(stringify!($reg), address, symfile, problems) {
+					registers.$reg = Some(value);
+				}
+			}
+		)+ };
+	}
+	set_address!(af, bc, de, hl, pc, sp);
+
+	for (key, value) in options.other {
+		let Some(name) = key.strip_prefix('[').and_then(|key| key.strip_suffix(']')) else {
+			problems.push((Some(key.clone()), format!("Unknown config key `{key}`")));
+			continue;
+		};
+		// Typed values are kept whole, so that they can be compared and reported as a whole.
+		let typed = value
+			.as_table()
+			.and_then(|table| parse_typed(name, table, charmap))
+			.map(|typed| Ok((parse_address(name, symfile), typed?)));
+		match typed {
+			Some(Ok((Some(address), typed))) => registers.typed_memory.push((address, typed)),
+			Some(Ok((None, _))) => {
+				let message = format!("Address \"{name}\" is not a valid address");
+				problems.push((Some(key), message));
+			}
+			Some(Err(message)) => problems.push((Some(key), message)),
+			None => match parse_memory_assignment(name, &value, symfile, charmap) {
+				Ok(data) => registers.memory.extend(data),
+				Err(message) => problems.push((Some(key), message)),
+			},
+		}
+	}
+}
+
+/// Reads the value of `f` as the Z, N, H, and C flags, with `None` for any which are masked out.
+///
+/// `f` may be a table with a `value` and `mask`, or a table of flags such as `{ z = true }`.
+/// A mask is either an integer or the letters of the flags it includes, such as `"z-c"`.
+fn parse_flags(f: &toml::Value) -> Result<[Option<bool>; 4], String> {
+	const FLAGS: [(char, u8); 4] = [('z', 0x80), ('n', 0x40), ('h', 0x20), ('c', 0x10)];
+
+	let byte = |value: &toml::Value, key: &str| match value {
+		toml::Value::Integer(value @ -128..=255) => Ok(low_byte(*value)),
+		_ => Err(format!("Value of `{key}` must be an 8-bit integer")),
+	};
+	let (value, mask) = match f {
+		toml::Value::Table(table) if table.contains_key("value") || table.contains_key("mask") => {
+			if let Some(key) = table.keys().find(|key| *key != "value" && *key != "mask") {
+				return Err(format!("Unknown key `{key}` in value of `f`"));
+			}
+			let mask = match table.get("mask") {
+				Some(toml::Value::String(mask)) => {
+					mask.chars().try_fold(0, |mask, flag| match flag {
+						'-' => Ok(mask),
+						_ => FLAGS
+							.iter()
+							.find(|(name, _)| *name == flag.to_ascii_lowercase())
+							.map(|(_, bit)| mask | bit)
+							.ok_or_else(|| format!("'{flag}' in mask of `f` is not a flag")),
+					})?
+				}
+				Some(mask) => byte(mask, "f.mask")?,
+				None => 0xF0,
+			};
+			let value = table
+				.get("value")
+				.ok_or_else(|| String::from("A mask for `f` needs a `value` to compare with"))?;
+			(byte(value, "f.value")?, mask)
+		}
+		toml::Value::Table(table) => {
+			let mut flags = [None; 4];
+			for (key, value) in table {
+				let index = FLAGS
+					.iter()
+					.position(|(name, _)| key.len() == 1 && key.starts_with(*name))
+					.ok_or_else(|| format!("Unknown config key `f.{key}`"))?;
+				flags[index] = Some(
+					value
+						.as_bool()
+						.ok_or_else(|| format!("Value of `f.{key}` must be true or false"))?,
+				);
+			}
+			return Ok(flags);
+		}
+		_ => return Err(String::from("Value of `f` must be an integer or a table")),
+	};
+	Ok(FLAGS.map(|(_, bit)| (mask & bit != 0).then_some(value & bit != 0)))
+}
+
+/// Replaces `${NAME}` references in the strings and keys within `value`.
+///
+/// A string which is nothing but a reference, like `"${TIMEOUT}"`, becomes a number or boolean if the variable holds one.
+/// Problems are reported at `key`, or at the key of each entry if `value` is a test's table.
+fn interpolate(
+	value: toml::Value,
+	options: &Options,
+	key: Option<&str>,
+	problems: &mut Problems,
+) -> toml::Value {
+	match value {
+		toml::Value::String(text) => match interpolate_string(&text, options) {
+			Ok(result) => {
+				let whole = text.starts_with("${") && text.find('}') == Some(text.len() - 1);
+				let typed = toml::Value::deserialize(toml::de::ValueDeserializer::new(&result))
+					.ok()
+					.filter(|value| {
+						matches!(
+							value,
+							toml::Value::Integer(_)
+								| toml::Value::Float(_) | toml::Value::Boolean(_)
+						)
+					});
+				match typed {
+					Some(typed) if whole => typed,
+					_ => toml::Value::String(result),
+				}
+			}
+			Err(message) => {
+				problems.push((key.map(String::from), message));
+				toml::Value::String(text)
+			}
+		},
+		toml::Value::Array(values) => toml::Value::Array(
+			values
+				.into_iter()
+				.map(|value| interpolate(value, options, key, problems))
+				.collect(),
+		),
+		toml::Value::Table(table) => {
+			let mut result = toml::Table::new();
+			for (name, value) in table {
+				let name = interpolate_string(&name, options).unwrap_or_else(|message| {
+					problems.push((Some(key.unwrap_or(&name).to_string()), message));
+					name
+				});
+				let value = interpolate(value, options, Some(key.unwrap_or(&name)), problems);
+				result.insert(name, value);
+			}
+			toml::Value::Table(result)
+		}
+		value => value,
+	}
+}
+
+/// Replaces each `${NAME}` in `text` with the value of the variable. `$$` is replaced with a single `$`.
+fn interpolate_string(text: &str, options: &Options) -> Result<String, String> {
+	let mut result = String::new();
+	let mut rest = text;
+	while let Some(start) = rest.find('$') {
+		result.push_str(&rest[..start]);
+		rest = &rest[start + 1..];
+		if let Some(after) = rest.strip_prefix('$') {
+			result.push('$');
+			rest = after;
+		} else if let Some(after) = rest.strip_prefix('{') {
+			let end = after
+				.find('}')
+				.ok_or_else(|| format!("Missing `}}` after `${{` in \"{text}\""))?;
+			let name = &after[..end];
+			let value = options
+				.variable(name)
+				.ok_or_else(|| format!("Variable `{name}` is not defined."))?;
+			result.push_str(&value);
+			rest = &after[end + 1..];
+		} else {
+			result.push('$');
+		}
+	}
+	result.push_str(rest);
+	Ok(result)
+}
+
+/// Finds the address of a symbol, or of a hardware register such as `rLCDC` if the symfile doesn't define it.
+fn lookup(name: &str, symfile: &Symbols) -> Option<u16> {
+	symfile
+		.get(name)
+		.map(|(_, address)| *address)
+		.or_else(|| HwReg::from_name(name).map(|reg| reg as u16))
+}
+
+pub(crate) fn parse_address(address: &str, symfile: &Symbols) -> Option<u16> {
+	lookup(address, symfile)
+		.or_else(|| u16::deserialize(toml::de::ValueDeserializer::new(address)).ok())
+}
+
+fn parse_memory(
+	name: &str,
+	value: &toml::Value,
+	charmap: Option<&Arc<Charmap>>,
+) -> Result<Vec<u8>, String> {
+	match value {
+		toml::Value::Integer(value) => {
+			if *value > 255 || *value < -128 {
+				let value_array = value
+					.to_le_bytes()
+					.iter()
+					.skip_while(|b| **b == 0)
+					.map(|b| format!("0x{b:02x}"))
+					.collect::<Vec<_>>()
+					.join(", ");
+
+				// Disallow non 8-bit values and present alternative
+				Err(format!(
+					"\"{value}\" is not an 8-bit value. Try \"[{value_array}]\" instead."
+				))
+			} else {
+				// Treat any byte size number as a byte
+				Ok(vec![low_byte(*value)])
+			}
+		}
+		toml::Value::String(value) => {
+			if let Some(charmap) = charmap {
+				// Convert string into the bytes the game stores it as
+				charmap
+					.encode(value)
+					.map_err(|message| format!("{message}, in \"{value}\""))
+			} else if value.is_ascii() {
+				// Convert string into sequence of bytes
+				Ok(value.bytes().collect::<Vec<_>>())
+			} else {
+				// Disallow any strings which contain non-ASCII values
+				Err(format!(
+					"String value \"{value}\" contains non-ASCII characters"
+				))
+			}
+		}
+		toml::Value::Array(value) => {
+			// Recursively call function on all toml::Value and return their collected result
+			value
+				.iter()
+				.map(|v| parse_memory(name, v, charmap))
+				.collect::<Result<Vec<Vec<u8>>, String>>()
+				.map(|mem| mem.into_iter().flatten().collect::<Vec<u8>>())
+		}
+		toml::Value::Boolean(value) => {
+			// Convert bool into either a 1 or a 0
+			Ok(vec![u8::from(*value)])
+		}
+		toml::Value::Table(table) => parse_memory_table(name, table, charmap),
+		_ => {
+			// Other types return error as they are not supported
+			Err(format!("Unsupported value for {name}: {value}"))
+		}
+	}
+}
+
+/// Returns an error naming the first key of `table` which is not in `allowed`.
+fn check_memory_keys(name: &str, table: &toml::Table, allowed: &[&str]) -> Result<(), String> {
+	match table.keys().find(|key| !allowed.contains(&key.as_str())) {
+		Some(key) => Err(format!("Unknown key `{key}` in memory value for {name}")),
+		None => Ok(()),
+	}
+}
+
+/// Reads the optional non-negative integer `key` of a memory value.
+fn memory_integer(name: &str, table: &toml::Table, key: &str) -> Result<Option<usize>, String> {
+	table
+		.get(key)
+		.map(|value| {
+			value
+				.as_integer()
+				.and_then(|value| usize::try_from(value).ok())
+				.ok_or_else(|| {
+					format!("Value of `{key}` for {name} must be a non-negative integer")
+				})
+		})
+		.transpose()
+}
+
+/// Reads a memory value written as a table, such as `{ hex = "DEADBEEF" }` or `{ u16le = 1234 }`.
+fn parse_memory_table(
+	name: &str,
+	table: &toml::Table,
+	charmap: Option<&Arc<Charmap>>,
+) -> Result<Vec<u8>, String> {
+	parse_typed(name, table, charmap)
+		.unwrap_or_else(|| {
+			Err(format!(
+				"Unsupported value for {name}: expected a table with a `hex`, `file`, `fill`, `range`, `mask`, `u16le`, `u16be`, `bcdle`, `bcdbe`, or `string` key"
+			))
+		})
+		.map(|typed| typed.encode())
+}
+
+/// Reads a block of bytes written as a table, such as `{ file = "data.bin" }`, or returns `None` if `table` is not a block.
+fn parse_block(
+	name: &str,
+	table: &toml::Table,
+	charmap: Option<&Arc<Charmap>>,
+) -> Option<Result<Vec<u8>, String>> {
+	let block = if let Some(hex) = table.get("hex") {
+		check_memory_keys(name, table, &["hex"]).and_then(|()| {
+			let hex = hex
+				.as_str()
+				.ok_or_else(|| format!("Value of `hex` for {name} must be a string"))?;
+			parse_hex(name, hex)
+		})
+	} else if let Some(path) = table.get("file") {
+		check_memory_keys(name, table, &["file", "offset", "length"]).and_then(|()| {
+			let path = path
+				.as_str()
+				.ok_or_else(|| format!("Value of `file` for {name} must be a path"))?;
+			read_memory_file(
+				path,
+				memory_integer(name, table, "offset")?,
+				memory_integer(name, table, "length")?,
+			)
+		})
+	} else if let Some(fill) = table.get("fill") {
+		check_memory_keys(name, table, &["fill", "size"]).and_then(|()| {
+			let fill = match fill {
+				toml::Value::Integer(fill @ -128..=255) => low_byte(*fill),
+				_ => {
+					return Err(format!(
+						"Value of `fill` for {name} must be an 8-bit integer"
+					))
+				}
+			};
+			let size = memory_integer(name, table, "size")?
+				.ok_or_else(|| format!("Fill value for {name} needs a `size`"))?;
+			Ok(vec![fill; size.min(0x10000)])
+		})
+	} else if table.contains_key("range") {
+		check_memory_keys(name, table, &["range", "equals"]).and_then(|()| {
+			let size = memory_integer(name, table, "range")?.unwrap_or_default();
+			let equals = table
+				.get("equals")
+				.ok_or_else(|| format!("Range of {name} needs a value it `equals`"))?;
+			let data = parse_memory(name, equals, charmap)?;
+			match data.as_slice() {
+				// A single byte is repeated to fill the range
+				[byte] => Ok(vec![*byte; size.min(0x10000)]),
+				_ if data.len() == size => Ok(data),
+				_ => Err(format!(
+					"Range of {name} is {size} bytes, but its value is {} bytes",
+					data.len()
+				)),
+			}
+		})
+	} else {
+		return None;
+	};
+	Some(block)
+}
+
+/// Reads a memory value with a type, such as `{ u16le = 1234 }`, or returns `None` if `table` has no type.
+fn parse_typed(
+	name: &str,
+	table: &toml::Table,
+	charmap: Option<&Arc<Charmap>>,
+) -> Option<Result<Typed, String>> {
+	let word = |key: &str| match table[key] {
+		toml::Value::Integer(value @ -0x8000..=0xFFFF) => Ok(low_word(value)),
+		_ => Err(format!(
+			"Value of `{key}` for {name} must be a 16-bit integer"
+		)),
+	};
+	let bcd = |key: &str, little_endian: bool| {
+		check_memory_keys(name, table, &[key, "size"])?;
+		let value = table[key]
+			.as_integer()
+			.and_then(|value| u64::try_from(value).ok())
+			.ok_or_else(|| format!("Value of `{key}` for {name} must be a non-negative integer"))?;
+		let digits = value.to_string().len();
+		let size = memory_integer(name, table, "size")?.unwrap_or(digits.div_ceil(2));
+		// A u64 has at most 20 digits, so larger sizes would only add zeros.
+		if size == 0 || size > 10 || size * 2 < digits {
+			return Err(format!(
+				"{value} does not fit in {size} bytes of BCD for {name}"
+			));
+		}
+		Ok(Typed::Bcd {
+			value,
+			size,
+			little_endian,
+		})
+	};
+
+	let byte = |key: &str| match table.get(key) {
+		Some(toml::Value::Integer(value @ -128..=255)) => Ok(low_byte(*value)),
+		_ => Err(format!(
+			"Value of `{key}` for {name} must be an 8-bit integer"
+		)),
+	};
+
+	Some(if table.contains_key("mask") {
+		check_memory_keys(name, table, &["value", "mask"]).and_then(|()| {
+			Ok(Typed::Masked {
+				value: byte("value")?,
+				mask: byte("mask")?,
+			})
+		})
+	} else if table.contains_key("u16le") {
+		check_memory_keys(name, table, &["u16le"]).and_then(|()| word("u16le").map(Typed::U16Le))
+	} else if table.contains_key("u16be") {
+		check_memory_keys(name, table, &["u16be"]).and_then(|()| word("u16be").map(Typed::U16Be))
+	} else if table.contains_key("bcdle") {
+		bcd("bcdle", true)
+	} else if table.contains_key("bcdbe") {
+		bcd("bcdbe", false)
+	} else if let Some(text) = table.get("string") {
+		check_memory_keys(name, table, &["string", "terminator"]).and_then(|()| {
+			let text = match (text.as_str(), charmap) {
+				(Some(text), Some(charmap)) => {
+					charmap.encode(text)?;
+					text
+				}
+				(Some(text), None) if text.is_ascii() => text,
+				_ => {
+					return Err(format!(
+						"Value of `string` for {name} must be an ASCII string"
+					))
+				}
+			};
+			let terminator = match table.get("terminator") {
+				Some(toml::Value::Integer(terminator @ -128..=255)) => Some(low_byte(*terminator)),
+				Some(_) => {
+					return Err(format!(
+						"Value of `terminator` for {name} must be an 8-bit integer"
+					))
+				}
+				None => None,
+			};
+			Ok(Typed::String {
+				text: String::from(text),
+				terminator,
+				charmap: charmap.cloned(),
+			})
+		})
+	} else {
+		return parse_block(name, table, charmap).map(|block| block.map(Typed::Block));
+	})
+}
+
+/// Reads the `result` table, along with the cycles, calls, and stack it may expect.
+fn apply_result(
+	test: &mut TestConfig,
+	mut result: toml::Table,
+	symfile: &Symbols,
+	problems: &mut Problems,
+) {
+	test.cycles = take_cycles(&mut result, problems);
+	if let Some(value) = result.remove("return") {
+		match ArgOption::deserialize(value) {
+			Ok(value) => test.returns = resolve_arg("result", value, symfile, problems),
+			Err(msg) => problems.push((
+				Some(String::from("result")),
+				format!("Invalid value for `result.return`: {}", msg.message()),
+			)),
+		}
+	}
+	if let Some(calls) = result.remove("calls") {
+		test.calls = parse_calls(calls, symfile, problems);
+	}
+	let stack = result.remove("stack").and_then(|stack| {
+		parse_memory("result.stack", &stack, test.charmap.as_ref())
+			.map_err(|message| problems.push((Some(String::from("result")), message)))
+			.ok()
+	});
+	let mut registers = parse_expected(result, "result", symfile, test.charmap.as_ref(), problems);
+	registers.stack = stack.unwrap_or_default();
+	test.result = Some(registers);
+}
+
+/// Reads `charmap`: the path to an RGBDS charmap, or a table of characters and the bytes they're stored as.
+fn parse_charmap(value: toml::Value, problems: &mut Problems) -> Option<Charmap> {
+	let key = || Some(String::from("charmap"));
+	match value {
+		toml::Value::String(path) => {
+			let text = fs::read_to_string(&path)
+				.map_err(|msg| problems.push((key(), format!("Failed to read {path}: {msg}"))))
+				.ok()?;
+			Charmap::parse(&text)
+				.map_err(|errors| {
+					for error in errors {
+						problems.push((key(), format!("In charmap {path}, {error}")));
+					}
+				})
+				.ok()
+		}
+		toml::Value::Table(table) => {
+			let mut charmap = Charmap::default();
+			for (name, value) in table {
+				match parse_memory(&name, &value, None) {
+					Ok(bytes) if !bytes.is_empty() => charmap.insert(name, bytes),
+					Ok(_) => problems.push((key(), format!("Charmap \"{name}\" has no bytes"))),
+					Err(message) => problems.push((key(), message)),
+				}
+			}
+			Some(charmap)
+		}
+		_ => {
+			problems.push((
+				key(),
+				String::from("Value of `charmap` must be a path or a table of characters"),
+			));
+			None
+		}
+	}
+}
+
+/// Reads a save state or memory dump for `initial-state`.
+fn read_state(path: &str, problems: &mut Problems) -> Option<LoadedState> {
+	fs::read(path)
+		.map_err(|msg| format!("Failed to read {path}: {msg}"))
+		.and_then(|contents| {
+			savestate::load(&contents).map_err(|msg| format!("Failed to load {path}: {msg}"))
+		})
+		.map_err(|message| problems.push((Some(String::from("initial-state")), message)))
+		.ok()
+}
+
+/// Starts a test from a loaded state.
+/// A save state replaces every register the test would otherwise inherit,
+/// but the test's own registers and memory are applied afterwards, so they take priority.
+fn apply_state(registers: &mut Registers, mut state: Registers) {
+	let mut memory = mem::take(&mut registers.memory);
+	memory.append(&mut state.memory);
+	// Dumps only contain memory.
+	if state.pc.is_some() {
+		*registers = Registers {
+			typed_memory: mem::take(&mut registers.typed_memory),
+			..state
+		};
+	}
+	registers.memory = memory;
+}
+
+/// Reads `length` bytes from the file at `path`, starting at `offset`, or the rest of the file if `length` is absent.
+fn read_memory_file(
+	path: &str,
+	offset: Option<usize>,
+	length: Option<usize>,
+) -> Result<Vec<u8>, String> {
+	let mut contents = fs::read(path).map_err(|msg| format!("Failed to read {path}: {msg}"))?;
+	let offset = offset.unwrap_or(0);
+	let end = length.map_or(Some(contents.len()), |length| offset.checked_add(length));
+	match end {
+		Some(end) if offset <= end && end <= contents.len() => {
+			contents.truncate(end);
+			contents.drain(..offset);
+			Ok(contents)
+		}
+		_ => Err(format!(
+			"{path} is only {} bytes long, which is too short for the requested offset and length",
+			contents.len()
+		)),
+	}
+}
+
+/// Reads a string of hexadecimal digit pairs, ignoring whitespace between them.
+fn parse_hex(name: &str, hex: &str) -> Result<Vec<u8>, String> {
+	let digits = hex
+		.chars()
+		.filter(|c| !c.is_ascii_whitespace())
+		.collect::<String>();
+	if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+		return Err(format!(
+			"Hex value for {name} contains '{c}', which is not a hexadecimal digit"
+		));
+	}
+	if digits.len() % 2 != 0 {
+		return Err(format!("Hex value for {name} has an odd number of digits"));
+	}
+	Ok((0..digits.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap_or_default())
+		.collect())
+}
+
+fn parse_memory_assignment(
+	name: &str,
+	value: &toml::Value,
+	symfile: &Symbols,
+	charmap: Option<&Arc<Charmap>>,
+) -> Result<Vec<(u16, u8)>, String> {
+	let Some(address) = parse_address(name, symfile) else {
+		return Err(format!("Address \"{name}\" is not a valid address"));
+	};
+
+	parse_memory(name, value, charmap).map(|data| {
+		data.into_iter()
+			.zip(address..=u16::MAX)
+			.map(|(byte, address)| (address, byte))
+			.collect::<Vec<(u16, u8)>>()
+	})
+}
+
+/// Expands a test's `data` file into one table of configuration per row, along with its name.
+fn read_data(
+	path: &str,
+	table: &toml::Table,
+) -> Result<Vec<(Option<String>, toml::Table)>, String> {
+	let mut reader = csv::ReaderBuilder::new()
+		.trim(csv::Trim::All)
+		.from_path(path)
+		.map_err(|msg| format!("Failed to open {path}: {msg}"))?;
+	let headers = reader
+		.headers()
+		.cloned()
+		.map_err(|msg| format!("Failed to read {path}: {msg}"))?;
+
+	let mut rows = Vec::new();
+	for (i, record) in reader.records().enumerate() {
+		let record = record.map_err(|msg| format!("Failed to read {path}: {msg}"))?;
+		let mut row = table.clone();
+		row.remove("data");
+		let mut name = (i + 1).to_string();
+
+		for (column, cell) in headers.iter().zip(record.iter()) {
+			if cell.is_empty() {
+				continue;
+			}
+			if column == "name" {
+				name = String::from(cell);
+				continue;
+			}
+			// Anything which isn't a TOML value is most likely a symbol
+			let value = toml::Value::deserialize(toml::de::ValueDeserializer::new(cell))
+				.unwrap_or_else(|_| toml::Value::String(String::from(cell)));
+			if let Some(key) = column.strip_prefix("result.") {
+				let result = row
+					.entry("result")
+					.or_insert_with(|| toml::Value::Table(toml::Table::new()));
+				if let toml::Value::Table(result) = result {
+					result.insert(String::from(key), value);
+				}
+			} else {
+				row.insert(String::from(column), value);
+			}
+		}
+		rows.push((Some(name), row));
+	}
+	Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn option_keys_match_test_options() {
+		// Keys which are taken out of a test before `TestOptions` reads the rest
+		let taken = [
+			"charmap",
+			"cycles",
+			"cycles-min",
+			"cycles-max",
+			"calls",
+			"return",
+			"name",
+			"data",
+		];
+		for key in OPTION_KEYS {
+			let table = toml::Table::from_iter([(String::from(key), toml::Value::Boolean(true))]);
+			// Fields given the wrong type fail to deserialize, rather than landing in `other`
+			let is_field = !matches!(
+				TestOptions::deserialize(toml::Value::Table(table)),
+				Ok(options) if options.registers.other.contains_key(key)
+			);
+			assert_eq!(is_field, !taken.contains(&key), "{key}");
+		}
+	}
+}
@@ -0,0 +1,165 @@
+//! Calling conventions, which place a test's arguments and find its return value.
+
+use crate::registers::Registers;
+use crate::table::Location;
+
+/// An argument or return value, along with whether it is 8 or 16 bits wide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Arg {
+	pub value: u16,
+	pub word: bool,
+}
+
+impl Arg {
+	#[must_use]
+	pub fn byte(value: u8) -> Self {
+		Self {
+			value: value.into(),
+			word: false,
+		}
+	}
+
+	#[must_use]
+	pub fn word(value: u16) -> Self {
+		Self { value, word: true }
+	}
+
+	/// Guesses the width of a value which was written without one: addresses and values over 0xFF are 16 bits.
+	#[must_use]
+	pub fn infer(value: u16, is_address: bool) -> Self {
+		Self {
+			value,
+			word: is_address || value > 0xFF,
+		}
+	}
+}
+
+/// Where a routine expects its arguments, and where it leaves its return value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CallingConvention {
+	/// Arguments are placed in `args` in order; any beyond those are pushed onto the stack as 16-bit words,
+	/// with the first nearest the return address.
+	Fixed {
+		args: Vec<Location>,
+		returns: Location,
+	},
+	/// SDCC's `__sdcccall(1)`, used by GBDK.
+	///
+	/// The first argument is passed in `a` if it is 8 bits, or `de` if it is 16.
+	/// The second goes in `e` or `de` if the first was in `a`, or `a` or `bc` if it was in `de`.
+	/// The rest are pushed onto the stack, each taking 1 or 2 bytes, with the first nearest the return address.
+	/// Values are returned in `a` if they are 8 bits, or `bc` if they are 16.
+	Sdcc,
+}
+
+impl Default for CallingConvention {
+	fn default() -> Self {
+		Self::registers()
+	}
+}
+
+impl CallingConvention {
+	/// Arguments in `a`, `hl`, `de`, and `bc`, returning a value in `a`.
+	#[must_use]
+	pub fn registers() -> Self {
+		Self::Fixed {
+			args: vec![Location::A, Location::Hl, Location::De, Location::Bc],
+			returns: Location::A,
+		}
+	}
+
+	/// Every argument on the stack, returning a value in `hl`.
+	#[must_use]
+	pub fn stack() -> Self {
+		Self::Fixed {
+			args: Vec::new(),
+			returns: Location::Hl,
+		}
+	}
+
+	/// The location of each argument, up to the first which is passed on the stack.
+	fn locations(&self, args: &[Arg]) -> Vec<Location> {
+		match self {
+			Self::Fixed { args, .. } => args.clone(),
+			Self::Sdcc => {
+				let mut locations = Vec::new();
+				let first = args.first().map(|arg| arg.word);
+				if let Some(first) = first {
+					locations.push(if first { Location::De } else { Location::A });
+				}
+				if let Some(second) = args.get(1).map(|arg| arg.word) {
+					locations.push(match (first, second) {
+						(Some(false), false) => Location::E,
+						(Some(false), true) => Location::De,
+						(_, false) => Location::A,
+						(_, true) => Location::Bc,
+					});
+				}
+				locations
+			}
+		}
+	}
+
+	/// Where a return value of this width is found.
+	#[must_use]
+	pub fn return_location(&self, word: bool) -> Location {
+		match self {
+			Self::Fixed { returns, .. } => *returns,
+			Self::Sdcc if word => Location::Bc,
+			Self::Sdcc => Location::A,
+		}
+	}
+
+	/// Checks that each argument and the return value fit in their locations.
+	///
+	/// # Errors
+	///
+	/// Returns a message naming the first value which is too large.
+	pub fn check(&self, args: &[Arg], returns: Option<Arg>) -> Result<(), String> {
+		let fits = |location: Location, arg: Arg| location.is_word() || arg.value <= 0xFF;
+		for (number, (location, arg)) in (1..).zip(self.locations(args).iter().zip(args)) {
+			if !fits(*location, *arg) {
+				return Err(format!(
+					"Argument {number} (0x{:X}) is too large to be passed in {location}",
+					arg.value
+				));
+			}
+		}
+		match returns {
+			Some(arg) if !fits(self.return_location(arg.word), arg) => Err(format!(
+				"Return value 0x{:X} is too large to be returned in {}",
+				arg.value,
+				self.return_location(arg.word)
+			)),
+			_ => Ok(()),
+		}
+	}
+
+	/// Sets each argument in `registers`, returning the bytes of any which are passed on the stack,
+	/// from the lowest address up.
+	///
+	/// # Panics
+	///
+	/// Panics if an argument does not fit in its location; see [`CallingConvention::check`].
+	#[must_use]
+	pub fn place(&self, args: &[Arg], registers: &mut Registers) -> Vec<u8> {
+		let locations = self.locations(args);
+		for (location, arg) in locations.iter().zip(args) {
+			location.set(registers, arg.value);
+		}
+		let stack = args.iter().skip(locations.len());
+		match self {
+			Self::Fixed { .. } => stack.flat_map(|arg| arg.value.to_le_bytes()).collect(),
+			Self::Sdcc => stack
+				.flat_map(|arg| {
+					let [low, high] = arg.value.to_le_bytes();
+					if arg.word {
+						vec![low, high]
+					} else {
+						vec![low]
+					}
+				})
+				.collect(),
+		}
+	}
+}
@@ -0,0 +1,109 @@
+//! Tracking which ROM addresses tests execute, and writing it in the LCOV format read by coverage services.
+
+use crate::labels::Labels;
+use crate::mapfile::Section;
+use std::collections::BTreeMap;
+use std::io;
+
+/// How many times each ROM address was executed, keyed by ROM bank and address.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Coverage {
+	pub executed: BTreeMap<(usize, u16), u64>,
+}
+
+impl Coverage {
+	/// Counts an execution of `address`, if it is in ROM.
+	pub fn record(&mut self, rom_bank: usize, address: u16) {
+		let bank = match address {
+			0x0000..=0x3FFF => 0,
+			0x4000..=0x7FFF => rom_bank,
+			_ => return,
+		};
+		*self.executed.entry((bank, address)).or_default() += 1;
+	}
+
+	/// Adds the counts from `other` to this one.
+	pub fn merge(&mut self, other: &Coverage) {
+		for (location, count) in &other.executed {
+			*self.executed.entry(*location).or_default() += count;
+		}
+	}
+
+	/// Writes an LCOV record for the ROM at `source`.
+	///
+	/// RGBDS symfiles don't map addresses to source lines, so each "line" is an offset into the ROM, plus one.
+	/// Each global symbol in ROM is a function, which was hit if its first instruction ran,
+	/// except for those in `data_sections`, which are data rather than code.
+	///
+	/// # Errors
+	///
+	/// Returns any error from writing to `out`.
+	pub fn write_lcov(
+		&self,
+		mut out: impl io::Write,
+		source: &str,
+		labels: &Labels,
+		data_sections: &[Section],
+	) -> io::Result<()> {
+		let functions = labels
+			.iter()
+			.filter(|(bank, address, name)| {
+				*address < 0x8000 && (*address >= 0x4000 || *bank == 0) && !name.contains('.')
+			})
+			.filter(|(bank, address, _)| {
+				!data_sections
+					.iter()
+					.any(|section| section.contains(*bank, *address))
+			})
+			.map(|(bank, address, name)| {
+				let bank = usize::try_from(bank).unwrap_or(usize::MAX);
+				(bank, address, name)
+			})
+			.collect::<Vec<_>>();
+
+		writeln!(out, "TN:")?;
+		writeln!(out, "SF:{source}")?;
+		for (bank, address, name) in &functions {
+			writeln!(out, "FN:{},{name}", line(*bank, *address))?;
+		}
+		let mut hit = 0;
+		for (bank, address, name) in &functions {
+			let count = self.count(*bank, *address);
+			hit += usize::from(count > 0);
+			writeln!(out, "FNDA:{count},{name}")?;
+		}
+		writeln!(out, "FNF:{}", functions.len())?;
+		writeln!(out, "FNH:{hit}")?;
+
+		// Every executed address is a line, along with the start of each function.
+		let mut lines = self.executed.clone();
+		for (bank, address, _) in &functions {
+			lines.entry((*bank, *address)).or_default();
+		}
+		for ((bank, address), count) in &lines {
+			writeln!(out, "DA:{},{count}", line(*bank, *address))?;
+		}
+		writeln!(out, "LF:{}", lines.len())?;
+		writeln!(
+			out,
+			"LH:{}",
+			lines.values().filter(|count| **count > 0).count()
+		)?;
+		writeln!(out, "end_of_record")
+	}
+
+	fn count(&self, bank: usize, address: u16) -> u64 {
+		self.executed
+			.get(&(bank, address))
+			.copied()
+			.unwrap_or_default()
+	}
+}
+
+/// The line number standing in for a ROM address: its offset in the ROM file, plus one.
+fn line(bank: usize, address: u16) -> usize {
+	match address {
+		0x0000..=0x3FFF => usize::from(address) + 1,
+		_ => bank * 0x4000 + usize::from(address - 0x4000) + 1,
+	}
+}
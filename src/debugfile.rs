@@ -0,0 +1,136 @@
+//! Assertions and messages attached to ROM addresses by a debug file.
+//!
+//! Each line of a debug file names an address, followed by what to do when `pc` reaches it:
+//!
+//! ```not_rust
+//! ; Comments start with a semicolon.
+//! Function.entry: assert a != 0 "a must not be zero"
+//! Function.loop: message "HL is %HL%"
+//! ```
+//!
+//! Assertions use the same comparisons as the `invariants` option, and fail the test if they do not hold.
+//! Messages are printed like those following `ld d, d`, with `%REGISTER%` replaced by the register's value.
+
+use crate::config::{self, Symbols};
+use crate::invariant::Invariant;
+use crate::memory::Inspect;
+use crate::message;
+use gb_cpu_sim::cpu;
+
+#[derive(Debug, thiserror::Error)]
+#[error("line {line}: {message}")]
+pub struct DebugFileError {
+	pub line: usize,
+	pub message: String,
+}
+
+/// What happens when `pc` reaches a [`DebugPoint`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+	/// Fails the test if the condition does not hold, printing the message if there is one.
+	Assert {
+		condition: Invariant,
+		message: Option<String>,
+	},
+	/// Prints a message.
+	Message(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugPoint {
+	pub address: u16,
+	pub action: Action,
+}
+
+impl DebugPoint {
+	/// Runs the point's action, returning the message to print and whether the test should fail.
+	pub fn evaluate<A: Inspect>(&self, cpu_state: &cpu::State<A>) -> Option<(String, bool)> {
+		match &self.action {
+			Action::Assert { condition, .. } if condition.holds(cpu_state) => None,
+			Action::Assert {
+				condition,
+				message: Some(message),
+			} => Some((
+				format!(
+					"{} (`{condition}`)",
+					message::expand(message, cpu_state, self.address)
+				),
+				true,
+			)),
+			Action::Assert {
+				condition,
+				message: None,
+			} => Some((format!("`{condition}` does not hold"), true)),
+			Action::Message(text) => Some((message::expand(text, cpu_state, self.address), false)),
+		}
+	}
+}
+
+/// Reads every point in a debug file, naming addresses with the symfile.
+///
+/// # Errors
+///
+/// Returns every line which could not be read.
+pub fn parse(text: &str, symfile: &Symbols) -> Result<Vec<DebugPoint>, Vec<DebugFileError>> {
+	let mut points = Vec::new();
+	let mut errors = Vec::new();
+	for (index, line) in text.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with(';') {
+			continue;
+		}
+		match parse_line(line, symfile) {
+			Ok(point) => points.push(point),
+			Err(message) => errors.push(DebugFileError {
+				line: index + 1,
+				message,
+			}),
+		}
+	}
+	if errors.is_empty() {
+		Ok(points)
+	} else {
+		Err(errors)
+	}
+}
+
+fn parse_line(line: &str, symfile: &Symbols) -> Result<DebugPoint, String> {
+	let (address, rest) = line.split_once(':').ok_or_else(|| {
+		format!("Expected `address: assert ...` or `address: message ...`, found `{line}`")
+	})?;
+	let address = address.trim();
+	let address = config::parse_address(address, symfile)
+		.ok_or_else(|| format!("`{address}` is not a number or symbol"))?;
+	let rest = rest.trim();
+	let (kind, argument) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+	let argument = argument.trim();
+	let action = match kind {
+		"assert" => {
+			let (condition, message) = match argument.split_once('"') {
+				Some((condition, message)) => (condition.trim(), Some(unquote(message)?)),
+				None => (argument, None),
+			};
+			Action::Assert {
+				condition: config::parse_invariant(condition, symfile)?,
+				message,
+			}
+		}
+		"message" => Action::Message(match argument.strip_prefix('"') {
+			Some(text) => unquote(text)?,
+			None => String::from(argument),
+		}),
+		_ => {
+			return Err(format!(
+				"Unknown action `{kind}`; expected `assert` or `message`"
+			))
+		}
+	};
+	Ok(DebugPoint { address, action })
+}
+
+/// Reads the rest of a string whose opening quote has been removed.
+fn unquote(text: &str) -> Result<String, String> {
+	text.strip_suffix('"')
+		.map(String::from)
+		.ok_or_else(|| format!("Missing closing quote in `{text}`"))
+}
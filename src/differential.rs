@@ -0,0 +1,261 @@
+//! Running the same code on evunit's CPU and on a reference core, to find where they disagree.
+//!
+//! This is for validating the simulator itself: implement [`Backend`] for another emulator's CPU,
+//! then use [`find_divergence`] to step both from the same state and report the first instruction
+//! after which their registers or the writes they made differ. [`Flat`] is a backend which runs
+//! the same CPU over plain memory, for finding where evunit's memory model changes a program's behavior.
+//!
+//! Requires the `differential` feature.
+
+use crate::memory::{AccessKind, Inspect};
+use crate::registers::flags_byte;
+use gb_cpu_sim::{cpu, memory};
+use std::fmt;
+
+/// The registers of a CPU, as compared between backends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CoreRegisters {
+	pub a: u8,
+	/// All four flags, in the upper 4 bits.
+	pub f: u8,
+	pub b: u8,
+	pub c: u8,
+	pub d: u8,
+	pub e: u8,
+	pub h: u8,
+	pub l: u8,
+	pub pc: u16,
+	pub sp: u16,
+	pub ime: bool,
+}
+
+impl CoreRegisters {
+	#[must_use]
+	pub fn capture<A: Inspect>(cpu_state: &cpu::State<A>) -> Self {
+		Self {
+			a: cpu_state.a,
+			f: flags_byte(cpu_state),
+			b: cpu_state.b,
+			c: cpu_state.c,
+			d: cpu_state.d,
+			e: cpu_state.e,
+			h: cpu_state.h,
+			l: cpu_state.l,
+			pc: cpu_state.pc,
+			sp: cpu_state.sp,
+			ime: cpu_state.ime,
+		}
+	}
+}
+
+impl fmt::Display for CoreRegisters {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"a: {:02x} f: {:02x} bc: {:02x}{:02x} de: {:02x}{:02x} hl: {:02x}{:02x} pc: {:04x} sp: {:04x} ime: {}",
+			self.a,
+			self.f,
+			self.b,
+			self.c,
+			self.d,
+			self.e,
+			self.h,
+			self.l,
+			self.pc,
+			self.sp,
+			self.ime
+		)
+	}
+}
+
+/// A reference CPU core to compare evunit's against.
+pub trait Backend {
+	/// Replaces the core's registers and all 64 KiB of its memory.
+	fn load(&mut self, registers: &CoreRegisters, memory: &[u8]);
+	/// Runs one instruction, returning each write it made as (address, value), in order.
+	fn step(&mut self) -> Vec<(u16, u8)>;
+	fn registers(&self) -> CoreRegisters;
+}
+
+/// 64 KiB of RAM, recording each write made to it.
+struct FlatMemory {
+	bytes: Vec<u8>,
+	writes: Vec<(u16, u8)>,
+}
+
+impl memory::AddressSpace for FlatMemory {
+	fn read(&self, address: u16) -> u8 {
+		self.bytes[usize::from(address)]
+	}
+
+	fn write(&mut self, address: u16, value: u8) {
+		self.bytes[usize::from(address)] = value;
+		self.writes.push((address, value));
+	}
+}
+
+/// A reference core made of evunit's CPU and flat memory, where every address can be read and written like RAM.
+///
+/// Because the CPU is the same, this can't find mistakes in instructions. Instead, a divergence shows where
+/// evunit's memory model affected the program: a bank switch, an IO register, or a write to ROM or echo RAM.
+pub struct Flat(cpu::State<FlatMemory>);
+
+impl Flat {
+	#[must_use]
+	pub fn new() -> Self {
+		Self(cpu::State::new(FlatMemory {
+			bytes: vec![0; 0x10000],
+			writes: Vec::new(),
+		}))
+	}
+}
+
+impl Default for Flat {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Backend for Flat {
+	fn load(&mut self, registers: &CoreRegisters, memory: &[u8]) {
+		let cpu_state = &mut self.0;
+		cpu_state.address_space.bytes = memory.to_vec();
+		cpu_state.address_space.bytes.resize(0x10000, 0);
+		cpu_state.a = registers.a;
+		cpu_state.f.set_z(registers.f & 0x80 != 0);
+		cpu_state.f.set_n(registers.f & 0x40 != 0);
+		cpu_state.f.set_h(registers.f & 0x20 != 0);
+		cpu_state.f.set_c(registers.f & 0x10 != 0);
+		cpu_state.b = registers.b;
+		cpu_state.c = registers.c;
+		cpu_state.d = registers.d;
+		cpu_state.e = registers.e;
+		cpu_state.h = registers.h;
+		cpu_state.l = registers.l;
+		cpu_state.pc = registers.pc;
+		cpu_state.sp = registers.sp;
+		cpu_state.ime = registers.ime;
+	}
+
+	fn step(&mut self) -> Vec<(u16, u8)> {
+		self.0.tick();
+		std::mem::take(&mut self.0.address_space.writes)
+	}
+
+	fn registers(&self) -> CoreRegisters {
+		CoreRegisters {
+			a: self.0.a,
+			f: flags_byte(&self.0),
+			b: self.0.b,
+			c: self.0.c,
+			d: self.0.d,
+			e: self.0.e,
+			h: self.0.h,
+			l: self.0.l,
+			pc: self.0.pc,
+			sp: self.0.sp,
+			ime: self.0.ime,
+		}
+	}
+}
+
+/// The first instruction after which evunit and the reference core disagreed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+	/// The number of instructions which both cores agreed on.
+	pub instructions: usize,
+	/// Registers before the instruction which diverged, which both cores agreed on.
+	pub before: CoreRegisters,
+	pub evunit: CoreRegisters,
+	pub reference: CoreRegisters,
+	pub evunit_writes: Vec<(u16, u8)>,
+	pub reference_writes: Vec<(u16, u8)>,
+}
+
+impl fmt::Display for Divergence {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		writeln!(
+			f,
+			"Diverged after {} instructions, at 0x{:04x}",
+			self.instructions, self.before.pc
+		)?;
+		writeln!(f, "Before:    {}", self.before)?;
+		writeln!(f, "evunit:    {}", self.evunit)?;
+		writeln!(f, "Reference: {}", self.reference)?;
+		if self.evunit_writes != self.reference_writes {
+			let writes = |writes: &[(u16, u8)]| {
+				writes
+					.iter()
+					.map(|(address, value)| format!("[{address:04x}] = {value:02x}"))
+					.collect::<Vec<_>>()
+					.join(", ")
+			};
+			writeln!(f, "evunit wrote:    {}", writes(&self.evunit_writes))?;
+			writeln!(f, "Reference wrote: {}", writes(&self.reference_writes))?;
+		}
+		Ok(())
+	}
+}
+
+/// Loads the state of `cpu_state` into `reference`, then steps both for up to `instructions` instructions,
+/// stopping at the first one after which their registers or writes differ.
+///
+/// Both cores run until the limit; `halt`, `stop`, and invalid opcodes are compared like any other instruction.
+pub fn find_divergence<A: Inspect>(
+	cpu_state: &mut cpu::State<A>,
+	reference: &mut impl Backend,
+	instructions: usize,
+) -> Option<Divergence> {
+	let memory = (0..=u16::MAX)
+		.map(|address| cpu_state.address_space.peek(address))
+		.collect::<Vec<u8>>();
+	reference.load(&CoreRegisters::capture(cpu_state), &memory);
+	cpu_state.address_space.enable_access_log();
+	cpu_state.address_space.take_access_log();
+
+	for executed in 0..instructions {
+		let before = CoreRegisters::capture(cpu_state);
+		cpu_state.address_space.set_pc(before.pc);
+		cpu_state.tick();
+		let evunit_writes = cpu_state
+			.address_space
+			.take_access_log()
+			.into_iter()
+			.filter(|access| access.kind == AccessKind::Write)
+			.map(|access| (access.address, access.value))
+			.collect::<Vec<_>>();
+		let reference_writes = reference.step();
+		let evunit = CoreRegisters::capture(cpu_state);
+		let reference = reference.registers();
+		if evunit != reference || evunit_writes != reference_writes {
+			return Some(Divergence {
+				instructions: executed,
+				before,
+				evunit,
+				reference,
+				evunit_writes,
+				reference_writes,
+			});
+		}
+	}
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::memory::AddressSpace;
+
+	#[test]
+	fn flat_agrees_with_evunit() {
+		// nop; inc a; jr -3
+		let mut rom = vec![0; 0x8000];
+		rom[0x100..0x104].copy_from_slice(&[0x00, 0x3C, 0x18, 0xFD]);
+		let mut cpu_state = cpu::State::new(AddressSpace::with(&rom));
+		cpu_state.pc = 0x100;
+		cpu_state.sp = 0xFFFE;
+		let mut reference = Flat::new();
+		assert_eq!(find_divergence(&mut cpu_state, &mut reference, 100), None);
+		assert_eq!(reference.registers(), CoreRegisters::capture(&cpu_state));
+	}
+}
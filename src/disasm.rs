@@ -0,0 +1,248 @@
+//! Decoding SM83 machine code into RGBDS-style mnemonics.
+
+use std::fmt;
+
+/// A decoded instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+	pub address: u16,
+	/// The opcode and operands, between one and three bytes long.
+	pub bytes: Vec<u8>,
+	/// The instruction as it would be written in assembly, such as `ld a, [hl+]`.
+	pub mnemonic: String,
+}
+
+impl fmt::Display for Instruction {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let bytes = self
+			.bytes
+			.iter()
+			.map(|byte| format!("{byte:02x}"))
+			.collect::<Vec<String>>()
+			.join(" ");
+		write!(f, "0x{:04x}: {bytes:<8}  {}", self.address, self.mnemonic)
+	}
+}
+
+const REGISTERS: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const PAIRS: [&str; 4] = ["bc", "de", "hl", "sp"];
+const STACK_PAIRS: [&str; 4] = ["bc", "de", "hl", "af"];
+const CONDITIONS: [&str; 4] = ["nz", "z", "nc", "c"];
+const ARITHMETIC: [&str; 8] = [
+	"add a,", "adc a,", "sub a,", "sbc a,", "and a,", "xor a,", "or a,", "cp a,",
+];
+const ROTATIONS: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+/// Decodes the instruction at `address`, reading its bytes with `read`.
+///
+/// Opcodes which the SM83 does not implement are shown as `db` directives.
+pub fn decode(address: u16, read: impl Fn(u16) -> u8) -> Instruction {
+	let opcode = read(address);
+	let n8 = read(address.wrapping_add(1));
+	let n16 = u16::from_le_bytes([n8, read(address.wrapping_add(2))]);
+	// Relative jumps are shown with the address they jump to.
+	#[allow(clippy::cast_possible_wrap)]
+	let e8 = n8 as i8;
+	let target = address.wrapping_add(2).wrapping_add_signed(e8.into());
+
+	let (group, row, column) = (
+		opcode >> 6,
+		usize::from(opcode >> 3 & 7),
+		usize::from(opcode & 7),
+	);
+	let (pair, odd) = (row >> 1, row & 1);
+	let (mnemonic, length) = match (group, column) {
+		(0, 0) => match row {
+			0 => (String::from("nop"), 1),
+			1 => (format!("ld [${n16:04X}], sp"), 3),
+			2 => (String::from("stop"), 2),
+			3 => (format!("jr ${target:04X}"), 2),
+			_ => (format!("jr {}, ${target:04X}", CONDITIONS[row - 4]), 2),
+		},
+		(0, 1) if odd == 0 => (format!("ld {}, ${n16:04X}", PAIRS[pair]), 3),
+		(0, 1) => (format!("add hl, {}", PAIRS[pair]), 1),
+		(0, 2) => {
+			let pointer = ["[bc]", "[de]", "[hl+]", "[hl-]"][pair];
+			if odd == 0 {
+				(format!("ld {pointer}, a"), 1)
+			} else {
+				(format!("ld a, {pointer}"), 1)
+			}
+		}
+		(0, 3) => (format!("{} {}", ["inc", "dec"][odd], PAIRS[pair]), 1),
+		(0, 4) => (format!("inc {}", REGISTERS[row]), 1),
+		(0, 5) => (format!("dec {}", REGISTERS[row]), 1),
+		(0, 6) => (format!("ld {}, ${n8:02X}", REGISTERS[row]), 2),
+		(0, _) => {
+			let names = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+			(String::from(names[row]), 1)
+		}
+		(1, _) if opcode == 0x76 => (String::from("halt"), 1),
+		(1, _) => (format!("ld {}, {}", REGISTERS[row], REGISTERS[column]), 1),
+		(2, _) => (format!("{} {}", ARITHMETIC[row], REGISTERS[column]), 1),
+		(_, 0) => match row {
+			0..=3 => (format!("ret {}", CONDITIONS[row]), 1),
+			4 => (format!("ldh [$FF{n8:02X}], a"), 2),
+			5 => (format!("add sp, {e8}"), 2),
+			6 => (format!("ldh a, [$FF{n8:02X}]"), 2),
+			_ => (format!("ld hl, sp{e8:+}"), 2),
+		},
+		(_, 1) if odd == 0 => (format!("pop {}", STACK_PAIRS[pair]), 1),
+		(_, 1) => (String::from(["ret", "reti", "jp hl", "ld sp, hl"][pair]), 1),
+		(_, 2) => match row {
+			0..=3 => (format!("jp {}, ${n16:04X}", CONDITIONS[row]), 3),
+			4 => (String::from("ldh [c], a"), 1),
+			5 => (format!("ld [${n16:04X}], a"), 3),
+			6 => (String::from("ldh a, [c]"), 1),
+			_ => (format!("ld a, [${n16:04X}]"), 3),
+		},
+		(_, 3) => match row {
+			0 => (format!("jp ${n16:04X}"), 3),
+			1 => (decode_prefixed(n8), 2),
+			6 => (String::from("di"), 1),
+			7 => (String::from("ei"), 1),
+			_ => (format!("db ${opcode:02X}"), 1),
+		},
+		(_, 4) if row < 4 => (format!("call {}, ${n16:04X}", CONDITIONS[row]), 3),
+		(_, 5) if odd == 0 => (format!("push {}", STACK_PAIRS[pair]), 1),
+		(_, 5) if pair == 0 => (format!("call ${n16:04X}"), 3),
+		(_, 6) => (format!("{} ${n8:02X}", ARITHMETIC[row]), 2),
+		(_, 7) => (format!("rst ${:02X}", row * 8), 1),
+		_ => (format!("db ${opcode:02X}"), 1),
+	};
+
+	Instruction {
+		address,
+		bytes: (0..length)
+			.map(|offset| read(address.wrapping_add(offset)))
+			.collect(),
+		mnemonic,
+	}
+}
+
+/// Decodes the second byte of a `$CB`-prefixed instruction.
+fn decode_prefixed(opcode: u8) -> String {
+	let (y, z) = (usize::from(opcode >> 3 & 7), usize::from(opcode & 7));
+	match opcode >> 6 {
+		0 => format!("{} {}", ROTATIONS[y], REGISTERS[z]),
+		1 => format!("bit {y}, {}", REGISTERS[z]),
+		2 => format!("res {y}, {}", REGISTERS[z]),
+		_ => format!("set {y}, {}", REGISTERS[z]),
+	}
+}
+
+/// Decodes up to `before` instructions leading up to `pc`, the instruction at `pc`, and `after` instructions following it.
+///
+/// Instructions have different lengths, so the ones before `pc` are found by decoding from the earliest address
+/// which leads to `pc`. They may not be what actually ran if `pc` was reached by a jump.
+pub fn around(pc: u16, before: usize, after: usize, read: impl Fn(u16) -> u8) -> Vec<Instruction> {
+	let mut instructions = Vec::new();
+	// No instruction is longer than three bytes.
+	let earliest = pc.saturating_sub(u16::try_from(before * 3).unwrap_or(u16::MAX));
+	for start in earliest..pc {
+		let mut address = start;
+		let mut leading = Vec::new();
+		while address < pc {
+			let instruction = decode(address, &read);
+			address = address.saturating_add(u16::try_from(instruction.bytes.len()).unwrap_or(1));
+			leading.push(instruction);
+		}
+		if address == pc {
+			let skip = leading.len().saturating_sub(before);
+			instructions.extend(leading.into_iter().skip(skip));
+			break;
+		}
+	}
+
+	let mut address = pc;
+	for _ in 0..=after {
+		let instruction = decode(address, &read);
+		address = address.wrapping_add(u16::try_from(instruction.bytes.len()).unwrap_or(1));
+		instructions.push(instruction);
+	}
+	instructions
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Decodes `bytes` as if they were at `address`.
+	fn disassemble(address: u16, bytes: &[u8]) -> Instruction {
+		decode(address, |at| {
+			bytes
+				.get(usize::from(at.wrapping_sub(address)))
+				.copied()
+				.unwrap_or(0)
+		})
+	}
+
+	fn check(cases: &[(u16, &[u8], &str)]) {
+		for &(address, bytes, mnemonic) in cases {
+			let instruction = disassemble(address, bytes);
+			assert_eq!(instruction.mnemonic, mnemonic, "{bytes:02x?}");
+			assert_eq!(instruction.bytes, bytes, "{mnemonic}");
+		}
+	}
+
+	#[test]
+	fn prefixed() {
+		check(&[
+			(0, &[0xCB, 0x00], "rlc b"),
+			(0, &[0xCB, 0x1E], "rr [hl]"),
+			(0, &[0xCB, 0x37], "swap a"),
+			(0, &[0xCB, 0x3F], "srl a"),
+			(0, &[0xCB, 0x7C], "bit 7, h"),
+			(0, &[0xCB, 0x86], "res 0, [hl]"),
+			(0, &[0xCB, 0xFF], "set 7, a"),
+		]);
+	}
+
+	#[test]
+	fn immediate_16() {
+		check(&[
+			(0, &[0x01, 0x34, 0x12], "ld bc, $1234"),
+			(0, &[0x31, 0xFE, 0xFF], "ld sp, $FFFE"),
+			(0, &[0x08, 0x00, 0xC0], "ld [$C000], sp"),
+			(0, &[0xC3, 0x50, 0x01], "jp $0150"),
+			(0, &[0xCA, 0x00, 0x40], "jp z, $4000"),
+			(0, &[0xCD, 0x00, 0x20], "call $2000"),
+			(0, &[0xD4, 0x00, 0x20], "call nc, $2000"),
+			(0, &[0xEA, 0x00, 0xD0], "ld [$D000], a"),
+			(0, &[0xFA, 0x00, 0xD0], "ld a, [$D000]"),
+		]);
+	}
+
+	#[test]
+	fn relative_jumps() {
+		check(&[
+			(0x0150, &[0x18, 0x00], "jr $0152"),
+			(0x0150, &[0x18, 0xFE], "jr $0150"),
+			(0x0150, &[0x20, 0x10], "jr nz, $0162"),
+			(0x0150, &[0x38, 0x80], "jr c, $00D2"),
+			// Targets wrap around the address space.
+			(0xFFF0, &[0x18, 0x7F], "jr $0071"),
+			(0x0000, &[0x28, 0xF0], "jr z, $FFF2"),
+		]);
+	}
+
+	#[test]
+	fn unused_opcodes() {
+		check(&[
+			(0, &[0xD3], "db $D3"),
+			(0, &[0xE4], "db $E4"),
+			(0, &[0xFD], "db $FD"),
+		]);
+	}
+
+	#[test]
+	fn around_pc() {
+		let bytes = [0x00, 0x3E, 0x01, 0xC3, 0x00, 0x01, 0xC9];
+		let read = |address: u16| bytes.get(usize::from(address)).copied().unwrap_or(0);
+		let mnemonics = around(3, 2, 1, read)
+			.into_iter()
+			.map(|instruction| instruction.mnemonic)
+			.collect::<Vec<String>>();
+		assert_eq!(mnemonics, ["nop", "ld a, $01", "jp $0100", "ret"]);
+	}
+}
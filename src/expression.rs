@@ -0,0 +1,324 @@
+//! Expected values computed from a test's initial state, such as `initial.a + initial.b`.
+//!
+//! Expressions combine numbers, symbols, and the initial values of registers and memory,
+//! written as `initial.hl` or `initial.[wCount]`, with these operators, from tightest to loosest:
+//!
+//! ```not_rust
+//! -x  ~x
+//! x * y
+//! x + y  x - y
+//! x << y  x >> y
+//! x & y
+//! x ^ y
+//! x | y
+//! ```
+//!
+//! Arithmetic wraps around like the CPU's, at 8 or 16 bits depending on the value being compared.
+
+use crate::config::{parse_address, parse_location, Symbols};
+use crate::memory::Peek;
+use crate::registers::Value;
+use crate::table::Location;
+use gb_cpu_sim::cpu;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+	Multiply,
+	Add,
+	Subtract,
+	ShiftLeft,
+	ShiftRight,
+	And,
+	Xor,
+	Or,
+}
+
+impl Operator {
+	/// Every operator, along with how tightly it binds, from loosest to tightest.
+	/// Longer operators come before those they start with.
+	const OPERATORS: [(&'static str, Operator, u8); 8] = [
+		("|", Operator::Or, 0),
+		("^", Operator::Xor, 1),
+		("&", Operator::And, 2),
+		("<<", Operator::ShiftLeft, 3),
+		(">>", Operator::ShiftRight, 3),
+		("+", Operator::Add, 4),
+		("-", Operator::Subtract, 4),
+		("*", Operator::Multiply, 5),
+	];
+
+	fn apply(self, left: u16, right: u16) -> u16 {
+		let shift = u32::from(right);
+		match self {
+			Operator::Multiply => left.wrapping_mul(right),
+			Operator::Add => left.wrapping_add(right),
+			Operator::Subtract => left.wrapping_sub(right),
+			Operator::ShiftLeft => left.checked_shl(shift).unwrap_or(0),
+			Operator::ShiftRight => left.checked_shr(shift).unwrap_or(0),
+			Operator::And => left & right,
+			Operator::Xor => left ^ right,
+			Operator::Or => left | right,
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expression {
+	/// A number or symbol.
+	Number(u16),
+	/// The value of a register or byte of memory when the test began.
+	Initial(Location),
+	Negate(Box<Expression>),
+	Complement(Box<Expression>),
+	Binary(Box<Expression>, Operator, Box<Expression>),
+}
+
+impl Expression {
+	/// Computes the expression's value from the state the test began in.
+	#[must_use]
+	pub fn evaluate(&self, initial: &Initial) -> u16 {
+		match self {
+			Expression::Number(value) => *value,
+			Expression::Initial(location) => initial.get(*location),
+			Expression::Negate(inner) => inner.evaluate(initial).wrapping_neg(),
+			Expression::Complement(inner) => !inner.evaluate(initial),
+			Expression::Binary(left, operator, right) => {
+				operator.apply(left.evaluate(initial), right.evaluate(initial))
+			}
+		}
+	}
+
+	/// Adds each register and byte of memory which the expression reads to `locations`.
+	pub fn locations(&self, locations: &mut Vec<Location>) {
+		match self {
+			Expression::Number(_) => {}
+			Expression::Initial(location) => locations.push(*location),
+			Expression::Negate(inner) | Expression::Complement(inner) => inner.locations(locations),
+			Expression::Binary(left, _, right) => {
+				left.locations(locations);
+				right.locations(locations);
+			}
+		}
+	}
+}
+
+/// The values of registers and memory when a test began, for evaluating expressions.
+#[derive(Clone, Debug, Default)]
+pub struct Initial {
+	values: Vec<(Location, u16)>,
+}
+
+impl Initial {
+	/// Reads each of `locations` from the CPU.
+	pub fn capture<S: Peek>(
+		cpu: &cpu::State<S>,
+		locations: impl IntoIterator<Item = Location>,
+	) -> Self {
+		let values = locations
+			.into_iter()
+			.map(|location| {
+				let value = match location.read(cpu) {
+					Value::Byte(value) => u16::from(value),
+					Value::Word(value) => value,
+					Value::Flag(value) => u16::from(value),
+					_ => 0,
+				};
+				(location, value)
+			})
+			.collect();
+		Self { values }
+	}
+
+	/// The value of `location`, or 0 if it wasn't captured.
+	#[must_use]
+	pub fn get(&self, location: Location) -> u16 {
+		self.values
+			.iter()
+			.find(|(captured, _)| *captured == location)
+			.map_or(0, |(_, value)| *value)
+	}
+}
+
+/// Whether a value in a result is an expression, rather than a single number or symbol or some text.
+///
+/// Values which mention the initial state are expressions even if they don't parse, so that the mistake is reported.
+pub(crate) fn is_expression(text: &str, symfile: &Symbols) -> bool {
+	match parse(text, symfile) {
+		Ok(expression) => !matches!(expression, Expression::Number(_)),
+		Err(_) => tokenize(text)
+			.is_ok_and(|tokens| tokens.iter().any(|token| token.starts_with("initial."))),
+	}
+}
+
+/// Reads an expression such as `initial.hl + 2`.
+pub(crate) fn parse(text: &str, symfile: &Symbols) -> Result<Expression, String> {
+	let tokens = tokenize(text)?;
+	let mut parser = Parser {
+		tokens: &tokens,
+		symfile,
+	};
+	let expression = parser.binary(0)?;
+	match parser.tokens.first() {
+		None => Ok(expression),
+		Some(token) => Err(format!("Unexpected `{token}` in `{text}`")),
+	}
+}
+
+/// Splits an expression into operators, parentheses, and operands.
+/// Memory operands such as `initial.[wCount]` are kept whole, brackets included.
+fn tokenize(text: &str) -> Result<Vec<&str>, String> {
+	let is_operand = |char: char| char.is_ascii_alphanumeric() || "_.$#@[]".contains(char);
+	let mut tokens = Vec::new();
+	let mut rest = text.trim_start();
+	while let Some(char) = rest.chars().next() {
+		let length = if let Some((operator, _, _)) = Operator::OPERATORS
+			.iter()
+			.find(|(operator, _, _)| rest.starts_with(operator))
+		{
+			operator.len()
+		} else if "~()".contains(char) {
+			1
+		} else if is_operand(char) {
+			rest.find(|char: char| !is_operand(char))
+				.unwrap_or(rest.len())
+		} else {
+			return Err(format!("Unexpected `{char}` in `{text}`"));
+		};
+		let (token, after) = rest.split_at(length);
+		tokens.push(token);
+		rest = after.trim_start();
+	}
+	Ok(tokens)
+}
+
+struct Parser<'a> {
+	tokens: &'a [&'a str],
+	symfile: &'a Symbols,
+}
+
+impl<'a> Parser<'a> {
+	fn next(&mut self) -> Option<&'a str> {
+		let (token, rest) = self.tokens.split_first()?;
+		self.tokens = rest;
+		Some(token)
+	}
+
+	/// Reads operators which bind at least as tightly as `precedence`, from left to right.
+	fn binary(&mut self, precedence: u8) -> Result<Expression, String> {
+		let mut left = self.unary()?;
+		while let Some((_, operator, binding)) = self.tokens.first().and_then(|token| {
+			Operator::OPERATORS
+				.iter()
+				.find(|(text, _, binding)| text == token && *binding >= precedence)
+		}) {
+			self.next();
+			let right = self.binary(binding + 1)?;
+			left = Expression::Binary(Box::new(left), *operator, Box::new(right));
+		}
+		Ok(left)
+	}
+
+	fn unary(&mut self) -> Result<Expression, String> {
+		match self.next() {
+			Some("-") => Ok(Expression::Negate(Box::new(self.unary()?))),
+			Some("~") => Ok(Expression::Complement(Box::new(self.unary()?))),
+			Some("(") => {
+				let inner = self.binary(0)?;
+				match self.next() {
+					Some(")") => Ok(inner),
+					_ => Err(String::from("Missing a closing `)`")),
+				}
+			}
+			Some(operand) => self.operand(operand),
+			None => Err(String::from(
+				"Expected a value at the end of the expression",
+			)),
+		}
+	}
+
+	fn operand(&self, operand: &str) -> Result<Expression, String> {
+		if let Some(name) = operand.strip_prefix("initial.") {
+			return match parse_location(name, self.symfile) {
+				Some(location) => location.map(Expression::Initial),
+				None => Err(format!("`{name}` is not a register or memory")),
+			};
+		}
+		parse_address(operand, self.symfile)
+			.map(Expression::Number)
+			.ok_or_else(|| format!("`{operand}` is not a number or symbol"))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn symbols() -> Symbols {
+		Symbols::from([(String::from("wCount"), (0, 0xC000))])
+	}
+
+	/// Evaluates `text` with `a` as 0x12, `hl` as 0xC0F0, and `[wCount]` as 3.
+	fn evaluate(text: &str) -> u16 {
+		let initial = Initial {
+			values: vec![
+				(Location::A, 0x12),
+				(Location::Hl, 0xC0F0),
+				(Location::Address(0xC000), 3),
+			],
+		};
+		parse(text, &symbols()).unwrap().evaluate(&initial)
+	}
+
+	#[test]
+	fn precedence() {
+		assert_eq!(evaluate("initial.a + 2 * 3"), 0x18);
+		assert_eq!(evaluate("2 * 3 + initial.a"), 0x18);
+		assert_eq!(evaluate("1 + 1 << 2"), 8);
+		assert_eq!(evaluate("0xF0 | 0x0F & 0x03"), 0xF3);
+		assert_eq!(evaluate("6 ^ 3 | 8"), 0x0D);
+		assert_eq!(evaluate("10 - 2 - 3"), 5);
+	}
+
+	#[test]
+	fn unary() {
+		assert_eq!(evaluate("-1"), 0xFFFF);
+		assert_eq!(evaluate("-initial.[wCount] + 4"), 1);
+		assert_eq!(evaluate("~initial.a & 0xFF"), 0xED);
+		assert_eq!(evaluate("--2"), 2);
+	}
+
+	#[test]
+	fn parentheses() {
+		assert_eq!(evaluate("(initial.a + 2) * 3"), 0x3C);
+		assert_eq!(evaluate("((initial.[wCount]))"), 3);
+		assert_eq!(
+			parse("(1 + 2", &symbols()),
+			Err(String::from("Missing a closing `)`"))
+		);
+		assert_eq!(
+			parse("1 + 2)", &symbols()),
+			Err(String::from("Unexpected `)` in `1 + 2)`"))
+		);
+	}
+
+	#[test]
+	fn wrapping() {
+		assert_eq!(evaluate("initial.hl + 0x20"), 0xC110);
+		assert_eq!(evaluate("0xFFFF + 2"), 1);
+		assert_eq!(evaluate("1 << 15"), 0x8000);
+		assert_eq!(evaluate("1 << 16"), 0);
+		assert_eq!(evaluate("initial.hl >> 100"), 0);
+	}
+
+	#[test]
+	fn detection() {
+		let symbols = symbols();
+		assert!(is_expression("initial.a", &symbols));
+		assert!(is_expression("wCount + 1", &symbols));
+		assert!(is_expression("initial.a + wTypo", &symbols));
+		assert!(!is_expression("wCount", &symbols));
+		assert!(!is_expression("0x10", &symbols));
+		assert!(!is_expression("HELLO WORLD", &symbols));
+		assert!(!is_expression("wInitial.x", &symbols));
+	}
+}
@@ -0,0 +1,40 @@
+//! Deterministic entry points for fuzzing the CPU and memory, such as with `cargo fuzz`.
+//!
+//! Each function runs a fixed number of instructions over a memory image, with no timing or randomness,
+//! so that any input which panics can be replayed exactly.
+
+use crate::memory::{AddressSpace, FlatMemory};
+use gb_cpu_sim::{cpu, memory};
+
+/// Runs up to `instructions` instructions, stopping early at `halt`, `stop`, or an invalid opcode.
+/// Returns the number of instructions which were run.
+pub fn execute<S: memory::AddressSpace>(
+	cpu_state: &mut cpu::State<S>,
+	instructions: usize,
+) -> usize {
+	for executed in 0..instructions {
+		match cpu_state.tick() {
+			cpu::TickResult::Halt | cpu::TickResult::Stop | cpu::TickResult::InvalidOpcode => {
+				return executed + 1;
+			}
+			cpu::TickResult::Ok | cpu::TickResult::Break | cpu::TickResult::Debug => {}
+		}
+	}
+	instructions
+}
+
+/// Runs the CPU on its own over 64 KiB of RAM starting with `image`, beginning at 0x0100.
+#[must_use]
+pub fn execute_image(image: &[u8], instructions: usize) -> cpu::State<FlatMemory> {
+	let mut cpu_state = cpu::State::new(FlatMemory::with_image(image));
+	execute(&mut cpu_state, instructions);
+	cpu_state
+}
+
+/// Runs `rom` from its entry point with evunit's Game Boy memory map.
+#[must_use]
+pub fn execute_rom(rom: &Vec<u8>, instructions: usize) -> cpu::State<AddressSpace<'_>> {
+	let mut cpu_state = cpu::State::new(AddressSpace::with(rom));
+	execute(&mut cpu_state, instructions);
+	cpu_state
+}
@@ -0,0 +1,88 @@
+//! Conditions which must hold after every instruction a test runs.
+
+use crate::memory::Peek;
+use crate::registers::Value;
+use crate::table::Location;
+use gb_cpu_sim::cpu;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+	Equal,
+	NotEqual,
+	Less,
+	LessOrEqual,
+	Greater,
+	GreaterOrEqual,
+}
+
+impl Comparison {
+	/// Every comparison, along with the operator it is written as.
+	/// Longer operators come first, so that `<=` is not mistaken for `<`.
+	pub const OPERATORS: [(&'static str, Comparison); 6] = [
+		("==", Comparison::Equal),
+		("!=", Comparison::NotEqual),
+		("<=", Comparison::LessOrEqual),
+		(">=", Comparison::GreaterOrEqual),
+		("<", Comparison::Less),
+		(">", Comparison::Greater),
+	];
+
+	#[must_use]
+	pub fn compare(self, left: u16, right: u16) -> bool {
+		match self {
+			Comparison::Equal => left == right,
+			Comparison::NotEqual => left != right,
+			Comparison::Less => left < right,
+			Comparison::LessOrEqual => left <= right,
+			Comparison::Greater => left > right,
+			Comparison::GreaterOrEqual => left >= right,
+		}
+	}
+}
+
+impl fmt::Display for Comparison {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let (operator, _) = Comparison::OPERATORS
+			.iter()
+			.find(|(_, comparison)| comparison == self)
+			.expect("every comparison has an operator");
+		write!(f, "{operator}")
+	}
+}
+
+/// A comparison between a register or byte of memory and a value, such as `sp >= 0xDF00`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Invariant {
+	pub location: Location,
+	pub comparison: Comparison,
+	pub value: u16,
+}
+
+impl Invariant {
+	/// Returns true if the invariant holds for the current state of the CPU.
+	pub fn holds<S: Peek>(&self, cpu: &cpu::State<S>) -> bool {
+		let actual = match self.location.read(cpu) {
+			Value::Byte(value) => u16::from(value),
+			Value::Word(value) => value,
+			Value::Flag(value) => u16::from(value),
+			_ => return true,
+		};
+		self.comparison.compare(actual, self.value)
+	}
+}
+
+impl fmt::Display for Invariant {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let Invariant {
+			location,
+			comparison,
+			value,
+		} = self;
+		if location.is_word() {
+			write!(f, "{location} {comparison} 0x{value:04X}")
+		} else {
+			write!(f, "{location} {comparison} 0x{value:02X}")
+		}
+	}
+}
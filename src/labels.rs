@@ -0,0 +1,98 @@
+//! Naming addresses after the symbols which precede them, for showing where code was running.
+
+use crate::config::Symbols;
+use crate::mapfile::Section;
+use std::collections::BTreeMap;
+
+/// Looks up symbols by their bank and address, the reverse of a [`Symbols`] map.
+#[derive(Clone, Debug, Default)]
+pub struct Labels {
+	by_address: BTreeMap<(u32, u16), String>,
+	sections: Vec<Section>,
+}
+
+impl Labels {
+	#[must_use]
+	pub fn new(symfile: &Symbols) -> Self {
+		let mut by_address = BTreeMap::<(u32, u16), String>::new();
+		for (name, location) in symfile {
+			// When several symbols share an address, prefer global labels, then shorter names,
+			// so that the choice doesn't depend on the order of the symfile.
+			let rank = |name: &str| (name.contains('.'), name.len(), String::from(name));
+			match by_address.get_mut(location) {
+				Some(existing) if rank(existing) <= rank(name) => {}
+				Some(existing) => existing.clone_from(name),
+				None => {
+					by_address.insert(*location, name.clone());
+				}
+			}
+		}
+		Self {
+			by_address,
+			sections: Vec::new(),
+		}
+	}
+
+	/// Names addresses after the section containing them, such as `"Variables"+3`,
+	/// where no symbol precedes them within it.
+	#[must_use]
+	pub fn with_sections(mut self, sections: Vec<Section>) -> Self {
+		self.sections = sections;
+		self
+	}
+
+	/// Every symbol, as its bank, address, and name, in order of bank and address.
+	pub fn iter(&self) -> impl Iterator<Item = (u32, u16, &str)> {
+		self.by_address
+			.iter()
+			.map(|((bank, address), name)| (*bank, *address, name.as_str()))
+	}
+
+	/// Names `address` after the nearest symbol at or before it, such as `Main+3`,
+	/// or after its section if that symbol is in an earlier one.
+	///
+	/// `rom_bank` is the bank mapped to 0x4000-0x7FFF; symbols outside of it are assumed to be in bank 0.
+	#[must_use]
+	pub fn name(&self, rom_bank: usize, address: u16) -> Option<String> {
+		let bank = match address {
+			0x4000..=0x7FFF => u32::try_from(rom_bank).ok()?,
+			_ => 0,
+		};
+		let section = self
+			.sections
+			.iter()
+			.find(|section| section.contains(bank, address));
+		let symbol = self
+			.by_address
+			.range(..=(bank, address))
+			.next_back()
+			.filter(|((symbol_bank, symbol_address), _)| {
+				*symbol_bank == bank
+					&& region(*symbol_address) == region(address)
+					&& section.is_none_or(|section| *symbol_address >= section.start)
+			});
+		let (name, start) = match (symbol, section) {
+			(Some(((_, symbol_address), name)), _) => (name.clone(), *symbol_address),
+			(None, Some(section)) => (format!("\"{}\"", section.name), section.start),
+			(None, None) => return None,
+		};
+		Some(match address - start {
+			0 => name,
+			offset => format!("{name}+{offset}"),
+		})
+	}
+}
+
+/// The start of the memory region containing `address`, so that symbols don't name addresses in other regions.
+fn region(address: u16) -> u16 {
+	match address {
+		0x0000..=0x3FFF => 0x0000,
+		0x4000..=0x7FFF => 0x4000,
+		0x8000..=0x9FFF => 0x8000,
+		0xA000..=0xBFFF => 0xA000,
+		0xC000..=0xCFFF => 0xC000,
+		0xD000..=0xFDFF => 0xD000,
+		0xFE00..=0xFF7F => 0xFE00,
+		0xFF80..=0xFFFF => 0xFF80,
+	}
+}
@@ -3,17 +3,47 @@
 
 pub use gb_cpu_sim::cpu;
 
+pub mod cdb;
+pub mod charmap;
+pub mod condition;
+pub mod config;
+pub mod convention;
+pub mod coverage;
+pub mod debugfile;
+#[cfg(feature = "differential")]
+pub mod differential;
+pub mod disasm;
+pub mod expression;
+pub mod fuzz;
+pub mod invariant;
+pub mod labels;
 pub mod log;
+pub mod mapfile;
 pub mod memory;
+pub mod message;
+pub mod patch;
 pub mod prelude;
+pub mod profile;
+pub mod reg;
 pub mod registers;
+pub mod report;
+pub mod reporter;
+pub mod savestate;
+#[cfg(feature = "scripts")]
+pub mod script;
+pub mod snapshot;
+pub mod suite;
+pub mod sym;
+pub mod table;
 pub mod test;
+pub mod testrom;
+pub mod vectors;
 
 use crate::log::{Logger, SilenceLevel};
-use crate::memory::AddressSpace;
-use crate::test::TestConfig;
+use crate::suite::Suite;
+use crate::test::{RomVariant, TestConfig};
 use std::collections::HashMap;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader, Read};
 use std::path::Path;
 use std::process::exit;
@@ -26,6 +56,8 @@ pub enum Error {
 	TestsFailed(u32),
 	#[error("{0}")]
 	CompareFailed(registers::CompareResult),
+	#[error("Invalid shard \"{0}\"; expected K/N, where K is between 1 and N")]
+	InvalidShard(String),
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -55,43 +87,187 @@ pub fn read_symfile(path: &Option<String>) -> HashMap<String, (u32, u16)> {
 	open_symfile(path.as_deref().map(AsRef::<Path>::as_ref))
 }
 
+/// Reads an RGBDS symfile, or the symbols in an RGBLINK map file, GBDK `.noi` file,
+/// or SDCC `.cdb` file if `path` has one of their extensions.
 #[must_use]
 pub fn open_symfile(path: Option<&Path>) -> HashMap<String, (u32, u16)> {
-	let mut symfile = HashMap::new();
+	let Some(path) = path else {
+		return HashMap::new();
+	};
+	match path.extension().and_then(|extension| extension.to_str()) {
+		Some("cdb") => open_cdb(path).symbols(),
+		Some("map") => open_map(path).symbols,
+		Some("noi") => open_noi(path),
+		_ => open_sym(path).symbols(),
+	}
+}
 
-	if let Some(path) = path.as_ref() {
-		let dpath = path.display();
-		let file = File::open(path).unwrap_or_else(|error| {
-			eprintln!("Failed to open {dpath}: {error}");
-			exit(1);
-		});
-		let symbols = BufReader::new(file)
-			.lines()
-			.map(|line| {
-				line.unwrap_or_else(|error| {
-					eprintln!("Error reading {dpath}: {error}");
-					exit(1);
-				})
+/// Reads every symbol in an RGBDS symfile, along with its bank and any metadata.
+#[must_use]
+pub fn open_sym(path: &Path) -> sym::SymFile {
+	let dpath = path.display();
+	let text = fs::read_to_string(path).unwrap_or_else(|error| {
+		eprintln!("Failed to open {dpath}: {error}");
+		exit(1);
+	});
+	sym::SymFile::parse(&text).unwrap_or_else(|errors| {
+		for error in errors {
+			eprintln!("Failed to parse {dpath} {error}");
+		}
+		exit(1);
+	})
+}
+
+/// Reads the symbols in a GBDK `.noi` file.
+fn open_noi(path: &Path) -> HashMap<String, (u32, u16)> {
+	let dpath = path.display();
+	let file = File::open(path).unwrap_or_else(|error| {
+		eprintln!("Failed to open {dpath}: {error}");
+		exit(1);
+	});
+	let mut symfile = BufReader::new(file)
+		.lines()
+		.map(|line| {
+			line.unwrap_or_else(|error| {
+				eprintln!("Error reading {dpath}: {error}");
+				exit(1);
 			})
-			.enumerate()
-			.filter_map(|(n, line)| {
-				gb_sym_file::parse_line(&line).map(|parse_result| {
-					parse_result.unwrap_or_else(|parse_error| {
-						eprintln!("Failed to parse {dpath} line {}: {parse_error}", n + 1);
-						exit(1);
-					})
+		})
+		.enumerate()
+		.filter_map(|(n, line)| {
+			parse_noi_line(&line).map(|parse_result| {
+				parse_result.unwrap_or_else(|parse_error| {
+					eprintln!("Failed to parse {dpath} line {}: {parse_error}", n + 1);
+					exit(1);
 				})
 			})
-			// We are only interested in banked symbols
-			.filter_map(|(name, loc)| match loc {
-				gb_sym_file::Location::Banked(bank, addr) => Some((name, (bank, addr))),
-				_ => None,
-			});
-		symfile.extend(symbols);
-	}
+		})
+		.collect::<HashMap<_, _>>();
+	// C symbols are prefixed with `_`, which configs may leave out.
+	let aliases = symfile
+		.iter()
+		.filter_map(|(name, location)| Some((name.strip_prefix('_')?, *location)))
+		.filter(|(name, _)| !symfile.contains_key(*name))
+		.map(|(name, location)| (String::from(name), location))
+		.collect::<Vec<_>>();
+	symfile.extend(aliases);
 	symfile
 }
 
+/// Reads the C variables, types, and addresses in an SDCC `.cdb` file.
+#[must_use]
+pub fn open_cdb(path: &Path) -> cdb::DebugInfo {
+	let dpath = path.display();
+	let text = fs::read_to_string(path).unwrap_or_else(|error| {
+		eprintln!("Failed to open {dpath}: {error}");
+		exit(1);
+	});
+	cdb::DebugInfo::parse(&text).unwrap_or_else(|errors| {
+		for error in errors {
+			eprintln!("Failed to parse {dpath} {error}");
+		}
+		exit(1);
+	})
+}
+
+/// Reads the sections and symbols in an RGBLINK map file.
+#[must_use]
+pub fn open_map(path: &Path) -> mapfile::MapFile {
+	let dpath = path.display();
+	let text = fs::read_to_string(path).unwrap_or_else(|error| {
+		eprintln!("Failed to open {dpath}: {error}");
+		exit(1);
+	});
+	mapfile::MapFile::parse(&text).unwrap_or_else(|errors| {
+		for error in errors {
+			eprintln!("Failed to parse {dpath} {error}");
+		}
+		exit(1);
+	})
+}
+
+/// A symbol's name, bank, and address.
+type Symbol = (String, (u32, u16));
+
+/// Reads a line of a GBDK `.noi` file, such as `DEF _main 0x200`.
+/// Banked addresses hold their bank above the lower 16 bits, such as `0x14000` for bank 1.
+fn parse_noi_line(line: &str) -> Option<std::result::Result<Symbol, String>> {
+	let mut words = line.split_whitespace();
+	if words.next()? != "DEF" {
+		return None;
+	}
+	let (Some(name), Some(value), None) = (words.next(), words.next(), words.next()) else {
+		return Some(Err(String::from("Expected `DEF name value`")));
+	};
+	let parsed = match value
+		.strip_prefix("0x")
+		.or_else(|| value.strip_prefix("0X"))
+	{
+		Some(hex) => u32::from_str_radix(hex, 16),
+		None => value.parse(),
+	};
+	let Ok(parsed) = parsed else {
+		return Some(Err(format!("Invalid value `{value}`")));
+	};
+	let [low, high, ..] = parsed.to_le_bytes();
+	let address = u16::from_le_bytes([low, high]);
+	Some(Ok((String::from(name), (parsed >> 16, address))))
+}
+
+/// Applies the IPS or BPS patch at `path` to `rom`.
+#[must_use]
+pub fn patch_rom(rom: &[u8], path: &str) -> Vec<u8> {
+	let contents = fs::read(path).unwrap_or_else(|msg| {
+		eprintln!("Failed to open {path}: {msg}");
+		exit(1)
+	});
+	let mut rom = patch::apply(rom, &contents).unwrap_or_else(|error| {
+		eprintln!("Failed to apply {path}: {error}");
+		exit(1);
+	});
+	if rom.len() < 0x4000 {
+		rom.resize(0x4000, 0xFF);
+	}
+	rom
+}
+
+pub(crate) fn is_patch(path: &str) -> bool {
+	let extension = Path::new(path).extension();
+	extension.is_some_and(|extension| extension == "ips" || extension == "bps")
+}
+
+/// Builds the ROM of every test which does not run on the main ROM unmodified,
+/// keyed by [`TestConfig::rom_variant`].
+///
+/// Tests with their own ROM or a non-patch overlay open it as a replacement for `rom`,
+/// and patches are applied on top. `patches` are applied to each ROM opened this way
+/// before the test's own, so that they match `rom`, which is expected to have them already.
+#[must_use]
+pub fn open_test_roms<'t>(
+	rom: &[u8],
+	patches: &[String],
+	tests: impl IntoIterator<Item = &'t TestConfig>,
+) -> HashMap<RomVariant, Vec<u8>> {
+	let mut roms = HashMap::new();
+	for variant in tests.into_iter().filter_map(TestConfig::rom_variant) {
+		if roms.contains_key(&variant) {
+			continue;
+		}
+		let (variant_path, variant_patches) = &variant;
+		let mut variant_rom = match variant_path {
+			Some(path) => patches
+				.iter()
+				.fold(open_rom(path), |rom, path| patch_rom(&rom, path)),
+			None => rom.to_vec(),
+		};
+		for path in variant_patches {
+			variant_rom = patch_rom(&variant_rom, path);
+		}
+		roms.insert(variant, variant_rom);
+	}
+	roms
+}
+
 /// Run all provided unit tests using a given ROM.
 ///
 /// # Errors
@@ -100,17 +276,9 @@ pub fn open_symfile(path: Option<&Path>) -> HashMap<String, (u32, u16)> {
 pub fn run_tests(rom_path: &str, tests: &[TestConfig], silence_level: SilenceLevel) -> Result<()> {
 	// Load the ROM
 	let rom = open_rom(rom_path);
-	let address_space = AddressSpace::with(&rom);
 	let mut logger = Logger::new(silence_level, rom_path);
 
-	for test in tests {
-		// Prepare test
-		let mut cpu = cpu::State::new(address_space.clone());
-		let mut test_logger = logger.make_test(test);
-
-		// Run and exit
-		test.run(&mut cpu, &mut test_logger);
-	}
+	Suite::new(tests.to_vec()).run(&rom, &mut logger, |_, _, _| {});
 
 	if logger.finish() {
 		Ok(())
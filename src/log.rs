@@ -1,6 +1,23 @@
+use crate::charmap::Charmap;
+use crate::coverage::Coverage;
+use crate::disasm::{self, Instruction};
+use crate::labels::Labels;
+use crate::memory::{Access, AccessKind, Inspect, RamFill, Warning};
+use crate::message;
+use crate::profile;
+use crate::reg::HwReg;
+use crate::registers::{CompareResult, Difference};
+use crate::report::{Executed, Heat, MemoryRow, Span, Step, TestReport, TestStatus};
+use crate::reporter::{
+	BreakpointDetails, BreakpointKind, FailureDetails, JsonReporter, MarkdownReporter,
+	NdjsonReporter, Reporter, Summary, TextReporter,
+};
 use crate::Error;
-use gb_cpu_sim::{cpu, memory};
-use owo_colors::OwoColorize;
+use gb_cpu_sim::cpu;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 
 use crate::test::{FailureReason, TestConfig};
 
@@ -12,115 +29,522 @@ pub enum SilenceLevel {
 	All,     // Silences all output unless an error occurs.
 }
 
-/// Tracks and prints test results.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OutputFormat {
+	/// Colored, human-readable messages.
+	#[default]
+	Text,
+	/// A single JSON report printed once all tests are complete.
+	Json,
+	/// A Markdown summary of the results, printed once all tests are complete.
+	Markdown,
+	/// One JSON object per line for each event, printed as tests run.
+	Ndjson,
+}
+
+/// Tracks test results, and passes them on to a [`Reporter`].
 pub struct Logger<'a> {
-	silence_all: bool,
-	silence_passing: bool,
+	silence_level: SilenceLevel,
 	rom_path: &'a str,
+	/// Names the addresses in failure output.
+	labels: Option<&'a Labels>,
+	/// Shared with forked loggers, so that output from each thread is not interleaved.
+	reporter: Arc<Mutex<dyn Reporter + Send + 'a>>,
 	pub pass: u32,
 	pub failure: u32,
+	pub skipped: u32,
+	/// The results of each test, in the order they were run.
+	pub reports: Vec<TestReport>,
+	/// The ROM addresses executed by every test which records coverage.
+	pub coverage: Coverage,
+	/// How many of the slowest tests are listed in the summary.
+	slowest: usize,
 }
 
+/// How many instructions before and after `pc` are disassembled when a test fails.
+const DISASSEMBLY_BEFORE: usize = 3;
+const DISASSEMBLY_AFTER: usize = 3;
+
 pub struct TestLogger<'a, 'b> {
 	logger: &'b mut Logger<'a>,
 	name: &'b String,
 	enable_breakpoints: bool,
+	/// How the game stores text, for decoding debug messages.
+	charmap: Option<&'b Charmap>,
+	/// The ROM bank used to name addresses in 0x4000-0x7FFF, updated whenever the CPU's state is reported.
+	rom_bank: usize,
+	cycles: u64,
+	instructions: u64,
+	/// When the test started, for measuring how long it took.
+	started: Instant,
+	history: Vec<Executed>,
+	profile: Vec<Span>,
+	trace: Vec<Step>,
+	watched: Vec<Access>,
+	heat_map: Vec<Heat>,
+	ram_seed: Option<u64>,
+	messages: Vec<String>,
+	warnings: Vec<String>,
 }
 
 impl<'a> Logger<'a> {
 	#[must_use]
 	pub fn new(silence_level: SilenceLevel, rom_path: &'a str) -> Logger<'a> {
-		let (silence_all, silence_passing) = match silence_level {
-			SilenceLevel::None => (false, false),
-			SilenceLevel::Passing => (false, true),
-			SilenceLevel::All => (true, true),
-		};
-
 		Logger {
-			silence_all,
-			silence_passing,
+			silence_level,
 			rom_path,
+			labels: None,
+			reporter: Arc::new(Mutex::new(TextReporter::new(
+				silence_level,
+				rom_path,
+				io::stdout(),
+			))),
+			pass: 0,
+			failure: 0,
+			skipped: 0,
+			reports: Vec::new(),
+			coverage: Coverage::default(),
+			slowest: 0,
+		}
+	}
+	/// Replaces the reporter with one of the built-in formats, printing to stdout.
+	#[must_use]
+	pub fn with_format(self, format: OutputFormat) -> Self {
+		self.with_output(format, io::stdout())
+	}
+	/// Replaces the reporter with one of the built-in formats, writing to `out`.
+	#[must_use]
+	pub fn with_output(self, format: OutputFormat, out: impl io::Write + Send + 'a) -> Self {
+		match format {
+			OutputFormat::Text => {
+				let reporter = TextReporter::new(self.silence_level, self.rom_path, out);
+				self.with_reporter(reporter)
+			}
+			OutputFormat::Json => self.with_reporter(JsonReporter(out)),
+			OutputFormat::Markdown => self.with_reporter(MarkdownReporter(out)),
+			OutputFormat::Ndjson => self.with_reporter(NdjsonReporter(out)),
+		}
+	}
+	/// Names addresses after the symbols in `labels` when reporting failures.
+	#[must_use]
+	pub fn with_labels(mut self, labels: &'a Labels) -> Self {
+		self.labels = Some(labels);
+		self
+	}
+	/// Lists the `count` tests which ran for the most cycles in the summary.
+	#[must_use]
+	pub fn with_slowest(mut self, count: usize) -> Self {
+		self.slowest = count;
+		self
+	}
+	#[must_use]
+	pub fn with_reporter(mut self, reporter: impl Reporter + Send + 'a) -> Self {
+		self.reporter = Arc::new(Mutex::new(reporter));
+		self
+	}
+	fn reporter(&self) -> MutexGuard<'_, dyn Reporter + Send + 'a> {
+		// A reporter which panicked on another thread has nothing left to protect.
+		self.reporter.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+	/// Creates an empty logger with the same settings, for running tests on another thread.
+	pub(crate) fn fork(&self) -> Logger<'a> {
+		Logger {
+			silence_level: self.silence_level,
+			rom_path: self.rom_path,
+			labels: self.labels,
+			reporter: Arc::clone(&self.reporter),
 			pass: 0,
 			failure: 0,
+			skipped: 0,
+			reports: Vec::new(),
+			coverage: Coverage::default(),
+			slowest: self.slowest,
 		}
 	}
+	/// Adds the results of a forked logger to this one.
+	pub(crate) fn merge(&mut self, other: Logger<'a>) {
+		self.pass += other.pass;
+		self.failure += other.failure;
+		self.skipped += other.skipped;
+		self.reports.extend(other.reports);
+		self.coverage.merge(&other.coverage);
+	}
 	pub fn make_test<'b>(&'b mut self, config: &'b TestConfig) -> TestLogger<'a, 'b> {
+		self.reporter().test_started(&config.name);
 		TestLogger {
 			logger: self,
 			name: &config.name,
 			enable_breakpoints: config.enable_breakpoints,
+			charmap: config.charmap.as_deref(),
+			rom_bank: config.bank.unwrap_or(1),
+			cycles: 0,
+			instructions: 0,
+			started: Instant::now(),
+			history: Vec::new(),
+			profile: Vec::new(),
+			trace: Vec::new(),
+			watched: Vec::new(),
+			heat_map: Vec::new(),
+			ram_seed: match config.ram_fill {
+				RamFill::Random(seed) => Some(seed),
+				RamFill::Byte(_) => None,
+			},
+			messages: Vec::new(),
+			warnings: Vec::new(),
 		}
 	}
 	#[must_use]
 	pub fn finish(&self) -> bool {
-		// When in SILENCE_ALL only print the final message if a test failed.
-		if !self.silence_all || self.failure != 0 {
-			println!(
-				"{}: All tests complete. {}/{} passed.",
-				self.rom_path,
-				self.pass,
-				self.pass + self.failure,
-			);
-		}
+		self.reporter().finished(&Summary {
+			rom: self.rom_path,
+			passed: self.pass,
+			failed: self.failure,
+			skipped: self.skipped,
+			warnings: self
+				.reports
+				.iter()
+				.map(|report| report.warnings.len())
+				.sum(),
+			instructions: self.reports.iter().map(|report| report.instructions).sum(),
+			cycles: self.reports.iter().map(|report| report.cycles).sum(),
+			time: self.reports.iter().map(|report| report.time).sum(),
+			tests: &self.reports,
+			slowest: profile::slowest(&self.reports, self.slowest),
+		});
 		self.failure == 0
 	}
 }
 
 impl TestLogger<'_, '_> {
-	pub fn log_breakpoint<A: memory::AddressSpace>(&mut self, cpu_state: &cpu::State<A>) {
+	/// Names `address` after the nearest symbol at or before it, if a symfile was given.
+	fn label(&self, address: u16) -> Option<String> {
+		self.logger
+			.labels
+			.and_then(|labels| labels.name(self.rom_bank, address))
+	}
+	/// Adds a report for the test.
+	///
+	/// `location` names where the test stopped, if that explains the failure.
+	fn report(
+		&mut self,
+		status: TestStatus,
+		reason: Option<FailureReason>,
+		error: Option<&Error>,
+		location: Option<String>,
+	) {
+		let (mismatches, conditions) = match error {
+			Some(Error::CompareFailed(result)) => (
+				result
+					.mismatches(|address| {
+						self.label(address)
+							.unwrap_or_else(|| format!("{address:X}"))
+					})
+					.collect(),
+				result.conditions().to_vec(),
+			),
+			_ => (Vec::new(), Vec::new()),
+		};
+		let description = reason.map(|reason| {
+			let description = reason.describe(|address| {
+				self.label(address)
+					.unwrap_or_else(|| format!("0x{address:04x}"))
+			});
+			match location {
+				Some(location) => format!("{description} at {location}"),
+				None => description,
+			}
+		});
+		self.logger.reports.push(TestReport {
+			name: self.name.clone(),
+			status,
+			reason,
+			description,
+			mismatches,
+			conditions,
+			memory: Vec::new(),
+			cycles: self.cycles,
+			instructions: self.instructions,
+			time: self.started.elapsed(),
+			history: if status == TestStatus::Failed {
+				self.history.clone()
+			} else {
+				Vec::new()
+			},
+			profile: self.profile.clone(),
+			// Traces are long, and only useful for explaining failures.
+			trace: if status == TestStatus::Failed {
+				self.trace.clone()
+			} else {
+				Vec::new()
+			},
+			watched: self.watched.clone(),
+			heat_map: self.heat_map.clone(),
+			messages: self.messages.clone(),
+			warnings: self.warnings.clone(),
+			ram_seed: self.ram_seed,
+			skip_reason: None,
+		});
+	}
+	/// The report most recently added by [`TestLogger::report`].
+	fn last_report(&self) -> &TestReport {
+		&self.logger.reports[self.logger.reports.len() - 1]
+	}
+	/// Sets the number of cycles the test has run for, which is included in its report.
+	pub fn record_cycles(&mut self, cycles: u64) {
+		self.cycles = cycles;
+	}
+	/// Sets the number of instructions the test has executed, which is included in its report.
+	pub fn record_instructions(&mut self, instructions: u64) {
+		self.instructions = instructions;
+	}
+	/// Sets the ROM bank and address of the last instructions executed by the test,
+	/// which are included in its report if it fails.
+	pub fn record_history(&mut self, history: impl IntoIterator<Item = (usize, u16)>) {
+		let labels = self.logger.labels;
+		self.history = history
+			.into_iter()
+			.map(|(bank, pc)| Executed {
+				pc,
+				label: labels.and_then(|labels| labels.name(bank, pc)),
+			})
+			.collect();
+	}
+	/// Sets each subroutine the test called, which is included in its report.
+	/// Spans are named after symbols, if a symfile was given.
+	pub fn record_profile(&mut self, mut spans: Vec<Span>) {
+		if let Some(labels) = self.logger.labels {
+			for span in &mut spans {
+				span.label = labels.name(span.bank, span.address);
+			}
+		}
+		self.profile = spans;
+	}
+	/// Adds the ROM addresses executed by the test to the logger's coverage.
+	pub fn record_coverage(&mut self, coverage: &Coverage) {
+		self.logger.coverage.merge(coverage);
+	}
+	/// Sets the instructions executed by the test, which are included in its report if it fails.
+	pub fn record_trace(&mut self, trace: Vec<Step>) {
+		self.trace = trace;
+	}
+	/// Sets the accesses to watched memory made by the test, which are included in its report.
+	pub fn record_watched(&mut self, accesses: Vec<Access>) {
+		self.watched = accesses;
+	}
+	/// Counts the reads and writes to each address in `accesses`, which are included in the test's report.
+	/// Reads from ROM are left out, since nearly all of them are the CPU fetching instructions.
+	pub fn record_heat_map(&mut self, accesses: &[Access]) {
+		let mut counts = BTreeMap::<u16, (u64, u64)>::new();
+		for access in accesses {
+			match access.kind {
+				AccessKind::Read if access.address < 0x8000 => {}
+				AccessKind::Read => counts.entry(access.address).or_default().0 += 1,
+				AccessKind::Write => counts.entry(access.address).or_default().1 += 1,
+			}
+		}
+		self.heat_map = counts
+			.into_iter()
+			.map(|(address, (reads, writes))| Heat {
+				address,
+				label: self.label(address),
+				reads,
+				writes,
+			})
+			.collect();
+	}
+	fn breakpoint<A: Inspect>(&mut self, kind: BreakpointKind, cpu_state: &cpu::State<A>) {
 		if self.enable_breakpoints {
-			println!(
-				"{}: BREAKPOINT in {} \n{cpu_state}",
-				self.logger.rom_path, self.name
-			);
+			self.rom_bank = cpu_state.address_space.rom_bank();
+			self.logger.reporter().breakpoint(&BreakpointDetails {
+				test: self.name,
+				kind,
+				pc: cpu_state.pc,
+				location: self.label(cpu_state.pc),
+				state: cpu_state,
+			});
 		}
 	}
-	pub fn log_debug<A: memory::AddressSpace>(&mut self, cpu_state: &cpu::State<A>) {
+	pub fn log_breakpoint<A: Inspect>(&mut self, cpu_state: &cpu::State<A>) {
+		self.breakpoint(BreakpointKind::Breakpoint, cpu_state);
+	}
+	/// Reports a `ld d, d`: the message following it, if it has one, or otherwise the state of the CPU.
+	pub fn log_debug<A: Inspect>(&mut self, cpu_state: &cpu::State<A>) {
+		match message::decode(cpu_state, self.charmap) {
+			Some(message) => self.message(message),
+			None => self.breakpoint(BreakpointKind::Debug, cpu_state),
+		}
+	}
+	/// Prints a debug message from the test, which is included in its report.
+	/// Like breakpoints, messages are ignored unless `enable_breakpoints` is set.
+	pub fn message(&mut self, message: String) {
 		if self.enable_breakpoints {
-			println!(
-				"{}: DEBUG in {} \n{cpu_state}",
-				self.logger.rom_path, self.name
-			);
+			self.logger.reporter().message(self.name, &message);
+			self.messages.push(message);
 		}
 	}
-	pub fn pass(&mut self) {
-		if !self.logger.silence_passing {
-			println!(
-				"{}: {} {}",
-				self.logger.rom_path,
-				self.name,
-				"passed".green()
-			);
+	/// Reports a non-fatal problem with the test, which is included in its report.
+	pub fn warn(&mut self, msg: &str) {
+		self.warnings.push(String::from(msg));
+		self.logger.reporter().warning(self.name, msg);
+	}
+	/// Reports each warning raised by memory, with addresses named after symbols.
+	pub fn record_warnings(&mut self, warnings: &[Warning]) {
+		for warning in warnings {
+			let message = warning.describe(|address| {
+				self.label(address)
+					.unwrap_or_else(|| format!("0x{address:04x}"))
+			});
+			self.warn(&message);
 		}
+	}
+	/// Whether any warnings have been reported for the test.
+	#[must_use]
+	pub fn has_warnings(&self) -> bool {
+		!self.warnings.is_empty()
+	}
+	pub fn pass(&mut self) {
 		self.logger.pass += 1;
+		self.report(TestStatus::Passed, None, None, None);
+		self.logger.reporter().test_passed(self.last_report());
 	}
-	pub fn failure<A: memory::AddressSpace>(
+	/// Records that the test failed as expected, which counts as a pass.
+	pub fn expected_failure(&mut self, failure_reason: FailureReason, msg: Option<&Error>) {
+		self.logger.pass += 1;
+		self.report(TestStatus::ExpectedFailure, Some(failure_reason), msg, None);
+		self.logger.reporter().test_passed(self.last_report());
+	}
+	/// Records that the test was skipped rather than run.
+	pub fn skip(&mut self, reason: &str) {
+		self.logger.skipped += 1;
+		self.logger.reports.push(TestReport {
+			name: self.name.clone(),
+			status: TestStatus::Skipped,
+			reason: None,
+			description: None,
+			mismatches: Vec::new(),
+			conditions: Vec::new(),
+			memory: Vec::new(),
+			cycles: 0,
+			instructions: 0,
+			time: Duration::ZERO,
+			history: Vec::new(),
+			profile: Vec::new(),
+			trace: Vec::new(),
+			watched: Vec::new(),
+			heat_map: Vec::new(),
+			messages: Vec::new(),
+			warnings: Vec::new(),
+			ram_seed: None,
+			skip_reason: (!reason.is_empty()).then(|| String::from(reason)),
+		});
+		self.logger.reporter().test_skipped(self.last_report());
+	}
+	pub fn failure<A: Inspect>(
 		&mut self,
 		failure_reason: &FailureReason,
 		cpu_state: &cpu::State<A>,
 	) {
-		println!(
-			"{}: {} {}:\n{}\n{}",
-			self.logger.rom_path,
-			self.name,
-			"failed".red(),
-			match failure_reason {
-				FailureReason::InvalidOpcode => "Invalid opcode",
-				FailureReason::Crash => "Crashed",
-				FailureReason::Timeout => "Timeout",
-			},
-			cpu_state
-		);
 		self.logger.failure += 1;
+		self.rom_bank = cpu_state.address_space.rom_bank();
+		// Only reasons which don't name an address of their own are explained by where the test stopped.
+		let location = match failure_reason {
+			FailureReason::Crash
+			| FailureReason::InvalidOpcode
+			| FailureReason::Timeout
+			| FailureReason::WrongBank => self.label(cpu_state.pc),
+			_ => None,
+		};
+		self.report(TestStatus::Failed, Some(*failure_reason), None, location);
+		// A test which failed for raising warnings finished, so the code it stopped at is not of interest.
+		let disassembly = if *failure_reason == FailureReason::Warned {
+			Vec::new()
+		} else {
+			disasm::around(
+				cpu_state.pc,
+				DISASSEMBLY_BEFORE,
+				DISASSEMBLY_AFTER,
+				|address| cpu_state.address_space.peek(address),
+			)
+		};
+		self.report_failure(None, cpu_state, &disassembly);
 	}
-	pub fn incorrect(&mut self, msg: &Error) {
-		print!(
-			"{}: {} {}:\n{}",
-			self.logger.rom_path,
-			self.name,
-			"failed".red(),
-			msg,
-		);
+	pub fn incorrect<A: Inspect>(&mut self, msg: &Error, cpu_state: &cpu::State<A>) {
 		self.logger.failure += 1;
+		self.rom_bank = cpu_state.address_space.rom_bank();
+		self.report(
+			TestStatus::Failed,
+			Some(FailureReason::Incorrect),
+			Some(msg),
+			None,
+		);
+		if let Error::CompareFailed(result) = msg {
+			let memory = self.memory_rows(result, cpu_state);
+			let last = self.logger.reports.len() - 1;
+			self.logger.reports[last].memory = memory;
+		}
+		self.report_failure(Some(msg), cpu_state, &[]);
+	}
+	/// Lays out each line of memory containing a byte which didn't match as a hexdump,
+	/// with the expected values of those bytes beneath.
+	fn memory_rows<A: Inspect>(
+		&self,
+		result: &CompareResult,
+		cpu_state: &cpu::State<A>,
+	) -> Vec<MemoryRow> {
+		let mut expected = BTreeMap::new();
+		for (address, values) in result
+			.differences()
+			.iter()
+			.filter_map(Difference::expected_bytes)
+		{
+			for (address, value) in (address..=u16::MAX).zip(values) {
+				expected.insert(address, value);
+			}
+		}
+		let mut rows: Vec<MemoryRow> = Vec::new();
+		for (address, value) in expected {
+			let start = address & !0xF;
+			if rows.last().map(|row| row.address) != Some(start) {
+				rows.push(MemoryRow {
+					address: start,
+					label: self.label(start),
+					actual: (start..=start | 0xF)
+						.map(|address| cpu_state.address_space.peek(address))
+						.collect(),
+					expected: vec![None; 16],
+				});
+			}
+			if let Some(row) = rows.last_mut() {
+				row.expected[usize::from(address & 0xF)] = Some(value);
+			}
+		}
+		// Lines of IO have no symbols, so name the registers which differ instead
+		for row in rows.iter_mut().filter(|row| row.label.is_none()) {
+			let registers = (row.address..=row.address | 0xF)
+				.zip(&row.expected)
+				.filter(|(_, expected)| expected.is_some())
+				.filter_map(|(address, _)| HwReg::try_from(address).ok())
+				.map(HwReg::name)
+				.collect::<Vec<_>>();
+			if !registers.is_empty() {
+				row.label = Some(registers.join(", "));
+			}
+		}
+		rows
+	}
+	fn report_failure<A: Inspect>(
+		&self,
+		error: Option<&Error>,
+		cpu_state: &cpu::State<A>,
+		disassembly: &[Instruction],
+	) {
+		self.logger.reporter().test_failed(&FailureDetails {
+			report: self.last_report(),
+			error,
+			double_speed: cpu_state.address_space.peek(HwReg::Key1 as u16) & 0x80 != 0,
+			state: cpu_state,
+			pc: cpu_state.pc,
+			disassembly,
+		});
 	}
 }
@@ -1,402 +1,1577 @@
 use clap::Parser;
+use evunit::cdb::DebugInfo;
+use evunit::config::{self, Config, Options, Symbols};
+use evunit::debugfile::{self, DebugPoint};
+use evunit::labels::Labels;
+use evunit::mapfile::{MapFile, Section};
+use evunit::memory::{DumpFormat, Peek};
 use evunit::prelude::*;
-use serde::Deserialize;
-use std::collections::HashMap;
+use evunit::profile;
+use evunit::registers::Model;
+use evunit::report::{TestReport, TestStatus, TraceFormat};
+use evunit::reporter::{JsonReporter, TextReporter};
+use evunit::savestate;
+use evunit::snapshot::Snapshot;
+use evunit::test::Dump;
+use evunit::testrom::{self, Outcome};
+use evunit::vectors;
+use owo_colors::OwoColorize;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{stdin, BufReader, Read};
-use std::process::exit;
+use std::io::{self, stdin, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{exit, Command};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+use std::{env, thread};
+
+/// Where tests are dumped when they ask to be with the `dump` option, or `--dump-all` is given, but `--dump-dir` isn't.
+const DEFAULT_DUMP_DIR: &str = "dumps";
 
 pub const SILENCE_NONE: u8 = 0;
 pub const SILENCE_PASSING: u8 = 1; // Silences passing messages when tests succeed.
 pub const SILENCE_ALL: u8 = 2; // Silences all output unless an error occurs.
 
-#[derive(Parser)]
-#[clap(author, version, about, long_about = None)]
-struct Cli {
-	/// Path to the test configuration file
-	#[clap(short, long, value_parser, value_name = "PATH")]
-	config: String,
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Format {
+	Text,
+	Json,
+	Markdown,
+	Ndjson,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum TraceStyle {
+	Text,
+	Doctor,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum DumpStyle {
+	Text,
+	Binary,
+	Json,
+}
+
+impl DumpStyle {
+	fn format(self) -> DumpFormat {
+		match self {
+			DumpStyle::Text => DumpFormat::Text,
+			DumpStyle::Binary => DumpFormat::Binary,
+			DumpStyle::Json => DumpFormat::Json,
+		}
+	}
+
+	fn extension(self) -> &'static str {
+		match self {
+			DumpStyle::Text => "txt",
+			DumpStyle::Binary => "bin",
+			DumpStyle::Json => "json",
+		}
+	}
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum HeatMapStyle {
+	Text,
+	Json,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ConfigFormat {
+	Toml,
+	Json,
+	Yaml,
+}
+
+/// The options needed to read tests from config files.
+#[derive(clap::Args)]
+struct ConfigArgs {
+	/// Path to a test configuration file, or a glob pattern matching several. May be passed multiple times.
+	#[clap(short, long, value_parser, value_name = "PATH", required = true)]
+	config: Vec<String>,
+
+	/// Language of the config files. By default this is chosen by their extension, or TOML if it is not recognized.
+	#[clap(long, value_enum, value_name = "FORMAT")]
+	config_format: Option<ConfigFormat>,
+
+	/// Path to a symfile, an RGBLINK .map file, a GBDK .noi file, or an SDCC .cdb file
+	#[clap(short = 'n', long, value_parser, value_name = "PATH")]
+	symfile: Option<String>,
+
+	/// Path to an RGBLINK map file, whose section names may be used in region options and are shown in failures.
+	/// Its symbols are also used, except where the symfile has its own.
+	#[clap(long, value_parser, value_name = "PATH")]
+	map: Option<String>,
+
+	/// Value for `${NAME}` in config strings, overriding any environment variable of the same name. May be passed multiple times.
+	#[clap(short = 'D', long, value_parser = parse_define, value_name = "NAME=VALUE")]
+	define: Vec<(String, String)>,
+}
+
+fn parse_define(define: &str) -> Result<(String, String), String> {
+	define
+		.split_once('=')
+		.map(|(name, value)| (String::from(name), String::from(value)))
+		.ok_or_else(|| format!("expected NAME=VALUE, found \"{define}\""))
+}
+
+/// The options which choose which tests run, and in what order.
+#[derive(clap::Args)]
+struct SelectArgs {
+	/// Only run tests whose names contain PATTERN, or match it as a glob if it contains `*`, `?`, or `[`. May be passed multiple times.
+	#[clap(short, long, value_parser, value_name = "PATTERN")]
+	filter: Vec<String>,
+
+	/// Only run tests with this tag. May be passed multiple times.
+	#[clap(long, value_parser, value_name = "TAG")]
+	tag: Vec<String>,
+
+	/// Don't run tests with this tag. May be passed multiple times.
+	#[clap(long, value_parser, value_name = "TAG")]
+	skip_tag: Vec<String>,
+
+	/// Only run the Kth of N roughly equal parts of the selected tests, such as `2/4`
+	#[clap(long, value_parser, value_name = "K/N")]
+	shard: Option<Shard>,
+
+	/// Run tests in a random order. The seed is printed so that the order can be repeated.
+	#[clap(long, value_parser, value_name = "SEED", num_args = 0..=1)]
+	shuffle: Option<Option<u64>>,
+
+	/// Only run tests which failed the last time they were run
+	#[clap(long)]
+	failed_only: bool,
+
+	/// Only run tests with exactly this name. Used by --watch to re-run the tests affected by a change.
+	#[clap(long, value_parser, value_name = "NAME", hide = true)]
+	only: Vec<String>,
+
+	/// Record the names of failing tests in this file after each run. --failed-only uses .evunit-failures unless this is given
+	#[clap(long, value_parser, value_name = "PATH")]
+	failures_file: Option<String>,
+}
+
+/// Where `--failed-only` looks for failing tests when `--failures-file` isn't given.
+const DEFAULT_FAILURES_FILE: &str = ".evunit-failures";
+
+impl SelectArgs {
+	/// The file failing tests are recorded in, which is only written when asked for or used by `--failed-only`.
+	fn failures_file(&self) -> Option<&str> {
+		self.failures_file
+			.as_deref()
+			.or(self.failed_only.then_some(DEFAULT_FAILURES_FILE))
+	}
+}
+
+#[derive(clap::Args)]
+struct RunArgs {
+	#[clap(flatten)]
+	configs: ConfigArgs,
+
+	#[clap(flatten)]
+	select: SelectArgs,
 
 	/// Directory where crash dumps should be placed. Each dump contains the entire address space in a text file, seperated by memory type.
 	#[clap(short, long, value_parser, value_name = "PATH")]
 	dump_dir: Option<String>,
 
+	/// Format of dumps. `binary` writes the whole 64 KiB address space, and `json` writes each region as an array of bytes
+	#[clap(long, value_enum, value_name = "FORMAT", default_value = "text")]
+	dump_format: DumpStyle,
+
+	/// Dump every test, including those which pass. Dumps go to `dumps` unless `--dump-dir` is given
+	#[clap(long)]
+	dump_all: bool,
+
+	/// Write the state of each failing test to `DIR/<test>.s0` as a BESS save state, which SameBoy and other emulators can load.
+	/// Cartridge RAM is also written to `DIR/<test>.sav` if the ROM has any
+	#[clap(long, value_parser, value_name = "DIR")]
+	export_state: Option<String>,
+
+	/// Record every instruction each test executes, and show the trace of any test which fails.
+	/// If DIR is given, traces are written to `DIR/<test>.trace.txt` instead of being printed.
+	#[clap(
+		long,
+		value_name = "DIR",
+		num_args = 0..=1,
+		require_equals = true,
+		default_missing_value = "-"
+	)]
+	trace: Option<String>,
+
+	/// Write when each subroutine was called and returned to PATH, in the Chrome trace format.
+	/// The profile can be viewed at ui.perfetto.dev.
+	#[clap(long, value_name = "PATH")]
+	profile: Option<String>,
+
+	/// Write which ROM addresses the tests executed to PATH, in the LCOV format read by coverage services.
+	/// Each global symbol is reported as a function.
+	#[clap(long, value_name = "PATH")]
+	coverage: Option<String>,
+
+	/// Treat sections of the map file whose names match PATTERN as data rather than code. Tests fail if they execute one,
+	/// and coverage leaves out their symbols. `*` matches any number of characters and `?` matches one. May be passed multiple times
+	#[clap(long, value_name = "PATTERN")]
+	data_section: Vec<String>,
+
+	/// Write the cycles spent in each subroutine and its callees to PATH, in the callgrind format.
+	/// The profile can be explored with KCachegrind or QCachegrind.
+	#[clap(long, value_name = "PATH")]
+	callgrind: Option<String>,
+
+	/// Print the cycles spent in each subroutine across every test, sorted by the cycles spent in the subroutine itself
+	#[clap(long)]
+	profile_summary: bool,
+
+	/// Print the N tests which ran for the most cycles, along with how long each took
+	#[clap(long, value_name = "N")]
+	slowest: Option<usize>,
+
+	/// Format of each line of a trace. `doctor` matches the logs read by gameboy-doctor,
+	/// so that traces can be compared with other emulators.
+	#[clap(long, value_enum, value_name = "FORMAT", default_value = "text")]
+	trace_format: TraceStyle,
+
+	/// Count each test's reads and writes to every address, and write them to `DIR/<test>.heat.txt`
+	/// (or `.heat.json` with `--heat-map-format json`). Reads from ROM are left out.
+	#[clap(long, value_name = "DIR")]
+	heat_map: Option<String>,
+
+	/// Format of the files written by `--heat-map`
+	#[clap(long, value_enum, value_name = "FORMAT", default_value = "text")]
+	heat_map_format: HeatMapStyle,
+
 	/// Silence passing tests. Pass -s again to silence all output unless an error occurs.
 	#[clap(short, long, action = clap::ArgAction::Count)]
 	silent: u8,
 
-	/// Path to a symfile
-	#[clap(short = 'n', long, value_parser, value_name = "PATH")]
-	symfile: Option<String>,
+	/// Output format. `json` prints a single machine-readable report once all tests are complete,
+	/// `markdown` prints a summary table suitable for CI job summaries,
+	/// and `ndjson` prints a JSON object for each event as tests run.
+	#[clap(long, value_enum, default_value = "text")]
+	format: Format,
+
+	/// IPS or BPS patch to apply to the ROM before running any tests. May be passed multiple times.
+	#[clap(short, long, value_parser, value_name = "PATH")]
+	patch: Vec<String>,
+
+	/// Debug file of assertions and messages to attach to ROM addresses. May be passed multiple times.
+	#[clap(long, value_parser, value_name = "PATH")]
+	debug_file: Vec<String>,
+
+	/// Directory where the snapshots of tests with the `snapshot` option are kept
+	#[clap(long, value_parser, value_name = "PATH", default_value = "snapshots")]
+	snapshot_dir: String,
+
+	/// Overwrite snapshots with the memory of each test, rather than comparing against them
+	#[clap(long)]
+	update_snapshots: bool,
+
+	/// Fail any test which raises a warning, such as accessing echo RAM or reading an unknown IO register
+	#[clap(long)]
+	warnings_as_errors: bool,
+
+	/// Stop running tests after the first failure
+	#[clap(long, conflicts_with = "max_failures")]
+	fail_fast: bool,
+
+	/// Stop running tests after N failures
+	#[clap(long, value_parser, value_name = "N")]
+	max_failures: Option<u32>,
+
+	/// Number of tests to run at once
+	#[clap(short, long, value_parser, value_name = "N", default_value_t = 1)]
+	jobs: usize,
 
-	/// Path to the ROM
-	#[clap(value_parser, value_name = "PATH")]
-	rom: String,
+	/// Re-run the tests whenever the config, ROM, symfile, or patches change
+	#[clap(long)]
+	watch: bool,
+
+	/// Print the name of each test which would run, without running them. Kept for compatibility with `evunit list`.
+	#[clap(long, hide = true)]
+	list: bool,
+
+	/// Run every test on two builds of a ROM, reporting tests whose outcome, cycle count, or final memory differ
+	#[clap(long, num_args = 2, value_names = ["OLD", "NEW"], conflicts_with = "rom")]
+	compare: Vec<String>,
+
+	/// Path to the ROM. Several ROMs may be given to run every test on each of them.
+	#[clap(
+		value_parser,
+		value_name = "PATH",
+		required_unless_present_any = ["list", "compare"]
+	)]
+	rom: Vec<String>,
 }
 
-fn read_config(path: &str, symfile: &HashMap<String, (u32, u16)>) -> Vec<TestConfig> {
-	fn parse_u8(value: &toml::Value, hint: &str) -> Option<u8> {
-		match value {
-			toml::Value::Integer(value) => {
-				if -128 <= *value && *value < 256 {
-					Some(*value as u8)
-				} else {
-					eprintln!("Value of `{hint}` must be an 8-bit integer.");
-					None
-				}
+#[derive(clap::Args)]
+struct ListArgs {
+	#[clap(flatten)]
+	configs: ConfigArgs,
+
+	#[clap(flatten)]
+	select: SelectArgs,
+}
+
+#[derive(clap::Args)]
+struct DumpArgs {
+	#[clap(flatten)]
+	configs: ConfigArgs,
+
+	#[clap(flatten)]
+	select: SelectArgs,
+
+	/// Directory where dumps should be placed, one file per test
+	#[clap(short, long, value_parser, value_name = "PATH", required = true)]
+	dump_dir: String,
+
+	/// Format of dumps. `binary` writes the whole 64 KiB address space, and `json` writes each region as an array of bytes
+	#[clap(long, value_enum, value_name = "FORMAT", default_value = "text")]
+	dump_format: DumpStyle,
+
+	/// IPS or BPS patch to apply to the ROM before running any tests. May be passed multiple times.
+	#[clap(short, long, value_parser, value_name = "PATH")]
+	patch: Vec<String>,
+
+	/// Number of tests to run at once
+	#[clap(short, long, value_parser, value_name = "N", default_value_t = 1)]
+	jobs: usize,
+
+	/// Path to the ROM. Several ROMs may be given to dump every test on each of them.
+	#[clap(value_parser, value_name = "PATH", required = true)]
+	rom: Vec<String>,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ModelArg {
+	Dmg,
+	Mgb,
+	Sgb,
+	Cgb,
+}
+
+#[derive(clap::Args)]
+struct TestRomArgs {
+	/// Model whose boot ROM's register values the ROMs start with
+	#[clap(long, value_enum, default_value = "dmg")]
+	model: ModelArg,
+
+	/// Number of cycles a ROM may run for before it fails
+	#[clap(long, value_parser, value_name = "CYCLES", default_value_t = 1 << 28)]
+	timeout: u64,
+
+	/// Paths to the test ROMs
+	#[clap(value_parser, value_name = "PATH", required = true)]
+	rom: Vec<String>,
+}
+
+#[derive(clap::Args)]
+struct VectorArgs {
+	/// Number of failing vectors to show from each file
+	#[clap(long, value_parser, value_name = "N", default_value_t = 3)]
+	show: usize,
+
+	/// JSON files of test vectors, or directories containing them
+	#[clap(value_parser, value_name = "PATH", required = true)]
+	path: Vec<String>,
+}
+
+#[derive(clap::Subcommand)]
+enum Commands {
+	/// Run tests on one or more ROMs. This is the default when no command is given.
+	Run(Box<RunArgs>),
+	/// Print the name of each test which would run, without running them
+	List(ListArgs),
+	/// Run tests and write a dump of memory after each one, whether it passed or failed
+	Dump(DumpArgs),
+	/// Report every problem in the configs and symfile without running any tests
+	Check(ConfigArgs),
+	/// Run hardware test ROMs, such as Mooneye's and Blargg's, from their entry points until they report a result
+	TestRom(TestRomArgs),
+	/// Check the CPU against single-instruction JSON test vectors
+	Vectors(VectorArgs),
+}
+
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+	#[clap(subcommand)]
+	command: Option<Commands>,
+
+	#[clap(flatten)]
+	run: RunArgs,
+}
+
+/// Reads the names of tests which failed in previous runs, one per line.
+fn read_failures(path: &str) -> Vec<String> {
+	match fs::read_to_string(path) {
+		Ok(text) => text
+			.lines()
+			.filter(|line| !line.is_empty())
+			.map(String::from)
+			.collect(),
+		Err(msg) if msg.kind() == io::ErrorKind::NotFound => Vec::new(),
+		Err(msg) => {
+			eprintln!("Failed to read {path}: {msg}");
+			exit(1);
+		}
+	}
+}
+
+/// Records the names of failing tests, removing the file when there are none.
+fn write_failures(path: &str, failures: &[String]) {
+	let result = if failures.is_empty() {
+		fs::remove_file(path).or_else(|msg| {
+			if msg.kind() == io::ErrorKind::NotFound {
+				Ok(())
+			} else {
+				Err(msg)
 			}
-			_ => {
-				eprintln!("Value of `{hint}` must be an 8-bit integer.");
-				None
+		})
+	} else {
+		fs::write(path, failures.join("\n") + "\n")
+	};
+	if let Err(msg) = result {
+		eprintln!("Failed to write {path}: {msg}");
+	}
+}
+
+/// Expands any glob patterns in the configs passed on the command line.
+fn config_paths(patterns: &[String]) -> Vec<String> {
+	let mut paths = Vec::new();
+	for pattern in patterns {
+		if !pattern.contains(['*', '?', '[']) {
+			paths.push(pattern.clone());
+			continue;
+		}
+		let matches = glob::glob(pattern).unwrap_or_else(|msg| {
+			eprintln!("Invalid config pattern {pattern}: {msg}");
+			exit(1);
+		});
+		let start = paths.len();
+		for path in matches {
+			match path {
+				Ok(path) => paths.push(path.to_string_lossy().into_owned()),
+				Err(msg) => eprintln!("Failed to read {}: {msg}", msg.path().display()),
 			}
 		}
+		if paths.len() == start {
+			eprintln!("No config files match {pattern}");
+			exit(1);
+		}
 	}
+	paths
+}
 
-	fn parse_u16(
-		value: &toml::Value,
-		hint: &str,
-		symfile: &HashMap<String, (u32, u16)>,
-	) -> Option<u16> {
-		match value {
-			toml::Value::Integer(value) => {
-				if -32768 <= *value && *value < 65536 {
-					Some(*value as u16)
-				} else {
-					eprintln!("Value of `{hint}` must be a 16-bit integer.");
-					None
-				}
-			}
-			toml::Value::String(value) => {
-				if let Some((_, addr)) = symfile.get(value) {
-					Some(*addr)
-				} else {
-					eprintln!("Symbol \"{value}\" not found.");
-					exit(1);
-				}
+/// Runs evunit again, without `--watch`, each time one of its input files changes.
+///
+/// Each run is a separate process so that errors in a half-written config or ROM
+/// are reported without ending the watch.
+fn watch(args: &RunArgs) -> ! {
+	const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+	if args.configs.config.iter().any(|path| path == "-") {
+		eprintln!("--watch cannot be used when reading the config from stdin.");
+		exit(1);
+	}
+	let exe = env::current_exe().unwrap_or_else(|msg| {
+		eprintln!("Failed to find evunit executable: {msg}");
+		exit(1);
+	});
+	let command = env::args_os()
+		.skip(1)
+		.filter(|arg| arg != "--watch")
+		.collect::<Vec<_>>();
+	// Changes to these affect every test.
+	let shared = args
+		.rom
+		.iter()
+		.chain(&args.compare)
+		.chain(&args.configs.symfile)
+		.chain(&args.configs.map)
+		.chain(&args.patch)
+		.chain(&args.debug_file)
+		.cloned()
+		.collect::<Vec<String>>();
+	// Config patterns are expanded on every check, so that new files are noticed.
+	let modified = |tests: &[TestConfig]| {
+		let mut paths = config_paths(&args.configs.config);
+		paths.extend(shared.iter().cloned());
+		paths.extend(tests.iter().flat_map(test_inputs).map(String::from));
+		paths
+			.into_iter()
+			.map(|path| {
+				let modified = fs::metadata(&path)
+					.and_then(|metadata| metadata.modified())
+					.ok();
+				(path, modified)
+			})
+			.collect::<HashMap<String, Option<SystemTime>>>()
+	};
+
+	let mut tests = watched_tests(&args.configs);
+	let mut last_modified = modified(&tests);
+	// The names of the tests to re-run, or `None` to run every test.
+	let mut affected: Option<Vec<String>> = None;
+	loop {
+		let mut run = Command::new(&exe);
+		run.args(&command);
+		for name in affected.iter().flatten() {
+			run.arg("--only").arg(name);
+		}
+		if let Err(msg) = run.status() {
+			eprintln!("Failed to run tests: {msg}");
+		}
+		eprintln!("Waiting for changes...");
+
+		let mut current = loop {
+			thread::sleep(POLL_INTERVAL);
+			let current = modified(&tests);
+			if current != last_modified {
+				break current;
 			}
-			_ => {
-				eprintln!("Value of `{hint}` must be a 16-bit integer.");
-				None
+		};
+		// Wait for assemblers and editors to finish writing before running tests.
+		loop {
+			thread::sleep(POLL_INTERVAL);
+			let next = modified(&tests);
+			if next == current {
+				break;
 			}
+			current = next;
+		}
+		let changed = current
+			.iter()
+			.filter(|(path, time)| last_modified.get(*path) != Some(*time))
+			.map(|(path, _)| path.as_str())
+			.collect::<HashSet<&str>>();
+		affected = if changed
+			.iter()
+			.any(|path| shared.iter().any(|shared| shared == path))
+		{
+			None
+		} else {
+			// Tests are read again so that new tests, and new files used by tests, are included.
+			tests = watched_tests(&args.configs);
+			Some(affected_tests(&tests, &changed))
+		};
+		last_modified = modified(&tests);
+	}
+}
+
+/// Reads the tests from the configs for `--watch`, quietly skipping any problems, since running the tests reports them.
+/// Each test's config is added to its inputs.
+fn watched_tests(configs: &ConfigArgs) -> Vec<TestConfig> {
+	let options = config_options(configs);
+	let mut tests = Vec::new();
+	for config_path in config_paths(&configs.config) {
+		let Ok(config_text) = fs::read_to_string(&config_path) else {
+			continue;
+		};
+		let options = options
+			.clone()
+			.with_format(config_format(configs, &config_path));
+		// Only the files each test reads are needed, so symbols don't have to be resolved.
+		let config = Config::parse_with(&config_text, &options, &Symbols::new());
+		tests.extend(config.tests.into_iter().map(|mut test| {
+			test.inputs.push(config_path.clone());
+			test
+		}));
+	}
+	tests
+}
+
+/// Every file which a change to should re-run `test`, besides those shared by every test.
+fn test_inputs(test: &TestConfig) -> Vec<&str> {
+	let mut inputs = test
+		.inputs
+		.iter()
+		.chain(&test.rom)
+		.chain(&test.overlay)
+		.chain(&test.patches)
+		.map(String::as_str)
+		.collect::<Vec<&str>>();
+	if let Some(setup) = &test.setup {
+		inputs.extend(test_inputs(setup));
+	}
+	inputs
+}
+
+/// Names the tests which use any of the `changed` files, along with those which start from their final state.
+fn affected_tests(tests: &[TestConfig], changed: &HashSet<&str>) -> Vec<String> {
+	let mut affected = tests
+		.iter()
+		.filter(|test| test_inputs(test).iter().any(|path| changed.contains(path)))
+		.map(|test| test.name.as_str())
+		.collect::<HashSet<&str>>();
+	loop {
+		let dependents = tests
+			.iter()
+			.filter(|test| !affected.contains(test.name.as_str()))
+			.filter(|test| {
+				test.after
+					.as_deref()
+					.is_some_and(|after| affected.contains(after))
+			})
+			.map(|test| test.name.as_str())
+			.collect::<Vec<&str>>();
+		if dependents.is_empty() {
+			break;
 		}
+		affected.extend(dependents);
 	}
+	affected.into_iter().map(String::from).collect()
+}
 
-	fn parse_bool(value: &toml::Value, hint: &str) -> Option<bool> {
-		if let toml::Value::Boolean(value) = value {
-			Some(*value)
-		} else {
-			eprintln!("Value of `{hint}` must be a boolean.");
-			None
+fn open_input(path: &str) -> Box<dyn Read> {
+	if path == "-" {
+		Box::new(BufReader::new(stdin()))
+	} else {
+		Box::new(File::open(path).unwrap_or_else(|msg| {
+			eprintln!("Failed to open {path}: {msg}");
+			exit(1)
+		}))
+	}
+}
+
+/// The options for reading configs which come from the command line: `--define`, and the environment.
+fn config_options(configs: &ConfigArgs) -> Options {
+	configs
+		.define
+		.iter()
+		.fold(Options::default(), |options, (name, value)| {
+			options.with_define(name, value)
+		})
+		.with_environment(true)
+}
+
+/// The format of the config at `path`, from `--config-format` or its extension.
+fn config_format(configs: &ConfigArgs, path: &str) -> config::Format {
+	match configs.config_format {
+		Some(ConfigFormat::Toml) => config::Format::Toml,
+		Some(ConfigFormat::Json) => config::Format::Json,
+		Some(ConfigFormat::Yaml) => config::Format::Yaml,
+		None => config::Format::from_path(path).unwrap_or_default(),
+	}
+}
+
+/// Reads every test from the configs, printing any problems found in them.
+///
+/// Returns the tests along with whether any of the problems were errors.
+/// Every config is read, so that all problems are reported at once.
+fn read_configs(configs: &ConfigArgs, symbol_files: &SymbolFiles) -> (Vec<TestConfig>, bool) {
+	let options = config_options(configs);
+	let options = match &symbol_files.debug_info {
+		Some(debug_info) => options.with_debug_info(debug_info.clone()),
+		None => options,
+	};
+	let options = match &symbol_files.map {
+		Some(map) => options.with_map(map.clone()),
+		None => options,
+	};
+
+	let mut tests = Vec::new();
+	let mut has_errors = false;
+	for config_path in config_paths(&configs.config) {
+		let mut config_text = String::new();
+		open_input(&config_path)
+			.read_to_string(&mut config_text)
+			.unwrap_or_else(|error| {
+				eprintln!("Failed to read {config_path}: {error}");
+				exit(1);
+			});
+		let format = config_format(configs, &config_path);
+		let config = Config::parse_with(
+			&config_text,
+			&options.clone().with_format(format),
+			&symbol_files.symbols,
+		);
+		for diagnostic in &config.diagnostics {
+			eprintln!("{}", diagnostic.display(&config_path, &config_text));
 		}
+		has_errors |= config.has_errors();
+		tests.extend(config.tests);
 	}
+	(tests, has_errors)
+}
 
-	fn parse_address(address: &str, symfile: &HashMap<String, (u32, u16)>) -> Option<u16> {
-		if let Some((_, address)) = symfile.get(address) {
-			// Attempt to get address from symfile
-			Some(*address)
-		} else {
-			u16::deserialize(toml::de::ValueDeserializer::new(address)).ok()
-		}
-	}
-
-	fn parse_memory(name: &str, value: &toml::Value) -> Result<Vec<u8>, String> {
-		match value {
-			toml::Value::Integer(value) => {
-				if *value > 255 || *value < -128 {
-					let value_array = value
-						.to_le_bytes()
-						.iter()
-						.skip_while(|b| **b == 0)
-						.map(|b| format!("0x{b:02x}"))
-						.collect::<Vec<_>>()
-						.join(", ");
-
-					// Disallow non 8-bit values and present alternative
-					Err(format!(
-						"\"{value}\" is not an 8-bit value. Try \"[{value_array}]\" instead."
-					))
-				} else {
-					// Treat any byte size number as a byte
-					Ok(vec![(*value as u8)])
-				}
+/// Validates the configs without running them, exiting with a failure if any problems are found.
+///
+/// Besides the configs themselves, this checks that every ROM, overlay, and patch they refer to exists.
+fn check(configs: &ConfigArgs) -> ! {
+	let (tests, mut has_errors) = read_configs(configs, &read_symbol_files(configs));
+	for test in &tests {
+		let files = test.rom.iter().chain(&test.overlay).chain(&test.patches);
+		for path in files {
+			if let Err(msg) = fs::metadata(path) {
+				eprintln!("{}: error: Failed to open {path}: {msg}", test.name);
+				has_errors = true;
 			}
-			toml::Value::String(value) => {
-				if !value.is_ascii() {
-					// Disallow any strings which contain non-ASCII values
-					Err(format!(
-						"String value \"{value}\" contains non-ASCII characters"
-					))
-				} else {
-					// Convert string into sequence of bytes
-					Ok(value.bytes().collect::<Vec<_>>())
+		}
+	}
+	if has_errors {
+		exit(1);
+	}
+	println!("{} tests OK.", tests.len());
+	exit(0);
+}
+
+/// Reads every test from the configs, exiting if any of them have errors.
+fn read_tests(configs: &ConfigArgs, symbol_files: &SymbolFiles) -> Vec<TestConfig> {
+	let (tests, has_errors) = read_configs(configs, symbol_files);
+	if has_errors {
+		exit(1);
+	}
+	tests
+}
+
+/// Reads the assertions and messages from each debug file, exiting if any of them have errors.
+fn read_debug_files(symfile: &Symbols, paths: &[String]) -> Vec<DebugPoint> {
+	let mut points = Vec::new();
+	let mut has_errors = false;
+	for path in paths {
+		let text = fs::read_to_string(path).unwrap_or_else(|msg| {
+			eprintln!("Failed to read {path}: {msg}");
+			exit(1);
+		});
+		match debugfile::parse(&text, symfile) {
+			Ok(file_points) => points.extend(file_points),
+			Err(errors) => {
+				for error in errors {
+					eprintln!("{path}: error: {error}");
 				}
-			}
-			toml::Value::Array(value) => {
-				// Recursively call function on all toml::Value and return their collected result
-				value
-					.iter()
-					.map(|v| parse_memory(name, v))
-					.collect::<Result<Vec<Vec<u8>>, String>>()
-					.map(|mem| mem.into_iter().flatten().collect::<Vec<u8>>())
-			}
-			toml::Value::Boolean(value) => {
-				// Convert bool into either a 1 or a 0
-				Ok(vec![if *value { 1 } else { 0 }])
-			}
-			_ => {
-				// Other types return error as they are not supported
-				Err(format!("Unsupported value for {name}: {value}"))
+				has_errors = true;
 			}
 		}
 	}
+	if has_errors {
+		exit(1);
+	}
+	points
+}
+
+/// The symfile and map file given on the command line, read once for everything which uses their symbols.
+struct SymbolFiles {
+	/// The symfile, along with the symbols in the map file if one was given.
+	symbols: Symbols,
+	/// The map file, which may have been given as `--map` or in place of a symfile.
+	map: Option<MapFile>,
+	/// SDCC's debug information, if it was given in place of a symfile.
+	debug_info: Option<DebugInfo>,
+}
 
-	fn parse_memory_assignment(
-		name: &str,
-		value: &toml::Value,
-		symfile: &HashMap<String, (u32, u16)>,
-	) -> Result<Vec<(u16, u8)>, String> {
-		let address = parse_address(name, symfile);
-		if address.is_none() {
-			return Err(format!("Address \"{}\" is not a valid address", name));
+/// Reads the symfile and map file, printing any errors in them only once.
+fn read_symbol_files(configs: &ConfigArgs) -> SymbolFiles {
+	let symfile = configs.symfile.as_deref().map(Path::new);
+	let has_extension = |expected: &str| {
+		symfile.is_some_and(|path| path.extension().is_some_and(|e| e == expected))
+	};
+	let debug_info = symfile.filter(|_| has_extension("cdb")).map(open_cdb);
+	let symfile_map = symfile.filter(|_| has_extension("map")).map(open_map);
+	let map = configs.map.as_deref().map(|path| open_map(Path::new(path)));
+	let mut symbols = match (&debug_info, &symfile_map) {
+		(Some(debug_info), _) => debug_info.symbols(),
+		(None, Some(symfile_map)) => symfile_map.symbols.clone(),
+		(None, None) => open_symfile(symfile),
+	};
+	if let Some(map) = &map {
+		for (name, location) in &map.symbols {
+			symbols.entry(name.clone()).or_insert(*location);
 		}
-		let address = address.unwrap();
+	}
+	SymbolFiles {
+		symbols,
+		map: map.or(symfile_map),
+		debug_info,
+	}
+}
 
-		parse_memory(name, value).map(|data| {
-			data.iter()
-				.enumerate()
-				.map(|(i, b)| (address + (i as u16), *b))
-				.collect::<Vec<(u16, u8)>>()
+/// Finds the sections of the map file matching any of `patterns`,
+/// exiting if there is no map file or a pattern matches nothing.
+fn read_data_sections(map: Option<&MapFile>, patterns: &[String]) -> Vec<Section> {
+	if patterns.is_empty() {
+		return Vec::new();
+	}
+	let Some(map) = map else {
+		eprintln!("--data-section requires a map file, passed with --map");
+		exit(1);
+	};
+	let patterns = patterns
+		.iter()
+		.map(|pattern| parse_pattern("--data-section", pattern))
+		.collect::<Vec<glob::Pattern>>();
+	for pattern in &patterns {
+		if !map
+			.sections
+			.iter()
+			.any(|section| pattern.matches(&section.name))
+		{
+			eprintln!("No section in the map file matches --data-section {pattern}");
+			exit(1);
+		}
+	}
+	map.sections
+		.iter()
+		.filter(|section| {
+			patterns
+				.iter()
+				.any(|pattern| pattern.matches(&section.name))
 		})
+		.cloned()
+		.collect()
+}
+
+/// Reads a glob pattern given to `option`, exiting if it is malformed.
+fn parse_pattern(option: &str, pattern: &str) -> glob::Pattern {
+	glob::Pattern::new(pattern).unwrap_or_else(|msg| {
+		eprintln!("Invalid pattern for {option} `{pattern}`: {msg}");
+		exit(1);
+	})
+}
+
+/// Names addresses in failure output after the symbols and sections of the symfile and map file, if they were given.
+fn read_labels(symbol_files: &SymbolFiles) -> Labels {
+	let labels = Labels::new(&symbol_files.symbols);
+	match &symbol_files.map {
+		Some(map) => labels.with_sections(map.sections.clone()),
+		None => labels,
 	}
+}
 
-	fn parse_configuration(
-		test: &mut TestConfig,
-		key: &str,
-		value: &toml::Value,
-		symfile: &HashMap<String, (u32, u16)>,
-	) {
-		match key {
-			"a" => test.initial.a = parse_u8(value, key),
-			"b" => test.initial.b = parse_u8(value, key),
-			"c" => test.initial.c = parse_u8(value, key),
-			"d" => test.initial.d = parse_u8(value, key),
-			"e" => test.initial.e = parse_u8(value, key),
-			"h" => test.initial.h = parse_u8(value, key),
-			"l" => test.initial.l = parse_u8(value, key),
-			"f.z" => test.initial.zf = parse_bool(value, key),
-			"f.n" => test.initial.nf = parse_bool(value, key),
-			"f.h" => test.initial.hf = parse_bool(value, key),
-			"f.c" => test.initial.cf = parse_bool(value, key),
-			"bc" => test.initial.bc = parse_u16(value, key, symfile),
-			"de" => test.initial.de = parse_u16(value, key, symfile),
-			"hl" => test.initial.hl = parse_u16(value, key, symfile),
-			"pc" => test.initial.pc = parse_u16(value, key, symfile),
-			"sp" => test.initial.sp = parse_u16(value, key, symfile),
-			"caller" => test.caller_address = parse_u16(value, key, symfile).unwrap_or(0xFFFF),
-			"crash" => {
-				if let toml::Value::Integer(_) | toml::Value::String(_) = value {
-					if let Some(address) = parse_u16(value, key, symfile) {
-						test.crash_addresses.push(address);
-					}
-				} else if let toml::Value::Array(addresses) = value {
-					for i in addresses {
-						if let Some(address) = parse_u16(i, key, symfile) {
-							test.crash_addresses.push(address);
-						}
-					}
-				} else {
-					eprintln!(
-						"Value of {key} must be a 16-bit integer or an array of 16-bit integers"
-					)
-				}
-			}
-			"enable-breakpoints" => test.enable_breakpoints = parse_bool(value, key).unwrap(),
-			"exit" => {
-				if let toml::Value::Integer(_) | toml::Value::String(_) = value {
-					if let Some(address) = parse_u16(value, key, symfile) {
-						test.exit_addresses.push(address);
-					}
-				} else if let toml::Value::Array(addresses) = value {
-					for i in addresses {
-						if let Some(address) = parse_u16(i, key, symfile) {
-							test.exit_addresses.push(address);
-						}
-					}
-				} else {
-					eprintln!(
-						"Value of {key} must be a 16-bit integer or an array of 16-bit integers"
-					)
-				}
+/// Creates a suite of the tests chosen by the selection options.
+fn select(select: &SelectArgs, tests: Vec<TestConfig>) -> Suite {
+	let mut suite = Suite::new(tests);
+	for pattern in &select.filter {
+		suite = suite.with_filter(if pattern.contains(['*', '?', '[']) {
+			Filter::Glob(parse_pattern("--filter", pattern))
+		} else {
+			Filter::Name(pattern.clone())
+		});
+	}
+	for tag in &select.tag {
+		suite = suite.with_filter(Filter::Tag(tag.clone()));
+	}
+	for tag in &select.skip_tag {
+		suite = suite.with_filter(Filter::SkipTag(tag.clone()));
+	}
+	if let Some(shard) = select.shard {
+		suite = suite.with_shard(shard);
+	}
+	if let Some(seed) = select.shuffle {
+		let seed = seed.unwrap_or_else(|| fastrand::u64(..));
+		eprintln!("Shuffling tests with seed {seed}");
+		suite = suite.with_shuffle(seed);
+	}
+	// Exact names are alternatives, so those from --failed-only and --only are combined here instead.
+	let mut names = None;
+	if let Some(path) = select.failures_file().filter(|_| select.failed_only) {
+		let previous_failures = read_failures(path);
+		if previous_failures.is_empty() {
+			eprintln!("No failed tests recorded in {path}.");
+			exit(0);
+		}
+		names = Some(previous_failures);
+	}
+	if !select.only.is_empty() {
+		names = Some(match names {
+			Some(names) => names
+				.into_iter()
+				.filter(|name| select.only.contains(name))
+				.collect(),
+			None => select.only.clone(),
+		});
+		if names.as_ref().is_some_and(Vec::is_empty) {
+			eprintln!("None of the tests affected by the change were selected.");
+			exit(0);
+		}
+	}
+	for name in names.into_iter().flatten() {
+		suite = suite.with_filter(Filter::Exact(name));
+	}
+	suite
+}
+
+/// How the tests are run on each ROM, and what is done with their results.
+struct Execution<'a> {
+	roms: &'a [String],
+	silence_level: SilenceLevel,
+	format: OutputFormat,
+	dump_dir: Option<&'a str>,
+	dump_format: DumpStyle,
+	/// Whether passing tests are dumped as well as failing ones.
+	dump_passing: bool,
+	/// Directory where the save states of failing tests are written.
+	state_dir: Option<&'a str>,
+	/// Directory where the traces of failing tests are written, rather than being printed.
+	trace_dir: Option<&'a str>,
+	trace_format: TraceFormat,
+	/// Directory where the reads and writes counted for each test are written.
+	heat_map_dir: Option<&'a str>,
+	heat_map_format: HeatMapStyle,
+	/// File where the subroutine calls of every test are written.
+	profile: Option<&'a str>,
+	/// File where the ROM addresses executed by the tests are written.
+	coverage: Option<&'a str>,
+	/// How many of the slowest tests are listed in each ROM's report.
+	slowest: usize,
+	/// Names addresses in failure output.
+	labels: &'a Labels,
+	/// Sections which hold data rather than code, whose symbols are left out of coverage.
+	data_sections: &'a [Section],
+}
+
+/// The directory where a ROM's output files go.
+/// Each ROM gets its own directory when there are several, so that tests with the same name don't collide.
+fn rom_dir(dir: &str, rom_path: &str, roms: &[String]) -> String {
+	if roms.len() > 1 {
+		let stem = Path::new(rom_path).file_stem().unwrap_or_default();
+		format!("{dir}/{}", stem.to_string_lossy())
+	} else {
+		String::from(dir)
+	}
+}
+
+/// The outcome of a test, as compared between ROMs by `--compare`.
+struct TestOutcome {
+	status: TestStatus,
+	cycles: u64,
+	/// Memory from 0x8000 to 0xFFFF once the test finished, except echo RAM.
+	memory: Vec<(u16, u8)>,
+}
+
+/// Runs the suite on a ROM without printing anything, returning the outcome of each test which ran.
+fn outcomes(suite: &Suite, rom_path: &str) -> HashMap<String, TestOutcome> {
+	let rom = open_rom(rom_path);
+	let memory = Mutex::new(HashMap::new());
+	let mut logger =
+		Logger::new(SilenceLevel::All, rom_path).with_reporter(JsonReporter(io::sink()));
+	suite.run(&rom, &mut logger, |test, cpu_state, _| {
+		let final_memory = (0x8000..=0xFFFF)
+			.filter(|address| !(0xE000..=0xFDFF).contains(address))
+			.map(|address| (address, cpu_state.address_space.peek(address)))
+			.collect::<Vec<_>>();
+		memory
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.insert(test.name.clone(), final_memory);
+	});
+	let mut memory = memory.into_inner().unwrap_or_else(PoisonError::into_inner);
+	logger
+		.reports
+		.into_iter()
+		.filter(|report| report.status != TestStatus::Skipped)
+		.map(|report| {
+			let outcome = TestOutcome {
+				status: report.status,
+				cycles: report.cycles,
+				memory: memory.remove(&report.name).unwrap_or_default(),
+			};
+			(report.name, outcome)
+		})
+		.collect()
+}
+
+/// Runs every test on two builds of a ROM and prints each difference between them,
+/// exiting with a failure if there were any.
+fn compare_roms(suite: &Suite, [old_path, new_path]: [&String; 2], labels: &Labels) -> ! {
+	let old = outcomes(suite, old_path);
+	let new = outcomes(suite, new_path);
+	let mut changed = 0;
+	for test in suite.selected() {
+		let (Some(old), Some(new)) = (old.get(&test.name), new.get(&test.name)) else {
+			continue;
+		};
+		let mut differences = Vec::new();
+		if old.status != new.status {
+			let status = |status| match status {
+				TestStatus::Passed => "passed",
+				TestStatus::Failed => "failed",
+				TestStatus::Skipped => "skipped",
+				TestStatus::ExpectedFailure => "failed as expected",
+			};
+			differences.push(format!("{} -> {}", status(old.status), status(new.status)));
+		}
+		if old.cycles != new.cycles {
+			differences.push(format!("{} -> {} cycles", old.cycles, new.cycles));
+		}
+		let mut bytes = old
+			.memory
+			.iter()
+			.zip(&new.memory)
+			.filter(|(old, new)| old != new);
+		if let Some(((address, old_value), (_, new_value))) = bytes.next() {
+			let name = labels
+				.name(0, *address)
+				.unwrap_or_else(|| format!("0x{address:04x}"));
+			let others = match bytes.count() {
+				0 => String::new(),
+				1 => String::from(" and 1 other byte"),
+				count => format!(" and {count} other bytes"),
+			};
+			differences.push(format!(
+				"[{name}] 0x{old_value:02x} -> 0x{new_value:02x}{others}"
+			));
+		}
+		if !differences.is_empty() {
+			changed += 1;
+			println!("{}: {}", test.name, differences.join(", "));
+		}
+	}
+	println!(
+		"{changed} of {} tests differ between {old_path} and {new_path}.",
+		old.len()
+	);
+	exit(i32::from(changed != 0));
+}
+
+/// Runs the suite on each ROM in turn.
+///
+/// Returns whether every test passed, along with the report of each test which ran.
+fn execute(suite: &mut Suite, execution: &Execution) -> (bool, Vec<TestReport>) {
+	let mut passed = true;
+	let mut reports = Vec::new();
+	// Failures on earlier ROMs count towards `--max-failures` on later ones.
+	let max_failures = suite.max_failures;
+	let mut failures = 0;
+	let mut profile = Vec::new();
+	let mut coverage = Vec::new();
+	for (pid, rom_path) in execution.roms.iter().enumerate() {
+		if let Some(max_failures) = max_failures {
+			suite.max_failures = Some(max_failures.saturating_sub(failures));
+		}
+
+		// Tests with their own ROM don't depend on the one given, so they only run once.
+		if pid == 1 {
+			suite.filters.push(Filter::MainRom);
+		}
+		let rom = open_rom(rom_path);
+
+		let mut logger = Logger::new(execution.silence_level, rom_path)
+			.with_labels(execution.labels)
+			.with_slowest(execution.slowest);
+		logger = match (execution.format, execution.trace_dir) {
+			// Traces written to files aren't printed as well.
+			(OutputFormat::Text, Some(_)) => logger.with_reporter(
+				TextReporter::new(execution.silence_level, rom_path, io::stdout())
+					.with_traces(false),
+			),
+			(OutputFormat::Text, None) => logger.with_reporter(
+				TextReporter::new(execution.silence_level, rom_path, io::stdout())
+					.with_trace_format(execution.trace_format),
+			),
+			(format, _) => logger.with_format(format),
+		};
+
+		let dump_dir = execution
+			.dump_dir
+			.map(|dump_dir| rom_dir(dump_dir, rom_path, execution.roms));
+		let trace_dir = execution
+			.trace_dir
+			.map(|trace_dir| rom_dir(trace_dir, rom_path, execution.roms));
+		let heat_map_dir = execution
+			.heat_map_dir
+			.map(|heat_map_dir| rom_dir(heat_map_dir, rom_path, execution.roms));
+		let state_dir = execution
+			.state_dir
+			.map(|state_dir| rom_dir(state_dir, rom_path, execution.roms));
+
+		// create dump, trace, and heat map dirs if they do not exist already
+		for dir in dump_dir.iter().chain(&trace_dir).chain(&heat_map_dir) {
+			if let Err(msg) = fs::create_dir_all(dir) {
+				eprintln!("Failed to create directory {dir}: {msg}");
+				exit(1);
 			}
-			"timeout" => {
-				if let toml::Value::Integer(value) = value {
-					test.timeout = *value as usize;
-				} else {
-					eprintln!("Value of `{key}` must be an integer.");
-				}
+		}
+
+		// Tests with a `dump` option are dumped even if `--dump-dir` wasn't given.
+		let default_dump = if execution.dump_passing {
+			Dump::Always
+		} else if dump_dir.is_some() {
+			Dump::OnFailure
+		} else {
+			Dump::Never
+		};
+		let test_dump_dir = dump_dir
+			.clone()
+			.unwrap_or_else(|| rom_dir(DEFAULT_DUMP_DIR, rom_path, execution.roms));
+		let completed = suite.run(&rom, &mut logger, |test, cpu_state, passed| {
+			if let Some(state_dir) = state_dir.as_deref().filter(|_| !passed) {
+				export_state(state_dir, &test.name, cpu_state);
 			}
-			"result" => {
-				if let toml::Value::Table(value) = value {
-					let mut result = Registers::new();
-					for (key, value) in value {
-						match key.as_str() {
-							"a" => result.a = parse_u8(value, key),
-							"b" => result.b = parse_u8(value, key),
-							"c" => result.c = parse_u8(value, key),
-							"d" => result.d = parse_u8(value, key),
-							"e" => result.e = parse_u8(value, key),
-							"h" => result.h = parse_u8(value, key),
-							"l" => result.l = parse_u8(value, key),
-							"f.z" => result.zf = parse_bool(value, key),
-							"f.n" => result.nf = parse_bool(value, key),
-							"f.h" => result.hf = parse_bool(value, key),
-							"f.c" => result.cf = parse_bool(value, key),
-							"bc" => result.bc = parse_u16(value, key, symfile),
-							"de" => result.de = parse_u16(value, key, symfile),
-							"hl" => result.hl = parse_u16(value, key, symfile),
-							"pc" => result.pc = parse_u16(value, key, symfile),
-							"sp" => result.sp = parse_u16(value, key, symfile),
-							&_ => {
-								let mut indices = key.char_indices();
-								if let (Some((_, '[')), Some((begin, _)), Some((end, ']'))) =
-									(indices.next(), indices.next(), indices.last())
-								{
-									match parse_memory_assignment(&key[begin..end], value, symfile)
-									{
-										Err(cause) => eprintln!("{}", cause),
-										Ok(data) => result.memory = data,
-									};
-								} else {
-									eprintln!("Unknown config key {key} = {value:?}");
-								}
-							}
-						}
-					}
-					test.result = Some(result);
-				} else {
-					eprintln!("Value of `{key}` must be a table.");
-				}
+			if !test.dump.unwrap_or(default_dump).applies(passed) {
+				return;
 			}
-			"stack" => {
-				match parse_memory("stack", value) {
-					Err(cause) => eprintln!("{}", cause),
-					Ok(data) => test.stack.extend(data),
-				};
+
+			if let Err(msg) = fs::create_dir_all(&test_dump_dir) {
+				eprintln!("Failed to create directory {test_dump_dir}: {msg}");
+				return;
 			}
-			_ => {
-				let mut indices = key.char_indices();
-				if let (Some((_, '[')), Some((begin, _)), Some((end, ']'))) =
-					(indices.next(), indices.next(), indices.last())
-				{
-					match parse_memory_assignment(&key[begin..end], value, symfile) {
-						Err(cause) => eprintln!("{}", cause),
-						Ok(data) => test.initial.memory = data,
-					};
-				} else {
-					eprintln!("Unknown config key {key} = {value:?}");
-				}
+			let path = format!(
+				"{test_dump_dir}/{}.{}",
+				test.name,
+				execution.dump_format.extension()
+			);
+
+			match File::create(&path) {
+				Ok(file) => cpu_state
+					.address_space
+					.dump_as(file, execution.dump_format.format())
+					.unwrap_or_else(|msg| {
+						eprintln!("Failed to write dump to {path}: {msg}");
+					}),
+				Err(msg) => eprintln!("Failed to open {path}: {msg}"),
 			}
+		});
+
+		if let Some(ref trace_dir) = trace_dir {
+			write_traces(trace_dir, &logger.reports, execution.trace_format);
+		}
+		if let Some(ref heat_map_dir) = heat_map_dir {
+			write_heat_maps(heat_map_dir, &logger.reports, execution.heat_map_format);
+		}
+		if execution.profile.is_some() {
+			profile.extend(profile_events(pid, rom_path, &logger.reports));
+		}
+		if execution.coverage.is_some() {
+			logger
+				.coverage
+				.write_lcov(
+					&mut coverage,
+					rom_path,
+					execution.labels,
+					execution.data_sections,
+				)
+				.expect("writing to a Vec can't fail");
 		}
-	}
 
-	let mut global_config = TestConfig::new(String::from("Global"));
-	let mut tests: Vec<TestConfig> = vec![];
-	let toml_file = path.parse::<toml::Value>().unwrap_or_else(|msg| {
-		eprintln!("Failed to parse config file: {msg}");
-		exit(1);
-	});
+		if !completed {
+			eprintln!("{rom_path}: Stopped early; remaining tests were not run.");
+		}
 
-	let config = if let toml::Value::Table(config) = toml_file {
-		config
-	} else {
-		panic!("TOML root is not a table (Please report this and provide the TOML file used.)");
-	};
+		passed &= logger.finish();
+		failures += logger.failure;
+		reports.append(&mut logger.reports);
 
-	for (key, value) in config {
-		if let toml::Value::Table(table) = value {
-			let mut test = global_config.clone();
-			test.name = key;
-			for (key, value) in table.iter() {
-				parse_configuration(&mut test, key, value, symfile);
-			}
-			tests.push(test);
-		} else {
-			parse_configuration(&mut global_config, &key, &value, symfile);
+		if !completed || max_failures.is_some_and(|max_failures| failures >= max_failures) {
+			break;
+		}
+	}
+	suite.filters.retain(|filter| *filter != Filter::MainRom);
+	if let Some(path) = execution.profile {
+		write_profile(path, profile);
+	}
+	if let Some(path) = execution.coverage {
+		if let Err(msg) = fs::write(path, coverage) {
+			eprintln!("Failed to write coverage to {path}: {msg}");
 		}
 	}
+	(passed, reports)
+}
 
-	tests
+/// Converts the subroutine calls of each test into Chrome trace events, with a thread for each test.
+/// Each cycle is shown as one microsecond.
+fn profile_events(pid: usize, rom_path: &str, reports: &[TestReport]) -> Vec<serde_json::Value> {
+	let mut events = vec![json!({
+		"name": "process_name",
+		"ph": "M",
+		"pid": pid,
+		"args": { "name": rom_path },
+	})];
+	for (tid, report) in reports.iter().enumerate() {
+		if report.profile.is_empty() {
+			continue;
+		}
+		events.push(json!({
+			"name": "thread_name",
+			"ph": "M",
+			"pid": pid,
+			"tid": tid,
+			"args": { "name": report.name },
+		}));
+		events.extend(report.profile.iter().map(|span| {
+			json!({
+				"name": span.name(),
+				"ph": "X",
+				"pid": pid,
+				"tid": tid,
+				"ts": span.start,
+				"dur": span.end - span.start,
+			})
+		}));
+	}
+	events
 }
 
-fn main() {
-	fn open_input(path: &str) -> Box<dyn Read> {
-		if path == "-" {
-			Box::new(BufReader::new(stdin()))
-		} else {
-			Box::new(File::open(path).unwrap_or_else(|msg| {
-				eprintln!("Failed to open {path}: {msg}");
-				exit(1)
-			}))
+fn write_profile(path: &str, events: Vec<serde_json::Value>) {
+	let profile = json!({ "traceEvents": events });
+	let result = File::create(path)
+		.map_err(|msg| msg.to_string())
+		.and_then(|file| serde_json::to_writer(file, &profile).map_err(|msg| msg.to_string()));
+	if let Err(msg) = result {
+		eprintln!("Failed to write profile to {path}: {msg}");
+	}
+}
+
+/// Writes the trace of each failing test to its own file in `dir`.
+fn write_traces(dir: &str, reports: &[TestReport], format: TraceFormat) {
+	for report in reports.iter().filter(|report| !report.trace.is_empty()) {
+		let path = format!("{dir}/{}.trace.txt", report.name);
+		let result = File::create(&path).and_then(|mut file| {
+			report
+				.trace
+				.iter()
+				.try_for_each(|step| writeln!(file, "{}", format.format(step)))
+		});
+		if let Err(msg) = result {
+			eprintln!("Failed to write trace to {path}: {msg}");
 		}
 	}
+}
 
-	let cli = Cli::parse();
+/// Writes a test's final state as a save state, and its cartridge RAM as a save file if the ROM has any.
+fn export_state(dir: &str, name: &str, cpu_state: &cpu::State<AddressSpace>) {
+	if let Err(msg) = fs::create_dir_all(dir) {
+		eprintln!("Failed to create directory {dir}: {msg}");
+		return;
+	}
+	let path = format!("{dir}/{name}.s0");
+	if let Err(msg) = File::create(&path).and_then(|file| savestate::write(cpu_state, file)) {
+		eprintln!("Failed to write save state to {path}: {msg}");
+	}
+	let sram = savestate::cartridge_ram(&cpu_state.address_space);
+	if !sram.is_empty() {
+		let path = format!("{dir}/{name}.sav");
+		if let Err(msg) = fs::write(&path, sram) {
+			eprintln!("Failed to write cartridge RAM to {path}: {msg}");
+		}
+	}
+}
 
-	let rom_path = cli.rom;
-	let config_path = cli.config;
+fn write_heat_maps(dir: &str, reports: &[TestReport], format: HeatMapStyle) {
+	for report in reports.iter().filter(|report| !report.heat_map.is_empty()) {
+		let (path, result) = match format {
+			HeatMapStyle::Text => {
+				let path = format!("{dir}/{}.heat.txt", report.name);
+				let result = File::create(&path).and_then(|mut file| {
+					writeln!(file, "Address     Reads    Writes  Symbol")?;
+					report.heat_map.iter().try_for_each(|heat| {
+						let line = format!(
+							"0x{:04x}   {:>8}  {:>8}  {}",
+							heat.address,
+							heat.reads,
+							heat.writes,
+							heat.label.as_deref().unwrap_or_default()
+						);
+						writeln!(file, "{}", line.trim_end())
+					})
+				});
+				(path, result)
+			}
+			HeatMapStyle::Json => {
+				let path = format!("{dir}/{}.heat.json", report.name);
+				let result = File::create(&path).and_then(|file| {
+					serde_json::to_writer_pretty(file, &report.heat_map).map_err(io::Error::from)
+				});
+				(path, result)
+			}
+		};
+		if let Err(msg) = result {
+			eprintln!("Failed to write heat map to {path}: {msg}");
+		}
+	}
+}
 
-	let rom = open_rom(&rom_path);
+fn run(args: &RunArgs) {
+	if args.list {
+		list(&args.configs, &args.select);
+		return;
+	}
 
-	let address_space = AddressSpace::with(&rom);
+	if args.watch {
+		watch(args);
+	}
 
-	let mut config_text = String::new();
-	open_input(&config_path)
-		.read_to_string(&mut config_text)
-		.unwrap_or_else(|error| {
-			eprintln!("Failed to read {config_path}: {error}");
-			exit(1);
-		});
+	let symbol_files = read_symbol_files(&args.configs);
+	let tests = read_tests(&args.configs, &symbol_files);
+	let debug_points = read_debug_files(&symbol_files.symbols, &args.debug_file);
+	let mut suite = args
+		.patch
+		.iter()
+		.fold(select(&args.select, tests), Suite::with_patch)
+		.with_jobs(args.jobs);
+	if args.fail_fast {
+		suite = suite.with_max_failures(1);
+	} else if let Some(max_failures) = args.max_failures {
+		suite = suite.with_max_failures(max_failures);
+	}
+	let data_sections = read_data_sections(symbol_files.map.as_ref(), &args.data_section);
+	for test in &mut suite.tests {
+		test.data_sections.extend(data_sections.iter().cloned());
+		test.trace |= args.trace.is_some();
+		test.profile |= args.profile.is_some() || args.callgrind.is_some() || args.profile_summary;
+		test.coverage |= args.coverage.is_some();
+		test.heat_map |= args.heat_map.is_some();
+		test.warnings_as_errors |= args.warnings_as_errors;
+		test.debug_points.extend(debug_points.iter().cloned());
+		if !test.snapshot.is_empty() {
+			let path = format!("{}/{}.snap", args.snapshot_dir, test.name);
+			let snapshot =
+				Snapshot::new(path, test.snapshot.clone()).with_update(args.update_snapshots);
+			test.conditions.push(Arc::new(snapshot));
+		}
+	}
 
-	let symfile = open_symfile(cli.symfile.as_ref().map(|x| x.as_ref()));
-	let tests = read_config(&config_text, &symfile);
+	if let [old, new] = args.compare.as_slice() {
+		compare_roms(&suite, [old, new], &read_labels(&symbol_files));
+	}
 
-	let silence_level = match cli.silent {
+	let silence_level = match args.silent {
 		SILENCE_NONE => SilenceLevel::None,
 		SILENCE_PASSING => SilenceLevel::Passing,
 		SILENCE_ALL.. => SilenceLevel::All,
 	};
 
-	let mut logger = Logger::new(silence_level, &rom_path);
+	let format = match args.format {
+		Format::Text => OutputFormat::Text,
+		Format::Json => OutputFormat::Json,
+		Format::Markdown => OutputFormat::Markdown,
+		Format::Ndjson => OutputFormat::Ndjson,
+	};
 
-	// create dump dir if it does not exist already
-	if let Some(ref dump_dir) = cli.dump_dir {
-		if let Err(msg) = fs::create_dir_all(dump_dir) {
-			eprintln!("Failed to create dump dir {dump_dir}: {msg}");
-			exit(1);
+	let labels = read_labels(&symbol_files);
+	let failures_file = args.select.failures_file();
+	let previous_failures = failures_file.map(read_failures).unwrap_or_default();
+	let (passed, reports) = execute(
+		&mut suite,
+		&Execution {
+			roms: &args.rom,
+			silence_level,
+			format,
+			dump_dir: args.dump_dir.as_deref(),
+			dump_format: args.dump_format,
+			dump_passing: args.dump_all,
+			state_dir: args.export_state.as_deref(),
+			trace_dir: args.trace.as_deref().filter(|dir| *dir != "-"),
+			trace_format: match args.trace_format {
+				TraceStyle::Text => TraceFormat::Text,
+				TraceStyle::Doctor => TraceFormat::Doctor,
+			},
+			heat_map_dir: args.heat_map.as_deref(),
+			heat_map_format: args.heat_map_format,
+			profile: args.profile.as_deref(),
+			coverage: args.coverage.as_deref(),
+			slowest: args.slowest.unwrap_or(0),
+			labels: &labels,
+			data_sections: &data_sections,
+		},
+	);
+
+	if args.profile_summary {
+		let costs = profile::summarize(&reports);
+		// Other formats are meant to be parsed, so the table mustn't be mixed into them.
+		let result = if let Format::Text = args.format {
+			profile::write_table(io::stdout(), &costs)
+		} else {
+			profile::write_table(io::stderr(), &costs)
+		};
+		if let Err(msg) = result {
+			eprintln!("Failed to write profile summary: {msg}");
 		}
 	}
 
-	for test in &tests {
-		let mut cpu_state = cpu::State::new(address_space.clone());
-		let mut test_logger = logger.make_test(test);
-
-		if test.run(&mut cpu_state, &mut test_logger) {
-			continue;
+	if let Some(ref path) = args.callgrind {
+		let result = File::create(path)
+			.and_then(|file| profile::write_callgrind(file, &args.rom.join(" "), &reports));
+		if let Err(msg) = result {
+			eprintln!("Failed to write profile to {path}: {msg}");
 		}
+	}
 
-		if let Some(ref dump_dir) = cli.dump_dir {
-			let path = String::from(dump_dir) + &format!("/{}.txt", test.name);
+	if let Some(path) = failures_file {
+		// A test fails if it failed on any ROM.
+		// Tests which were not run this time keep their previous result.
+		let failures = suite
+			.tests
+			.iter()
+			.map(|test| &test.name)
+			.filter(|name| {
+				let mut results = reports
+					.iter()
+					.filter(|report| report.name == **name)
+					.peekable();
+				if results.peek().is_none() {
+					previous_failures.contains(name)
+				} else {
+					results.any(|report| report.status == TestStatus::Failed)
+				}
+			})
+			.cloned()
+			.collect::<Vec<String>>();
+		write_failures(path, &failures);
+	}
 
-			match File::create(&path) {
-				Ok(file) => cpu_state.address_space.dump(file).unwrap_or_else(|msg| {
-					eprintln!("Failed to write dump to {path}: {msg}");
-				}),
-				Err(msg) => eprintln!("Failed to open {path}: {msg}"),
+	if !passed {
+		exit(1);
+	}
+}
+
+fn list(configs: &ConfigArgs, select_args: &SelectArgs) {
+	let suite = select(
+		select_args,
+		read_tests(configs, &read_symbol_files(configs)),
+	);
+	for test in suite.selected() {
+		println!("{}", test.name);
+	}
+}
+
+fn dump(args: &DumpArgs) {
+	let symbol_files = read_symbol_files(&args.configs);
+	let mut suite = args
+		.patch
+		.iter()
+		.fold(
+			select(&args.select, read_tests(&args.configs, &symbol_files)),
+			Suite::with_patch,
+		)
+		.with_jobs(args.jobs);
+	let (passed, _) = execute(
+		&mut suite,
+		&Execution {
+			roms: &args.rom,
+			silence_level: SilenceLevel::None,
+			format: OutputFormat::Text,
+			dump_dir: Some(&args.dump_dir),
+			dump_format: args.dump_format,
+			dump_passing: true,
+			state_dir: None,
+			trace_dir: None,
+			trace_format: TraceFormat::Text,
+			heat_map_dir: None,
+			heat_map_format: HeatMapStyle::Text,
+			profile: None,
+			coverage: None,
+			slowest: 0,
+			labels: &read_labels(&symbol_files),
+			data_sections: &[],
+		},
+	);
+	if !passed {
+		exit(1);
+	}
+}
+
+fn test_roms(args: &TestRomArgs) {
+	let model = match args.model {
+		ModelArg::Dmg => Model::Dmg,
+		ModelArg::Mgb => Model::Mgb,
+		ModelArg::Sgb => Model::Sgb,
+		ModelArg::Cgb => Model::Cgb,
+	};
+	let mut passed = 0;
+	for rom_path in &args.rom {
+		let rom = open_rom(rom_path);
+		let report = testrom::run(&rom, model, args.timeout);
+		match &report.outcome {
+			Outcome::Passed => {
+				passed += 1;
+				println!(
+					"{rom_path}: {} in {} cycles",
+					"passed".green(),
+					report.cycles
+				);
 			}
+			Outcome::Failed(reason) => println!("{rom_path}: {}: {reason}", "failed".red()),
+			Outcome::Timeout => println!(
+				"{rom_path}: {}: no result after {} cycles",
+				"failed".red(),
+				report.cycles
+			),
+		}
+		if report.outcome != Outcome::Passed && !report.serial.is_empty() {
+			println!("Serial output:\n{}", report.serial.trim_end());
+		}
+	}
+	println!("{passed}/{} test ROMs passed.", args.rom.len());
+	if passed != args.rom.len() {
+		exit(1);
+	}
+}
+
+/// Expands each directory into the JSON files inside it, in order.
+fn vector_paths(paths: &[String]) -> Vec<String> {
+	let mut files = Vec::new();
+	for path in paths {
+		if !Path::new(path).is_dir() {
+			files.push(path.clone());
+			continue;
 		}
+		let entries = fs::read_dir(path).unwrap_or_else(|msg| {
+			eprintln!("Failed to read {path}: {msg}");
+			exit(1);
+		});
+		let mut json = entries
+			.filter_map(|entry| Some(entry.ok()?.path()))
+			.filter(|entry| {
+				entry
+					.extension()
+					.is_some_and(|extension| extension == "json")
+			})
+			.map(|entry| entry.to_string_lossy().into_owned())
+			.collect::<Vec<_>>();
+		json.sort();
+		files.extend(json);
 	}
+	files
+}
 
-	if !logger.finish() {
+fn check_vectors(args: &VectorArgs) {
+	let (mut passed, mut total) = (0, 0);
+	for path in vector_paths(&args.path) {
+		let json = fs::read_to_string(&path).unwrap_or_else(|msg| {
+			eprintln!("Failed to read {path}: {msg}");
+			exit(1);
+		});
+		let results = vectors::check(&json).unwrap_or_else(|msg| {
+			eprintln!("Failed to parse {path}: {msg}");
+			exit(1);
+		});
+		let file_passed = results.total - results.failures.len();
+		passed += file_passed;
+		total += results.total;
+		if results.failures.is_empty() {
+			println!(
+				"{path}: {} {file_passed}/{}",
+				"passed".green(),
+				results.total
+			);
+			continue;
+		}
+		println!("{path}: {} {file_passed}/{}", "failed".red(), results.total);
+		for failure in results.failures.iter().take(args.show) {
+			print!("{}:\n{}", failure.name, failure.differences);
+		}
+	}
+	println!("{passed}/{total} test vectors passed.");
+	if passed != total {
 		exit(1);
 	}
 }
+
+fn main() {
+	let cli = Cli::parse();
+
+	// Running tests without a command is kept from before there were any others.
+	match &cli.command {
+		None => run(&cli.run),
+		Some(Commands::Run(args)) => run(args),
+		Some(Commands::List(args)) => list(&args.configs, &args.select),
+		Some(Commands::Dump(args)) => dump(args),
+		Some(Commands::Check(configs)) => check(configs),
+		Some(Commands::TestRom(args)) => test_roms(args),
+		Some(Commands::Vectors(args)) => check_vectors(args),
+	}
+}
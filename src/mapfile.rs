@@ -0,0 +1,234 @@
+//! Sections and symbols from the map files written by RGBLINK's `-m` option.
+//!
+//! Each bank lists the sections placed in it, followed by the symbols in each section:
+//!
+//! ```not_rust
+//! WRAM0 bank #0:
+//!     SECTION: $c000-$c0ff ($0100 bytes) ["Variables"]
+//!              $c000 = wPlayer
+//!              $c010 = wPlayer.end
+//!     EMPTY: $c100-$cfff ($0f00 bytes)
+//! ```
+//!
+//! The older `ROM Bank #1:` headers are also understood.
+
+use crate::config::Symbols;
+
+#[derive(Debug, thiserror::Error)]
+#[error("line {line}: {message}")]
+pub struct MapFileError {
+	pub line: usize,
+	pub message: String,
+}
+
+/// A section placed by the linker, such as `SECTION "Variables", WRAM0`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Section {
+	pub name: String,
+	pub bank: u32,
+	pub start: u16,
+	/// The length of the section in bytes, which may be 0.
+	pub size: u16,
+}
+
+impl Section {
+	#[must_use]
+	pub fn contains(&self, bank: u32, address: u16) -> bool {
+		self.bank == bank && address >= self.start && address - self.start < self.size
+	}
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MapFile {
+	/// Every section, in the order they are listed.
+	pub sections: Vec<Section>,
+	pub symbols: Symbols,
+}
+
+impl MapFile {
+	/// Reads the sections and symbols in a map file.
+	///
+	/// # Errors
+	///
+	/// Returns every line which could not be read.
+	pub fn parse(text: &str) -> Result<Self, Vec<MapFileError>> {
+		let mut map = Self::default();
+		let mut errors = Vec::new();
+		let mut bank = None;
+		for (index, line) in text.lines().enumerate() {
+			let error = |message: String| MapFileError {
+				line: index + 1,
+				message,
+			};
+			if !line.starts_with(char::is_whitespace) {
+				// A header; only those of banks are followed by sections
+				bank = parse_bank(line);
+				continue;
+			}
+			let line = line.trim();
+			let result = if let Some(section) = line.strip_prefix("SECTION:") {
+				bank.ok_or_else(|| String::from("Section outside of a bank"))
+					.and_then(|bank| parse_section(section.trim(), bank))
+					.map(|section| map.sections.push(section))
+			} else if let Some((address, name)) = line.split_once(" = ") {
+				bank.ok_or_else(|| String::from("Symbol outside of a bank"))
+					.and_then(|bank| Ok((bank, parse_hex(address)?)))
+					.map(|location| {
+						map.symbols.insert(String::from(name.trim()), location);
+					})
+			} else {
+				// Empty space, totals, and the summary
+				Ok(())
+			};
+			if let Err(message) = result {
+				errors.push(error(message));
+			}
+		}
+		if errors.is_empty() {
+			Ok(map)
+		} else {
+			Err(errors)
+		}
+	}
+
+	/// Every section with this name and a nonzero size.
+	pub fn named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Section> {
+		self.sections
+			.iter()
+			.filter(move |section| section.name == name && section.size != 0)
+	}
+}
+
+/// Reads the bank number from a header such as `ROMX bank #2:` or `ROM Bank #0 (HOME):`.
+fn parse_bank(header: &str) -> Option<u32> {
+	let (_, number) = header
+		.split_once(" bank #")
+		.or_else(|| header.split_once(" Bank #"))?;
+	let end = number
+		.find(|char: char| !char.is_ascii_digit())
+		.unwrap_or(number.len());
+	number[..end].parse().ok()
+}
+
+/// Reads a section's position, such as `$c000-$c0ff ($0100 bytes) ["Variables"]`.
+/// Empty sections only list their start, such as `$0150 ($0000 bytes) ["Empty"]`.
+fn parse_section(section: &str, bank: u32) -> Result<Section, String> {
+	let malformed = || format!("Malformed section `{section}`");
+	let (range, rest) = section.split_once(' ').ok_or_else(malformed)?;
+	let start = range.split_once('-').map_or(range, |(start, _)| start);
+	let (size, name) = rest
+		.strip_prefix('(')
+		.and_then(|rest| rest.split_once(" byte"))
+		.ok_or_else(malformed)?;
+	let name = name
+		.split_once("[\"")
+		.and_then(|(_, name)| name.rsplit_once("\"]"))
+		.map(|(name, _)| name)
+		.ok_or_else(malformed)?;
+	Ok(Section {
+		name: String::from(name),
+		bank,
+		start: parse_hex(start)?,
+		size: parse_hex(size)?,
+	})
+}
+
+fn parse_hex(value: &str) -> Result<u16, String> {
+	value
+		.trim()
+		.strip_prefix('$')
+		.and_then(|hex| u16::from_str_radix(hex, 16).ok())
+		.ok_or_else(|| format!("Invalid address `{}`", value.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const FIXTURE: &str = "\
+SUMMARY:
+	ROM0: 5 bytes used / 16379 free
+
+ROM0 bank #0:
+	SECTION: $0100-$0101 ($0002 bytes) [\"Code\"]
+	         $0100 = IncA
+	EMPTY: $0102-$010f ($000e bytes)
+	SECTION: $0110-$0112 ($0003 bytes) [\"More code\"]
+	SECTION: $0150 ($0000 bytes) [\"Empty\"]
+	TOTAL EMPTY: $3ffb bytes
+
+ROMX bank #2:
+	SECTION: $4000-$40ff ($0100 bytes) [\"Tables\"]
+	         $4000 = Tables
+	         $4080 = Tables.second
+
+WRAM0 bank #0:
+	SECTION: $c000-$c00f ($0010 bytes) [\"Variables\"]
+	         $c004 = wX
+	TOTAL EMPTY: $0ff0 bytes
+";
+
+	#[test]
+	fn sections_and_symbols() {
+		let map = MapFile::parse(FIXTURE).unwrap();
+		let names = map
+			.sections
+			.iter()
+			.map(|section| section.name.as_str())
+			.collect::<Vec<&str>>();
+		assert_eq!(names, ["Code", "More code", "Empty", "Tables", "Variables"]);
+		assert_eq!(
+			map.sections[3],
+			Section {
+				name: String::from("Tables"),
+				bank: 2,
+				start: 0x4000,
+				size: 0x100,
+			}
+		);
+		assert_eq!(map.symbols["IncA"], (0, 0x0100));
+		assert_eq!(map.symbols["Tables.second"], (2, 0x4080));
+		assert_eq!(map.symbols["wX"], (0, 0xC004));
+	}
+
+	#[test]
+	fn named_sections() {
+		let map = MapFile::parse(FIXTURE).unwrap();
+		assert_eq!(map.named("Empty").count(), 0);
+		let tables = map.named("Tables").next().unwrap();
+		assert!(tables.contains(2, 0x40FF));
+		assert!(!tables.contains(2, 0x4100));
+		assert!(!tables.contains(1, 0x4000));
+	}
+
+	#[test]
+	fn old_bank_headers() {
+		let map = MapFile::parse("ROM Bank #1:\n  SECTION: $4000-$4001 ($0002 bytes) [\"Old\"]\n")
+			.unwrap();
+		assert_eq!(map.sections[0].bank, 1);
+	}
+
+	#[test]
+	fn malformed_lines() {
+		let text = "\
+SUMMARY:
+	$c000 = wOrphan
+WRAM0 bank #0:
+	SECTION: $c000-$c00f [\"Variables\"]
+	$c0zz = wBad
+";
+		let errors = MapFile::parse(text)
+			.unwrap_err()
+			.iter()
+			.map(ToString::to_string)
+			.collect::<Vec<String>>();
+		assert_eq!(
+			errors,
+			[
+				"line 2: Symbol outside of a bank",
+				"line 4: Malformed section `$c000-$c00f [\"Variables\"]`",
+				"line 5: Invalid address `$c0zz`",
+			]
+		);
+	}
+}
@@ -1,9 +1,209 @@
+use crate::reg::{HwReg, BIT_FIELDS};
 use gb_cpu_sim::memory;
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::fmt;
 use std::io::{Error, Write};
+use std::mem;
+
+/// Value written to poisoned memory, chosen to be recognizable in dumps.
+pub const POISON: u8 = 0xA5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessKind {
+	Read,
+	Write,
+}
+
+/// A non-fatal problem with how a test accessed memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Warning {
+	/// Echo RAM, a mirror of WRAM, was read or written.
+	EchoRam { pc: u16, address: u16 },
+	/// ROM was written to outside of the ROM bank register, which is the only part of an MBC that is emulated.
+	RomWrite { pc: u16, address: u16 },
+	/// An IO register which does not exist was read.
+	UnknownIo { pc: u16, address: u16 },
+}
+
+impl Warning {
+	/// Describes the warning, naming addresses with `name`.
+	pub fn describe(&self, name: impl Fn(u16) -> String) -> String {
+		match *self {
+			Warning::EchoRam { pc, address } => {
+				format!("Accessed echo RAM at {} from {}", name(address), name(pc))
+			}
+			Warning::RomWrite { pc, address } => format!(
+				"Wrote to ROM at {} from {} (only the ROM bank register is emulated)",
+				name(address),
+				name(pc)
+			),
+			Warning::UnknownIo { pc, address } => format!(
+				"Read unknown IO register {} from {}",
+				name(address),
+				name(pc)
+			),
+		}
+	}
+
+	fn pc(self) -> u16 {
+		match self {
+			Warning::EchoRam { pc, .. }
+			| Warning::RomWrite { pc, .. }
+			| Warning::UnknownIo { pc, .. } => pc,
+		}
+	}
+}
+
+impl fmt::Display for Warning {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let description = self.describe(|address| format!("0x{address:04x}"));
+		write!(f, "{description}")
+	}
+}
+
+/// What VRAM, WRAM, and HRAM contain when a test begins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RamFill {
+	/// Every byte has the same value.
+	Byte(u8),
+	/// Pseudo-random bytes generated from a seed, so that the same contents can be recreated.
+	Random(u64),
+}
+
+impl Default for RamFill {
+	fn default() -> Self {
+		Self::Byte(0)
+	}
+}
+
+/// A single read or write made while running a test.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Access {
+	/// Address of the instruction which made this access.
+	pub pc: u16,
+	pub kind: AccessKind,
+	pub address: u16,
+	pub value: u8,
+	/// The value at `address` before this access. The same as `value` for reads.
+	pub previous: u8,
+}
+
+impl fmt::Display for Access {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let Access {
+			pc,
+			address,
+			value,
+			previous,
+			..
+		} = self;
+		match self.kind {
+			AccessKind::Read => write!(f, "0x{pc:04x} read 0x{value:02X} from [{address:X}]"),
+			AccessKind::Write => write!(
+				f,
+				"0x{pc:04x} wrote 0x{value:02X} to [{address:X}] (was 0x{previous:02X})"
+			),
+		}
+	}
+}
+
+/// An address space which can be read without the side effects of a CPU read, so that comparing a test's
+/// results doesn't change them.
+pub trait Peek: memory::AddressSpace {
+	/// Reads from memory without affecting the access log, poisoned memory, or forbidden memory.
+	fn peek(&self, address: u16) -> u8;
+}
+
+/// State of an address space which the test runner inspects, beyond what the CPU needs.
+pub trait Inspect: Peek {
+	/// The ROM bank currently mapped to 0x4000-0x7FFF.
+	fn rom_bank(&self) -> usize;
+	fn set_rom_bank(&mut self, bank: usize);
+	/// Replaces the contents of VRAM, WRAM, and HRAM.
+	fn fill_ram(&mut self, fill: RamFill);
+	/// Fills `address` with [`POISON`]. Reading it before it has been written to is an error.
+	/// Only RAM can be poisoned; other addresses are left alone. Poisoning is not a write, so it isn't logged and never switches banks.
+	fn poison(&mut self, address: u16);
+	/// The first poisoned address which was read, if any.
+	fn poisoned_read(&self) -> Option<u16>;
+	/// Forbids reading or writing `address`. Doing so is an error.
+	fn forbid(&mut self, address: u16, kind: AccessKind);
+	/// The first forbidden read or write, if any.
+	fn forbidden_access(&self) -> Option<(AccessKind, u16)>;
+	/// Forgets the poisoned read and forbidden access recorded so far, so that only later accesses are reported.
+	fn clear_violations(&mut self);
+	/// Makes reads from the two bytes after `address` return the byte before them, as if the CPU had not
+	/// incremented `pc` past the opcode at `address` because of the halt bug. `None` restores normal reads.
+	fn set_halt_bug(&mut self, address: Option<u16>);
+	/// Begins recording writes to ROM made while the cartridge has no MBC to receive them.
+	fn forbid_rom_writes(&mut self);
+	/// The first write to ROM without an MBC, as the address of the instruction which made it and the address written to.
+	fn rom_write(&self) -> Option<(u16, u16)>;
+	/// Sets the address of the instruction being executed, which subsequent accesses are attributed to.
+	fn set_pc(&mut self, pc: u16);
+	/// Begins recording every read and write.
+	fn enable_access_log(&mut self);
+	/// Returns and clears the accesses recorded so far.
+	fn take_access_log(&mut self) -> Vec<Access>;
+	/// Returns and clears the warnings raised so far.
+	fn take_warnings(&mut self) -> Vec<Warning>;
+}
+
+/// 64 KiB of plain RAM, with none of the Game Boy's memory map, for running the CPU on its own.
+pub struct FlatMemory(Box<[u8]>);
+
+impl Default for FlatMemory {
+	fn default() -> Self {
+		Self(vec![0; 0x10000].into_boxed_slice())
+	}
+}
+
+impl FlatMemory {
+	/// Creates memory starting with `image`, which is cut short at 64 KiB. The rest is filled with zeroes.
+	#[must_use]
+	pub fn with_image(image: &[u8]) -> Self {
+		let mut memory = Self::default();
+		let length = image.len().min(memory.0.len());
+		memory.0[..length].copy_from_slice(&image[..length]);
+		memory
+	}
+}
+
+impl memory::AddressSpace for FlatMemory {
+	fn read(&self, address: u16) -> u8 {
+		self.0[usize::from(address)]
+	}
+	fn write(&mut self, address: u16, value: u8) {
+		self.0[usize::from(address)] = value;
+	}
+}
+
+impl Peek for FlatMemory {
+	fn peek(&self, address: u16) -> u8 {
+		self.0[usize::from(address)]
+	}
+}
+
+/// How [`AddressSpace::dump_as`] writes memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DumpFormat {
+	/// The selected banks and each region of memory in hexadecimal, followed by decoded IO registers.
+	#[default]
+	Text,
+	/// All 64 KiB of the address space, as the CPU sees it, so that a byte's offset is its address.
+	Binary,
+	/// Each region of memory as an array of bytes, along with the selected banks and IO registers.
+	Json,
+}
 
 #[derive(Clone)]
 pub struct AddressSpace<'a> {
 	pub rom: &'a Vec<u8>,
+	// Only the ROM bank register of an MBC is emulated; if the cartridge has one, writes to 0x2000-0x3FFF select a bank.
+	pub rom_bank: usize,
 	pub vram: [u8; 0x2000], // VRAM locking is not emulated as there is not PPU present.
 	pub sram: [u8; 0x2000],
 	pub wram: [u8; 0x2000],
@@ -11,51 +211,386 @@ pub struct AddressSpace<'a> {
 	// OAM includes the 105 unused bytes of OAM; they will throw a warning.
 	pub oam: [u8; 0x100],
 	// All MMIO registers are special-cased; many serve no function.
+	// For now they are simply stored so that their state can be inspected.
+	pub io: [u8; 0x80],
 	// HRAM does not include 0xFFFF (or IE register)
 	pub hram: [u8; 0x7F],
+	pub ie: u8,
+	// Addresses which should be written before they are read.
+	poisoned: HashSet<u16>,
+	poisoned_read: Cell<Option<u16>>,
+	// Addresses which must not be read or written at all.
+	forbidden_reads: HashSet<u16>,
+	forbidden_writes: HashSet<u16>,
+	forbidden_access: Cell<Option<(AccessKind, u16)>>,
+	// The opcode whose operands are read one byte early, because of the halt bug.
+	halt_bug: Option<u16>,
+	// Whether writes to ROM are recorded, and the first one made, as (pc, address).
+	strict_rom: bool,
+	rom_write: Option<(u16, u16)>,
+	pc: u16,
+	access_log: Option<RefCell<Vec<Access>>>,
+	// Only the first warning of each kind from each instruction is kept, so that loops don't repeat them.
+	warnings: RefCell<Vec<Warning>>,
 }
 
 impl memory::AddressSpace for AddressSpace<'_> {
 	fn read(&self, address: u16) -> u8 {
-		let address = address as usize;
+		let address = match self.halt_bug {
+			Some(opcode) if (1..=2).contains(&address.wrapping_sub(opcode)) => {
+				address.wrapping_sub(1)
+			}
+			_ => address,
+		};
+		if self.poisoned_read.get().is_none() && self.poisoned.contains(&address) {
+			self.poisoned_read.set(Some(address));
+		}
+		if self.forbidden_access.get().is_none() && self.forbidden_reads.contains(&address) {
+			self.forbidden_access.set(Some((AccessKind::Read, address)));
+		}
 		match address {
-			0x0000..=0x3FFF => self.rom[address],
-			0xC000..=0xDFFF => self.wram[address - 0xC000],
-			0xFF80..=0xFFFE => self.hram[address - 0xFF80],
-			_ => panic!("Unimplemented address range for 0x{address:04x}"),
+			0xE000..=0xFDFF => self.warn(Warning::EchoRam {
+				pc: self.pc,
+				address,
+			}),
+			0xFF00..=0xFF7F
+				if HwReg::try_from(address).is_err() && !(0xFF30..=0xFF3F).contains(&address) =>
+			{
+				self.warn(Warning::UnknownIo {
+					pc: self.pc,
+					address,
+				});
+			}
+			_ => {}
 		}
+		let value = self.read_unlogged(address);
+		if let Some(access_log) = &self.access_log {
+			access_log.borrow_mut().push(Access {
+				pc: self.pc,
+				kind: AccessKind::Read,
+				address,
+				value,
+				previous: value,
+			});
+		}
+		value
 	}
 
 	fn write(&mut self, address: u16, value: u8) {
-		let address = address as usize;
+		self.poisoned.remove(&address);
+		if self.strict_rom && self.rom_write.is_none() && address < 0x8000 && !self.has_mbc() {
+			self.rom_write = Some((self.pc, address));
+		}
+		if self.forbidden_access.get().is_none() && self.forbidden_writes.contains(&address) {
+			self.forbidden_access
+				.set(Some((AccessKind::Write, address)));
+		}
+		if let Some(access_log) = &self.access_log {
+			access_log.borrow_mut().push(Access {
+				pc: self.pc,
+				kind: AccessKind::Write,
+				address,
+				value,
+				previous: self.read_unlogged(address),
+			});
+		}
+		let index = usize::from(address);
 		match address {
-			0x0000..=0x3FFF => eprintln!("Wrote to ROM (MBC registers are not yet emulated)"),
-			0xC000..=0xDFFF => self.wram[address - 0xC000] = value,
-			0xFF80..=0xFFFE => self.hram[address - 0xFF80] = value,
-			_ => panic!("Unimplemented address range for 0x{address:04x}"),
+			0x2000..=0x3FFF if self.has_mbc() => self.select_rom_bank(address, value),
+			0x0000..=0x7FFF => self.warn(Warning::RomWrite {
+				pc: self.pc,
+				address,
+			}),
+			0x8000..=0x9FFF => self.vram[index - 0x8000] = value,
+			0xA000..=0xBFFF => self.sram[index - 0xA000] = value,
+			0xC000..=0xDFFF => self.wram[index - 0xC000] = value,
+			0xE000..=0xFDFF => {
+				self.warn(Warning::EchoRam {
+					pc: self.pc,
+					address,
+				});
+				self.wram[index - 0xE000] = value;
+			}
+			0xFE00..=0xFEFF => self.oam[index - 0xFE00] = value,
+			0xFF00..=0xFF7F => self.io[index - 0xFF00] = value,
+			0xFF80..=0xFFFE => self.hram[index - 0xFF80] = value,
+			0xFFFF => self.ie = value,
 		}
 	}
 }
 
+impl Peek for AddressSpace<'_> {
+	fn peek(&self, address: u16) -> u8 {
+		self.read_unlogged(address)
+	}
+}
+
+impl Inspect for AddressSpace<'_> {
+	fn rom_bank(&self) -> usize {
+		self.rom_bank
+	}
+
+	fn set_rom_bank(&mut self, bank: usize) {
+		self.rom_bank = bank;
+	}
+
+	fn fill_ram(&mut self, fill: RamFill) {
+		let ram = [&mut self.vram[..], &mut self.wram[..], &mut self.hram[..]];
+		match fill {
+			RamFill::Byte(value) => {
+				for ram in ram {
+					ram.fill(value);
+				}
+			}
+			RamFill::Random(seed) => {
+				let mut rng = fastrand::Rng::with_seed(seed);
+				for ram in ram {
+					rng.fill(ram);
+				}
+			}
+		}
+	}
+	fn poison(&mut self, address: u16) {
+		let index = usize::from(address);
+		let byte = match address {
+			0x8000..=0x9FFF => &mut self.vram[index - 0x8000],
+			0xA000..=0xBFFF => &mut self.sram[index - 0xA000],
+			0xC000..=0xDFFF => &mut self.wram[index - 0xC000],
+			0xFF80..=0xFFFE => &mut self.hram[index - 0xFF80],
+			_ => return,
+		};
+		*byte = POISON;
+		self.poisoned.insert(address);
+	}
+
+	fn poisoned_read(&self) -> Option<u16> {
+		self.poisoned_read.get()
+	}
+
+	fn forbid(&mut self, address: u16, kind: AccessKind) {
+		match kind {
+			AccessKind::Read => self.forbidden_reads.insert(address),
+			AccessKind::Write => self.forbidden_writes.insert(address),
+		};
+	}
+
+	fn forbidden_access(&self) -> Option<(AccessKind, u16)> {
+		self.forbidden_access.get()
+	}
+
+	fn clear_violations(&mut self) {
+		self.poisoned_read.set(None);
+		self.forbidden_access.set(None);
+	}
+
+	fn set_halt_bug(&mut self, address: Option<u16>) {
+		self.halt_bug = address;
+	}
+	fn forbid_rom_writes(&mut self) {
+		self.strict_rom = true;
+	}
+	fn rom_write(&self) -> Option<(u16, u16)> {
+		self.rom_write
+	}
+	fn set_pc(&mut self, pc: u16) {
+		self.pc = pc;
+	}
+
+	fn enable_access_log(&mut self) {
+		self.access_log.get_or_insert_with(RefCell::default);
+	}
+
+	fn take_access_log(&mut self) -> Vec<Access> {
+		self.access_log
+			.as_mut()
+			.map(|access_log| access_log.take())
+			.unwrap_or_default()
+	}
+
+	fn take_warnings(&mut self) -> Vec<Warning> {
+		self.warnings.take()
+	}
+}
+
 impl AddressSpace<'_> {
 	#[must_use]
-	pub fn with(rom: &Vec<u8>) -> AddressSpace {
+	pub fn with(rom: &Vec<u8>) -> AddressSpace<'_> {
 		AddressSpace {
 			rom,
+			rom_bank: 1,
 			vram: [0; 0x2000],
 			sram: [0; 0x2000],
 			wram: [0; 0x2000],
 			oam: [0; 0x100],
+			io: [0; 0x80],
 			hram: [0; 0x7F],
+			ie: 0,
+			poisoned: HashSet::new(),
+			poisoned_read: Cell::new(None),
+			forbidden_reads: HashSet::new(),
+			forbidden_writes: HashSet::new(),
+			forbidden_access: Cell::new(None),
+			halt_bug: None,
+			strict_rom: false,
+			rom_write: None,
+			pc: 0,
+			access_log: None,
+			warnings: RefCell::default(),
 		}
 	}
 
-	/// Dumps the contents of memory to a buffer.
+	/// Reads from memory without affecting the access log or poisoned memory.
+	///
+	/// # Panics
+	///
+	/// Panics if `address` belongs to memory which is not yet emulated.
+	#[must_use]
+	pub fn read_unlogged(&self, address: u16) -> u8 {
+		let address = address as usize;
+		match address {
+			0x0000..=0x3FFF => self.rom.get(address).copied().unwrap_or(0xFF),
+			// Banks beyond the end of the ROM read as open bus
+			0x4000..=0x7FFF => self
+				.rom
+				.get(self.rom_bank * 0x4000 + address - 0x4000)
+				.copied()
+				.unwrap_or(0xFF),
+			0x8000..=0x9FFF => self.vram[address - 0x8000],
+			0xA000..=0xBFFF => self.sram[address - 0xA000],
+			0xC000..=0xDFFF => self.wram[address - 0xC000],
+			0xE000..=0xFDFF => self.wram[address - 0xE000],
+			0xFE00..=0xFEFF => self.oam[address - 0xFE00],
+			0xFF00..=0xFF7F => self.io[address - 0xFF00],
+			0xFF80..=0xFFFE => self.hram[address - 0xFF80],
+			0xFFFF => self.ie,
+			_ => panic!("Unimplemented address range for 0x{address:04x}"),
+		}
+	}
+
+	fn warn(&self, warning: Warning) {
+		let mut warnings = self.warnings.borrow_mut();
+		let repeated = warnings.iter().any(|existing| {
+			mem::discriminant(existing) == mem::discriminant(&warning)
+				&& existing.pc() == warning.pc()
+		});
+		if !repeated {
+			warnings.push(warning);
+		}
+	}
+
+	/// The cartridge type in the ROM header, or 0 (ROM only) if the ROM is too short to have one.
+	fn cartridge_type(&self) -> u8 {
+		self.rom.get(0x147).copied().unwrap_or(0)
+	}
+
+	pub(crate) fn has_mbc(&self) -> bool {
+		has_mbc(self.cartridge_type())
+	}
+
+	pub(crate) fn is_mbc5(&self) -> bool {
+		(0x19..=0x1E).contains(&self.cartridge_type())
+	}
+
+	/// Handles a write to the MBC's ROM bank number.
+	fn select_rom_bank(&mut self, address: u16, value: u8) {
+		self.rom_bank = if self.is_mbc5() {
+			// MBC5's bank number is 9 bits: the low 8 at 0x2000-0x2FFF, and bit 8 at 0x3000-0x3FFF.
+			// It is also the only MBC able to map bank 0 to 0x4000.
+			if address < 0x3000 {
+				self.rom_bank & 0x100 | usize::from(value)
+			} else {
+				self.rom_bank & 0xFF | usize::from(value & 1) << 8
+			}
+		} else {
+			let mask = match self.cartridge_type() {
+				// MBC2
+				0x05 | 0x06 => 0x0F,
+				// MBC3
+				0x0F..=0x13 => 0x7F,
+				// MBC1, and others which only use 5 bits
+				_ => 0x1F,
+			};
+			usize::from(value & mask).max(1)
+		};
+	}
+
+	/// Dumps the contents of memory to a buffer as text.
+	///
+	/// # Errors
+	///
+	/// Fails if the buffer could not be written to.
+	pub fn dump<W: Write>(&self, file: W) -> Result<(), Error> {
+		self.dump_as(file, DumpFormat::Text)
+	}
+
+	/// Dumps the contents of memory to a buffer in the given format.
 	///
 	/// # Errors
 	///
 	/// Fails if the buffer could not be written to.
-	pub fn dump<W: Write>(&self, mut file: W) -> Result<(), Error> {
+	pub fn dump_as<W: Write>(&self, mut file: W, format: DumpFormat) -> Result<(), Error> {
+		match format {
+			DumpFormat::Text => self.dump_text(file),
+			DumpFormat::Binary => {
+				let image = (0..=u16::MAX)
+					.map(|address| self.read_unlogged(address))
+					.collect::<Vec<u8>>();
+				file.write_all(&image)
+			}
+			DumpFormat::Json => {
+				let region = |name, start: u16, memory: &[u8]| serde_json::json!({ "name": name, "start": start, "bytes": memory });
+				let io = BIT_FIELDS
+					.iter()
+					.map(|register| {
+						let address = register.reg as u16;
+						serde_json::json!({
+							"name": register.reg.name(),
+							"address": address,
+							"value": self.read_unlogged(address),
+						})
+					})
+					.collect::<Vec<_>>();
+				let banks = self
+					.banks()
+					.into_iter()
+					.map(|(name, bank)| (name.to_ascii_lowercase(), serde_json::json!(bank)))
+					.collect::<serde_json::Map<_, _>>();
+				let dump = serde_json::json!({
+					"banks": banks,
+					"regions": [
+						region("VRAM", 0x8000, &self.vram),
+						region("SRAM", 0xA000, &self.sram),
+						region("WRAM", 0xC000, &self.wram),
+						region("OAM", 0xFE00, &self.oam),
+						region("IO", 0xFF00, &self.io),
+						region("HRAM", 0xFF80, &self.hram),
+						region("IE", 0xFFFF, &[self.ie]),
+					],
+					"io": io,
+				});
+				serde_json::to_writer(&mut file, &dump).map_err(Error::from)?;
+				writeln!(file)
+			}
+		}
+	}
+
+	/// The bank selected for each switchable region, by name.
+	///
+	/// Only ROM banking is emulated; the VRAM and WRAM banks are those selected by `VBK` and `SVBK`,
+	/// although every bank shares the same memory.
+	fn banks(&self) -> [(&'static str, usize); 3] {
+		let wram = usize::from(self.read_unlogged(HwReg::Svbk as u16) & 0x07).max(1);
+		[
+			("ROM", self.rom_bank),
+			(
+				"VRAM",
+				usize::from(self.read_unlogged(HwReg::Vbk as u16) & 0x01),
+			),
+			("WRAM", wram),
+		]
+	}
+
+	fn dump_text<W: Write>(&self, mut file: W) -> Result<(), Error> {
 		fn dump_memory<W: Write>(
 			name: &str,
 			start: usize,
@@ -76,7 +611,23 @@ impl AddressSpace<'_> {
 					.collect::<Vec<String>>()
 					.join(" ");
 
-				writeln!(file, "0x{address:04x}: {formatted_chunk}")?;
+				// Name any hardware registers in the chunk after its bytes, along with their column
+				let registers = (address..address + chunk.len())
+					.filter_map(|address| u16::try_from(address).ok())
+					.filter_map(|address| {
+						let register = HwReg::try_from(address).ok()?;
+						Some(format!("{:x}:{register}", address & 0xF))
+					})
+					.collect::<Vec<_>>();
+				if registers.is_empty() {
+					writeln!(file, "0x{address:04x}: {formatted_chunk}")?;
+				} else {
+					writeln!(
+						file,
+						"0x{address:04x}: {formatted_chunk}  {}",
+						registers.join(" ")
+					)?;
+				}
 			}
 
 			// Add extra whitespace to separate the dumps a bit more
@@ -85,10 +636,127 @@ impl AddressSpace<'_> {
 			Ok(())
 		}
 
+		writeln!(file, "[Banks]")?;
+		for (name, bank) in self.banks() {
+			writeln!(file, "{name}: {bank}")?;
+		}
+		writeln!(file)?;
+
 		dump_memory("VRAM", 0x8000, &self.vram, &mut file)?;
+		dump_memory("SRAM", 0xA000, &self.sram, &mut file)?;
 		dump_memory("WRAM", 0xC000, &self.wram, &mut file)?;
+		dump_memory("OAM", 0xFE00, &self.oam, &mut file)?;
+		dump_memory("IO", 0xFF00, &self.io, &mut file)?;
 		dump_memory("HRAM", 0xFF80, &self.hram, &mut file)?;
+		dump_memory("IE", 0xFFFF, &[self.ie], &mut file)?;
+
+		// IO registers are easier to read with their bits named
+		writeln!(file, "[IO registers]")?;
+		for register in BIT_FIELDS {
+			let address = register.reg as u16;
+			let value = self.read_unlogged(address);
+			let decoded = register.decode(value);
+			writeln!(
+				file,
+				"0x{address:04x}: 0x{value:02x} {:<5} {decoded}",
+				register.reg.name()
+			)?;
+		}
 
 		Ok(())
 	}
 }
+
+/// Whether `address` is in VRAM, SRAM, WRAM, or HRAM, which are the only memory that can be poisoned.
+pub(crate) fn is_ram(address: u16) -> bool {
+	matches!(address, 0x8000..=0xDFFF | 0xFF80..=0xFFFE)
+}
+
+/// Whether a cartridge type from the ROM header (`0x147`) has an MBC to switch banks.
+pub(crate) fn has_mbc(cartridge_type: u8) -> bool {
+	// Cartridge types without an MBC: ROM only, and ROM with RAM (with or without a battery)
+	!matches!(cartridge_type, 0x00 | 0x08 | 0x09)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::table::Location;
+	use gb_cpu_sim::cpu;
+	use gb_cpu_sim::memory::AddressSpace as _;
+
+	fn rom(cartridge_type: u8) -> Vec<u8> {
+		let mut rom = vec![0; 0x8000];
+		rom[0x147] = cartridge_type;
+		rom
+	}
+
+	#[test]
+	fn no_mbc_ignores_bank_writes() {
+		let rom = rom(0x00);
+		let mut memory = AddressSpace::with(&rom);
+		memory.write(0x2000, 3);
+		assert_eq!(memory.rom_bank, 1);
+		assert!(matches!(
+			memory.take_warnings()[..],
+			[Warning::RomWrite {
+				address: 0x2000,
+				..
+			}]
+		));
+	}
+
+	#[test]
+	fn mbc1_masks_bank_number() {
+		let rom = rom(0x01);
+		let mut memory = AddressSpace::with(&rom);
+		memory.write(0x2000, 0x23);
+		assert_eq!(memory.rom_bank, 0x03);
+		memory.write(0x3FFF, 0x20);
+		assert_eq!(memory.rom_bank, 1);
+	}
+
+	#[test]
+	fn mbc5_bank_bit_8() {
+		let rom = rom(0x19);
+		let mut memory = AddressSpace::with(&rom);
+		memory.write(0x2000, 0x12);
+		memory.write(0x3000, 0x01);
+		assert_eq!(memory.rom_bank, 0x112);
+		memory.write(0x2FFF, 0x00);
+		assert_eq!(memory.rom_bank, 0x100);
+		memory.write(0x3000, 0x00);
+		assert_eq!(memory.rom_bank, 0);
+	}
+
+	#[test]
+	fn poison_is_not_a_write() {
+		let rom = rom(0x01);
+		let mut memory = AddressSpace::with(&rom);
+		memory.enable_access_log();
+		memory.poison(0x2000);
+		memory.poison(0xC000);
+		assert_eq!(memory.rom_bank, 1);
+		assert_eq!(memory.peek(0xC000), POISON);
+		assert!(memory.take_access_log().is_empty());
+		assert!(memory.take_warnings().is_empty());
+		memory.read(0xC000);
+		assert_eq!(memory.poisoned_read(), Some(0xC000));
+	}
+
+	#[test]
+	fn comparing_is_not_an_access() {
+		let rom = rom(0x00);
+		let mut cpu_state = cpu::State::new(AddressSpace::with(&rom));
+		cpu_state.address_space.enable_access_log();
+		cpu_state.address_space.poison(0xC000);
+		cpu_state.address_space.forbid(0xFF4C, AccessKind::Read);
+		for address in [0xC000, 0xE000, 0xFF4C] {
+			Location::Address(address).read(&cpu_state);
+		}
+		assert!(cpu_state.address_space.take_access_log().is_empty());
+		assert!(cpu_state.address_space.take_warnings().is_empty());
+		assert_eq!(cpu_state.address_space.poisoned_read(), None);
+		assert_eq!(cpu_state.address_space.forbidden_access(), None);
+	}
+}
@@ -0,0 +1,103 @@
+//! Decoding the debug messages which BGB and Emulicious print when `ld d, d` is followed by a magic word.
+//!
+//! The message is either embedded in the ROM after the magic word, which the `jr` skips over:
+//!
+//! ```not_rust
+//!     ld d, d
+//!     jr .end
+//!     dw $6464, $0000
+//!     db "Hello, world!"
+//! .end
+//! ```
+//!
+//! or is a NUL-terminated string pointed to by it, as `dw $6464, $0001, Message, BANK(Message)`.
+
+use crate::charmap::Charmap;
+use crate::memory::Inspect;
+use crate::registers::flags_byte;
+use gb_cpu_sim::cpu;
+
+/// Longest message read through a pointer, in case its NUL terminator is missing.
+const MAX_LENGTH: u16 = 256;
+
+/// Decodes the message following the `ld d, d` just executed, if there is one.
+///
+/// Register names between percent signs, such as `%HL%`, are replaced with their values in hexadecimal.
+/// The text is read with `charmap` if the game has one, or as UTF-8 otherwise.
+pub fn decode<A: Inspect>(cpu_state: &cpu::State<A>, charmap: Option<&Charmap>) -> Option<String> {
+	let peek = |address: u16| cpu_state.address_space.peek(address);
+	let word = |address: u16| u16::from_le_bytes([peek(address), peek(address.wrapping_add(1))]);
+	// `pc` has already moved past the `ld d, d`.
+	let pc = cpu_state.pc;
+	if peek(pc) != 0x18 || word(pc.wrapping_add(2)) != 0x6464 {
+		return None;
+	}
+	let text = match word(pc.wrapping_add(4)) {
+		0x0000 => {
+			let end = u16::from(peek(pc.wrapping_add(1))) + 2;
+			(6..end)
+				.map(|offset| peek(pc.wrapping_add(offset)))
+				.take_while(|byte| *byte != 0)
+				.collect::<Vec<u8>>()
+		}
+		// The bank is ignored; the string is read from whichever bank is mapped.
+		0x0001 => {
+			let address = word(pc.wrapping_add(6));
+			(0..MAX_LENGTH)
+				.map(|offset| peek(address.wrapping_add(offset)))
+				.take_while(|byte| *byte != 0)
+				.collect::<Vec<u8>>()
+		}
+		_ => return None,
+	};
+	let text = match charmap {
+		Some(charmap) => charmap.decode(&text),
+		None => String::from_utf8_lossy(&text).into_owned(),
+	};
+	// `%PC%` is the address of the `ld d, d`, rather than the instruction after it.
+	Some(expand(&text, cpu_state, pc.wrapping_sub(1)))
+}
+
+/// Replaces each `%REGISTER%` in `text` with the register's value, using `pc` for `%PC%`.
+#[must_use]
+pub fn expand<A: Inspect>(text: &str, cpu_state: &cpu::State<A>, pc: u16) -> String {
+	let mut expanded = String::new();
+	let mut rest = text;
+	while let Some(start) = rest.find('%') {
+		expanded += &rest[..start];
+		let after = &rest[start + 1..];
+		let value = after
+			.find('%')
+			.and_then(|end| Some((end, register(&after[..end], cpu_state, pc)?)));
+		if let Some((end, value)) = value {
+			expanded += &value;
+			rest = &after[end + 1..];
+		} else {
+			expanded.push('%');
+			rest = after;
+		}
+	}
+	expanded + rest
+}
+
+fn register<A: Inspect>(name: &str, cpu_state: &cpu::State<A>, pc: u16) -> Option<String> {
+	let byte = |value: u8| format!("0x{value:02x}");
+	let word = |value: u16| format!("0x{value:04x}");
+	Some(match name.to_ascii_uppercase().as_str() {
+		"A" => byte(cpu_state.a),
+		"F" => byte(flags_byte(cpu_state)),
+		"B" => byte(cpu_state.b),
+		"C" => byte(cpu_state.c),
+		"D" => byte(cpu_state.d),
+		"E" => byte(cpu_state.e),
+		"H" => byte(cpu_state.h),
+		"L" => byte(cpu_state.l),
+		"AF" => word(u16::from_be_bytes([cpu_state.a, flags_byte(cpu_state)])),
+		"BC" => word(cpu_state.get_bc()),
+		"DE" => word(cpu_state.get_de()),
+		"HL" => word(cpu_state.get_hl()),
+		"SP" => word(cpu_state.sp),
+		"PC" => word(pc),
+		_ => return None,
+	})
+}
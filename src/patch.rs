@@ -0,0 +1,398 @@
+//! Application of IPS and BPS patches to ROM images.
+
+#[derive(Debug, thiserror::Error)]
+pub enum PatchError {
+	#[error("Not an IPS or BPS patch")]
+	UnknownFormat,
+	#[error("Patch ends unexpectedly")]
+	UnexpectedEnd,
+	#[error("Patch reads outside of the ROM")]
+	OutOfBounds,
+	#[error("ROM checksum 0x{actual:08x} does not match the patch's expected 0x{expected:08x}. Is this the correct base ROM?")]
+	SourceChecksum { expected: u32, actual: u32 },
+	#[error(
+		"Patched ROM checksum 0x{actual:08x} does not match the patch's expected 0x{expected:08x}"
+	)]
+	TargetChecksum { expected: u32, actual: u32 },
+	#[error("Patch checksum does not match; the patch file is probably corrupted")]
+	PatchChecksum,
+	#[error("Patched ROM does not match the size the patch declares ({expected} bytes)")]
+	TargetSize { expected: usize },
+}
+
+type Result<T> = std::result::Result<T, PatchError>;
+
+/// Applies an IPS or BPS patch to `rom`, detecting the format from its header.
+///
+/// # Errors
+///
+/// Fails if the patch is malformed, or, for BPS patches, if any checksum does not match.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+	if let Some(records) = patch.strip_prefix(b"PATCH") {
+		apply_ips(rom, records)
+	} else if patch.starts_with(b"BPS1") {
+		apply_bps(rom, patch)
+	} else {
+		Err(PatchError::UnknownFormat)
+	}
+}
+
+struct Reader<'a> {
+	data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+		if length > self.data.len() {
+			return Err(PatchError::UnexpectedEnd);
+		}
+		let (taken, rest) = self.data.split_at(length);
+		self.data = rest;
+		Ok(taken)
+	}
+
+	fn byte(&mut self) -> Result<u8> {
+		Ok(self.take(1)?[0])
+	}
+
+	fn big_endian(&mut self, length: usize) -> Result<usize> {
+		Ok(self
+			.take(length)?
+			.iter()
+			.fold(0, |acc, b| (acc << 8) | usize::from(*b)))
+	}
+
+	/// Reads one of BPS's variable-length integers.
+	fn number(&mut self) -> Result<usize> {
+		let mut data = 0usize;
+		let mut shift = 1usize;
+		loop {
+			let x = self.byte()?;
+			data = usize::from(x & 0x7F)
+				.checked_mul(shift)
+				.and_then(|x| data.checked_add(x))
+				.ok_or(PatchError::OutOfBounds)?;
+			if x & 0x80 != 0 {
+				return Ok(data);
+			}
+			shift = shift.checked_shl(7).ok_or(PatchError::OutOfBounds)?;
+			data = data.checked_add(shift).ok_or(PatchError::OutOfBounds)?;
+		}
+	}
+}
+
+fn apply_ips(rom: &[u8], records: &[u8]) -> Result<Vec<u8>> {
+	let mut target = rom.to_vec();
+	let mut reader = Reader { data: records };
+
+	loop {
+		if reader.data.starts_with(b"EOF") {
+			reader.take(3)?;
+			// Some patches truncate the ROM after the end marker
+			if reader.data.len() >= 3 {
+				target.truncate(reader.big_endian(3)?);
+			}
+			return Ok(target);
+		}
+
+		let offset = reader.big_endian(3)?;
+		let length = reader.big_endian(2)?;
+		let data = if length == 0 {
+			// Run-length encoded record
+			let length = reader.big_endian(2)?;
+			vec![reader.byte()?; length]
+		} else {
+			reader.take(length)?.to_vec()
+		};
+
+		if target.len() < offset + data.len() {
+			target.resize(offset + data.len(), 0);
+		}
+		target[offset..offset + data.len()].copy_from_slice(&data);
+	}
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+	if patch.len() < 16 {
+		return Err(PatchError::UnexpectedEnd);
+	}
+	let (body, footer) = patch.split_at(patch.len() - 12);
+	let footer_word = |i: usize| {
+		u32::from_le_bytes([
+			footer[i * 4],
+			footer[i * 4 + 1],
+			footer[i * 4 + 2],
+			footer[i * 4 + 3],
+		])
+	};
+
+	// The patch's own checksum covers everything before it, including the other two
+	if crc32(&patch[..patch.len() - 4]) != footer_word(2) {
+		return Err(PatchError::PatchChecksum);
+	}
+	let expected = footer_word(0);
+	let actual = crc32(rom);
+	if actual != expected {
+		return Err(PatchError::SourceChecksum { expected, actual });
+	}
+
+	let mut reader = Reader { data: &body[4..] };
+	let _source_size = reader.number()?;
+	let target_size = reader.number()?;
+	let metadata_size = reader.number()?;
+	reader.take(metadata_size)?;
+
+	// The declared size is only trusted once the ROM matches it, so a malformed patch can't allocate without bound
+	let mut target = Vec::with_capacity(target_size.min(rom.len().saturating_mul(2)));
+	let mut source_offset = 0usize;
+	let mut target_offset = 0usize;
+
+	let relative = |offset: usize, data: usize| {
+		let distance = data >> 1;
+		if data & 1 == 0 {
+			offset.checked_add(distance)
+		} else {
+			offset.checked_sub(distance)
+		}
+		.ok_or(PatchError::OutOfBounds)
+	};
+
+	while !reader.data.is_empty() {
+		let data = reader.number()?;
+		let length = (data >> 2) + 1;
+		if length > target_size - target.len() {
+			return Err(PatchError::TargetSize {
+				expected: target_size,
+			});
+		}
+		match data & 3 {
+			// SourceRead
+			0 => {
+				let start = target.len();
+				let bytes = rom
+					.get(start..start + length)
+					.ok_or(PatchError::OutOfBounds)?;
+				target.extend_from_slice(bytes);
+			}
+			// TargetRead
+			1 => target.extend_from_slice(reader.take(length)?),
+			// SourceCopy
+			2 => {
+				source_offset = relative(source_offset, reader.number()?)?;
+				let end = source_offset
+					.checked_add(length)
+					.ok_or(PatchError::OutOfBounds)?;
+				let bytes = rom.get(source_offset..end).ok_or(PatchError::OutOfBounds)?;
+				target.extend_from_slice(bytes);
+				source_offset = end;
+			}
+			// TargetCopy; may overlap with the bytes being written, so copy one at a time
+			_ => {
+				target_offset = relative(target_offset, reader.number()?)?;
+				for _ in 0..length {
+					let byte = *target.get(target_offset).ok_or(PatchError::OutOfBounds)?;
+					target.push(byte);
+					target_offset += 1;
+				}
+			}
+		}
+	}
+
+	if target.len() != target_size {
+		return Err(PatchError::TargetSize {
+			expected: target_size,
+		});
+	}
+	let expected = footer_word(1);
+	let actual = crc32(&target);
+	if actual != expected {
+		return Err(PatchError::TargetChecksum { expected, actual });
+	}
+
+	Ok(target)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+	!data.iter().fold(!0u32, |crc, byte| {
+		(0..8).fold(crc ^ u32::from(*byte), |crc, _| {
+			if crc & 1 == 0 {
+				crc >> 1
+			} else {
+				(crc >> 1) ^ 0xEDB8_8320
+			}
+		})
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Writes one of BPS's variable-length integers.
+	fn number(patch: &mut Vec<u8>, mut value: usize) {
+		loop {
+			let low = u8::try_from(value & 0x7F).unwrap();
+			value >>= 7;
+			if value == 0 {
+				patch.push(0x80 | low);
+				return;
+			}
+			patch.push(low);
+			value -= 1;
+		}
+	}
+
+	/// Builds a BPS patch from its actions, which are already encoded, with correct checksums.
+	fn bps(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+		let mut patch = b"BPS1".to_vec();
+		number(&mut patch, source.len());
+		number(&mut patch, target.len());
+		number(&mut patch, 0);
+		patch.extend_from_slice(actions);
+		patch.extend_from_slice(&crc32(source).to_le_bytes());
+		patch.extend_from_slice(&crc32(target).to_le_bytes());
+		patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+		patch
+	}
+
+	#[test]
+	fn crc32_check_value() {
+		assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+	}
+
+	#[test]
+	fn unknown_format() {
+		assert!(matches!(
+			apply(&[0; 4], b"NOTAPATCH"),
+			Err(PatchError::UnknownFormat)
+		));
+	}
+
+	#[test]
+	fn ips_records() {
+		let patch = b"PATCH\x00\x00\x01\x00\x02\xAA\xBB\x00\x00\x06\x00\x01\xCCEOF";
+		let rom = apply(&[0; 4], patch).unwrap();
+		assert_eq!(rom, [0x00, 0xAA, 0xBB, 0x00, 0x00, 0x00, 0xCC]);
+	}
+
+	#[test]
+	fn ips_rle_record() {
+		let patch = b"PATCH\x00\x00\x01\x00\x00\x00\x03\x55EOF";
+		let rom = apply(&[0; 6], patch).unwrap();
+		assert_eq!(rom, [0x00, 0x55, 0x55, 0x55, 0x00, 0x00]);
+	}
+
+	#[test]
+	fn ips_truncation() {
+		let patch = b"PATCH\x00\x00\x00\x00\x01\x11EOF\x00\x00\x02";
+		let rom = apply(&[0; 8], patch).unwrap();
+		assert_eq!(rom, [0x11, 0x00]);
+	}
+
+	#[test]
+	fn ips_unexpected_end() {
+		let patch = b"PATCH\x00\x00\x00\x00\x04\x11";
+		assert!(matches!(
+			apply(&[0; 8], patch),
+			Err(PatchError::UnexpectedEnd)
+		));
+	}
+
+	#[test]
+	fn bps_actions() {
+		let source = [1, 2, 3, 4];
+		let target = [1, 2, 9, 9, 9, 3, 4];
+		let mut actions = Vec::new();
+		// SourceRead of 2 bytes
+		number(&mut actions, 1 << 2);
+		// TargetRead of 1 byte
+		number(&mut actions, 1);
+		actions.push(9);
+		// TargetCopy of 2 bytes from the 9 just written, overlapping the bytes being copied
+		number(&mut actions, 1 << 2 | 3);
+		number(&mut actions, 2 << 1);
+		// SourceCopy of 2 bytes from offset 2
+		number(&mut actions, 1 << 2 | 2);
+		number(&mut actions, 2 << 1);
+		let patch = bps(&source, &target, &actions);
+		assert_eq!(apply(&source, &patch).unwrap(), target);
+	}
+
+	#[test]
+	fn bps_source_checksum() {
+		let mut actions = Vec::new();
+		number(&mut actions, 0);
+		let patch = bps(&[1], &[1], &actions);
+		assert!(matches!(
+			apply(&[2], &patch),
+			Err(PatchError::SourceChecksum { .. })
+		));
+	}
+
+	#[test]
+	fn bps_target_checksum() {
+		let mut actions = Vec::new();
+		number(&mut actions, 1);
+		actions.push(5);
+		let patch = bps(&[1], &[6], &actions);
+		assert!(matches!(
+			apply(&[1], &patch),
+			Err(PatchError::TargetChecksum { .. })
+		));
+	}
+
+	#[test]
+	fn bps_patch_checksum() {
+		let mut actions = Vec::new();
+		number(&mut actions, 0);
+		let mut patch = bps(&[1], &[1], &actions);
+		patch[4] ^= 1;
+		assert!(matches!(
+			apply(&[1], &patch),
+			Err(PatchError::PatchChecksum)
+		));
+	}
+
+	#[test]
+	fn bps_source_copy_out_of_bounds() {
+		let mut actions = Vec::new();
+		// SourceCopy of 1 byte from far past the end of the ROM
+		number(&mut actions, 2);
+		number(&mut actions, 1 << 24);
+		let patch = bps(&[1], &[1], &actions);
+		assert!(matches!(apply(&[1], &patch), Err(PatchError::OutOfBounds)));
+	}
+
+	#[test]
+	fn bps_target_size() {
+		let mut actions = Vec::new();
+		number(&mut actions, 0);
+		// The patch declares a 2-byte ROM, but only writes 1 byte
+		let mut patch = bps(&[1], &[1, 1], &actions);
+		let checksum = crc32(&[1]).to_le_bytes();
+		let footer = patch.len() - 8;
+		patch[footer..footer + 4].copy_from_slice(&checksum);
+		let length = patch.len() - 4;
+		let checksum = crc32(&patch[..length]).to_le_bytes();
+		patch[length..].copy_from_slice(&checksum);
+		assert!(matches!(
+			apply(&[1], &patch),
+			Err(PatchError::TargetSize { expected: 2 })
+		));
+	}
+
+	#[test]
+	fn bps_target_overrun() {
+		let mut actions = Vec::new();
+		// TargetRead of 1 byte, then a TargetCopy which would repeat it far past the declared size
+		number(&mut actions, 1);
+		actions.push(7);
+		number(&mut actions, (1 << 24) << 2 | 3);
+		number(&mut actions, 0);
+		let patch = bps(&[1], &[7], &actions);
+		assert!(matches!(
+			apply(&[1], &patch),
+			Err(PatchError::TargetSize { expected: 1 })
+		));
+	}
+}
@@ -1,5 +1,13 @@
-pub use crate::log::{Logger, SilenceLevel};
-pub use crate::memory::AddressSpace;
+pub use crate::condition::Condition;
+pub use crate::config::Config;
+pub use crate::convention::{Arg, CallingConvention};
+pub use crate::log::{Logger, OutputFormat, SilenceLevel};
+pub use crate::memory::{AddressSpace, Peek};
 pub use crate::registers::Registers;
-pub use crate::test::TestConfig;
-pub use crate::{cpu, open_rom, open_symfile, run_tests};
+pub use crate::reporter::Reporter;
+pub use crate::suite::{Filter, Shard, Suite};
+pub use crate::table::{Location, TruthTable};
+pub use crate::test::{Policy, TestConfig};
+pub use crate::{
+	cpu, open_cdb, open_map, open_rom, open_sym, open_symfile, open_test_roms, patch_rom, run_tests,
+};
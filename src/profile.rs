@@ -0,0 +1,185 @@
+//! Summaries of where tests spent their cycles.
+
+use crate::report::{Span, TestReport, TestStatus};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+/// The cycles spent in a subroutine, across every call made to it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FunctionCost {
+	/// The subroutine's symbol, or its address if there is none.
+	pub name: String,
+	pub calls: u64,
+	/// Cycles spent in the subroutine itself, excluding the subroutines it called.
+	pub self_cycles: u64,
+	/// Cycles spent in the subroutine and the subroutines it called.
+	/// Recursive calls are only counted once.
+	pub total_cycles: u64,
+}
+
+/// The cycles spent in each span, excluding the spans it called.
+fn self_cycles(spans: &[Span]) -> Vec<u64> {
+	let mut cycles = spans
+		.iter()
+		.map(|span| span.end - span.start)
+		.collect::<Vec<u64>>();
+	for span in spans {
+		if let Some(caller) = span.caller {
+			cycles[caller] -= span.end - span.start;
+		}
+	}
+	cycles
+}
+
+/// Adds up the cycles spent in each subroutine called by `reports`,
+/// sorted by the cycles spent in the subroutine itself, most first.
+pub fn summarize<'a>(reports: impl IntoIterator<Item = &'a TestReport>) -> Vec<FunctionCost> {
+	let mut costs = HashMap::<String, FunctionCost>::new();
+	for report in reports {
+		let spans = &report.profile;
+		let self_cycles = self_cycles(spans);
+		for (index, span) in spans.iter().enumerate() {
+			let name = span.name();
+			let duration = span.end - span.start;
+			// A call within another call to the same subroutine is already part of the outer call's total.
+			let mut caller = span.caller;
+			let mut recursive = false;
+			while let Some(index) = caller {
+				recursive |= spans[index].name() == name;
+				caller = spans[index].caller;
+			}
+
+			let cost = costs.entry(name.clone()).or_insert_with(|| FunctionCost {
+				name,
+				..FunctionCost::default()
+			});
+			cost.calls += 1;
+			cost.self_cycles += self_cycles[index];
+			if !recursive {
+				cost.total_cycles += duration;
+			}
+		}
+	}
+	let mut costs = costs.into_values().collect::<Vec<FunctionCost>>();
+	costs.sort_by(|a, b| {
+		b.self_cycles
+			.cmp(&a.self_cycles)
+			.then_with(|| a.name.cmp(&b.name))
+	});
+	costs
+}
+
+/// Writes `costs` as a table, with a row for each subroutine.
+///
+/// # Errors
+///
+/// Returns any error from writing to `out`.
+pub fn write_table(mut out: impl io::Write, costs: &[FunctionCost]) -> io::Result<()> {
+	let width = costs
+		.iter()
+		.map(|cost| cost.name.len())
+		.chain(["Subroutine".len()])
+		.max()
+		.unwrap_or_default();
+	writeln!(
+		out,
+		"{:<width$}  {:>8}  {:>12}  {:>12}",
+		"Subroutine", "Calls", "Self", "Total"
+	)?;
+	for cost in costs {
+		writeln!(
+			out,
+			"{:<width$}  {:>8}  {:>12}  {:>12}",
+			cost.name, cost.calls, cost.self_cycles, cost.total_cycles
+		)?;
+	}
+	Ok(())
+}
+
+/// The `count` tests which ran for the most cycles, most first.
+/// Skipped tests are left out.
+pub fn slowest<'a>(
+	reports: impl IntoIterator<Item = &'a TestReport>,
+	count: usize,
+) -> Vec<&'a TestReport> {
+	let mut reports = reports
+		.into_iter()
+		.filter(|report| report.status != TestStatus::Skipped)
+		.collect::<Vec<&TestReport>>();
+	reports.sort_by(|a, b| b.cycles.cmp(&a.cycles).then_with(|| b.time.cmp(&a.time)));
+	reports.truncate(count);
+	reports
+}
+
+/// Writes the cycles and wall-clock time of each of `reports` as a table.
+///
+/// # Errors
+///
+/// Returns any error from writing to `out`.
+pub fn write_timings(mut out: impl io::Write, reports: &[&TestReport]) -> io::Result<()> {
+	let width = reports
+		.iter()
+		.map(|report| report.name.len())
+		.chain(["Test".len()])
+		.max()
+		.unwrap_or_default();
+	writeln!(out, "{:<width$}  {:>12}  {:>12}", "Test", "Cycles", "Time")?;
+	for report in reports {
+		let time = format!("{:.2?}", report.time);
+		writeln!(
+			out,
+			"{:<width$}  {:>12}  {time:>12}",
+			report.name, report.cycles
+		)?;
+	}
+	Ok(())
+}
+
+/// Writes the subroutine calls made by `reports` in the callgrind format,
+/// which callgrind viewers can show with a breakdown of each subroutine's callers and callees.
+///
+/// `command` is shown as the program which was profiled, such as the path to the ROM.
+///
+/// # Errors
+///
+/// Returns any error from writing to `out`.
+pub fn write_callgrind<'a>(
+	mut out: impl io::Write,
+	command: &str,
+	reports: impl IntoIterator<Item = &'a TestReport>,
+) -> io::Result<()> {
+	// The self cost of each subroutine, and the number of calls and total cost of each of its callees.
+	let mut functions = BTreeMap::<String, (u64, BTreeMap<String, (u64, u64)>)>::new();
+	for report in reports {
+		let spans = &report.profile;
+		let self_cycles = self_cycles(spans);
+		for (index, span) in spans.iter().enumerate() {
+			let duration = span.end - span.start;
+			functions.entry(span.name()).or_default().0 += self_cycles[index];
+			if let Some(caller) = span.caller {
+				let callees = &mut functions.entry(spans[caller].name()).or_default().1;
+				let (calls, cost) = callees.entry(span.name()).or_default();
+				*calls += 1;
+				*cost += duration;
+			}
+		}
+	}
+
+	writeln!(out, "# callgrind format")?;
+	writeln!(out, "version: 1")?;
+	writeln!(out, "creator: evunit")?;
+	writeln!(out, "cmd: {command}")?;
+	writeln!(out, "events: Cycles")?;
+	for (name, (self_cycles, callees)) in &functions {
+		writeln!(out)?;
+		writeln!(out, "fn={name}")?;
+		writeln!(out, "0 {self_cycles}")?;
+		for (callee, (calls, cost)) in callees {
+			writeln!(out, "cfn={callee}")?;
+			writeln!(out, "calls={calls} 0")?;
+			writeln!(out, "0 {cost}")?;
+		}
+	}
+	Ok(())
+}
@@ -0,0 +1,243 @@
+//! Memory-mapped hardware registers and descriptions of their bit fields.
+
+use std::fmt;
+
+macro_rules! hw_regs {
+	($($reg:ident = $addr:literal => $name:literal,)+) => {
+		/// A memory-mapped hardware register, identified by its address.
+		///
+		/// New registers may be added in minor releases, so match on this enum with a wildcard arm.
+		#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+		#[non_exhaustive]
+		#[repr(u16)]
+		pub enum HwReg {
+			$($reg = $addr,)+
+		}
+
+		impl HwReg {
+			/// Every known register, in order of address.
+			pub const ALL: &'static [HwReg] = &[$(HwReg::$reg,)+];
+
+			/// The name of this register as used by `hardware.inc`, such as `rLCDC`.
+			#[must_use]
+			pub fn name(self) -> &'static str {
+				match self {
+					$(HwReg::$reg => $name,)+
+				}
+			}
+		}
+
+		impl fmt::Display for HwReg {
+			fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+				write!(f, "{}", self.name())
+			}
+		}
+
+		impl TryFrom<u16> for HwReg {
+			type Error = u16;
+
+			fn try_from(address: u16) -> Result<Self, Self::Error> {
+				match address {
+					$($addr => Ok(HwReg::$reg),)+
+					_ => Err(address),
+				}
+			}
+		}
+	};
+}
+
+impl HwReg {
+	/// Iterates over every known register, in order of address.
+	pub fn iter() -> impl Iterator<Item = HwReg> {
+		HwReg::ALL.iter().copied()
+	}
+
+	/// Finds the register with this name from `hardware.inc`, such as `rLCDC`.
+	#[must_use]
+	pub fn from_name(name: &str) -> Option<HwReg> {
+		HwReg::iter().find(|reg| reg.name() == name)
+	}
+
+	/// Returns the bit fields of this register, if it has a description in [`BIT_FIELDS`].
+	#[must_use]
+	pub fn fields(self) -> Option<&'static RegisterFields> {
+		BIT_FIELDS.iter().find(|register| register.reg == self)
+	}
+}
+
+hw_regs! {
+	P1 = 0xFF00 => "rP1",
+	Sb = 0xFF01 => "rSB",
+	Sc = 0xFF02 => "rSC",
+	Div = 0xFF04 => "rDIV",
+	Tima = 0xFF05 => "rTIMA",
+	Tma = 0xFF06 => "rTMA",
+	Tac = 0xFF07 => "rTAC",
+	If = 0xFF0F => "rIF",
+	Nr10 = 0xFF10 => "rNR10",
+	Nr11 = 0xFF11 => "rNR11",
+	Nr12 = 0xFF12 => "rNR12",
+	Nr13 = 0xFF13 => "rNR13",
+	Nr14 = 0xFF14 => "rNR14",
+	Nr21 = 0xFF16 => "rNR21",
+	Nr22 = 0xFF17 => "rNR22",
+	Nr23 = 0xFF18 => "rNR23",
+	Nr24 = 0xFF19 => "rNR24",
+	Nr30 = 0xFF1A => "rNR30",
+	Nr31 = 0xFF1B => "rNR31",
+	Nr32 = 0xFF1C => "rNR32",
+	Nr33 = 0xFF1D => "rNR33",
+	Nr34 = 0xFF1E => "rNR34",
+	Nr41 = 0xFF20 => "rNR41",
+	Nr42 = 0xFF21 => "rNR42",
+	Nr43 = 0xFF22 => "rNR43",
+	Nr44 = 0xFF23 => "rNR44",
+	Nr50 = 0xFF24 => "rNR50",
+	Nr51 = 0xFF25 => "rNR51",
+	Nr52 = 0xFF26 => "rNR52",
+	Lcdc = 0xFF40 => "rLCDC",
+	Stat = 0xFF41 => "rSTAT",
+	Scy = 0xFF42 => "rSCY",
+	Scx = 0xFF43 => "rSCX",
+	Ly = 0xFF44 => "rLY",
+	Lyc = 0xFF45 => "rLYC",
+	Dma = 0xFF46 => "rDMA",
+	Bgp = 0xFF47 => "rBGP",
+	Obp0 = 0xFF48 => "rOBP0",
+	Obp1 = 0xFF49 => "rOBP1",
+	Wy = 0xFF4A => "rWY",
+	Wx = 0xFF4B => "rWX",
+	Key0 = 0xFF4C => "rKEY0",
+	Key1 = 0xFF4D => "rKEY1",
+	Vbk = 0xFF4F => "rVBK",
+	Bank = 0xFF50 => "rBANK",
+	Hdma1 = 0xFF51 => "rHDMA1",
+	Hdma2 = 0xFF52 => "rHDMA2",
+	Hdma3 = 0xFF53 => "rHDMA3",
+	Hdma4 = 0xFF54 => "rHDMA4",
+	Hdma5 = 0xFF55 => "rHDMA5",
+	Rp = 0xFF56 => "rRP",
+	Bcps = 0xFF68 => "rBCPS",
+	Bcpd = 0xFF69 => "rBCPD",
+	Ocps = 0xFF6A => "rOCPS",
+	Ocpd = 0xFF6B => "rOCPD",
+	Opri = 0xFF6C => "rOPRI",
+	Svbk = 0xFF70 => "rSVBK",
+	Undocumented72 = 0xFF72 => "rFF72",
+	Undocumented73 = 0xFF73 => "rFF73",
+	Undocumented74 = 0xFF74 => "rFF74",
+	Undocumented75 = 0xFF75 => "rFF75",
+	Pcm12 = 0xFF76 => "rPCM12",
+	Pcm34 = 0xFF77 => "rPCM34",
+	Ie = 0xFFFF => "rIE",
+}
+
+/// A named group of bits within a hardware register.
+#[derive(Clone, Copy, Debug)]
+pub struct BitField {
+	pub name: &'static str,
+	/// Bits belonging to this field. Fields spanning several bits are shown as a number.
+	pub mask: u8,
+}
+
+/// Describes how the bits of a hardware register should be decoded.
+#[derive(Clone, Copy, Debug)]
+pub struct RegisterFields {
+	pub reg: HwReg,
+	/// Fields are listed from the most significant bit to the least.
+	pub fields: &'static [BitField],
+}
+
+macro_rules! fields {
+	($($name:literal = $mask:literal),+ $(,)?) => {
+		&[$(BitField { name: $name, mask: $mask }),+]
+	};
+}
+
+const INTERRUPT_FIELDS: &[BitField] = fields![
+	"JOYPAD" = 0x10,
+	"SERIAL" = 0x08,
+	"TIMER" = 0x04,
+	"STAT" = 0x02,
+	"VBLANK" = 0x01,
+];
+
+/// Registers which are worth decoding in diagnostics.
+pub const BIT_FIELDS: &[RegisterFields] = &[
+	RegisterFields {
+		reg: HwReg::Lcdc,
+		fields: fields![
+			"ON" = 0x80,
+			"WIN_MAP" = 0x40,
+			"WIN_ON" = 0x20,
+			"BG_TILES" = 0x10,
+			"BG_MAP" = 0x08,
+			"OBJ_16" = 0x04,
+			"OBJ_ON" = 0x02,
+			"BG_ON" = 0x01,
+		],
+	},
+	RegisterFields {
+		reg: HwReg::Stat,
+		fields: fields![
+			"LYC_INT" = 0x40,
+			"MODE2_INT" = 0x20,
+			"MODE1_INT" = 0x10,
+			"MODE0_INT" = 0x08,
+			"LYC_EQ" = 0x04,
+			"MODE" = 0x03,
+		],
+	},
+	RegisterFields {
+		reg: HwReg::Tac,
+		fields: fields!["ON" = 0x04, "CLOCK" = 0x03],
+	},
+	RegisterFields {
+		reg: HwReg::Nr52,
+		fields: fields![
+			"ON" = 0x80,
+			"CH4_ON" = 0x08,
+			"CH3_ON" = 0x04,
+			"CH2_ON" = 0x02,
+			"CH1_ON" = 0x01,
+		],
+	},
+	RegisterFields {
+		reg: HwReg::If,
+		fields: INTERRUPT_FIELDS,
+	},
+	RegisterFields {
+		reg: HwReg::Ie,
+		fields: INTERRUPT_FIELDS,
+	},
+	RegisterFields {
+		reg: HwReg::Key1,
+		fields: fields!["DOUBLE_SPEED" = 0x80, "PREPARE" = 0x01],
+	},
+];
+
+impl RegisterFields {
+	/// Formats `value` as a list of its fields.
+	/// Single-bit fields are only listed while set, wider fields are always listed with their value.
+	#[must_use]
+	pub fn decode(&self, value: u8) -> String {
+		self.fields
+			.iter()
+			.filter_map(|field| {
+				let bits = value & field.mask;
+				if !field.mask.is_power_of_two() {
+					Some(format!(
+						"{}={}",
+						field.name,
+						bits >> field.mask.trailing_zeros()
+					))
+				} else if bits != 0 {
+					Some(String::from(field.name))
+				} else {
+					None
+				}
+			})
+			.collect::<Vec<String>>()
+			.join(" ")
+	}
+}
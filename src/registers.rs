@@ -1,40 +1,390 @@
+use crate::charmap::{self, Charmap};
+use crate::expression::{Expression, Initial};
+use crate::memory::Peek;
+use crate::reg::HwReg;
+use crate::report::Mismatch;
+use crate::table::Location;
 use crate::{Error, Result};
 use gb_cpu_sim::{cpu, memory};
 use paste::paste;
+use serde::Deserialize;
+use std::borrow::Cow;
 use std::fmt;
+use std::mem;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
 
-#[derive(Clone, Debug)]
-enum CompareSource {
+/// Where a compared value was read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompareSource {
+	/// A register or flag, named as in the configuration file (such as `a`, `bc`, or `f.z`).
 	Register(&'static str),
 	Address(u16),
+	/// The addresses from `start` to `end`, inclusive.
+	Range {
+		start: u16,
+		end: u16,
+	},
+}
+
+impl CompareSource {
+	/// Describes the source, naming addresses with `name`.
+	/// Hardware registers are named along with their address, like `[rSTAT] (0xFF41)`.
+	pub fn describe(&self, name: impl Fn(u16) -> String) -> String {
+		match self {
+			CompareSource::Register(register) => String::from(*register),
+			CompareSource::Address(address) => match HwReg::try_from(*address) {
+				Ok(register) => format!("[{register}] (0x{address:04X})"),
+				Err(_) => format!("[{}]", name(*address)),
+			},
+			CompareSource::Range { start, end } => format!("[{}-{}]", name(*start), name(*end)),
+		}
+	}
 }
 
 impl fmt::Display for CompareSource {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let description = self.describe(|address| format!("{address:X}"));
+		write!(f, "{description}")
+	}
+}
+
+/// A value from a register, flag, or memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+	Byte(u8),
+	Word(u16),
+	Flag(bool),
+	/// A number decoded from several bytes of memory, shown in decimal.
+	Integer(u64),
+	/// A string decoded from memory.
+	Text(String),
+	/// Bytes of memory which could not be decoded.
+	Bytes(Vec<u8>),
+	/// A byte of which only the bits in `mask` are known, shown in binary with `x` for the others.
+	Masked {
+		value: u8,
+		mask: u8,
+	},
+	/// Any value from `min` to `max`, inclusive.
+	Between {
+		min: Box<Value>,
+		max: Box<Value>,
+	},
+}
+
+impl fmt::Display for Value {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Value::Byte(value) => write!(f, "0x{value:02X}"),
+			Value::Word(value) => write!(f, "0x{value:04X}"),
+			Value::Flag(value) => write!(f, "{value}"),
+			Value::Integer(value) => write!(f, "{value}"),
+			Value::Text(value) => write!(f, "\"{value}\""),
+			Value::Bytes(bytes) => {
+				// Long runs of bytes are cut short, since they are only useful at a glance.
+				const SHOWN: usize = 16;
+				let mut shown = bytes
+					.iter()
+					.take(SHOWN)
+					.map(|byte| format!("0x{byte:02X}"))
+					.collect::<Vec<String>>();
+				if bytes.len() > SHOWN {
+					shown.push(format!("... {} bytes in total", bytes.len()));
+				}
+				write!(f, "[{}]", shown.join(", "))
+			}
+			Value::Masked { value, mask } => {
+				write!(f, "0b")?;
+				for bit in (0..8).rev() {
+					match (mask >> bit & 1, value >> bit & 1) {
+						(0, _) => write!(f, "x")?,
+						(_, bit) => write!(f, "{bit}")?,
+					}
+				}
+				Ok(())
+			}
+			Value::Between { min, max } => write!(f, "between {min} and {max}"),
+		}
+	}
+}
+
+/// A value in memory which is compared as a whole, rather than as individual bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Typed {
+	/// A byte of which only the bits in `mask` are compared.
+	Masked { value: u8, mask: u8 },
+	/// A 16-bit integer, stored with its low byte first.
+	U16Le(u16),
+	/// A 16-bit integer, stored with its high byte first.
+	U16Be(u16),
+	/// A binary-coded decimal number, with two digits in each of `size` bytes.
+	Bcd {
+		value: u64,
+		size: usize,
+		/// Whether the least significant digits are stored first.
+		little_endian: bool,
+	},
+	/// A block of bytes, such as the contents of a file.
+	/// Differences are reported for each range of addresses which does not match, rather than each byte.
+	Block(Vec<u8>),
+	/// A string, optionally followed by a terminating byte.
+	String {
+		text: String,
+		terminator: Option<u8>,
+		/// How the text is stored. `None` stores it as ASCII.
+		charmap: Option<Arc<Charmap>>,
+	},
+}
+
+impl Typed {
+	/// The bytes which represent this value in memory.
+	#[must_use]
+	pub fn encode(&self) -> Vec<u8> {
+		match self {
+			Typed::Masked { value, .. } => vec![*value],
+			Typed::Block(bytes) => bytes.clone(),
+			Typed::U16Le(value) => value.to_le_bytes().to_vec(),
+			Typed::U16Be(value) => value.to_be_bytes().to_vec(),
+			Typed::Bcd {
+				value,
+				size,
+				little_endian,
+			} => {
+				let mut remaining = *value;
+				let mut bytes = (0..*size)
+					.map(|_| {
+						let digits = remaining % 100;
+						remaining /= 100;
+						u8::try_from(((digits / 10) << 4) | (digits % 10)).unwrap_or_default()
+					})
+					.collect::<Vec<u8>>();
+				if !little_endian {
+					bytes.reverse();
+				}
+				bytes
+			}
+			Typed::String {
+				text,
+				terminator,
+				charmap,
+			} => {
+				// Text is checked against the charmap when it is read, so it always encodes.
+				let bytes = match charmap {
+					Some(charmap) => charmap.encode(text).unwrap_or_default(),
+					None => text.bytes().collect(),
+				};
+				bytes.into_iter().chain(*terminator).collect::<Vec<u8>>()
+			}
+		}
+	}
+
+	/// Returns true if `bytes`, read from memory, match this value.
+	#[must_use]
+	pub fn matches(&self, bytes: &[u8]) -> bool {
+		match self {
+			Typed::Masked { value, mask } => bytes
+				.first()
+				.is_some_and(|byte| byte & mask == value & mask),
+			_ => bytes == self.encode(),
+		}
+	}
+
+	/// This value, for display.
+	#[must_use]
+	pub fn expected(&self) -> Value {
+		match self {
+			Typed::Masked { value, mask } => Value::Masked {
+				value: *value,
+				mask: *mask,
+			},
+			_ => self.decode(&self.encode()),
+		}
+	}
+
+	/// Interprets `bytes` as the same type as this value, for display.
+	#[must_use]
+	pub fn decode(&self, bytes: &[u8]) -> Value {
+		// Values which run past the end of the address space are cut short.
+		if bytes.len() != self.encode().len() {
+			return Value::Bytes(bytes.to_vec());
+		}
 		match self {
-			CompareSource::Register(name) => write!(f, "{name}"),
-			CompareSource::Address(address) => write!(f, "[{address:X}]"),
+			Typed::Masked { .. } => Value::Byte(bytes[0]),
+			Typed::Block(_) => Value::Bytes(bytes.to_vec()),
+			Typed::U16Le(_) => Value::Integer(u64::from(u16::from_le_bytes([bytes[0], bytes[1]]))),
+			Typed::U16Be(_) => Value::Integer(u64::from(u16::from_be_bytes([bytes[0], bytes[1]]))),
+			Typed::Bcd { little_endian, .. } => {
+				let mut bytes = bytes.to_vec();
+				if *little_endian {
+					bytes.reverse();
+				}
+				if bytes.iter().any(|byte| byte >> 4 > 9 || byte & 0xF > 9) {
+					return Value::Bytes(bytes);
+				}
+				Value::Integer(bytes.iter().fold(0, |value, byte| {
+					value * 100 + u64::from(byte >> 4) * 10 + u64::from(byte & 0xF)
+				}))
+			}
+			Typed::String {
+				terminator,
+				charmap,
+				..
+			} => {
+				// A matching terminator is implied by the expected value, so it is left out.
+				let bytes = match (bytes.split_last(), terminator) {
+					(Some((last, text)), Some(terminator)) if last == terminator => text,
+					_ => bytes,
+				};
+				Value::Text(match charmap {
+					Some(charmap) => charmap.decode(bytes),
+					None => bytes.iter().copied().map(charmap::escape).collect(),
+				})
+			}
+		}
+	}
+}
+
+/// A register or memory location which did not match its expected value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Difference {
+	pub source: CompareSource,
+	pub actual: Value,
+	pub expected: Value,
+}
+
+impl Difference {
+	/// The first address and expected bytes of a difference in memory which was compared byte by byte,
+	/// rather than decoded as a number or string.
+	#[must_use]
+	pub fn expected_bytes(&self) -> Option<(u16, Vec<u8>)> {
+		let address = match self.source {
+			CompareSource::Address(address) | CompareSource::Range { start: address, .. } => {
+				address
+			}
+			CompareSource::Register(_) => return None,
+		};
+		match &self.expected {
+			Value::Byte(value) => Some((address, vec![*value])),
+			Value::Bytes(values) => Some((address, values.clone())),
+			_ => None,
 		}
 	}
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct CompareResult {
-	contents: Vec<(CompareSource, String, String)>,
+	differences: Vec<Difference>,
+	/// Messages from each custom condition which failed.
+	conditions: Vec<String>,
+}
+
+impl CompareResult {
+	#[must_use]
+	pub fn is_empty(&self) -> bool {
+		self.differences.is_empty() && self.conditions.is_empty()
+	}
+
+	/// Each register and memory location which did not match, in the order they were checked.
+	#[must_use]
+	pub fn differences(&self) -> &[Difference] {
+		&self.differences
+	}
+
+	/// Messages from each custom condition which failed.
+	#[must_use]
+	pub fn conditions(&self) -> &[String] {
+		&self.conditions
+	}
+
+	/// Adds a difference for each run of consecutive bytes in `actual` which don't match `expected`.
+	fn push_block(&mut self, address: u16, actual: &[u8], expected: &[u8]) {
+		let mut offset = 0;
+		while offset < actual.len() {
+			if actual[offset] == expected[offset] {
+				offset += 1;
+				continue;
+			}
+			let start = offset;
+			while offset < actual.len() && actual[offset] != expected[offset] {
+				offset += 1;
+			}
+			// `actual` never extends past the end of the address space.
+			let address =
+				|offset: usize| address.wrapping_add(u16::try_from(offset).unwrap_or_default());
+			let source = if offset - start == 1 {
+				CompareSource::Address(address(start))
+			} else {
+				CompareSource::Range {
+					start: address(start),
+					end: address(offset - 1),
+				}
+			};
+			self.differences.push(Difference {
+				source,
+				actual: Value::Bytes(actual[start..offset].to_vec()),
+				expected: Value::Bytes(expected[start..offset].to_vec()),
+			});
+		}
+	}
+
+	pub(crate) fn push_condition(&mut self, message: String) {
+		self.conditions.push(message);
+	}
+
+	/// Each difference as strings, naming addresses with `name`.
+	pub(crate) fn mismatches<'a>(
+		&'a self,
+		name: impl Fn(u16) -> String + 'a,
+	) -> impl Iterator<Item = Mismatch> + 'a {
+		self.differences.iter().map(move |difference| Mismatch {
+			source: difference.source.describe(&name),
+			actual: difference.actual.to_string(),
+			expected: difference.expected.to_string(),
+		})
+	}
+}
+
+impl fmt::Display for Difference {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let Difference {
+			source,
+			actual,
+			expected,
+		} = self;
+		let mismatch = Mismatch {
+			source: source.to_string(),
+			actual: actual.to_string(),
+			expected: expected.to_string(),
+		};
+		write!(f, "{mismatch}")
+	}
 }
 
 impl fmt::Display for CompareResult {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		for (source, result, expected) in &self.contents {
-			writeln!(
-				f,
-				"{source} ({result}) does not match expected value ({expected})",
-			)?;
+		for difference in &self.differences {
+			writeln!(f, "{difference}")?;
+		}
+		for message in &self.conditions {
+			writeln!(f, "{message}")?;
 		}
 		Ok(())
 	}
 }
 
+/// A Game Boy model, which determines the values of the registers after the boot ROM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Model {
+	Dmg,
+	/// Game Boy Pocket
+	Mgb,
+	Sgb,
+	/// Game Boy Color, running a CGB-compatible game.
+	Cgb,
+}
+
 // All of these parameters are optional. This is because the initial values as
 // well as the resulting values do not all need to be present, and in the case
 // of results, may even be unknown.
@@ -47,12 +397,18 @@ pub struct Registers {
 	pub e: Option<u8>,
 	pub h: Option<u8>,
 	pub l: Option<u8>,
+	/// All four flags at once, for configs which give F as a byte. The lower 4 bits are ignored.
+	pub f: Option<u8>,
 	// f is decomposed into 4 bools to test them independantly.
 	pub zf: Option<bool>,
 	pub nf: Option<bool>,
 	pub hf: Option<bool>,
 	pub cf: Option<bool>,
+	/// Whether interrupts are enabled, as by `ei` and `di`.
+	pub ime: Option<bool>,
 	// TODO: These 16-bit registers make sense in the config file, but should they be part of this struct?
+	/// `a` and the flags. The lower 4 bits are ignored, like those of `f`.
+	pub af: Option<u16>,
 	pub bc: Option<u16>,
 	pub de: Option<u16>,
 	pub hl: Option<u16>,
@@ -64,6 +420,30 @@ pub struct Registers {
 	// For very very large configs this may have a higher memory usage.
 	// If this becomes a problem, consider moving AddressSpace here.
 	pub memory: Vec<(u16, u8)>,
+	/// Values spanning several bytes, which are written and compared as a whole.
+	pub typed_memory: Vec<(u16, Typed)>,
+	/// Bytes starting at the final `sp`, lowest address first. Only used when comparing.
+	pub stack: Vec<u8>,
+	/// Ranges which registers or bytes of memory must be within. Only used when comparing.
+	pub ranges: Vec<(Location, RangeInclusive<u16>)>,
+	/// Values computed from the state the test began in. Only used when comparing; see [`Registers::evaluate`].
+	pub expressions: Vec<(Location, Expression)>,
+}
+
+/// Reads the flags as the upper 4 bits of a byte, as they would be pushed by `push af`.
+pub(crate) fn flags_byte<S: memory::AddressSpace>(cpu: &cpu::State<S>) -> u8 {
+	[cpu.f.get_z(), cpu.f.get_n(), cpu.f.get_h(), cpu.f.get_c()]
+		.into_iter()
+		.fold(0, |f, flag| (f << 1) | u8::from(flag))
+		<< 4
+}
+
+/// Sets the flags from the upper 4 bits of a byte, as they would be popped by `pop af`.
+fn set_flags<S: memory::AddressSpace>(cpu: &mut cpu::State<S>, f: u8) {
+	cpu.f.set_z(f & 0x80 != 0);
+	cpu.f.set_n(f & 0x40 != 0);
+	cpu.f.set_h(f & 0x20 != 0);
+	cpu.f.set_c(f & 0x10 != 0);
 }
 
 macro_rules! impl_with {
@@ -107,13 +487,28 @@ impl Registers {
 			($($($i:ident)+),+) => { $( optional_set!($($i)+); )+ };
 		}
 
+		// `a` and the flags are more specific than `af`, so they are set afterwards.
+		if let Some(af) = self.af {
+			let [a, f] = af.to_be_bytes();
+			cpu.a = a;
+			set_flags(cpu, f);
+		}
 		optional_set!(a, b, c, d, e, h, l);
+		if let Some(f) = self.f {
+			set_flags(cpu, f);
+		}
 		optional_set!(f z, f n, f h, f c);
 		optional_set!(set bc, set de, set hl, pc, sp);
+		optional_set!(ime);
 
 		for (addr, value) in &self.memory {
 			cpu.address_space.write(*addr, *value);
 		}
+		for (addr, value) in &self.typed_memory {
+			for (addr, byte) in (*addr..=u16::MAX).zip(value.encode()) {
+				cpu.address_space.write(addr, byte);
+			}
+		}
 	}
 
 	/// Compares this set of registers to the CPU, returning an error if they do not match.
@@ -122,52 +517,225 @@ impl Registers {
 	///
 	/// Returns an error if the CPU's state does not match `self`
 	/// The error message contains a list of the values that did not match.
-	pub fn compare<S: memory::AddressSpace>(&self, cpu: &cpu::State<S>) -> Result<()> {
+	pub fn compare<S: Peek>(&self, cpu: &cpu::State<S>) -> Result<()> {
+		let errors = self.mismatches(cpu);
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(Error::CompareFailed(errors))
+		}
+	}
+
+	/// Replaces each of `expressions` with its value, computed from `initial`.
+	#[must_use]
+	pub fn evaluate(&self, initial: &Initial) -> Cow<'_, Self> {
+		if self.expressions.is_empty() {
+			return Cow::Borrowed(self);
+		}
+		let mut evaluated = self.clone();
+		for (location, expression) in mem::take(&mut evaluated.expressions) {
+			let value = expression.evaluate(initial);
+			// Arithmetic wraps around at the width of the location, like the CPU's.
+			let value = if location.is_word() {
+				value
+			} else {
+				value & 0xFF
+			};
+			location.set(&mut evaluated, value);
+		}
+		Cow::Owned(evaluated)
+	}
+
+	/// The registers and memory which `expressions` read from the initial state.
+	#[must_use]
+	pub fn initial_locations(&self) -> Vec<Location> {
+		let mut locations = Vec::new();
+		for (_, expression) in &self.expressions {
+			expression.locations(&mut locations);
+		}
+		locations
+	}
+
+	/// Lists the values of the CPU's state which do not match `self`.
+	#[must_use]
+	pub fn mismatches<S: Peek>(&self, cpu: &cpu::State<S>) -> CompareResult {
 		let mut errors = CompareResult::default();
 
 		macro_rules! check {
-			(impl $format:literal, $cfg:ident, $name:expr, $cpu:expr) => {
+			(impl $kind:ident, $cfg:ident, $name:expr, $cpu:expr) => {
 				if let Some(value) = self.$cfg {
 					if $cpu != value {
-						errors.contents.push((
-							CompareSource::Register(stringify!($name)),
-							format!($format, $cpu),
-							format!($format, value)
-						))
+						errors.differences.push(Difference {
+							source: CompareSource::Register(stringify!($name)),
+							actual: Value::$kind($cpu),
+							expected: Value::$kind(value),
+						})
 					}
 				}
 			};
-			($format:literal, $reg:ident) => {
-				check!(impl $format, $reg, $reg, cpu.$reg)
+			($kind:ident, $reg:ident) => {
+				check!(impl $kind, $reg, $reg, cpu.$reg)
 			};
-			($format:literal, get $reg:ident) => {
-				paste! { check!(impl $format, $reg, $reg, cpu.[<get_ $reg>]()) }
+			($kind:ident, get $reg:ident) => {
+				paste! { check!(impl $kind, $reg, $reg, cpu.[<get_ $reg>]()) }
 			};
-			($format:literal, f $flag:ident) => {
-				paste! { check!(impl $format, [<$flag f>], f.$flag, cpu.f.[<get_ $flag>]()) }
+			($kind:ident, f $flag:ident) => {
+				paste! { check!(impl $kind, [<$flag f>], f.$flag, cpu.f.[<get_ $flag>]()) }
 			};
-			($format:literal, $($($i:ident)+),+) => { $( check!($format, $($i)+); )+ };
+			($kind:ident, $($($i:ident)+),+) => { $( check!($kind, $($i)+); )+ };
+		}
+		check!(Byte, a, b, c, d, e, h, l);
+		if let Some(af) = self.af {
+			let actual = u16::from_be_bytes([cpu.a, flags_byte(cpu)]);
+			if actual != af & 0xFFF0 {
+				errors.differences.push(Difference {
+					source: CompareSource::Register("af"),
+					actual: Value::Word(actual),
+					expected: Value::Word(af & 0xFFF0),
+				});
+			}
 		}
-		check!("0x{:02X}", a, b, c, d, e, h, l);
-		check!("{}", f z, f n, f h, f c);
-		check!("0x{:04X}", get bc, get de, get hl, sp, pc);
+		if let Some(f) = self.f {
+			let actual = flags_byte(cpu);
+			if actual != f & 0xF0 {
+				errors.differences.push(Difference {
+					source: CompareSource::Register("f"),
+					actual: Value::Byte(actual),
+					expected: Value::Byte(f & 0xF0),
+				});
+			}
+		}
+		check!(Flag, f z, f n, f h, f c);
+		check!(Word, get bc, get de, get hl, sp, pc);
+		check!(Flag, ime);
+
+		self.compare_memory(cpu, &mut errors);
+
+		for (location, range) in &self.ranges {
+			let actual = location.read(cpu);
+			let in_range = match actual {
+				Value::Byte(value) => range.contains(&u16::from(value)),
+				Value::Word(value) => range.contains(&value),
+				Value::Flag(value) => range.contains(&u16::from(value)),
+				_ => false,
+			};
+			if !in_range {
+				let bound = |value: u16| {
+					Box::new(if location.is_word() {
+						Value::Word(value)
+					} else {
+						Value::Byte(value.to_le_bytes()[0])
+					})
+				};
+				errors.differences.push(Difference {
+					source: location.source(),
+					actual,
+					expected: Value::Between {
+						min: bound(*range.start()),
+						max: bound(*range.end()),
+					},
+				});
+			}
+		}
+
+		errors
+	}
 
+	/// Adds the bytes of memory and the stack which do not match `self` to `errors`.
+	fn compare_memory<S: Peek>(&self, cpu: &cpu::State<S>, errors: &mut CompareResult) {
 		for (addr, value) in &self.memory {
-			let result = cpu.address_space.read(*addr);
+			let result = cpu.address_space.peek(*addr);
 			if result != *value {
-				errors.contents.push((
-					CompareSource::Address(*addr),
-					result.to_string(),
-					value.to_string(),
-				));
+				errors.differences.push(Difference {
+					source: CompareSource::Address(*addr),
+					actual: Value::Byte(result),
+					expected: Value::Byte(*value),
+				});
 			}
 		}
 
-		if errors.contents.is_empty() {
-			Ok(())
-		} else {
-			Err(Error::CompareFailed(errors))
+		for (addr, value) in &self.typed_memory {
+			let actual = (*addr..=u16::MAX)
+				.zip(value.encode())
+				.map(|(addr, _)| cpu.address_space.peek(addr))
+				.collect::<Vec<u8>>();
+			if let Typed::Block(expected) = value {
+				errors.push_block(*addr, &actual, expected);
+			} else if !value.matches(&actual) {
+				errors.differences.push(Difference {
+					source: CompareSource::Address(*addr),
+					actual: value.decode(&actual),
+					expected: value.expected(),
+				});
+			}
 		}
+
+		if !self.stack.is_empty() {
+			let actual = (cpu.sp..=u16::MAX)
+				.zip(&self.stack)
+				.map(|(addr, _)| cpu.address_space.peek(addr))
+				.collect::<Vec<u8>>();
+			if actual != self.stack {
+				errors.differences.push(Difference {
+					source: CompareSource::Register("stack"),
+					actual: Value::Bytes(actual),
+					expected: Value::Bytes(self.stack.clone()),
+				});
+			}
+		}
+	}
+
+	/// Records every register of the CPU. Memory is not included.
+	#[must_use]
+	pub fn capture<S: memory::AddressSpace>(cpu: &cpu::State<S>) -> Self {
+		Self::new()
+			.with_a(cpu.a)
+			.with_b(cpu.b)
+			.with_c(cpu.c)
+			.with_d(cpu.d)
+			.with_e(cpu.e)
+			.with_h(cpu.h)
+			.with_l(cpu.l)
+			.with_zf(cpu.f.get_z())
+			.with_nf(cpu.f.get_n())
+			.with_hf(cpu.f.get_h())
+			.with_cf(cpu.f.get_c())
+			.with_ime(cpu.ime)
+			.with_af(u16::from_be_bytes([cpu.a, flags_byte(cpu)]))
+			.with_bc(cpu.get_bc())
+			.with_de(cpu.get_de())
+			.with_hl(cpu.get_hl())
+			.with_pc(cpu.pc)
+			.with_sp(cpu.sp)
+	}
+
+	/// Creates a set of registers holding the values left by `model`'s boot ROM.
+	#[must_use]
+	pub fn preset(model: Model) -> Self {
+		Self::new().with_preset(model)
+	}
+
+	/// Sets `a`, `f`, `bc`, `de`, and `hl` to the values left by `model`'s boot ROM,
+	/// and `pc` and `sp` to where it leaves them: the entry point at 0x100, with the stack at 0xFFFE.
+	#[must_use]
+	pub fn with_preset(self, model: Model) -> Self {
+		// In order: a, f, b, c, d, e, h, l
+		let registers = match model {
+			Model::Dmg => [0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+			Model::Mgb => [0xFF, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+			Model::Sgb => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+			Model::Cgb => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+		};
+		self.with_a(registers[0])
+			.with_f(registers[1])
+			.with_b(registers[2])
+			.with_c(registers[3])
+			.with_d(registers[4])
+			.with_e(registers[5])
+			.with_h(registers[6])
+			.with_l(registers[7])
+			.with_pc(0x100)
+			.with_sp(0xFFFE)
 	}
 
 	#[must_use]
@@ -180,16 +748,23 @@ impl Registers {
 			e: None,
 			h: None,
 			l: None,
+			f: None,
 			zf: None,
 			nf: None,
 			hf: None,
 			cf: None,
+			ime: None,
+			af: None,
 			bc: None,
 			de: None,
 			hl: None,
 			pc: None,
 			sp: None,
 			memory: Vec::new(),
+			typed_memory: Vec::new(),
+			stack: Vec::new(),
+			ranges: Vec::new(),
+			expressions: Vec::new(),
 		}
 	}
 
@@ -200,10 +775,13 @@ impl Registers {
 	impl_with!(e: u8);
 	impl_with!(h: u8);
 	impl_with!(l: u8);
+	impl_with!(f: u8);
 	impl_with!(zf: bool);
 	impl_with!(nf: bool);
 	impl_with!(hf: bool);
 	impl_with!(cf: bool);
+	impl_with!(ime: bool);
+	impl_with!(af: u16);
 	impl_with!(bc: u16);
 	impl_with!(de: u16);
 	impl_with!(hl: u16);
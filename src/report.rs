@@ -0,0 +1,286 @@
+//! Structured test results, for consumption by other tools.
+
+use crate::memory::Access;
+use crate::test::FailureReason;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TestStatus {
+	Passed,
+	Failed,
+	Skipped,
+	/// The test failed, as it was expected to. Counted as a pass.
+	ExpectedFailure,
+}
+
+/// A register or memory location which did not match its expected value.
+#[derive(Clone, Debug, Serialize)]
+pub struct Mismatch {
+	/// Register name or memory address.
+	pub source: String,
+	pub actual: String,
+	pub expected: String,
+}
+
+impl fmt::Display for Mismatch {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let Mismatch {
+			source,
+			actual,
+			expected,
+		} = self;
+		write!(
+			f,
+			"{source} ({actual}) does not match expected value ({expected})"
+		)
+	}
+}
+
+/// 16 bytes of memory around a failed comparison.
+#[derive(Clone, Debug, Serialize)]
+pub struct MemoryRow {
+	pub address: u16,
+	/// The symbol `address` falls under, if a symfile was given.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub label: Option<String>,
+	pub actual: Vec<u8>,
+	/// The expected value of each byte which did not match, or `None` for those which did or weren't checked.
+	pub expected: Vec<Option<u8>>,
+}
+
+impl fmt::Display for MemoryRow {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let actual = self
+			.actual
+			.iter()
+			.map(|value| format!("{value:02X}"))
+			.collect::<Vec<_>>()
+			.join(" ");
+		let expected = self
+			.expected
+			.iter()
+			.map(|value| value.map_or_else(|| String::from(".."), |value| format!("{value:02X}")))
+			.collect::<Vec<_>>()
+			.join(" ");
+		write!(f, "{:04X}: {actual}", self.address)?;
+		if let Some(label) = &self.label {
+			write!(f, "  {label}")?;
+		}
+		write!(f, "\n      {expected}")
+	}
+}
+
+/// The outcome of a single test.
+#[derive(Clone, Debug, Serialize)]
+pub struct TestReport {
+	pub name: String,
+	pub status: TestStatus,
+	/// Why the test failed, if it did, including when it was expected to.
+	pub reason: Option<FailureReason>,
+	/// The reason in words, with addresses named after symbols when a symfile was given.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub description: Option<String>,
+	/// Only populated when the test ran to completion but its result was incorrect.
+	pub mismatches: Vec<Mismatch>,
+	/// Messages from custom conditions which failed.
+	pub conditions: Vec<String>,
+	/// Each line of 16 bytes containing memory which did not match, as a hexdump.
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub memory: Vec<MemoryRow>,
+	/// Number of cycles the test ran for.
+	pub cycles: u64,
+	/// Number of instructions the test executed.
+	pub instructions: u64,
+	/// How long the test took to run, in wall-clock time. Serialized as seconds.
+	#[serde(serialize_with = "seconds")]
+	pub time: Duration,
+	/// The last instructions executed before the test crashed, hit an invalid opcode, or timed out.
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub history: Vec<Executed>,
+	/// Each subroutine the test called, in the order they were called, if it was profiled.
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub profile: Vec<Span>,
+	/// Every instruction the test executed, if it was traced and failed.
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub trace: Vec<Step>,
+	/// Every read and write of the test's watched addresses, in order.
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub watched: Vec<Access>,
+	/// How many times the test read and wrote each address, if its accesses were counted.
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub heat_map: Vec<Heat>,
+	/// Debug messages printed by the test with `ld d, d`.
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub messages: Vec<String>,
+	/// Non-fatal problems found while running the test, such as accessing echo RAM.
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub warnings: Vec<String>,
+	/// The seed RAM was filled from, if it was filled with random bytes.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub ram_seed: Option<u64>,
+	/// Why the test was skipped, if it was and a reason was given.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub skip_reason: Option<String>,
+}
+
+pub(crate) fn seconds<S: Serializer>(time: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+	serializer.serialize_f64(time.as_secs_f64())
+}
+
+/// Serializes tests as just their name, cycles, and time, for listing the slowest tests without repeating their reports.
+pub(crate) fn timings<S: Serializer>(
+	reports: &[&TestReport],
+	serializer: S,
+) -> Result<S::Ok, S::Error> {
+	#[derive(Serialize)]
+	struct Timing<'a> {
+		name: &'a str,
+		cycles: u64,
+		#[serde(serialize_with = "seconds")]
+		time: Duration,
+	}
+
+	serializer.collect_seq(reports.iter().map(|report| Timing {
+		name: &report.name,
+		cycles: report.cycles,
+		time: report.time,
+	}))
+}
+
+/// The address of an instruction executed by a test, and the symbol it falls under.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Executed {
+	pub pc: u16,
+	/// The nearest symbol at or before `pc`, such as `Main+3`, if a symfile was given.
+	pub label: Option<String>,
+}
+
+impl fmt::Display for Executed {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "0x{:04x}", self.pc)?;
+		if let Some(label) = &self.label {
+			write!(f, " ({label})")?;
+		}
+		Ok(())
+	}
+}
+
+/// A call to a subroutine, and the cycles during which it ran.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Span {
+	pub address: u16,
+	/// The ROM bank mapped to 0x4000-0x7FFF when the subroutine was called.
+	pub bank: usize,
+	/// The symbol at `address`, if a symfile was given.
+	pub label: Option<String>,
+	/// Cycles elapsed since the test began when the subroutine was called.
+	pub start: u64,
+	/// Cycles elapsed since the test began when the subroutine returned, or the test ended.
+	pub end: u64,
+	/// The index of the span which called this one, or `None` for the test itself.
+	pub caller: Option<usize>,
+}
+
+impl Span {
+	/// The subroutine's symbol, or its address if there is none.
+	#[must_use]
+	pub fn name(&self) -> String {
+		self.label
+			.clone()
+			.unwrap_or_else(|| format!("0x{:04x}", self.address))
+	}
+}
+
+/// How many times a test read and wrote an address.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Heat {
+	pub address: u16,
+	/// The nearest symbol at or before `address`, such as `wBuffer+3`, if a symfile was given.
+	pub label: Option<String>,
+	pub reads: u64,
+	pub writes: u64,
+}
+
+/// An instruction executed by a test, along with the registers before it ran.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct Step {
+	pub pc: u16,
+	/// The opcode at `pc`, followed by the next three bytes.
+	pub memory: [u8; 4],
+	pub af: u16,
+	pub bc: u16,
+	pub de: u16,
+	pub hl: u16,
+	pub sp: u16,
+}
+
+impl fmt::Display for Step {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let Step {
+			pc,
+			memory,
+			af,
+			bc,
+			de,
+			hl,
+			sp,
+		} = self;
+		let opcode = memory[0];
+		write!(
+			f,
+			"0x{pc:04x}: {opcode:02x}  af={af:04x} bc={bc:04x} de={de:04x} hl={hl:04x} sp={sp:04x}"
+		)
+	}
+}
+
+/// How each line of a trace is written.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TraceFormat {
+	/// The address and opcode of each instruction, followed by the register pairs.
+	#[default]
+	Text,
+	/// The log format read by gameboy-doctor, for comparing traces with other emulators.
+	Doctor,
+}
+
+impl TraceFormat {
+	#[must_use]
+	pub fn format(self, step: &Step) -> String {
+		match self {
+			TraceFormat::Text => step.to_string(),
+			TraceFormat::Doctor => DoctorLine(step).to_string(),
+		}
+	}
+}
+
+/// A [`Step`] in the log format read by gameboy-doctor.
+struct DoctorLine<'a>(&'a Step);
+
+impl fmt::Display for DoctorLine<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let Step {
+			pc,
+			memory,
+			af,
+			bc,
+			de,
+			hl,
+			sp,
+		} = self.0;
+		let registers = [*af, *bc, *de, *hl]
+			.into_iter()
+			.flat_map(u16::to_be_bytes)
+			.zip(["A", "F", "B", "C", "D", "E", "H", "L"]);
+		for (value, name) in registers {
+			write!(f, "{name}:{value:02X} ")?;
+		}
+		let [m0, m1, m2, m3] = memory;
+		write!(
+			f,
+			"SP:{sp:04X} PC:{pc:04X} PCMEM:{m0:02X},{m1:02X},{m2:02X},{m3:02X}"
+		)
+	}
+}
@@ -0,0 +1,567 @@
+//! Destinations for test results, such as the console or a JSON report.
+
+use crate::disasm::Instruction;
+use crate::log::SilenceLevel;
+use crate::profile;
+use crate::report::{self, TestReport, TestStatus, TraceFormat};
+use crate::Error;
+use owo_colors::OwoColorize;
+use serde::Serialize;
+use std::time::Duration;
+use std::{fmt, io};
+
+/// Receives results from a [`crate::log::Logger`] as tests run.
+///
+/// Only passing and failing tests must be handled; every other event is ignored by default.
+pub trait Reporter {
+	fn test_started(&mut self, _test: &str) {}
+	/// Called when a test with breakpoints enabled reaches `ld b, b` or `ld d, d`.
+	fn breakpoint(&mut self, _details: &BreakpointDetails<'_>) {}
+	/// Called when a test prints a debug message with `ld d, d`.
+	fn message(&mut self, _test: &str, _message: &str) {}
+	fn warning(&mut self, _test: &str, _message: &str) {}
+	fn test_passed(&mut self, report: &TestReport);
+	fn test_failed(&mut self, details: &FailureDetails<'_>);
+	fn test_skipped(&mut self, _report: &TestReport) {}
+	/// Called once all tests are complete.
+	fn finished(&mut self, _summary: &Summary<'_>) {}
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BreakpointKind {
+	/// `ld b, b`
+	Breakpoint,
+	/// `ld d, d`
+	Debug,
+}
+
+pub struct BreakpointDetails<'a> {
+	pub test: &'a str,
+	pub kind: BreakpointKind,
+	pub pc: u16,
+	/// The symbol `pc` is under, such as `Main+3`, if a symfile was given.
+	pub location: Option<String>,
+	/// The state of the CPU, formatted for display.
+	pub state: &'a dyn fmt::Display,
+}
+
+pub struct FailureDetails<'a> {
+	pub report: &'a TestReport,
+	/// The comparison which failed, if the test ran to completion.
+	pub error: Option<&'a Error>,
+	/// Whether the CPU was running at double speed.
+	pub double_speed: bool,
+	/// The final state of the CPU, formatted for display.
+	pub state: &'a dyn fmt::Display,
+	pub pc: u16,
+	/// The instructions around `pc`, if the test stopped before finishing.
+	pub disassembly: &'a [Instruction],
+}
+
+/// The results of every test.
+#[derive(Serialize)]
+pub struct Summary<'a> {
+	pub rom: &'a str,
+	pub passed: u32,
+	pub failed: u32,
+	pub skipped: u32,
+	/// Warnings raised across every test.
+	pub warnings: usize,
+	/// Instructions executed across every test.
+	pub instructions: u64,
+	/// Cycles simulated across every test.
+	pub cycles: u64,
+	/// Wall-clock time spent running every test, added up across threads. Serialized as seconds.
+	#[serde(serialize_with = "report::seconds")]
+	pub time: Duration,
+	pub tests: &'a [TestReport],
+	/// The tests which ran for the most cycles, most first, if they were asked for.
+	#[serde(
+		serialize_with = "report::timings",
+		skip_serializing_if = "Vec::is_empty"
+	)]
+	pub slowest: Vec<&'a TestReport>,
+}
+
+impl Summary<'_> {
+	/// Cycles simulated per second of wall-clock time spent running tests.
+	#[must_use]
+	pub fn throughput(&self) -> f64 {
+		#[allow(clippy::cast_precision_loss)]
+		let cycles = self.cycles as f64;
+		match self.time.as_secs_f64() {
+			0.0 => 0.0,
+			seconds => cycles / seconds,
+		}
+	}
+
+	/// The totals in words, such as "Simulated 10 instructions (40 cycles) in 1.00ms, 0.04M cycles/s."
+	fn totals(&self) -> String {
+		format!(
+			"Simulated {} instructions ({} cycles) in {:.2?}, {:.2}M cycles/s.",
+			self.instructions,
+			self.cycles,
+			self.time,
+			self.throughput() / 1_000_000.0
+		)
+	}
+}
+
+/// Why a test failed, preferring the description which names addresses after symbols.
+fn describe(report: &TestReport) -> String {
+	report
+		.description
+		.clone()
+		.or_else(|| report.reason.map(|reason| reason.to_string()))
+		.unwrap_or_default()
+}
+
+/// Prints a message if writing output fails, since reporters are unable to return errors.
+fn check(result: io::Result<()>) {
+	if let Err(msg) = result {
+		eprintln!("Failed to write output: {msg}");
+	}
+}
+
+/// Colored, human-readable messages.
+pub struct TextReporter<'a, W> {
+	silence_all: bool,
+	silence_passing: bool,
+	show_traces: bool,
+	trace_format: TraceFormat,
+	rom_path: &'a str,
+	out: W,
+}
+
+impl<'a, W: io::Write> TextReporter<'a, W> {
+	#[must_use]
+	pub fn new(silence_level: SilenceLevel, rom_path: &'a str, out: W) -> Self {
+		let (silence_all, silence_passing) = match silence_level {
+			SilenceLevel::None => (false, false),
+			SilenceLevel::Passing => (false, true),
+			SilenceLevel::All => (true, true),
+		};
+		Self {
+			silence_all,
+			silence_passing,
+			show_traces: true,
+			trace_format: TraceFormat::default(),
+			rom_path,
+			out,
+		}
+	}
+	/// Chooses whether the traces of failing tests are printed.
+	#[must_use]
+	pub fn with_traces(mut self, show_traces: bool) -> Self {
+		self.show_traces = show_traces;
+		self
+	}
+	/// Chooses how the traces of failing tests are printed.
+	#[must_use]
+	pub fn with_trace_format(mut self, trace_format: TraceFormat) -> Self {
+		self.trace_format = trace_format;
+		self
+	}
+}
+
+impl<W: io::Write> Reporter for TextReporter<'_, W> {
+	fn breakpoint(&mut self, details: &BreakpointDetails<'_>) {
+		let kind = match details.kind {
+			BreakpointKind::Breakpoint => "BREAKPOINT",
+			BreakpointKind::Debug => "DEBUG",
+		};
+		let location = details
+			.location
+			.as_ref()
+			.map(|location| format!(" at {location}"))
+			.unwrap_or_default();
+		check(writeln!(
+			self.out,
+			"{}: {kind} in {}{location} \n{}",
+			self.rom_path, details.test, details.state
+		));
+	}
+	fn message(&mut self, test: &str, message: &str) {
+		check(writeln!(
+			self.out,
+			"{}: {test} {}: {message}",
+			self.rom_path,
+			"message".cyan()
+		));
+	}
+	fn warning(&mut self, test: &str, message: &str) {
+		check(writeln!(
+			self.out,
+			"{}: {test} {}: {message}",
+			self.rom_path,
+			"warning".yellow()
+		));
+	}
+	fn test_passed(&mut self, report: &TestReport) {
+		if !self.silence_passing {
+			let status = if report.status == TestStatus::ExpectedFailure {
+				"failed as expected"
+			} else {
+				"passed"
+			};
+			check(writeln!(
+				self.out,
+				"{}: {} {}",
+				self.rom_path,
+				report.name,
+				status.green()
+			));
+		}
+	}
+	fn test_skipped(&mut self, report: &TestReport) {
+		if !self.silence_passing {
+			let reason = report
+				.skip_reason
+				.as_ref()
+				.map(|reason| format!(": {reason}"))
+				.unwrap_or_default();
+			check(writeln!(
+				self.out,
+				"{}: {} {}{reason}",
+				self.rom_path,
+				report.name,
+				"skipped".yellow()
+			));
+		}
+	}
+	fn test_failed(&mut self, details: &FailureDetails<'_>) {
+		if let Some(error) = details.error {
+			check(writeln!(
+				self.out,
+				"{}: {} {}:",
+				self.rom_path,
+				details.report.name,
+				"failed".red(),
+			));
+			if let Error::CompareFailed(result) = error {
+				// The report names addresses after symbols, unlike the error itself.
+				// Bytes of memory are shown in the hexdump below instead.
+				for (mismatch, _) in details
+					.report
+					.mismatches
+					.iter()
+					.zip(result.differences())
+					.filter(|(_, difference)| difference.expected_bytes().is_none())
+				{
+					check(writeln!(self.out, "{mismatch}"));
+				}
+				if !details.report.memory.is_empty() {
+					check(writeln!(
+						self.out,
+						"Memory (expected values beneath each line, where they differ):"
+					));
+				}
+				let mut next = None;
+				for row in &details.report.memory {
+					if next.is_some_and(|next| next != row.address) {
+						check(writeln!(self.out, "..."));
+					}
+					check(writeln!(self.out, "{row}"));
+					next = row.address.checked_add(16);
+				}
+				for condition in &details.report.conditions {
+					check(writeln!(self.out, "{condition}"));
+				}
+			} else {
+				check(write!(self.out, "{error}"));
+			}
+		} else {
+			let reason = describe(details.report);
+			// Normal speed is the usual case, so only double speed is worth pointing out.
+			let speed = if details.double_speed {
+				" (double speed)"
+			} else {
+				""
+			};
+			check(writeln!(
+				self.out,
+				"{}: {} {}:\n{reason}{speed}\n{}",
+				self.rom_path,
+				details.report.name,
+				"failed".red(),
+				details.state,
+			));
+		}
+		if let Some(seed) = details.report.ram_seed {
+			check(writeln!(
+				self.out,
+				"RAM was filled randomly; set `ram-fill = {{ random = {seed} }}` to repeat this test"
+			));
+		}
+		if !details.disassembly.is_empty() {
+			check(writeln!(self.out, "Disassembly:"));
+			for instruction in details.disassembly {
+				let marker = if instruction.address == details.pc {
+					">"
+				} else {
+					" "
+				};
+				check(writeln!(self.out, "\t{marker} {instruction}"));
+			}
+		}
+		if !details.report.history.is_empty() {
+			check(writeln!(self.out, "Recently executed:"));
+			for executed in &details.report.history {
+				check(writeln!(self.out, "\t{executed}"));
+			}
+		}
+		if self.show_traces && !details.report.trace.is_empty() {
+			check(writeln!(self.out, "Trace:"));
+			for step in &details.report.trace {
+				check(writeln!(self.out, "\t{}", self.trace_format.format(step)));
+			}
+		}
+		if !details.report.watched.is_empty() {
+			check(writeln!(self.out, "Accesses to watched memory:"));
+			for access in &details.report.watched {
+				check(writeln!(self.out, "\t{access}"));
+			}
+		}
+	}
+	fn finished(&mut self, summary: &Summary<'_>) {
+		// When in SILENCE_ALL only print the final message if a test failed.
+		if !self.silence_all || summary.failed != 0 {
+			let skipped = if summary.skipped == 0 {
+				String::new()
+			} else {
+				format!(" {} skipped.", summary.skipped)
+			};
+			let warnings = match summary.warnings {
+				0 => String::new(),
+				1 => String::from(" 1 warning."),
+				warnings => format!(" {warnings} warnings."),
+			};
+			check(writeln!(
+				self.out,
+				"{}: All tests complete. {}/{} passed.{skipped}{warnings}",
+				self.rom_path,
+				summary.passed,
+				summary.passed + summary.failed,
+			));
+			check(writeln!(
+				self.out,
+				"{}: {}",
+				self.rom_path,
+				summary.totals()
+			));
+		}
+		if !summary.slowest.is_empty() {
+			check(profile::write_timings(&mut self.out, &summary.slowest));
+		}
+	}
+}
+
+/// A single JSON report printed once all tests are complete.
+pub struct JsonReporter<W>(pub W);
+
+impl<W: io::Write> Reporter for JsonReporter<W> {
+	fn test_passed(&mut self, _report: &TestReport) {}
+	fn test_failed(&mut self, _details: &FailureDetails<'_>) {}
+	fn finished(&mut self, summary: &Summary<'_>) {
+		match serde_json::to_string_pretty(summary) {
+			Ok(json) => check(writeln!(self.0, "{json}")),
+			Err(msg) => eprintln!("Failed to serialize report: {msg}"),
+		}
+	}
+}
+
+/// A Markdown summary of the results, printed once all tests are complete.
+pub struct MarkdownReporter<W>(pub W);
+
+impl<W: io::Write> Reporter for MarkdownReporter<W> {
+	fn test_passed(&mut self, _report: &TestReport) {}
+	fn test_failed(&mut self, _details: &FailureDetails<'_>) {}
+	fn finished(&mut self, summary: &Summary<'_>) {
+		check(write!(self.0, "{}", markdown_summary(summary)));
+	}
+}
+
+/// Formats the results as a table of counts, followed by a table of failing tests.
+#[must_use]
+pub fn markdown_summary(summary: &Summary<'_>) -> String {
+	let mut markdown = format!(
+		"## {}\n\n| Passed | Failed | Skipped | Total |\n| ---: | ---: | ---: | ---: |\n| {} | {} | {} | {} |\n\n{}\n",
+		escape_markdown(summary.rom),
+		summary.passed,
+		summary.failed,
+		summary.skipped,
+		summary.passed + summary.failed + summary.skipped,
+		summary.totals(),
+	);
+	let failures = summary
+		.tests
+		.iter()
+		.filter(|report| report.status == TestStatus::Failed)
+		.map(|report| {
+			let reason = describe(report);
+			let details = report
+				.mismatches
+				.iter()
+				.map(|mismatch| {
+					format!(
+						"{}: expected {}, got {}",
+						mismatch.source, mismatch.expected, mismatch.actual
+					)
+				})
+				.chain(report.conditions.iter().cloned())
+				.map(|detail| escape_markdown(&detail))
+				.collect::<Vec<String>>()
+				.join("<br>");
+			format!(
+				"| {} | {reason} | {details} |",
+				escape_markdown(&report.name)
+			)
+		})
+		.collect::<Vec<String>>();
+	if !failures.is_empty() {
+		markdown += "\n### Failures\n\n| Test | Reason | Details |\n| --- | --- | --- |\n";
+		markdown += &failures.join("\n");
+		markdown += "\n";
+	}
+	let warnings = summary
+		.tests
+		.iter()
+		.flat_map(|report| {
+			report.warnings.iter().map(|warning| {
+				format!(
+					"| {} | {} |",
+					escape_markdown(&report.name),
+					escape_markdown(warning)
+				)
+			})
+		})
+		.collect::<Vec<String>>();
+	if !warnings.is_empty() {
+		markdown += "\n### Warnings\n\n| Test | Warning |\n| --- | --- |\n";
+		markdown += &warnings.join("\n");
+		markdown += "\n";
+	}
+	let slowest = summary
+		.slowest
+		.iter()
+		.map(|report| {
+			format!(
+				"| {} | {} | {:.2?} |",
+				escape_markdown(&report.name),
+				report.cycles,
+				report.time
+			)
+		})
+		.collect::<Vec<String>>();
+	if !slowest.is_empty() {
+		markdown += "\n### Slowest tests\n\n| Test | Cycles | Time |\n| --- | ---: | ---: |\n";
+		markdown += &slowest.join("\n");
+		markdown += "\n";
+	}
+	markdown
+}
+
+/// Escapes characters which would break a Markdown table cell.
+fn escape_markdown(text: &str) -> String {
+	text.replace('|', "\\|").replace('\n', "<br>")
+}
+
+/// One JSON object per line for each event, printed as tests run.
+pub struct NdjsonReporter<W>(pub W);
+
+/// A line of `NdjsonReporter` output.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum Event<'a> {
+	Started {
+		test: &'a str,
+	},
+	Breakpoint {
+		test: &'a str,
+		pc: u16,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		location: Option<&'a str>,
+	},
+	Debug {
+		test: &'a str,
+		pc: u16,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		location: Option<&'a str>,
+	},
+	Message {
+		test: &'a str,
+		message: &'a str,
+	},
+	Warning {
+		test: &'a str,
+		message: &'a str,
+	},
+	Finished(&'a TestReport),
+	Summary {
+		rom: &'a str,
+		passed: u32,
+		failed: u32,
+		skipped: u32,
+		warnings: usize,
+		instructions: u64,
+		cycles: u64,
+		#[serde(serialize_with = "report::seconds")]
+		time: Duration,
+		#[serde(
+			serialize_with = "report::timings",
+			skip_serializing_if = "<[_]>::is_empty"
+		)]
+		slowest: &'a [&'a TestReport],
+	},
+}
+
+impl<W: io::Write> NdjsonReporter<W> {
+	fn emit(&mut self, event: &Event<'_>) {
+		match serde_json::to_string(event) {
+			// Flushed so that progress can be seen as tests run
+			Ok(json) => check(writeln!(self.0, "{json}").and_then(|()| self.0.flush())),
+			Err(msg) => eprintln!("Failed to serialize event: {msg}"),
+		}
+	}
+}
+
+impl<W: io::Write> Reporter for NdjsonReporter<W> {
+	fn test_started(&mut self, test: &str) {
+		self.emit(&Event::Started { test });
+	}
+	fn breakpoint(&mut self, details: &BreakpointDetails<'_>) {
+		let (test, pc, location) = (details.test, details.pc, details.location.as_deref());
+		self.emit(&match details.kind {
+			BreakpointKind::Breakpoint => Event::Breakpoint { test, pc, location },
+			BreakpointKind::Debug => Event::Debug { test, pc, location },
+		});
+	}
+	fn message(&mut self, test: &str, message: &str) {
+		self.emit(&Event::Message { test, message });
+	}
+	fn warning(&mut self, test: &str, message: &str) {
+		self.emit(&Event::Warning { test, message });
+	}
+	fn test_passed(&mut self, report: &TestReport) {
+		self.emit(&Event::Finished(report));
+	}
+	fn test_failed(&mut self, details: &FailureDetails<'_>) {
+		self.emit(&Event::Finished(details.report));
+	}
+	fn test_skipped(&mut self, report: &TestReport) {
+		self.emit(&Event::Finished(report));
+	}
+	fn finished(&mut self, summary: &Summary<'_>) {
+		self.emit(&Event::Summary {
+			rom: summary.rom,
+			passed: summary.passed,
+			failed: summary.failed,
+			skipped: summary.skipped,
+			warnings: summary.warnings,
+			instructions: summary.instructions,
+			cycles: summary.cycles,
+			time: summary.time,
+			slowest: &summary.slowest,
+		});
+	}
+}
@@ -0,0 +1,405 @@
+//! Exporting the state of a test as a save state, so that it can be loaded into an emulator and debugged interactively,
+//! and loading save states and memory dumps for tests to start from.
+//!
+//! States are written in the Best Effort Save State (BESS) format, which `SameBoy` and several other emulators can load.
+//! A BESS file is a series of blocks, each a 4-character identifier and a length, followed by a footer pointing to the first block.
+//! The contents of memory are stored before the blocks, and the `CORE` block records where each region begins.
+
+use crate::memory::{AddressSpace, Inspect};
+use crate::registers::{flags_byte, Registers};
+use gb_cpu_sim::cpu;
+use serde::Deserialize;
+use std::io::{self, Write};
+
+const BESS_MAJOR: u16 = 1;
+const BESS_MINOR: u16 = 1;
+/// CGB palettes aren't emulated, so every color is left white.
+const PALETTE: [u8; 0x40] = [0xFF; 0x40];
+
+/// The size of cartridge RAM declared in a ROM's header.
+fn sram_size(rom: &[u8]) -> usize {
+	match rom.get(0x149) {
+		Some(2) => 0x2000,
+		Some(3) => 0x8000,
+		Some(4) => 0x20000,
+		Some(5) => 0x10000,
+		_ => 0,
+	}
+}
+
+/// The contents of cartridge RAM, at the size declared in the ROM's header, as it would be kept in a `.sav` file.
+#[must_use]
+pub fn cartridge_ram(memory: &AddressSpace) -> Vec<u8> {
+	banks(&memory.sram, sram_size(memory.rom))
+}
+
+/// Repeats `bank` to fill `size` bytes, since evunit emulates a single bank of each kind of RAM.
+fn banks(bank: &[u8], size: usize) -> Vec<u8> {
+	bank.iter().copied().cycle().take(size).collect()
+}
+
+/// Writes the registers and memory of `cpu_state` as a BESS save state.
+///
+/// The model is taken from the ROM's header: CGB-compatible ROMs are saved as a CGB, and others as a DMG.
+/// Banks of VRAM, WRAM, and cartridge RAM beyond the first share the same memory in evunit,
+/// so they are saved as copies of it.
+///
+/// # Errors
+///
+/// Fails if the file could not be written to.
+pub fn write<W: Write>(cpu_state: &cpu::State<AddressSpace>, mut file: W) -> io::Result<()> {
+	let memory = &cpu_state.address_space;
+	let cgb = memory.rom.get(0x143).is_some_and(|flags| flags & 0x80 != 0);
+
+	let mut wram = memory.wram[..0x1000].to_vec();
+	wram.extend(banks(
+		&memory.wram[0x1000..],
+		if cgb { 0x7000 } else { 0x1000 },
+	));
+	let vram = banks(&memory.vram, if cgb { 0x4000 } else { 0x2000 });
+	let sram = cartridge_ram(memory);
+	let palettes: &[u8] = if cgb { &PALETTE } else { &[] };
+	let regions: [&[u8]; 7] = [
+		&wram,
+		&vram,
+		&sram,
+		&memory.oam[..0xA0],
+		&memory.hram,
+		palettes,
+		palettes,
+	];
+
+	// Each region is stored at the start of the file, in the order the `CORE` block lists them.
+	let mut pointers = Vec::new();
+	let mut offset = 0u32;
+	for region in regions {
+		file.write_all(region)?;
+		pointers.extend(length(region).to_le_bytes());
+		pointers.extend(offset.to_le_bytes());
+		offset += length(region);
+	}
+	let first_block = offset;
+
+	let name = format!("evunit v{}", env!("CARGO_PKG_VERSION"));
+	block(&mut file, *b"NAME", name.as_bytes())?;
+
+	let mut info = vec![0; 0x12];
+	for (byte, header) in info.iter_mut().zip(
+		memory
+			.rom
+			.iter()
+			.skip(0x134)
+			.take(0x10)
+			.chain(memory.rom.iter().skip(0x14E).take(2)),
+	) {
+		*byte = *header;
+	}
+	block(&mut file, *b"INFO", &info)?;
+
+	let mut core = Vec::with_capacity(0xD0);
+	core.extend(BESS_MAJOR.to_le_bytes());
+	core.extend(BESS_MINOR.to_le_bytes());
+	core.extend(if cgb { b"CC  " } else { b"GD  " });
+	for register in [
+		cpu_state.pc,
+		u16::from_be_bytes([cpu_state.a, flags_byte(cpu_state)]),
+		cpu_state.get_bc(),
+		cpu_state.get_de(),
+		cpu_state.get_hl(),
+		cpu_state.sp,
+	] {
+		core.extend(register.to_le_bytes());
+	}
+	// Tests end once the CPU halts or stops, so it is always saved as running.
+	core.extend([u8::from(cpu_state.ime), memory.ie, 0, 0]);
+	core.extend(memory.io);
+	core.extend(pointers);
+	block(&mut file, *b"CORE", &core)?;
+
+	if memory.has_mbc() {
+		// The MBC is restored by replaying the writes which select the current bank.
+		let bank = memory.rom_bank.to_le_bytes();
+		let mut mbc = Vec::new();
+		if !sram.is_empty() {
+			mbc.extend([0x00, 0x00, 0x0A]);
+		}
+		mbc.extend([0x00, 0x20, bank[0]]);
+		if memory.is_mbc5() {
+			mbc.extend([0x00, 0x30, bank[1] & 0x01]);
+		}
+		block(&mut file, *b"MBC ", &mbc)?;
+	}
+
+	block(&mut file, *b"END ", &[])?;
+	file.write_all(&first_block.to_le_bytes())?;
+	file.write_all(b"BESS")
+}
+
+fn block<W: Write>(file: &mut W, id: [u8; 4], contents: &[u8]) -> io::Result<()> {
+	file.write_all(&id)?;
+	file.write_all(&length(contents).to_le_bytes())?;
+	file.write_all(contents)
+}
+
+fn length(bytes: &[u8]) -> u32 {
+	// Nothing saved comes close to 4 GiB.
+	u32::try_from(bytes.len()).unwrap_or(u32::MAX)
+}
+
+/// Registers and memory loaded from a save state or dump.
+#[derive(Clone, Debug)]
+pub struct LoadedState {
+	/// Registers and every byte of memory from 0x8000 onwards, except echo RAM.
+	/// Dumps don't include registers, so only their memory is set.
+	pub registers: Registers,
+	/// The ROM bank which was selected, if the state or dump records it.
+	pub bank: Option<usize>,
+}
+
+/// Reads a BESS save state, such as one written by [`write`], or a memory dump in any of evunit's formats.
+///
+/// # Errors
+///
+/// Returns an error if `contents` is not a save state or dump, or is truncated.
+pub fn load(contents: &[u8]) -> Result<LoadedState, String> {
+	if contents.ends_with(b"BESS") {
+		load_bess(contents)
+	} else if contents.len() == 0x10000 {
+		Ok(LoadedState {
+			registers: with_memory(Registers::new(), 0x8000, &contents[0x8000..]),
+			bank: None,
+		})
+	} else if contents.starts_with(b"{") {
+		load_json(contents)
+	} else {
+		load_text(contents)
+	}
+}
+
+/// Adds `bytes` to the memory of `registers`, starting at `start` and skipping echo RAM.
+fn with_memory(mut registers: Registers, start: u16, bytes: &[u8]) -> Registers {
+	registers.memory.extend(
+		(start..=u16::MAX)
+			.zip(bytes.iter().copied())
+			.filter(|(address, _)| !(0xE000..=0xFDFF).contains(address)),
+	);
+	registers
+}
+
+fn load_bess(contents: &[u8]) -> Result<LoadedState, String> {
+	let truncated = || String::from("Save state is truncated");
+	let bytes = |offset: usize, length: usize| {
+		offset
+			.checked_add(length)
+			.and_then(|end| contents.get(offset..end))
+			.ok_or_else(truncated)
+	};
+	let long = |offset: usize| {
+		bytes(offset, 4).map(|long| {
+			usize::try_from(u32::from_le_bytes([long[0], long[1], long[2], long[3]]))
+				.unwrap_or(usize::MAX)
+		})
+	};
+
+	// The footer is the offset of the first block, then "BESS".
+	let mut offset = long(contents.len().checked_sub(8).ok_or_else(truncated)?)?;
+	let mut core = None;
+	let mut mbc: &[u8] = &[];
+	loop {
+		let id = bytes(offset, 4)?;
+		let length = long(offset + 4)?;
+		let block = bytes(offset + 8, length)?;
+		match id {
+			b"CORE" => core = Some(block),
+			b"MBC " => mbc = block,
+			b"END " => break,
+			_ => {}
+		}
+		offset += 8 + length;
+	}
+	let core = core.ok_or_else(|| String::from("Save state has no CORE block"))?;
+	if core.len() < 0xD0 {
+		return Err(truncated());
+	}
+	let core_word = |offset: usize| u16::from_le_bytes([core[offset], core[offset + 1]]);
+	let [bc, de, hl] = [0x0C, 0x0E, 0x10].map(|offset| core_word(offset).to_be_bytes());
+	// Individual registers are loaded rather than pairs, so that a test can override any of them.
+	let mut registers = Registers::new()
+		.with_pc(core_word(0x08))
+		.with_af(core_word(0x0A))
+		.with_b(bc[0])
+		.with_c(bc[1])
+		.with_d(de[0])
+		.with_e(de[1])
+		.with_h(hl[0])
+		.with_l(hl[1])
+		.with_sp(core_word(0x12))
+		.with_ime(core[0x14] != 0);
+
+	// Each region is stored elsewhere in the file, at the offset the `CORE` block gives.
+	let region = |index: usize, length: usize| {
+		let pointer = 0x98 + index * 8;
+		let size = u32::from_le_bytes([
+			core[pointer],
+			core[pointer + 1],
+			core[pointer + 2],
+			core[pointer + 3],
+		]);
+		let offset = u32::from_le_bytes([
+			core[pointer + 4],
+			core[pointer + 5],
+			core[pointer + 6],
+			core[pointer + 7],
+		]);
+		let size = usize::try_from(size).unwrap_or(usize::MAX).min(length);
+		bytes(usize::try_from(offset).unwrap_or(usize::MAX), size)
+	};
+	registers = with_memory(registers, 0x8000, region(1, 0x2000)?);
+	registers = with_memory(registers, 0xA000, region(2, 0x2000)?);
+	registers = with_memory(registers, 0xC000, region(0, 0x2000)?);
+	registers = with_memory(registers, 0xFE00, region(3, 0xA0)?);
+	registers = with_memory(registers, 0xFF00, &core[0x18..0x98]);
+	registers = with_memory(registers, 0xFF80, region(4, 0x7F)?);
+	registers = with_memory(registers, 0xFFFF, &core[0x15..0x16]);
+
+	// The MBC is saved as the writes which restore it; only those selecting a ROM bank matter here.
+	let mut bank = None;
+	for write in mbc.chunks_exact(3) {
+		let address = u16::from_le_bytes([write[0], write[1]]);
+		let value = usize::from(write[2]);
+		match address {
+			0x2000..=0x2FFF => bank = Some(bank.unwrap_or(0) & 0x100 | value),
+			0x3000..=0x3FFF => bank = Some(bank.unwrap_or(0) & 0xFF | (value & 1) << 8),
+			_ => {}
+		}
+	}
+	Ok(LoadedState { registers, bank })
+}
+
+/// Records the registers and memory of the CPU, so that another test can start from them.
+/// `pc` is left out, since it is wherever the CPU happened to stop.
+#[must_use]
+pub fn capture<A: Inspect>(cpu_state: &cpu::State<A>) -> LoadedState {
+	let registers = Registers::new()
+		.with_af(u16::from_be_bytes([cpu_state.a, flags_byte(cpu_state)]))
+		.with_b(cpu_state.b)
+		.with_c(cpu_state.c)
+		.with_d(cpu_state.d)
+		.with_e(cpu_state.e)
+		.with_h(cpu_state.h)
+		.with_l(cpu_state.l)
+		.with_sp(cpu_state.sp)
+		.with_ime(cpu_state.ime);
+	let memory = (0x8000..=u16::MAX)
+		.map(|address| cpu_state.address_space.peek(address))
+		.collect::<Vec<u8>>();
+	LoadedState {
+		registers: with_memory(registers, 0x8000, &memory),
+		bank: Some(cpu_state.address_space.rom_bank()),
+	}
+}
+
+#[derive(Deserialize)]
+struct JsonDump {
+	#[serde(default)]
+	banks: JsonBanks,
+	regions: Vec<JsonRegion>,
+}
+
+#[derive(Default, Deserialize)]
+struct JsonBanks {
+	rom: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct JsonRegion {
+	start: u16,
+	bytes: Vec<u8>,
+}
+
+fn load_json(contents: &[u8]) -> Result<LoadedState, String> {
+	let dump: JsonDump =
+		serde_json::from_slice(contents).map_err(|msg| format!("Invalid JSON dump: {msg}"))?;
+	let registers = dump
+		.regions
+		.iter()
+		.fold(Registers::new(), |registers, region| {
+			with_memory(registers, region.start, &region.bytes)
+		});
+	Ok(LoadedState {
+		registers,
+		bank: dump.banks.rom,
+	})
+}
+
+/// Reads a text dump, made of lines like `0xc000: 0x00 0x01` under a header for each region.
+/// Only the first value on each line of decoded IO registers is a byte, so the rest of such lines are ignored.
+fn load_text(contents: &[u8]) -> Result<LoadedState, String> {
+	let text = std::str::from_utf8(contents)
+		.map_err(|_| String::from("Not a save state or memory dump"))?;
+	let hex = |text: &str| {
+		text.strip_prefix("0x")
+			.and_then(|digits| u16::from_str_radix(digits, 16).ok())
+	};
+	let mut registers = Registers::new();
+	let mut bank = None;
+	for (number, line) in (1..).zip(text.lines()) {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('[') {
+			continue;
+		}
+		let Some((address, values)) = line.split_once(':') else {
+			return Err(format!(
+				"Line {number} of dump is not an address and its bytes"
+			));
+		};
+		if address == "ROM" {
+			bank = values.trim().parse().ok();
+			continue;
+		}
+		// Other banks are recorded, but only the ROM bank is emulated.
+		if address.chars().all(|c| c.is_ascii_alphabetic()) {
+			continue;
+		}
+		let Some(address) = hex(address) else {
+			return Err(format!("Line {number} of dump has an invalid address"));
+		};
+		let bytes = values
+			.split_whitespace()
+			.map_while(|value| hex(value).and_then(|value| u8::try_from(value).ok()))
+			.collect::<Vec<u8>>();
+		registers = with_memory(registers, address, &bytes);
+	}
+	if registers.memory.is_empty() {
+		return Err(String::from("Not a save state or memory dump"));
+	}
+	Ok(LoadedState { registers, bank })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn bess_footer_truncated() {
+		assert_eq!(load(b"BESS").unwrap_err(), "Save state is truncated");
+		assert_eq!(load(b"\0\0\0BESS").unwrap_err(), "Save state is truncated");
+	}
+
+	#[test]
+	fn bess_block_truncated() {
+		// The first block starts at 0, but is longer than the file.
+		let mut contents = b"CORE\xFF\0\0\0".to_vec();
+		contents.extend(0_u32.to_le_bytes());
+		contents.extend(b"BESS");
+		assert_eq!(load(&contents).unwrap_err(), "Save state is truncated");
+	}
+
+	#[test]
+	fn bess_without_core() {
+		let mut contents = b"END \0\0\0\0".to_vec();
+		contents.extend(0_u32.to_le_bytes());
+		contents.extend(b"BESS");
+		assert_eq!(load(&contents).unwrap_err(), "Save state has no CORE block");
+	}
+}
@@ -0,0 +1,152 @@
+//! Checks written as [Rhai](https://rhai.rs) scripts, for conditions too complex to write as expected values.
+//!
+//! A script runs once the test finishes, with the final registers as constants
+//! (`a`, `b`, `c`, `d`, `e`, `h`, `l`, `f`, `bc`, `de`, `hl`, `sp`, and `pc`) and these functions:
+//!
+//! ```not_rust
+//! read(address)    // The byte at `address`
+//! read16(address)  // The little-endian word at `address`
+//! sym("wList")     // The address of a symbol or hardware register
+//! ```
+//!
+//! The script passes if it returns `true` or nothing. Returning `false` fails the test,
+//! as does returning a string, which is used as the failure's message, or throwing an error.
+
+use crate::condition::Condition;
+use crate::memory::{Access, Peek};
+use crate::registers::Registers;
+use rhai::{Dynamic, Engine, EvalAltResult, Position, Scope, AST, INT};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Most operations a script may run, so that one which never finishes fails instead.
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+#[derive(Clone, Debug)]
+pub struct Script {
+	/// The path the script was read from, or the script itself if it was written in the config on one line.
+	pub name: String,
+	ast: AST,
+	/// The address of every symbol the script mentions, for `sym`.
+	symbols: Arc<HashMap<String, u16>>,
+}
+
+impl Script {
+	/// Compiles a script, looking up each name it mentions with `resolve` so that `sym` can find it later.
+	///
+	/// # Errors
+	///
+	/// Returns a message describing the first syntax error in the script.
+	pub fn compile(
+		name: impl Into<String>,
+		source: &str,
+		resolve: impl Fn(&str) -> Option<u16>,
+	) -> Result<Self, String> {
+		let name = name.into();
+		let ast = Engine::new()
+			.compile(source)
+			.map_err(|error| format!("Failed to compile {name}: {error}"))?;
+		let symbols = source
+			.split(|char: char| !char.is_ascii_alphanumeric() && !"_.#@".contains(char))
+			.filter_map(|word| Some((String::from(word), resolve(word)?)))
+			.collect();
+		Ok(Self {
+			name,
+			ast,
+			symbols: Arc::new(symbols),
+		})
+	}
+
+	fn engine(&self, memory: &dyn Peek) -> Engine {
+		let memory = Arc::new(
+			(0..=u16::MAX)
+				.map(|address| memory.peek(address))
+				.collect::<Vec<u8>>(),
+		);
+		let mut engine = Engine::new();
+		engine.set_max_operations(MAX_OPERATIONS);
+
+		let bytes = Arc::clone(&memory);
+		engine.register_fn(
+			"read",
+			move |address: INT| -> Result<INT, Box<EvalAltResult>> {
+				Ok(INT::from(bytes[usize::from(to_address(address)?)]))
+			},
+		);
+		let bytes = memory;
+		engine.register_fn(
+			"read16",
+			move |address: INT| -> Result<INT, Box<EvalAltResult>> {
+				let address = to_address(address)?;
+				let low = bytes[usize::from(address)];
+				let high = bytes[usize::from(address.wrapping_add(1))];
+				Ok(INT::from(u16::from_le_bytes([low, high])))
+			},
+		);
+		let symbols = Arc::clone(&self.symbols);
+		engine.register_fn(
+			"sym",
+			move |name: &str| -> Result<INT, Box<EvalAltResult>> {
+				symbols
+					.get(name)
+					.map(|address| INT::from(*address))
+					.ok_or_else(|| Box::new(error(format!("Unknown symbol `{name}`"))))
+			},
+		);
+		engine
+	}
+}
+
+impl Condition for Script {
+	fn check(
+		&self,
+		registers: &Registers,
+		memory: &dyn Peek,
+		_accesses: &[Access],
+	) -> Result<(), String> {
+		let mut scope = Scope::new();
+		let bytes = [
+			("a", registers.a),
+			("b", registers.b),
+			("c", registers.c),
+			("d", registers.d),
+			("e", registers.e),
+			("h", registers.h),
+			("l", registers.l),
+			("f", registers.af.map(|af| af.to_le_bytes()[0])),
+		];
+		for (name, value) in bytes {
+			scope.push_constant(name, INT::from(value.unwrap_or_default()));
+		}
+		let words = [
+			("bc", registers.bc),
+			("de", registers.de),
+			("hl", registers.hl),
+			("sp", registers.sp),
+			("pc", registers.pc),
+		];
+		for (name, value) in words {
+			scope.push_constant(name, INT::from(value.unwrap_or_default()));
+		}
+		let result = self
+			.engine(memory)
+			.eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast)
+			.map_err(|error| format!("Script {} failed: {error}", self.name))?;
+		if result.is_unit() || result.as_bool() == Ok(true) {
+			Ok(())
+		} else if result.is_string() {
+			Err(result.to_string())
+		} else {
+			Err(format!("Script {} returned {result}", self.name))
+		}
+	}
+}
+
+fn to_address(address: INT) -> Result<u16, Box<EvalAltResult>> {
+	u16::try_from(address)
+		.map_err(|_| Box::new(error(format!("Address {address} is out of range"))))
+}
+
+fn error(message: String) -> EvalAltResult {
+	EvalAltResult::ErrorRuntime(message.into(), Position::NONE)
+}
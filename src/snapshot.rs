@@ -0,0 +1,139 @@
+//! Golden snapshots of memory, which record a test's output the first time it runs and check it afterwards.
+//!
+//! Snapshots are text files listing 16 bytes per line, so that changes to them are easy to review:
+//!
+//! ```not_rust
+//! C000: 00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F
+//! ```
+
+use crate::condition::Condition;
+use crate::memory::{Access, Peek};
+use crate::registers::Registers;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Compares regions of memory to a snapshot file once a test finishes,
+/// writing the file instead if it does not exist yet or `update` is set.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+	pub path: PathBuf,
+	/// Regions of memory to record, as (address, length).
+	pub regions: Vec<(u16, u16)>,
+	/// Overwrites the snapshot rather than comparing against it.
+	pub update: bool,
+}
+
+impl Snapshot {
+	#[must_use]
+	pub fn new(path: impl Into<PathBuf>, regions: Vec<(u16, u16)>) -> Self {
+		Self {
+			path: path.into(),
+			regions,
+			update: false,
+		}
+	}
+
+	#[must_use]
+	pub fn with_update(mut self, update: bool) -> Self {
+		self.update = update;
+		self
+	}
+
+	fn capture(&self, memory: &dyn Peek) -> BTreeMap<u16, u8> {
+		self.regions
+			.iter()
+			.flat_map(|(address, length)| {
+				(0..*length).map(move |offset| address.wrapping_add(offset))
+			})
+			.map(|address| (address, memory.peek(address)))
+			.collect()
+	}
+}
+
+impl Condition for Snapshot {
+	fn check(
+		&self,
+		_registers: &Registers,
+		memory: &dyn Peek,
+		_accesses: &[Access],
+	) -> Result<(), String> {
+		let actual = self.capture(memory);
+		let path = self.path.display();
+		if self.update || !self.path.exists() {
+			if let Some(dir) = self.path.parent() {
+				fs::create_dir_all(dir).map_err(|msg| {
+					format!("Failed to create directory {}: {msg}", dir.display())
+				})?;
+			}
+			return fs::write(&self.path, write(&actual))
+				.map_err(|msg| format!("Failed to write snapshot {path}: {msg}"));
+		}
+		let text = fs::read_to_string(&self.path)
+			.map_err(|msg| format!("Failed to read snapshot {path}: {msg}"))?;
+		let expected = read(&text).ok_or_else(|| format!("Snapshot {path} is malformed"))?;
+		let mut differences = actual
+			.iter()
+			.filter(|(address, value)| expected.get(address) != Some(value));
+		let Some((address, value)) = differences.next() else {
+			return Ok(());
+		};
+		let others = match differences.count() {
+			0 => String::new(),
+			1 => String::from(" (and 1 other byte)"),
+			count => format!(" (and {count} other bytes)"),
+		};
+		match expected.get(address) {
+			Some(expected) => Err(format!(
+				"[0x{address:04x}] (0x{value:02x}) does not match snapshot {path} (0x{expected:02x}){others}"
+			)),
+			None => Err(format!(
+				"[0x{address:04x}] is missing from snapshot {path}{others}; run with --update-snapshots to add it"
+			)),
+		}
+	}
+}
+
+/// Formats bytes as lines of up to 16, starting a new line wherever the addresses are not contiguous.
+fn write(bytes: &BTreeMap<u16, u8>) -> String {
+	let mut lines: Vec<(u16, Vec<u8>)> = Vec::new();
+	for (address, value) in bytes {
+		match lines.last_mut() {
+			Some((start, values))
+				if values.len() < 16
+					&& start.wrapping_add(u16::try_from(values.len()).unwrap_or_default())
+						== *address =>
+			{
+				values.push(*value);
+			}
+			_ => lines.push((*address, vec![*value])),
+		}
+	}
+	let lines = lines
+		.iter()
+		.map(|(address, values)| {
+			let values = values
+				.iter()
+				.map(|value| format!("{value:02X}"))
+				.collect::<Vec<_>>()
+				.join(" ");
+			format!("{address:04X}: {values}")
+		})
+		.collect::<Vec<_>>();
+	lines.join("\n") + "\n"
+}
+
+fn read(text: &str) -> Option<BTreeMap<u16, u8>> {
+	let mut bytes = BTreeMap::new();
+	for line in text.lines().filter(|line| !line.trim().is_empty()) {
+		let (address, values) = line.split_once(':')?;
+		let address = u16::from_str_radix(address.trim(), 16).ok()?;
+		for (offset, value) in (0..).zip(values.split_whitespace()) {
+			bytes.insert(
+				address.wrapping_add(offset),
+				u8::from_str_radix(value, 16).ok()?,
+			);
+		}
+	}
+	Some(bytes)
+}
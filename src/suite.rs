@@ -0,0 +1,442 @@
+//! Collections of tests, and how to select and run them.
+
+use crate::log::Logger;
+use crate::memory::AddressSpace;
+use crate::savestate::{self, LoadedState};
+use crate::test::TestConfig;
+use crate::Error;
+use crate::{open_test_roms, patch_rom};
+use gb_cpu_sim::cpu;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ptr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::{panic, thread};
+
+/// Selects which tests in a [`Suite`] are run.
+///
+/// Filters of the same kind are alternatives; a test is selected if it matches
+/// at least one `Name` or `Glob`, one `Exact`, one `Group`, and one `Tag` filter
+/// (when any are present), any `MainRom` filter, and no `SkipTag` filter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Filter {
+	/// Matches tests whose name contains this string.
+	Name(String),
+	/// Matches tests whose whole name matches this pattern,
+	/// where `*` matches any number of characters, `?` matches one, and `[...]` matches one of a set.
+	Glob(glob::Pattern),
+	/// Matches the test with exactly this name.
+	Exact(String),
+	/// Matches tests belonging to this group.
+	Group(String),
+	/// Matches tests with this tag.
+	Tag(String),
+	/// Excludes tests with this tag.
+	SkipTag(String),
+	/// Matches tests which run on the ROM given to [`Suite::run`],
+	/// rather than their own `rom` or an `overlay` which replaces it.
+	MainRom,
+}
+
+impl Filter {
+	#[must_use]
+	pub fn matches(&self, test: &TestConfig) -> bool {
+		match self {
+			Filter::Name(name) => test.name.contains(name.as_str()),
+			Filter::Glob(pattern) => pattern.matches(&test.name),
+			Filter::Exact(name) => test.name == *name,
+			Filter::Group(group) => test.group.as_ref() == Some(group),
+			Filter::Tag(tag) | Filter::SkipTag(tag) => test.tags.contains(tag),
+			Filter::MainRom => test.rom_variant().is_none_or(|(rom, _)| rom.is_none()),
+		}
+	}
+}
+
+/// One of several roughly equal parts of a [`Suite`], for splitting tests across machines.
+///
+/// Selected tests are dealt out to each shard in turn, so every shard runs a similar number
+/// of tests, and the same config always produces the same shards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Shard {
+	/// Which shard to run, starting from 0.
+	pub index: usize,
+	pub count: usize,
+}
+
+impl Shard {
+	/// Returns true if the `n`th selected test belongs to this shard.
+	#[must_use]
+	pub fn contains(&self, n: usize) -> bool {
+		n % self.count == self.index
+	}
+}
+
+impl FromStr for Shard {
+	type Err = Error;
+
+	/// Parses `K/N`, where `K` counts from 1.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || Error::InvalidShard(String::from(s));
+		let (k, n) = s.split_once('/').ok_or_else(invalid)?;
+		let k = k.trim().parse::<usize>().map_err(|_| invalid())?;
+		let n = n.trim().parse::<usize>().map_err(|_| invalid())?;
+		if k == 0 || k > n {
+			return Err(invalid());
+		}
+		Ok(Self {
+			index: k - 1,
+			count: n,
+		})
+	}
+}
+
+/// A collection of tests which are run together and summarized by a single [`Logger`].
+#[derive(Clone, Debug, Default)]
+pub struct Suite {
+	pub tests: Vec<TestConfig>,
+	pub filters: Vec<Filter>,
+	/// Number of threads to run tests on. Output from different tests may interleave when above 1.
+	pub jobs: usize,
+	/// Stop running tests once this many have failed.
+	/// Tests which are already running on other threads are allowed to finish.
+	pub max_failures: Option<u32>,
+	/// Only run this part of the selected tests.
+	pub shard: Option<Shard>,
+	/// Run the selected tests in a random order, determined by this seed.
+	pub shuffle: Option<u64>,
+	/// Paths to IPS or BPS patches applied to every ROM the tests run on,
+	/// before any patches of the tests' own.
+	pub patches: Vec<String>,
+}
+
+impl Suite {
+	#[must_use]
+	pub fn new(tests: Vec<TestConfig>) -> Self {
+		Self {
+			tests,
+			filters: Vec::new(),
+			jobs: 1,
+			max_failures: None,
+			shard: None,
+			shuffle: None,
+			patches: Vec::new(),
+		}
+	}
+
+	#[must_use]
+	pub fn with_filter(mut self, filter: Filter) -> Self {
+		self.filters.push(filter);
+		self
+	}
+
+	#[must_use]
+	pub fn with_jobs(mut self, jobs: usize) -> Self {
+		self.jobs = jobs;
+		self
+	}
+
+	#[must_use]
+	pub fn with_max_failures(mut self, max_failures: u32) -> Self {
+		self.max_failures = Some(max_failures);
+		self
+	}
+
+	#[must_use]
+	pub fn with_shard(mut self, shard: Shard) -> Self {
+		self.shard = Some(shard);
+		self
+	}
+
+	#[must_use]
+	pub fn with_shuffle(mut self, seed: u64) -> Self {
+		self.shuffle = Some(seed);
+		self
+	}
+
+	#[must_use]
+	pub fn with_patch(mut self, path: impl Into<String>) -> Self {
+		self.patches.push(path.into());
+		self
+	}
+
+	/// Returns true if `test` passes this suite's filters.
+	///
+	/// This does not account for sharding, which depends on the other selected tests.
+	#[must_use]
+	pub fn is_selected(&self, test: &TestConfig) -> bool {
+		let any = |kind: fn(&Filter) -> bool| {
+			let mut filters = self.filters.iter().filter(|filter| kind(filter)).peekable();
+			filters.peek().is_none() || filters.any(|filter| filter.matches(test))
+		};
+		any(|filter| matches!(filter, Filter::Name(_) | Filter::Glob(_)))
+			&& any(|filter| matches!(filter, Filter::Exact(_)))
+			&& any(|filter| matches!(filter, Filter::Group(_)))
+			&& any(|filter| matches!(filter, Filter::Tag(_)))
+			&& any(|filter| matches!(filter, Filter::MainRom))
+			&& !self
+				.filters
+				.iter()
+				.any(|filter| matches!(filter, Filter::SkipTag(_)) && filter.matches(test))
+	}
+
+	/// Iterates over the tests which pass this suite's filters and belong to its shard,
+	/// in the order they will be run.
+	///
+	/// Shuffling happens after sharding, so the seed does not change which tests are in each shard.
+	pub fn selected(&self) -> impl Iterator<Item = &TestConfig> {
+		let mut tests = self
+			.tests
+			.iter()
+			.filter(|test| self.is_selected(test))
+			.enumerate()
+			.filter(|(n, _)| self.shard.is_none_or(|shard| shard.contains(*n)))
+			.map(|(_, test)| test)
+			.collect::<Vec<&TestConfig>>();
+		if let Some(seed) = self.shuffle {
+			fastrand::Rng::with_seed(seed).shuffle(&mut tests);
+		}
+		tests.into_iter()
+	}
+
+	/// Iterates over the names of each group in this suite, in the order they first appear.
+	pub fn groups(&self) -> impl Iterator<Item = &str> {
+		let mut seen = Vec::new();
+		self.tests.iter().filter_map(move |test| {
+			let group = test.group.as_deref()?;
+			if seen.contains(&group) {
+				None
+			} else {
+				seen.push(group);
+				Some(group)
+			}
+		})
+	}
+
+	/// Runs each selected test on `rom`, logging results to `logger`.
+	///
+	/// `on_finish` is called after each test with its final state and whether it passed,
+	/// possibly from several threads at once. It is not called for skipped tests.
+	///
+	/// Each `setup` routine used by the selected tests runs first, once, and is reported like a test.
+	/// Tests whose setup fails are skipped.
+	/// Tests which run `after` another test wait for it to finish, and are skipped if it fails;
+	/// the other test is run even if it wasn't selected.
+	///
+	/// Returns false if some tests were not run because `max_failures` was reached.
+	pub fn run<F>(&self, rom: &[u8], logger: &mut Logger<'_>, on_finish: F) -> bool
+	where
+		F: Fn(&TestConfig, &cpu::State<AddressSpace<'_>>, bool) + Sync,
+	{
+		let rom = &self
+			.patches
+			.iter()
+			.fold(rom.to_vec(), |rom, path| patch_rom(&rom, path));
+		let tests = self.with_prerequisites(self.selected().collect());
+		let setups = setups(&tests);
+		let test_roms = open_test_roms(
+			rom,
+			&self.patches,
+			tests
+				.iter()
+				.copied()
+				.filter(|test| test.skip.is_none())
+				.chain(setups.iter().map(AsRef::as_ref)),
+		);
+		let address_space = |test: &TestConfig| match test.rom_variant() {
+			Some(variant) => AddressSpace::with(&test_roms[&variant]),
+			None => AddressSpace::with(rom),
+		};
+
+		let failures = AtomicU32::new(0);
+		let stopped = AtomicBool::new(false);
+
+		// Setups run before any tests, since every test using one starts from its final state.
+		let baselines = setups
+			.into_iter()
+			.map(|setup| {
+				let mut cpu_state = cpu::State::new(address_space(setup));
+				let passed = setup.run(&mut cpu_state, &mut logger.make_test(setup));
+				if !passed {
+					failures.fetch_add(1, Ordering::Relaxed);
+				}
+				let baseline = passed.then(|| Arc::new(savestate::capture(&cpu_state)));
+				(setup, baseline)
+			})
+			.collect::<Vec<(&Arc<TestConfig>, Option<Arc<LoadedState>>)>>();
+		// The final states of tests which others run `after`, once they pass.
+		let needed = tests
+			.iter()
+			.filter_map(|test| Some((test.group.as_deref(), test.after.as_deref()?)))
+			.collect::<HashSet<_>>();
+		let states = Mutex::new(HashMap::<(Option<String>, String), Arc<LoadedState>>::new());
+
+		let run_chunk = |tests: &[&TestConfig], logger: &mut Logger<'_>| {
+			for &test in tests {
+				if self
+					.max_failures
+					.is_some_and(|max_failures| failures.load(Ordering::Relaxed) >= max_failures)
+				{
+					stopped.store(true, Ordering::Relaxed);
+					return;
+				}
+				if let Some(reason) = &test.skip {
+					logger.make_test(test).skip(reason);
+					continue;
+				}
+				let baseline = if let Some(after) = &test.after {
+					let state = lock(&states)
+						.get(&(test.group.clone(), after.clone()))
+						.cloned();
+					let Some(state) = state else {
+						logger
+							.make_test(test)
+							.skip(&format!("{after} did not pass"));
+						continue;
+					};
+					Some(state)
+				} else if let Some(setup) = &test.setup {
+					let baseline = baselines
+						.iter()
+						.find(|(other, _)| Arc::ptr_eq(other, setup))
+						.and_then(|(_, baseline)| baseline.clone());
+					let Some(baseline) = baseline else {
+						logger.make_test(test).skip("setup failed");
+						continue;
+					};
+					Some(baseline)
+				} else {
+					None
+				};
+				let test = match baseline {
+					Some(baseline) => {
+						let mut test = test.clone();
+						test.baseline = Some(baseline);
+						Cow::Owned(test)
+					}
+					None => Cow::Borrowed(test),
+				};
+				let mut cpu_state = cpu::State::new(address_space(&test));
+				let mut test_logger = logger.make_test(&test);
+				let passed = test.run(&mut cpu_state, &mut test_logger);
+				if !passed {
+					failures.fetch_add(1, Ordering::Relaxed);
+				}
+				if passed && needed.contains(&(test.group.as_deref(), test.name.as_str())) {
+					let key = (test.group.clone(), test.name.clone());
+					lock(&states).insert(key, Arc::new(savestate::capture(&cpu_state)));
+				}
+				on_finish(&test, &cpu_state, passed);
+			}
+		};
+
+		for wave in waves(tests) {
+			self.run_chunks(&wave, logger, &run_chunk);
+		}
+		!stopped.into_inner()
+	}
+
+	/// Adds the tests which the given tests run `after`, and so on, if they weren't already included.
+	fn with_prerequisites<'t>(&'t self, mut tests: Vec<&'t TestConfig>) -> Vec<&'t TestConfig> {
+		let mut index = 0;
+		while let Some(test) = tests.get(index) {
+			index += 1;
+			let Some(after) = &test.after else {
+				continue;
+			};
+			let prerequisite = self
+				.tests
+				.iter()
+				.find(|other| other.group == test.group && other.name == *after);
+			if let Some(prerequisite) = prerequisite {
+				if !tests.iter().any(|other| ptr::eq(*other, prerequisite)) {
+					tests.push(prerequisite);
+				}
+			}
+		}
+		tests
+	}
+
+	/// Runs `tests` with `run_chunk`, split across `jobs` threads.
+	fn run_chunks<R>(&self, tests: &[&TestConfig], logger: &mut Logger<'_>, run_chunk: &R)
+	where
+		R: Fn(&[&TestConfig], &mut Logger<'_>) + Sync,
+	{
+		if self.jobs <= 1 || tests.len() <= 1 {
+			run_chunk(tests, logger);
+			return;
+		}
+
+		let chunk_size = tests.len().div_ceil(self.jobs);
+		let chunk_loggers = thread::scope(|scope| {
+			tests
+				.chunks(chunk_size)
+				.map(|chunk| {
+					let mut chunk_logger = logger.fork();
+					scope.spawn(move || {
+						run_chunk(chunk, &mut chunk_logger);
+						chunk_logger
+					})
+				})
+				.collect::<Vec<_>>()
+				.into_iter()
+				.map(|handle| {
+					handle
+						.join()
+						.unwrap_or_else(|panic| panic::resume_unwind(panic))
+				})
+				.collect::<Vec<_>>()
+		});
+		for chunk_logger in chunk_loggers {
+			logger.merge(chunk_logger);
+		}
+	}
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Splits tests into groups which run one after another,
+/// so that each test runs in a later group than the test it runs `after`.
+fn waves(mut tests: Vec<&TestConfig>) -> Vec<Vec<&TestConfig>> {
+	let mut waves = Vec::new();
+	let mut finished = HashSet::new();
+	while !tests.is_empty() {
+		let (ready, waiting): (Vec<&TestConfig>, Vec<&TestConfig>) =
+			tests.into_iter().partition(|test| {
+				test.after
+					.as_ref()
+					.is_none_or(|after| finished.contains(&(test.group.as_deref(), after.as_str())))
+			});
+		if ready.is_empty() {
+			// The rest wait on each other, or on tests which aren't in the suite, so they'll be skipped.
+			waves.push(waiting);
+			break;
+		}
+		finished.extend(
+			ready
+				.iter()
+				.map(|test| (test.group.as_deref(), test.name.as_str())),
+		);
+		waves.push(ready);
+		tests = waiting;
+	}
+	waves
+}
+
+/// Each setup routine used by a test which isn't skipped, without repeats.
+fn setups<'t>(tests: &[&'t TestConfig]) -> Vec<&'t Arc<TestConfig>> {
+	let mut setups = Vec::<&Arc<TestConfig>>::new();
+	for setup in tests
+		.iter()
+		.filter(|test| test.skip.is_none())
+		.filter_map(|test| test.setup.as_ref())
+	{
+		if !setups.iter().any(|other| Arc::ptr_eq(other, setup)) {
+			setups.push(setup);
+		}
+	}
+	setups
+}
@@ -0,0 +1,204 @@
+//! RGBDS symfiles, as written by RGBLINK's `-n` option.
+//!
+//! Each line gives a symbol's bank and address, then its name:
+//!
+//! ```not_rust
+//! ; File generated by rgblink
+//! 00:0150 Main
+//! 01:4000 LoadLevel
+//! BOOT:0000 BootEntry
+//! ```
+//!
+//! Anything after `;` is a comment. Symbols in the boot ROM use `BOOT` in place of a bank,
+//! and some tools add their own tokens after the name, which are kept as the symbol's metadata.
+
+use crate::config::Symbols;
+use std::fmt;
+
+#[derive(Debug, thiserror::Error)]
+#[error("line {line}: {message}")]
+pub struct SymError {
+	pub line: usize,
+	pub message: String,
+}
+
+/// Where a symbol's address is mapped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Bank {
+	/// A ROM, WRAM, VRAM, or SRAM bank, or 0 for memory which isn't banked.
+	Number(u32),
+	/// The boot ROM, which is mapped over the cartridge until it finishes.
+	Boot,
+}
+
+impl fmt::Display for Bank {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Bank::Number(bank) => write!(f, "{bank:02X}"),
+			Bank::Boot => write!(f, "BOOT"),
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+	pub name: String,
+	pub bank: Bank,
+	pub address: u16,
+	/// Any tokens which followed the name, in order.
+	pub metadata: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymFile {
+	/// Every symbol, in the order they are listed.
+	pub symbols: Vec<Symbol>,
+}
+
+impl SymFile {
+	/// Reads every symbol in a symfile.
+	///
+	/// # Errors
+	///
+	/// Returns every line which could not be read.
+	pub fn parse(text: &str) -> Result<Self, Vec<SymError>> {
+		let mut symbols = Vec::new();
+		let mut errors = Vec::new();
+		for (index, line) in text.lines().enumerate() {
+			match parse_line(line) {
+				Some(Ok(symbol)) => symbols.push(symbol),
+				Some(Err(message)) => errors.push(SymError {
+					line: index + 1,
+					message,
+				}),
+				None => {}
+			}
+		}
+		if errors.is_empty() {
+			Ok(Self { symbols })
+		} else {
+			Err(errors)
+		}
+	}
+
+	/// Every symbol in the boot ROM.
+	pub fn boot(&self) -> impl Iterator<Item = &Symbol> {
+		self.symbols
+			.iter()
+			.filter(|symbol| symbol.bank == Bank::Boot)
+	}
+
+	/// The bank and address of every symbol outside the boot ROM, keyed by name.
+	#[must_use]
+	pub fn symbols(&self) -> Symbols {
+		self.symbols
+			.iter()
+			.filter_map(|symbol| match symbol.bank {
+				Bank::Number(bank) => Some((symbol.name.clone(), (bank, symbol.address))),
+				Bank::Boot => None,
+			})
+			.collect()
+	}
+}
+
+/// Reads a line such as `01:4000 LoadLevel`, returning `None` if it is empty or a comment.
+fn parse_line(line: &str) -> Option<Result<Symbol, String>> {
+	let line = line.split_once(';').map_or(line, |(line, _)| line);
+	let mut tokens = line.split_whitespace();
+	let location = tokens.next()?;
+	let Some(name) = tokens.next() else {
+		return Some(Err(format!("Missing a name after `{location}`")));
+	};
+	let Some((bank, address)) = location.split_once(':') else {
+		return Some(Err(format!("Expected `BANK:ADDRESS`, found `{location}`")));
+	};
+	let bank = if bank.eq_ignore_ascii_case("BOOT") {
+		Bank::Boot
+	} else if let Ok(bank) = u32::from_str_radix(bank, 16) {
+		Bank::Number(bank)
+	} else {
+		return Some(Err(format!("Invalid bank `{bank}`")));
+	};
+	let Ok(address) = u16::from_str_radix(address, 16) else {
+		return Some(Err(format!("Invalid address `{address}`")));
+	};
+	Some(Ok(Symbol {
+		name: String::from(name),
+		bank,
+		address,
+		metadata: tokens.map(String::from).collect(),
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn banks_and_boot() {
+		let sym = SymFile::parse(
+			"00:0150 Main\n1a:4000 LoadLevel\nBOOT:0000 BootEntry\nboot:00fc BootLogo\n",
+		)
+		.unwrap();
+		let banks = sym
+			.symbols
+			.iter()
+			.map(|symbol| (symbol.bank, symbol.address))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			banks,
+			[
+				(Bank::Number(0), 0x0150),
+				(Bank::Number(0x1A), 0x4000),
+				(Bank::Boot, 0x0000),
+				(Bank::Boot, 0x00FC),
+			]
+		);
+		let boot = sym
+			.boot()
+			.map(|symbol| symbol.name.as_str())
+			.collect::<Vec<&str>>();
+		assert_eq!(boot, ["BootEntry", "BootLogo"]);
+		// Boot ROM symbols can't be looked up by name, since they overlap the cartridge.
+		let symbols = sym.symbols();
+		assert_eq!(symbols.len(), 2);
+		assert_eq!(symbols["LoadLevel"], (0x1A, 0x4000));
+		assert_eq!(Bank::Boot.to_string(), "BOOT");
+		assert_eq!(Bank::Number(0x1A).to_string(), "1A");
+	}
+
+	#[test]
+	fn metadata() {
+		let sym = SymFile::parse("01:4000 Table size=16 data\n").unwrap();
+		assert_eq!(sym.symbols[0].name, "Table");
+		assert_eq!(sym.symbols[0].metadata, ["size=16", "data"]);
+	}
+
+	#[test]
+	fn comments() {
+		let text = "; File generated by rgblink\n\n00:0150 Main ; entry point\n   ; indented\n";
+		let sym = SymFile::parse(text).unwrap();
+		assert_eq!(sym.symbols.len(), 1);
+		assert!(sym.symbols[0].metadata.is_empty());
+	}
+
+	#[test]
+	fn invalid_lines() {
+		let text = "00:0150\n0150 Main\nZZ:0150 Main\n00:XYZW Main\n00:10000 Main\n00:0150 Main\n";
+		let errors = SymFile::parse(text)
+			.unwrap_err()
+			.iter()
+			.map(ToString::to_string)
+			.collect::<Vec<String>>();
+		assert_eq!(
+			errors,
+			[
+				"line 1: Missing a name after `00:0150`",
+				"line 2: Expected `BANK:ADDRESS`, found `0150`",
+				"line 3: Invalid bank `ZZ`",
+				"line 4: Invalid address `XYZW`",
+				"line 5: Invalid address `10000`",
+			]
+		);
+	}
+}
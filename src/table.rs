@@ -0,0 +1,230 @@
+//! Generation of tests from tables of inputs and expected outputs.
+
+use crate::memory::Peek;
+use crate::registers::{CompareSource, Registers, Value};
+use crate::test::TestConfig;
+use gb_cpu_sim::cpu;
+use std::fmt;
+
+/// A register, flag, or byte of memory which a column of a [`TruthTable`] refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+	A,
+	B,
+	C,
+	D,
+	E,
+	H,
+	L,
+	/// Flags are set when their value is not 0.
+	Zf,
+	Nf,
+	Hf,
+	Cf,
+	Bc,
+	De,
+	Hl,
+	Pc,
+	Sp,
+	Address(u16),
+}
+
+impl fmt::Display for Location {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Location::A => write!(f, "a"),
+			Location::B => write!(f, "b"),
+			Location::C => write!(f, "c"),
+			Location::D => write!(f, "d"),
+			Location::E => write!(f, "e"),
+			Location::H => write!(f, "h"),
+			Location::L => write!(f, "l"),
+			Location::Zf => write!(f, "f.z"),
+			Location::Nf => write!(f, "f.n"),
+			Location::Hf => write!(f, "f.h"),
+			Location::Cf => write!(f, "f.c"),
+			Location::Bc => write!(f, "bc"),
+			Location::De => write!(f, "de"),
+			Location::Hl => write!(f, "hl"),
+			Location::Pc => write!(f, "pc"),
+			Location::Sp => write!(f, "sp"),
+			Location::Address(address) => write!(f, "[{address:X}]"),
+		}
+	}
+}
+
+impl Location {
+	/// Sets this location in `registers` to `value`.
+	///
+	/// # Panics
+	///
+	/// Panics if `value` does not fit in an 8-bit location.
+	pub fn set(self, registers: &mut Registers, value: u16) {
+		let byte = || {
+			u8::try_from(value)
+				.unwrap_or_else(|_| panic!("0x{value:X} is too large to be stored in {self}"))
+		};
+		match self {
+			Location::A => registers.a = Some(byte()),
+			Location::B => registers.b = Some(byte()),
+			Location::C => registers.c = Some(byte()),
+			Location::D => registers.d = Some(byte()),
+			Location::E => registers.e = Some(byte()),
+			Location::H => registers.h = Some(byte()),
+			Location::L => registers.l = Some(byte()),
+			Location::Zf => registers.zf = Some(value != 0),
+			Location::Nf => registers.nf = Some(value != 0),
+			Location::Hf => registers.hf = Some(value != 0),
+			Location::Cf => registers.cf = Some(value != 0),
+			Location::Bc => registers.bc = Some(value),
+			Location::De => registers.de = Some(value),
+			Location::Hl => registers.hl = Some(value),
+			Location::Pc => registers.pc = Some(value),
+			Location::Sp => registers.sp = Some(value),
+			Location::Address(address) => registers.memory.push((address, byte())),
+		}
+	}
+
+	/// Reads this location from the CPU.
+	pub fn read<S: Peek>(self, cpu: &cpu::State<S>) -> Value {
+		match self {
+			Location::A => Value::Byte(cpu.a),
+			Location::B => Value::Byte(cpu.b),
+			Location::C => Value::Byte(cpu.c),
+			Location::D => Value::Byte(cpu.d),
+			Location::E => Value::Byte(cpu.e),
+			Location::H => Value::Byte(cpu.h),
+			Location::L => Value::Byte(cpu.l),
+			Location::Zf => Value::Flag(cpu.f.get_z()),
+			Location::Nf => Value::Flag(cpu.f.get_n()),
+			Location::Hf => Value::Flag(cpu.f.get_h()),
+			Location::Cf => Value::Flag(cpu.f.get_c()),
+			Location::Bc => Value::Word(cpu.get_bc()),
+			Location::De => Value::Word(cpu.get_de()),
+			Location::Hl => Value::Word(cpu.get_hl()),
+			Location::Pc => Value::Word(cpu.pc),
+			Location::Sp => Value::Word(cpu.sp),
+			Location::Address(address) => Value::Byte(cpu.address_space.peek(address)),
+		}
+	}
+
+	/// Returns true if this location holds 16 bits, rather than 8 or a single flag.
+	#[must_use]
+	pub fn is_word(self) -> bool {
+		matches!(
+			self,
+			Location::Bc | Location::De | Location::Hl | Location::Pc | Location::Sp
+		)
+	}
+
+	/// Describes this location in a comparison's results.
+	#[must_use]
+	pub fn source(self) -> CompareSource {
+		CompareSource::Register(match self {
+			Location::A => "a",
+			Location::B => "b",
+			Location::C => "c",
+			Location::D => "d",
+			Location::E => "e",
+			Location::H => "h",
+			Location::L => "l",
+			Location::Zf => "f.z",
+			Location::Nf => "f.n",
+			Location::Hf => "f.h",
+			Location::Cf => "f.c",
+			Location::Bc => "bc",
+			Location::De => "de",
+			Location::Hl => "hl",
+			Location::Pc => "pc",
+			Location::Sp => "sp",
+			Location::Address(address) => return CompareSource::Address(address),
+		})
+	}
+}
+
+/// Describes how rows of inputs and expected outputs become tests.
+///
+/// Each generated test is a copy of `template`, with the inputs added to its initial state
+/// and the outputs added to its expected result.
+#[derive(Clone, Debug)]
+pub struct TruthTable {
+	/// The test which every row is based on. Its name is used as a prefix for each test's name.
+	pub template: TestConfig,
+	pub inputs: Vec<Location>,
+	pub outputs: Vec<Location>,
+}
+
+impl TruthTable {
+	#[must_use]
+	pub fn new(template: TestConfig) -> Self {
+		Self {
+			template,
+			inputs: Vec::new(),
+			outputs: Vec::new(),
+		}
+	}
+
+	#[must_use]
+	pub fn with_inputs(mut self, inputs: impl IntoIterator<Item = Location>) -> Self {
+		self.inputs.extend(inputs);
+		self
+	}
+
+	#[must_use]
+	pub fn with_outputs(mut self, outputs: impl IntoIterator<Item = Location>) -> Self {
+		self.outputs.extend(outputs);
+		self
+	}
+
+	/// Creates a test for each row of `(inputs, outputs)`, named after its inputs.
+	///
+	/// # Panics
+	///
+	/// Panics if a row has a different number of values than this table has locations,
+	/// or if a value does not fit in its location.
+	pub fn tests<I, O>(&self, rows: impl IntoIterator<Item = (I, O)>) -> Vec<TestConfig>
+	where
+		I: AsRef<[u16]>,
+		O: AsRef<[u16]>,
+	{
+		rows.into_iter()
+			.map(|(inputs, outputs)| self.test(inputs.as_ref(), outputs.as_ref()))
+			.collect()
+	}
+
+	fn test(&self, inputs: &[u16], outputs: &[u16]) -> TestConfig {
+		assert_eq!(
+			inputs.len(),
+			self.inputs.len(),
+			"Row has {} inputs, but the table has {}",
+			inputs.len(),
+			self.inputs.len()
+		);
+		assert_eq!(
+			outputs.len(),
+			self.outputs.len(),
+			"Row has {} outputs, but the table has {}",
+			outputs.len(),
+			self.outputs.len()
+		);
+
+		let mut test = self.template.clone();
+		let name = self
+			.inputs
+			.iter()
+			.zip(inputs)
+			.map(|(location, value)| format!("{location}=0x{value:02X}"))
+			.collect::<Vec<String>>()
+			.join(" ");
+		test.name = format!("{} {name}", test.name);
+
+		for (location, value) in self.inputs.iter().zip(inputs) {
+			location.set(&mut test.initial, *value);
+		}
+		let result = test.result.get_or_insert_with(Registers::new);
+		for (location, value) in self.outputs.iter().zip(outputs) {
+			location.set(result, *value);
+		}
+		test
+	}
+}
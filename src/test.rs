@@ -1,15 +1,46 @@
-use gb_cpu_sim::{cpu, memory};
+use gb_cpu_sim::cpu;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::{fmt, iter};
 
+use crate::charmap::Charmap;
+use crate::condition::Condition;
+use crate::convention::{Arg, CallingConvention};
+use crate::coverage::Coverage;
+use crate::debugfile::DebugPoint;
+use crate::expression::Initial;
+use crate::invariant::Invariant;
 use crate::log::TestLogger;
-use crate::registers::Registers;
+use crate::mapfile::Section;
+use crate::memory::{self, Access, AccessKind, Inspect, RamFill};
+use crate::reg::HwReg;
+use crate::registers::{flags_byte, CompareResult, Registers};
+use crate::report::{Span, Step};
+use crate::savestate::LoadedState;
+use crate::{disasm, is_patch, Error};
 
 #[derive(Debug, Clone)]
 // Necessary for backwards compat.
 // TODO: Correct this for next major release.
 #[allow(clippy::module_name_repetitions)]
+// Each flag is an independent option, rather than a state.
+#[allow(clippy::struct_excessive_bools)]
 pub struct TestConfig {
 	/// Test name. Important for diagnosing which test has failed.
 	pub name: String,
+	/// Name of the group this test belongs to, such as the file it was loaded from.
+	pub group: Option<String>,
+	/// Labels used to select tests from a [`crate::suite::Suite`].
+	pub tags: Vec<String>,
+	/// The test is known to fail. Failing counts as a pass, and passing counts as a failure.
+	pub expect_failure: bool,
+	/// When set, the test is reported as skipped instead of being run.
+	/// Contains the reason it was skipped, which may be empty.
+	pub skip: Option<String>,
 
 	/// The address from which the test function is "called".
 	/// Used to determine when a test should end.
@@ -23,21 +54,226 @@ pub struct TestConfig {
 	/// Enables printing of debug info on `ld b, b` and `ld d, d` opcodes.
 	pub enable_breakpoints: bool,
 	/// The test will automatically fail after this many cycles.
-	pub timeout: usize,
+	pub timeout: u64,
 
 	/// The initial state of the CPU's registers
 	pub initial: Registers,
 	/// The final expected state of the CPU, if any.
 	pub result: Option<Registers>,
+	/// The number of cycles the test must finish within, if it is performance-critical.
+	pub cycles: Option<RangeInclusive<u64>>,
+	/// The number of times each routine must be called by `call` or `rst`, as (name, address, count).
+	pub calls: Vec<(String, u16, u32)>,
+	/// States which must be matched the first time the test reaches an address, as (name, address, expected).
+	pub checkpoints: Vec<(String, u16, Registers)>,
+	/// Further calls made after the test's own, one after another, against the same machine.
+	pub steps: Vec<TestStep>,
+	/// Conditions which must hold after every instruction. The test fails as soon as one does not.
+	pub invariants: Vec<Invariant>,
+	/// Assertions and messages which run when `pc` reaches their address, usually read from a debug file.
+	pub debug_points: Vec<DebugPoint>,
+	/// Records every instruction the test executes, which is reported if it fails.
+	pub trace: bool,
+	/// Records when each subroutine is called and returns, which is included in the test's report.
+	pub profile: bool,
+	/// Counts how many times each ROM address is executed, which is added to the logger's coverage.
+	pub coverage: bool,
+	/// Counts the reads and writes made to each address, which are included in the test's report.
+	pub heat_map: bool,
+	/// Addresses whose reads and writes are recorded, and shown if the test fails.
+	pub watch: Vec<u16>,
+	/// Additional checks which must pass once the test has finished.
+	pub conditions: Vec<Arc<dyn Condition>>,
 	/// List of values pushed to stack before pushing caller address to stack and running test
 	pub stack: Vec<u8>,
+	/// Arguments placed according to `calling_convention` before the test runs.
+	/// Any passed on the stack are pushed before `stack`.
+	pub args: Vec<Arg>,
+	pub calling_convention: CallingConvention,
+	/// The value the routine must return, found according to `calling_convention`.
+	pub returns: Option<Arg>,
+	/// What to do when `stack` or the caller address would be pushed outside of WRAM and HRAM,
+	/// usually because the initial `sp` is wrong.
+	pub stack_check: Policy,
+	/// Path to a ROM which this test runs on instead of the main ROM.
+	pub rom: Option<String>,
+	/// Path to an alternate ROM which this test runs on instead of the main ROM.
+	/// IPS and BPS patches are applied to the main ROM (or `rom`) instead.
+	pub overlay: Option<String>,
+	/// Paths to IPS or BPS patches applied to the ROM before running this test.
+	pub patches: Vec<String>,
+	/// The ROM bank mapped to 0x4000-0x7FFF when the test begins.
+	pub bank: Option<usize>,
+	/// Addresses of routines which are allowed to switch ROM banks.
+	/// A trampoline remains active until it returns.
+	pub trampolines: Vec<u16>,
+	/// What to do when code runs from a different ROM bank than expected,
+	/// because the bank was switched outside of a trampoline.
+	pub bank_check: Policy,
+	/// The contents of VRAM, WRAM, and HRAM when the test begins, before any other memory is set.
+	pub ram_fill: RamFill,
+	/// Regions of memory, as (address, length), which must not be read before they are written to.
+	/// Their contents are replaced with [`crate::memory::POISON`] before the test begins.
+	pub poison: Vec<(u16, u16)>,
+	/// Regions of memory, as (address, length), which must not be read.
+	pub forbid_read: Vec<(u16, u16)>,
+	/// Regions of memory, as (address, length), which must not be written to.
+	pub forbid_write: Vec<(u16, u16)>,
+	/// Regions of memory, as (address, length), which must not be executed.
+	pub forbid_exec: Vec<(u16, u16)>,
+	/// Sections of ROM which hold data rather than code, and must not be executed.
+	pub data_sections: Vec<Section>,
+	/// Regions of memory, as (address, length), which are compared to a snapshot once the test finishes.
+	/// See [`crate::snapshot::Snapshot`], which is added to `conditions` for them.
+	pub snapshot: Vec<(u16, u16)>,
+	/// How the game stores text, for strings in memory and debug messages. `None` uses ASCII.
+	pub charmap: Option<Arc<Charmap>>,
+	/// Fails the test when it writes to ROM and the cartridge header says there is no MBC.
+	pub strict_rom: bool,
+	/// Models `halt` with interrupts disabled: if an interrupt is pending, `halt` doesn't halt,
+	/// and the halt bug reads the byte after it twice. Otherwise `halt` always ends the test.
+	pub accurate_halt: bool,
+	/// Fails the test when it raises any warnings, even if it would otherwise pass.
+	pub warnings_as_errors: bool,
+	/// When the test's memory is dumped, overriding the command line. `None` follows the command line.
+	pub dump: Option<Dump>,
+	/// A routine which runs once before every test from the same config, leaving the state they begin from.
+	/// [`crate::suite::Suite`] runs it and sets `baseline` to its final state.
+	pub setup: Option<Arc<TestConfig>>,
+	/// Registers and memory to set before `initial`, such as those left by `setup`.
+	pub baseline: Option<Arc<LoadedState>>,
+	/// The name of another test in the same group whose final state this test starts from,
+	/// instead of starting from `setup`. [`crate::suite::Suite`] runs that test first.
+	pub after: Option<String>,
+	/// Files read while parsing the test, such as its config, `data` table, memory files, and scripts,
+	/// so that `--watch` can re-run it when they change. Its ROM and patches are not included.
+	pub inputs: Vec<String>,
 }
 
-#[derive(PartialEq, Eq)]
+/// When a test's memory is dumped once it finishes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dump {
+	Never,
+	OnFailure,
+	Always,
+}
+
+impl Dump {
+	#[must_use]
+	pub fn applies(self, passed: bool) -> bool {
+		match self {
+			Dump::Never => false,
+			Dump::OnFailure => !passed,
+			Dump::Always => true,
+		}
+	}
+}
+
+/// How a potential problem should be handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+	#[default]
+	Ignore,
+	/// Print a warning, but allow the test to pass.
+	Warn,
+	Fail,
+}
+
+/// Identifies a modified ROM: an optional replacement for the main ROM, followed by a list of patches.
+pub type RomVariant = (Option<String>, Vec<String>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum FailureReason {
 	Crash,
 	InvalidOpcode,
 	Timeout,
+	/// The test finished, but its result did not match what was expected.
+	Incorrect,
+	/// Code ran from a ROM bank which was switched to outside of a trampoline.
+	WrongBank,
+	/// Poisoned memory at this address was read before being written to.
+	PoisonedRead(u16),
+	/// The initial stack would be pushed to this address, which is outside of WRAM and HRAM.
+	InvalidStack(u16),
+	/// The instruction at this address broke one of the test's invariants.
+	BrokenInvariant(u16),
+	/// An assertion from a debug file failed at this address.
+	FailedAssertion(u16),
+	/// A forbidden address was read.
+	ForbiddenRead(u16),
+	/// A forbidden address was written to.
+	ForbiddenWrite(u16),
+	/// A forbidden address was executed.
+	ForbiddenExec(u16),
+	/// An address in one of the test's data sections was executed.
+	ExecutedData(u16),
+	/// This ROM bank was to be mapped when the test began, but the cartridge has no MBC to switch banks.
+	NoMbc(usize),
+	/// The instruction at the first address wrote to ROM at the second, but the cartridge has no MBC.
+	RomWrite(u16, u16),
+	/// The test raised warnings, which were treated as errors.
+	Warned,
+	/// The test was expected to fail, but passed.
+	UnexpectedPass,
+}
+
+impl FailureReason {
+	/// Describes the failure, naming addresses with `name`.
+	pub fn describe(&self, name: impl Fn(u16) -> String) -> String {
+		match self {
+			FailureReason::InvalidOpcode => String::from("Invalid opcode"),
+			FailureReason::Crash => String::from("Crashed"),
+			FailureReason::Timeout => String::from("Timeout"),
+			FailureReason::Incorrect => String::from("Incorrect result"),
+			FailureReason::WrongBank => String::from("Executed code from the wrong ROM bank"),
+			FailureReason::PoisonedRead(address) => {
+				format!("Read poisoned memory at {}", name(*address))
+			}
+			FailureReason::InvalidStack(address) => {
+				format!(
+					"Initial stack pushes to {}, outside of WRAM and HRAM",
+					name(*address)
+				)
+			}
+			FailureReason::BrokenInvariant(address) => {
+				format!("Invariant broken by the instruction at {}", name(*address))
+			}
+			FailureReason::FailedAssertion(address) => {
+				format!("Assertion failed at {}", name(*address))
+			}
+			FailureReason::ForbiddenRead(address) => {
+				format!("Read forbidden memory at {}", name(*address))
+			}
+			FailureReason::ForbiddenWrite(address) => {
+				format!("Wrote to forbidden memory at {}", name(*address))
+			}
+			FailureReason::ForbiddenExec(address) => {
+				format!("Executed forbidden memory at {}", name(*address))
+			}
+			FailureReason::ExecutedData(address) => {
+				format!("Executed data at {}", name(*address))
+			}
+			FailureReason::NoMbc(bank) => {
+				format!("Cannot map bank {bank}, since the cartridge header says there is no MBC")
+			}
+			FailureReason::RomWrite(pc, address) => format!(
+				"Wrote to ROM at {} from {}, but the cartridge has no MBC",
+				name(*address),
+				name(*pc)
+			),
+			FailureReason::Warned => String::from("Warnings were treated as errors"),
+			FailureReason::UnexpectedPass => String::from("Passed, but was expected to fail"),
+		}
+	}
+}
+
+impl fmt::Display for FailureReason {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let description = self.describe(|address| format!("0x{address:04x}"));
+		write!(f, "{description}")
+	}
 }
 
 impl TestConfig {
@@ -45,6 +281,10 @@ impl TestConfig {
 	pub fn new(name: String) -> Self {
 		Self {
 			name,
+			group: None,
+			tags: vec![],
+			expect_failure: false,
+			skip: None,
 			caller_address: 0xFFFF,
 			exit_addresses: vec![],
 			crash_addresses: vec![],
@@ -52,32 +292,373 @@ impl TestConfig {
 			timeout: 65536,
 			initial: Registers::new(),
 			result: None,
+			cycles: None,
+			calls: vec![],
+			checkpoints: vec![],
+			steps: vec![],
+			invariants: vec![],
+			debug_points: vec![],
+			trace: false,
+			profile: false,
+			coverage: false,
+			heat_map: false,
+			watch: vec![],
+			conditions: vec![],
 			stack: vec![],
+			args: vec![],
+			calling_convention: CallingConvention::default(),
+			returns: None,
+			stack_check: Policy::Ignore,
+			rom: None,
+			overlay: None,
+			patches: vec![],
+			bank: None,
+			trampolines: vec![],
+			bank_check: Policy::Ignore,
+			ram_fill: RamFill::default(),
+			poison: vec![],
+			forbid_read: vec![],
+			forbid_write: vec![],
+			forbid_exec: vec![],
+			data_sections: vec![],
+			snapshot: vec![],
+			charmap: None,
+			strict_rom: false,
+			accurate_halt: false,
+			warnings_as_errors: false,
+			dump: None,
+			setup: None,
+			baseline: None,
+			after: None,
+			inputs: vec![],
 		}
 	}
 
-	pub fn run<A: memory::AddressSpace>(
+	/// Returns the ROM and patches this test uses, or `None` if it runs on the main ROM.
+	///
+	/// An overlay ending in `.ips` or `.bps` becomes the first patch.
+	#[must_use]
+	pub fn rom_variant(&self) -> Option<RomVariant> {
+		let (rom, mut patches) = match &self.overlay {
+			Some(overlay) if is_patch(overlay) => (self.rom.clone(), vec![overlay.clone()]),
+			Some(overlay) => (Some(overlay.clone()), vec![]),
+			None => (self.rom.clone(), vec![]),
+		};
+		patches.extend(self.patches.iter().cloned());
+		if rom.is_none() && patches.is_empty() {
+			None
+		} else {
+			Some((rom, patches))
+		}
+	}
+
+	pub fn run<A: Inspect>(
 		&self,
 		cpu_state: &mut cpu::State<A>,
 		logger: &mut TestLogger<'_, '_>,
 	) -> bool {
+		let mut trace = Trace::new(self.checkpoints.len());
+		let mut step_errors = Vec::new();
+		let result = self
+			.prepare(cpu_state, &mut trace, logger)
+			.and_then(|()| {
+				if self.profile {
+					trace.profiler.enter(cpu_state, 0);
+				}
+				let executed = self.execute(cpu_state, &mut trace, logger);
+				trace.accesses = cpu_state.address_space.take_access_log();
+				executed
+			})
+			.and_then(|()| {
+				trace.result = self.compare_result(cpu_state, &trace);
+				if !trace.result.is_empty() {
+					return Ok(());
+				}
+				self.run_steps(cpu_state, &mut trace, &mut step_errors, logger)
+			});
+		logger.record_warnings(&cpu_state.address_space.take_warnings());
+		let result = match result {
+			Ok(()) if self.warnings_as_errors && logger.has_warnings() => {
+				Err(FailureReason::Warned)
+			}
+			result => result,
+		};
+		if let Err(FailureReason::Crash | FailureReason::InvalidOpcode | FailureReason::Timeout) =
+			result
+		{
+			logger.record_history(trace.history.iter().copied());
+		}
+		if self.trace {
+			logger.record_trace(mem::take(&mut trace.steps));
+		}
+		if self.profile {
+			logger.record_profile(trace.profiler.finish());
+		}
+		if self.coverage {
+			logger.record_coverage(&trace.coverage);
+		}
+		let accesses = mem::take(&mut trace.accesses);
+		if !self.watch.is_empty() {
+			let watched = accesses
+				.iter()
+				.filter(|access| self.watch.contains(&access.address))
+				.copied()
+				.collect();
+			logger.record_watched(watched);
+		}
+		if self.heat_map {
+			logger.record_heat_map(&accesses);
+		}
+		match result {
+			Err(failure_reason) if self.expect_failure => {
+				logger.expected_failure(failure_reason, None);
+				true
+			}
+			Err(failure_reason) => {
+				logger.failure(&failure_reason, cpu_state);
+				false
+			}
+			Ok(()) => self.check(cpu_state, step_errors, &mut trace, &accesses, logger),
+		}
+	}
+
+	/// Sets up the initial state of the machine.
+	fn prepare<A: Inspect>(
+		&self,
+		cpu_state: &mut cpu::State<A>,
+		trace: &mut Trace,
+		logger: &mut TestLogger<'_, '_>,
+	) -> Result<(), FailureReason> {
+		if let Some(bank) = self
+			.bank
+			.or(self.baseline.as_ref().and_then(|state| state.bank))
+		{
+			if bank != 1 && !memory::has_mbc(cpu_state.address_space.peek(0x147)) {
+				return Err(FailureReason::NoMbc(bank));
+			}
+			cpu_state.address_space.set_rom_bank(bank);
+		}
+		cpu_state.address_space.fill_ram(self.ram_fill);
+		if let Some(baseline) = &self.baseline {
+			baseline.registers.configure(cpu_state);
+		}
+		for (address, length) in &self.poison {
+			for offset in 0..*length {
+				cpu_state.address_space.poison(address.wrapping_add(offset));
+			}
+		}
 		self.initial.configure(cpu_state);
+		let mut args = Registers::new();
+		let stack_args = self.calling_convention.place(&self.args, &mut args);
+		args.configure(cpu_state);
+		// Expected values may be computed from the registers and memory the test sets, before anything is pushed.
+		let expected = iter::once(&self.result)
+			.flatten()
+			.chain(self.checkpoints.iter().map(|(_, _, expected)| expected));
+		trace.initial =
+			Initial::capture(cpu_state, expected.flat_map(Registers::initial_locations));
+		let stack = [stack_args.as_slice(), &self.stack].concat();
+
+		if self.stack_check != Policy::Ignore {
+			if let Some(address) = invalid_stack_address(cpu_state.sp, stack.len()) {
+				if self.stack_check == Policy::Fail {
+					return Err(FailureReason::InvalidStack(address));
+				}
+				logger.warn(&format!(
+					"Stack pointer 0x{:04x} pushes to 0x{address:04x}, outside of WRAM and HRAM",
+					cpu_state.sp
+				));
+			}
+		}
 
 		// Push stack values onto the stack (if any)
-		for value in self.stack.iter().rev() {
-			cpu_state.sp -= 1;
+		for value in stack.iter().rev() {
+			cpu_state.sp = cpu_state.sp.wrapping_sub(1);
 			cpu_state.write(cpu_state.sp, *value);
 		}
 
 		// Push the return address onto the stack.
-		cpu_state.write(cpu_state.sp - 1, (self.caller_address & 0xFF) as u8);
-		cpu_state.write(cpu_state.sp - 2, ((self.caller_address >> 8) & 0xFF) as u8);
-		cpu_state.sp -= 2;
+		let [high, low] = self.caller_address.to_be_bytes();
+		cpu_state.write(cpu_state.sp.wrapping_sub(1), low);
+		cpu_state.write(cpu_state.sp.wrapping_sub(2), high);
+		cpu_state.sp = cpu_state.sp.wrapping_sub(2);
+
+		// Neither the access log nor forbidden accesses include setting up the test.
+		if !self.conditions.is_empty() || !self.watch.is_empty() || self.heat_map {
+			cpu_state.address_space.enable_access_log();
+		}
+		let forbidden = [
+			(&self.forbid_read, AccessKind::Read),
+			(&self.forbid_write, AccessKind::Write),
+		];
+		for (regions, kind) in forbidden {
+			for (address, length) in regions {
+				for offset in 0..*length {
+					cpu_state
+						.address_space
+						.forbid(address.wrapping_add(offset), kind);
+				}
+			}
+		}
+		if self.strict_rom {
+			cpu_state.address_space.forbid_rom_writes();
+		}
+		// Setting up the test doesn't raise warnings; a bad stack is covered by `stack_check`.
+		cpu_state.address_space.take_warnings();
+		Ok(())
+	}
+
+	/// Runs each step after the test's own call, stopping at the first whose result does not match.
+	/// Each step's call is profiled like a call made by the test.
+	fn run_steps<A: Inspect>(
+		&self,
+		cpu_state: &mut cpu::State<A>,
+		trace: &mut Trace,
+		errors: &mut Vec<String>,
+		logger: &mut TestLogger<'_, '_>,
+	) -> Result<(), FailureReason> {
+		for step in &self.steps {
+			for (address, length) in &step.poison {
+				for offset in 0..*length {
+					cpu_state.address_space.poison(address.wrapping_add(offset));
+				}
+			}
+			step.initial.configure(cpu_state);
+			let initial = Initial::capture(
+				cpu_state,
+				step.result.iter().flat_map(Registers::initial_locations),
+			);
+			let [high, low] = self.caller_address.to_be_bytes();
+			cpu_state.write(cpu_state.sp.wrapping_sub(1), low);
+			cpu_state.write(cpu_state.sp.wrapping_sub(2), high);
+			cpu_state.sp = cpu_state.sp.wrapping_sub(2);
+			// Like the test's own setup, a step's setup is neither logged nor forbidden.
+			cpu_state.address_space.take_access_log();
+			cpu_state.address_space.clear_violations();
+			if self.profile {
+				trace.profiler.enter(cpu_state, trace.cycles);
+			}
+			let executed = self.execute(cpu_state, trace, logger);
+			trace
+				.accesses
+				.extend(cpu_state.address_space.take_access_log());
+			executed.inspect_err(|_| logger.warn(&format!("Failed during {}", step.name)))?;
+			let Some(result) = step
+				.result
+				.as_ref()
+				.map(|result| result.evaluate(&initial).mismatches(cpu_state))
+			else {
+				continue;
+			};
+			let differences = result.differences().iter().map(ToString::to_string);
+			errors.extend(
+				differences
+					.chain(result.conditions().iter().cloned())
+					.map(|message| format!("At {}: {message}", step.name)),
+			);
+			if !errors.is_empty() {
+				break;
+			}
+		}
+		Ok(())
+	}
+
+	/// Compares the CPU to any checkpoint at its current address which has not been reached before.
+	fn check_checkpoints<A: Inspect>(&self, cpu_state: &cpu::State<A>, trace: &mut Trace) {
+		let checkpoints = self.checkpoints.iter().zip(&mut trace.checkpoints);
+		for ((_, address, expected), result) in checkpoints {
+			if *address == cpu_state.pc && result.is_none() {
+				*result = Some(expected.evaluate(&trace.initial).mismatches(cpu_state));
+			}
+		}
+	}
+
+	/// Runs the debug points at `pc`, failing if any of their assertions do not hold.
+	fn check_debug_points<A: Inspect>(
+		&self,
+		cpu_state: &cpu::State<A>,
+		logger: &mut TestLogger<'_, '_>,
+	) -> Result<(), FailureReason> {
+		let points = self
+			.debug_points
+			.iter()
+			.filter(|point| point.address == cpu_state.pc);
+		for point in points {
+			match point.evaluate(cpu_state) {
+				Some((message, true)) => {
+					logger.warn(&message);
+					return Err(FailureReason::FailedAssertion(point.address));
+				}
+				Some((message, false)) => logger.message(message),
+				None => {}
+			}
+		}
+		Ok(())
+	}
+
+	/// Runs the CPU until the call exits or fails, adding the cycles and instructions it took to `trace`.
+	/// Calls are only counted if the test expects a number of calls.
+	fn execute<A: Inspect>(
+		&self,
+		cpu_state: &mut cpu::State<A>,
+		trace: &mut Trace,
+		logger: &mut TestLogger<'_, '_>,
+	) -> Result<(), FailureReason> {
+		let mut banks = BankTracker::new(cpu_state.address_space.rom_bank());
+		let mut cycles = CycleCounter::new(cpu_state.cycles_elapsed);
+		// Earlier calls count towards the test's totals, but not towards this call's timeout.
+		let start = trace.cycles;
+		let count_calls = !self.calls.is_empty() || self.profile;
+		let mut halt_bug = false;
+
+		loop {
+			let pc = cpu_state.pc;
+			cpu_state.address_space.set_pc(pc);
+			self.check_checkpoints(cpu_state, trace);
+			self.check_debug_points(cpu_state, logger)?;
+			trace.record(cpu_state, self);
+			if count_calls {
+				trace.calls.before(cpu_state);
+			}
+			if halt_bug {
+				cpu_state.address_space.set_halt_bug(Some(pc));
+			}
+			let tick = cpu_state.tick();
+			if halt_bug {
+				cpu_state.address_space.set_halt_bug(None);
+				undo_halt_bug(cpu_state, pc);
+				halt_bug = false;
+			}
+			let cycles_elapsed = cycles.update(cpu_state.cycles_elapsed);
+			trace.cycles = start + cycles_elapsed;
+			trace.instructions += 1;
+			if count_calls {
+				let called = trace.calls.after(cpu_state);
+				if self.profile {
+					trace.profiler.step(cpu_state, called, trace.cycles);
+				}
+			}
+			logger.record_cycles(trace.cycles);
+			logger.record_instructions(trace.instructions);
 
-		let condition = loop {
-			match cpu_state.tick() {
+			match tick {
 				cpu::TickResult::Ok => {}
-				cpu::TickResult::Halt | cpu::TickResult::Stop => break Ok(()),
+				// With interrupts disabled, a pending interrupt ends `halt` at once, triggering the halt bug.
+				cpu::TickResult::Halt
+					if self.accurate_halt
+						&& !cpu_state.ime && pending_interrupts(cpu_state) != 0 =>
+				{
+					halt_bug = true;
+				}
+				cpu::TickResult::Halt => return Ok(()),
+				cpu::TickResult::Stop => {
+					// With KEY1's prepare bit set, `stop` switches CPU speed rather than stopping.
+					let key1 = cpu_state.address_space.peek(HwReg::Key1 as u16);
+					if key1 & 0x01 == 0 {
+						return Ok(());
+					}
+					cpu_state.write(HwReg::Key1 as u16, (key1 ^ 0x80) & 0x80);
+				}
 				cpu::TickResult::Break => {
 					logger.log_breakpoint(cpu_state);
 				}
@@ -85,45 +666,484 @@ impl TestConfig {
 					logger.log_debug(cpu_state);
 				}
 				cpu::TickResult::InvalidOpcode => {
-					break Err(FailureReason::InvalidOpcode);
+					return Err(FailureReason::InvalidOpcode);
 				}
 			}
 
+			// Checked before exiting, since the final `ret` may itself read forbidden memory.
+			self.check_step(cpu_state, pc, logger)?;
+
 			if cpu_state.pc == self.caller_address || self.exit_addresses.contains(&cpu_state.pc) {
-				break Ok(());
+				return Ok(());
+			}
+
+			if self.bank_check != Policy::Ignore {
+				if let Some((bank, expected)) = banks.step(&self.trampolines, cpu_state) {
+					if self.bank_check == Policy::Fail {
+						return Err(FailureReason::WrongBank);
+					}
+					logger.warn(&format!(
+						"Executing 0x{:04x} in bank {bank}, but bank {expected} was expected",
+						cpu_state.pc
+					));
+				}
 			}
 
 			if self.crash_addresses.contains(&cpu_state.pc) {
-				break Err(FailureReason::Crash);
+				return Err(FailureReason::Crash);
+			}
+
+			if let Some(address) = cpu_state.address_space.poisoned_read() {
+				return Err(FailureReason::PoisonedRead(address));
 			}
 
-			if cpu_state.cycles_elapsed >= self.timeout {
-				break Err(FailureReason::Timeout);
+			self.check_exec(cpu_state)?;
+
+			if cycles_elapsed >= self.timeout {
+				return Err(FailureReason::Timeout);
 			}
+		}
+	}
+
+	/// Checks that the next instruction is not in forbidden memory or a data section.
+	fn check_exec<A: Inspect>(&self, cpu_state: &cpu::State<A>) -> Result<(), FailureReason> {
+		let pc = cpu_state.pc;
+		if self
+			.forbid_exec
+			.iter()
+			.any(|(address, length)| pc.wrapping_sub(*address) < *length)
+		{
+			return Err(FailureReason::ForbiddenExec(pc));
+		}
+		let bank = match pc {
+			0x4000..=0x7FFF => {
+				u32::try_from(cpu_state.address_space.rom_bank()).unwrap_or(u32::MAX)
+			}
+			_ => 0,
 		};
+		if self
+			.data_sections
+			.iter()
+			.any(|section| section.contains(bank, pc))
+		{
+			return Err(FailureReason::ExecutedData(pc));
+		}
+		Ok(())
+	}
 
-		match condition {
-			Err(failure_reason) => {
-				logger.failure(&failure_reason, cpu_state);
-				false
+	/// Checks the memory accessed by the instruction at `pc`, and the test's invariants after it ran.
+	fn check_step<A: Inspect>(
+		&self,
+		cpu_state: &cpu::State<A>,
+		pc: u16,
+		logger: &mut TestLogger<'_, '_>,
+	) -> Result<(), FailureReason> {
+		match cpu_state.address_space.forbidden_access() {
+			Some((AccessKind::Read, address)) => return Err(FailureReason::ForbiddenRead(address)),
+			Some((AccessKind::Write, address)) => {
+				return Err(FailureReason::ForbiddenWrite(address))
+			}
+			None => {}
+		}
+		if let Some((pc, address)) = cpu_state.address_space.rom_write() {
+			return Err(FailureReason::RomWrite(pc, address));
+		}
+
+		if let Some(invariant) = self
+			.invariants
+			.iter()
+			.find(|invariant| !invariant.holds(cpu_state))
+		{
+			logger.warn(&format!("`{invariant}` was broken at 0x{pc:04x}"));
+			return Err(FailureReason::BrokenInvariant(pc));
+		}
+		Ok(())
+	}
+
+	/// Compares the test's own `result`, and the value it `returns`, to the state once its call returned.
+	fn compare_result<A: Inspect>(
+		&self,
+		cpu_state: &cpu::State<A>,
+		trace: &Trace,
+	) -> CompareResult {
+		let mut result = self
+			.result
+			.as_ref()
+			.map(|result| result.evaluate(&trace.initial));
+		if let Some(returns) = self.returns {
+			let result = result.get_or_insert_with(|| Cow::Owned(Registers::new()));
+			let location = self.calling_convention.return_location(returns.word);
+			location.set(result.to_mut(), returns.value);
+		}
+		result
+			.map(|result| result.mismatches(cpu_state))
+			.unwrap_or_default()
+	}
+
+	/// Checks the test's cycle count, call counts, checkpoints, and conditions against the final state of the machine,
+	/// along with the comparison of its own result and any mismatches from its steps.
+	fn check<A: Inspect>(
+		&self,
+		cpu_state: &mut cpu::State<A>,
+		steps: Vec<String>,
+		trace: &mut Trace,
+		accesses: &[Access],
+		logger: &mut TestLogger<'_, '_>,
+	) -> bool {
+		let cycles = trace.cycles;
+		let mut errors = mem::take(&mut trace.result);
+		for message in steps {
+			errors.push_condition(message);
+		}
+
+		if let Some(budget) = self
+			.cycles
+			.as_ref()
+			.filter(|budget| !budget.contains(&cycles))
+		{
+			let expected = match (*budget.start(), *budget.end()) {
+				(min, max) if min == max => format!("exactly {min}"),
+				(0, max) => format!("at most {max}"),
+				(min, u64::MAX) => format!("at least {min}"),
+				(min, max) => format!("between {min} and {max}"),
+			};
+			errors.push_condition(format!("Took {cycles} cycles, expected {expected}"));
+		}
+
+		for (name, address, expected) in &self.calls {
+			let actual = trace.calls.count(*address);
+			if actual != *expected {
+				let times = |count: u32| if count == 1 { "time" } else { "times" };
+				errors.push_condition(format!(
+					"{name} was called {actual} {}, expected {expected} {}",
+					times(actual),
+					times(*expected)
+				));
 			}
-			Ok(()) => {
-				if let Some(result) = &self.result {
-					match result.compare(cpu_state) {
-						Ok(()) => {
-							logger.pass();
-							true
-						}
-						Err(msg) => {
-							logger.incorrect(&msg);
-							false
-						}
+		}
+		for ((name, _, _), result) in self.checkpoints.iter().zip(&trace.checkpoints) {
+			match result {
+				Some(result) => {
+					for difference in result.differences() {
+						errors.push_condition(format!("At {name}: {difference}"));
+					}
+					for message in result.conditions() {
+						errors.push_condition(format!("At {name}: {message}"));
 					}
-				} else {
-					logger.pass();
-					true
 				}
+				None => errors.push_condition(format!("Checkpoint {name} was never reached")),
+			}
+		}
+
+		if !self.conditions.is_empty() {
+			let registers = Registers::capture(cpu_state);
+			for condition in &self.conditions {
+				if let Err(message) =
+					condition.check(&registers, &cpu_state.address_space, accesses)
+				{
+					errors.push_condition(message);
+				}
+			}
+		}
+
+		match (errors.is_empty(), self.expect_failure) {
+			(true, false) => {
+				logger.pass();
+				true
+			}
+			(true, true) => {
+				logger.failure(&FailureReason::UnexpectedPass, cpu_state);
+				false
+			}
+			(false, false) => {
+				logger.incorrect(&Error::CompareFailed(errors), cpu_state);
+				false
+			}
+			(false, true) => {
+				logger.expected_failure(
+					FailureReason::Incorrect,
+					Some(&Error::CompareFailed(errors)),
+				);
+				true
+			}
+		}
+	}
+}
+
+/// Returns the first address outside of WRAM and HRAM which `stack` bytes and the caller address would be pushed to, if any.
+/// This includes ROM, VRAM, SRAM, echo RAM, OAM, the unusable region after it, IO registers, and `IE`.
+fn invalid_stack_address(sp: u16, stack: usize) -> Option<u16> {
+	iter::successors(Some(sp), |sp| Some(sp.wrapping_sub(1)))
+		.skip(1)
+		.take(stack + 2)
+		.find(|address| !matches!(address, 0xC000..=0xDFFF | 0xFF80..=0xFFFE))
+}
+
+/// A call made by a multi-step test once its own call returns, such as `Alloc` after `InitHeap`.
+#[derive(Clone, Debug, Default)]
+pub struct TestStep {
+	/// Identifies the step in failures, such as `step 2`.
+	pub name: String,
+	/// Regions of memory, as (address, length), poisoned before the call so that it can't depend on what earlier calls left there.
+	pub poison: Vec<(u16, u16)>,
+	/// Registers and memory set before the call; `pc` is its entry point.
+	pub initial: Registers,
+	/// The expected state once the call returns, if any.
+	pub result: Option<Registers>,
+}
+
+/// Tracks which ROM bank code in 0x4000-0x7FFF is expected to run from.
+/// Only trampolines may change it.
+struct BankTracker {
+	expected: usize,
+	/// Stack pointer on entry to the active trampoline, used to detect when it returns.
+	trampoline_sp: Option<u16>,
+}
+
+impl BankTracker {
+	fn new(bank: usize) -> Self {
+		Self {
+			expected: bank,
+			trampoline_sp: None,
+		}
+	}
+
+	/// Returns the current and expected bank if code is running from an unexpected bank.
+	/// Each unexpected switch is only reported once.
+	fn step<A: Inspect>(
+		&mut self,
+		trampolines: &[u16],
+		cpu_state: &cpu::State<A>,
+	) -> Option<(usize, usize)> {
+		if self.trampoline_sp.is_some_and(|sp| cpu_state.sp > sp) {
+			self.trampoline_sp = None;
+		}
+		if self.trampoline_sp.is_none() && trampolines.contains(&cpu_state.pc) {
+			self.trampoline_sp = Some(cpu_state.sp);
+		}
+
+		let bank = cpu_state.address_space.rom_bank();
+		let expected = self.expected;
+		if self.trampoline_sp.is_some() {
+			self.expected = bank;
+			None
+		} else if (0x4000..0x8000).contains(&cpu_state.pc) && bank != expected {
+			self.expected = bank;
+			Some((bank, expected))
+		} else {
+			None
+		}
+	}
+}
+
+/// How many of the most recently executed instructions are shown when a test crashes or times out.
+const HISTORY_LENGTH: usize = 16;
+
+/// Records what happened while a test ran, beyond its final state.
+struct Trace {
+	calls: CallCounter,
+	profiler: Profiler,
+	coverage: Coverage,
+	/// The result of comparing each checkpoint, once it has been reached.
+	checkpoints: Vec<Option<CompareResult>>,
+	/// The ROM bank and address of the last few instructions executed.
+	history: VecDeque<(usize, u16)>,
+	/// Every instruction executed, if the test is traced.
+	steps: Vec<Step>,
+	/// The registers and memory which expected values are computed from, when the test began.
+	initial: Initial,
+	/// The result of comparing the test's own `result` and `returns`, once its call returned.
+	result: CompareResult,
+	/// The number of cycles and instructions executed by the test's call and its steps so far.
+	cycles: u64,
+	instructions: u64,
+	/// Every read and write made by the test's call and its steps, taken as each call returns.
+	accesses: Vec<Access>,
+}
+
+impl Trace {
+	fn new(checkpoints: usize) -> Self {
+		Self {
+			calls: CallCounter::default(),
+			profiler: Profiler::default(),
+			coverage: Coverage::default(),
+			checkpoints: vec![None; checkpoints],
+			history: VecDeque::with_capacity(HISTORY_LENGTH),
+			steps: Vec::new(),
+			initial: Initial::default(),
+			result: CompareResult::default(),
+			cycles: 0,
+			instructions: 0,
+			accesses: Vec::new(),
+		}
+	}
+
+	/// Records the instruction about to be executed,
+	/// including its registers and coverage if the test asks for them.
+	fn record<A: Inspect>(&mut self, cpu_state: &cpu::State<A>, config: &TestConfig) {
+		let pc = cpu_state.pc;
+		let bank = cpu_state.address_space.rom_bank();
+		if self.history.len() == HISTORY_LENGTH {
+			self.history.pop_front();
+		}
+		self.history.push_back((bank, pc));
+		if config.coverage {
+			self.coverage.record(bank, pc);
+		}
+		if config.trace {
+			self.steps.push(Step {
+				pc,
+				memory: [0, 1, 2, 3]
+					.map(|offset| cpu_state.address_space.peek(pc.wrapping_add(offset))),
+				af: u16::from_be_bytes([cpu_state.a, flags_byte(cpu_state)]),
+				bc: cpu_state.get_bc(),
+				de: cpu_state.get_de(),
+				hl: cpu_state.get_hl(),
+				sp: cpu_state.sp,
+			});
+		}
+	}
+}
+
+/// Counts how many times each address is called by `call` or `rst`.
+#[derive(Default)]
+struct CallCounter {
+	counts: HashMap<u16, u32>,
+	/// The stack pointer before the current instruction, if it is a `call` or `rst`.
+	pending: Option<u16>,
+}
+
+impl CallCounter {
+	/// Notes whether the instruction about to be executed is a `call` or `rst`.
+	fn before<A: Inspect>(&mut self, cpu_state: &cpu::State<A>) {
+		let opcode = cpu_state.address_space.peek(cpu_state.pc);
+		// call, call cc, and rst
+		let is_call = matches!(opcode, 0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC) || opcode & 0xC7 == 0xC7;
+		self.pending = is_call.then_some(cpu_state.sp);
+	}
+
+	/// Counts the call if the previous instruction pushed a return address, returning whether it did.
+	/// Conditional calls which were not taken leave the stack alone.
+	fn after<A: Inspect>(&mut self, cpu_state: &cpu::State<A>) -> bool {
+		let called = self
+			.pending
+			.take()
+			.is_some_and(|sp| cpu_state.sp == sp.wrapping_sub(2));
+		if called {
+			*self.counts.entry(cpu_state.pc).or_default() += 1;
+		}
+		called
+	}
+
+	fn count(&self, address: u16) -> u32 {
+		self.counts.get(&address).copied().unwrap_or_default()
+	}
+}
+
+/// Records when each subroutine was called and when it returned.
+#[derive(Default)]
+struct Profiler {
+	/// The index in `spans` and the stack pointer on entry of each subroutine which has not returned yet.
+	frames: Vec<(usize, u16)>,
+	/// Each subroutine called, in the order they were called.
+	spans: Vec<Span>,
+	/// The number of cycles elapsed as of the last instruction.
+	cycles: u64,
+}
+
+impl Profiler {
+	/// Notes that the subroutine at `pc` has been entered.
+	fn enter<A: Inspect>(&mut self, cpu_state: &cpu::State<A>, cycles: u64) {
+		self.frames.push((self.spans.len(), cpu_state.sp));
+		self.spans.push(Span {
+			address: cpu_state.pc,
+			bank: cpu_state.address_space.rom_bank(),
+			label: None,
+			start: cycles,
+			end: cycles,
+			caller: self.frames.iter().rev().nth(1).map(|(caller, _)| *caller),
+		});
+	}
+
+	/// Ends each subroutine which has returned, and starts a new one if the last instruction was a call.
+	fn step<A: Inspect>(&mut self, cpu_state: &cpu::State<A>, called: bool, cycles: u64) {
+		self.cycles = cycles;
+		// Returning pops the return address, leaving the stack pointer above where it was on entry.
+		while let Some(&(index, sp)) = self.frames.last() {
+			if cpu_state.sp <= sp {
+				break;
 			}
+			self.frames.pop();
+			self.spans[index].end = cycles;
+		}
+		if called {
+			self.enter(cpu_state, cycles);
+		}
+	}
+
+	/// Ends every subroutine which is still running, returning every span.
+	fn finish(&mut self) -> Vec<Span> {
+		for (index, _) in self.frames.drain(..) {
+			self.spans[index].end = self.cycles;
 		}
+		mem::take(&mut self.spans)
+	}
+}
+
+/// The interrupts which are both requested and enabled.
+fn pending_interrupts<A: Inspect>(cpu_state: &cpu::State<A>) -> u8 {
+	let address_space = &cpu_state.address_space;
+	address_space.peek(HwReg::If as u16) & address_space.peek(HwReg::Ie as u16) & 0x1F
+}
+
+/// Corrects the state after the instruction at `pc` ran with the halt bug.
+///
+/// Its operands were read one byte early, but the CPU still advanced `pc` past all of them,
+/// so `pc`, or the return address pushed by a call, is one byte further than on hardware.
+/// Absolute jumps and returns land where they would on hardware, and need no correction.
+fn undo_halt_bug<A: Inspect>(cpu_state: &mut cpu::State<A>, pc: u16) {
+	let instruction = disasm::decode(pc, |address| cpu_state.address_space.peek(address));
+	let length = u16::try_from(instruction.bytes.len()).unwrap_or(1);
+	let opcode = instruction.bytes[0];
+	let relative = matches!(opcode, 0x18 | 0x20 | 0x28 | 0x30 | 0x38);
+	let call = matches!(opcode, 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC) || opcode & 0xC7 == 0xC7;
+	if cpu_state.pc == pc.wrapping_add(length) || relative {
+		cpu_state.pc = cpu_state.pc.wrapping_sub(1);
+	} else if call {
+		let sp = cpu_state.sp;
+		let [low, high] = u16::from_le_bytes([
+			cpu_state.address_space.peek(sp),
+			cpu_state.address_space.peek(sp.wrapping_add(1)),
+		])
+		.wrapping_sub(1)
+		.to_le_bytes();
+		cpu_state.write(sp, low);
+		cpu_state.write(sp.wrapping_add(1), high);
+	}
+}
+
+/// Accumulates the simulator's cycle counter into a `u64`,
+/// so that long tests behave the same on hosts where `usize` is 32 bits.
+struct CycleCounter {
+	total: u64,
+	last: usize,
+}
+
+impl CycleCounter {
+	fn new(cycles_elapsed: usize) -> Self {
+		Self {
+			total: 0,
+			last: cycles_elapsed,
+		}
+	}
+
+	/// Returns the number of cycles since this counter was created.
+	fn update(&mut self, cycles_elapsed: usize) -> u64 {
+		let delta = cycles_elapsed.wrapping_sub(self.last);
+		self.last = cycles_elapsed;
+		self.total = self
+			.total
+			.saturating_add(u64::try_from(delta).unwrap_or(u64::MAX));
+		self.total
 	}
 }
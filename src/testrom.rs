@@ -0,0 +1,138 @@
+//! Running whole hardware test ROMs, such as Mooneye's and Blargg's, which report their own results.
+//!
+//! Mooneye's tests execute `ld b, b` once they finish, with the Fibonacci numbers 3, 5, 8, 13, 21, and 34
+//! in `b` through `l` if they passed, or `0x42` in each if they failed.
+//! Blargg's tests print their results over the serial port, ending with "Passed" or "Failed",
+//! and some also write their status to cartridge RAM at 0xA000, following the signature `DE B0 61`.
+
+use crate::memory::AddressSpace;
+use crate::reg::HwReg;
+use crate::registers::{Model, Registers};
+use gb_cpu_sim::cpu;
+use gb_cpu_sim::memory::AddressSpace as _;
+use serde::Serialize;
+
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL: [u8; 6] = [0x42; 6];
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+/// The status Blargg's tests leave at 0xA000 while they are still running.
+const BLARGG_RUNNING: u8 = 0x80;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+	Passed,
+	/// The ROM reported a failure, or stopped without reporting anything.
+	Failed(String),
+	/// The ROM didn't report a result before the timeout.
+	Timeout,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TestRomReport {
+	pub outcome: Outcome,
+	/// Everything the ROM printed over the serial port.
+	pub serial: String,
+	pub cycles: u64,
+}
+
+/// Runs a test ROM from its entry point until it reports a result, or for `timeout` cycles.
+#[must_use]
+pub fn run(rom: &Vec<u8>, model: Model, timeout: u64) -> TestRomReport {
+	let mut cpu_state = cpu::State::new(AddressSpace::with(rom));
+	Registers::preset(model).configure(&mut cpu_state);
+	let mut serial = Vec::new();
+
+	let outcome = loop {
+		let tick = cpu_state.tick();
+		let cycles = u64::try_from(cpu_state.cycles_elapsed).unwrap_or(u64::MAX);
+		match tick {
+			cpu::TickResult::Ok | cpu::TickResult::Debug => {}
+			cpu::TickResult::Break => {
+				if let Some(outcome) = mooneye_outcome(&cpu_state) {
+					break outcome;
+				}
+			}
+			cpu::TickResult::Halt | cpu::TickResult::Stop => {
+				break Outcome::Failed(String::from("Stopped without reporting a result"));
+			}
+			cpu::TickResult::InvalidOpcode => {
+				break Outcome::Failed(format!(
+					"Invalid opcode at 0x{:04x}",
+					cpu_state.pc.wrapping_sub(1)
+				));
+			}
+		}
+		// Transfers finish instantly, since there is nothing on the other end of the link cable.
+		if cpu_state.address_space.read(HwReg::Sc as u16) == 0x81 {
+			serial.push(cpu_state.address_space.read(HwReg::Sb as u16));
+			cpu_state.write(HwReg::Sc as u16, 0x01);
+			if let Some(outcome) = serial_outcome(&serial) {
+				break outcome;
+			}
+		}
+		if let Some(outcome) = sram_outcome(&cpu_state) {
+			break outcome;
+		}
+		if cycles >= timeout {
+			break Outcome::Timeout;
+		}
+	};
+
+	TestRomReport {
+		outcome,
+		serial: String::from_utf8_lossy(&serial).into_owned(),
+		cycles: u64::try_from(cpu_state.cycles_elapsed).unwrap_or(u64::MAX),
+	}
+}
+
+fn mooneye_outcome(cpu_state: &cpu::State<AddressSpace>) -> Option<Outcome> {
+	let registers = [
+		cpu_state.b,
+		cpu_state.c,
+		cpu_state.d,
+		cpu_state.e,
+		cpu_state.h,
+		cpu_state.l,
+	];
+	match registers {
+		MOONEYE_PASS => Some(Outcome::Passed),
+		MOONEYE_FAIL => Some(Outcome::Failed(String::from(
+			"Registers were set to the failure signature",
+		))),
+		_ => None,
+	}
+}
+
+fn serial_outcome(serial: &[u8]) -> Option<Outcome> {
+	let text = String::from_utf8_lossy(serial);
+	if text.contains("Passed") {
+		Some(Outcome::Passed)
+	} else if text.contains("Failed") {
+		Some(Outcome::Failed(String::from("Failed")))
+	} else {
+		None
+	}
+}
+
+fn sram_outcome(cpu_state: &cpu::State<AddressSpace>) -> Option<Outcome> {
+	let memory = &cpu_state.address_space;
+	if memory.sram[1..4] != BLARGG_SIGNATURE {
+		return None;
+	}
+	match memory.sram[0] {
+		BLARGG_RUNNING => None,
+		0 => Some(Outcome::Passed),
+		status => {
+			let message = memory.sram[4..]
+				.iter()
+				.take_while(|byte| **byte != 0)
+				.map(|byte| char::from(*byte))
+				.collect::<String>();
+			Some(Outcome::Failed(format!(
+				"Status 0x{status:02x}: {}",
+				message.trim()
+			)))
+		}
+	}
+}
@@ -0,0 +1,105 @@
+//! Checking the CPU against single-instruction test vectors, such as the JSON tests from the `SingleStepTests` project.
+//!
+//! Each file holds an array of tests, which give the state of the CPU and memory before and after one instruction.
+//! Only registers and memory are compared, along with the number of cycles taken,
+//! since evunit does not emulate the bus activity of each cycle.
+
+use crate::memory::FlatMemory;
+use crate::registers::{CompareResult, Registers};
+use gb_cpu_sim::cpu;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct Vector {
+	name: String,
+	initial: VectorState,
+	#[serde(rename = "final")]
+	expected: VectorState,
+	/// The bus activity of each M-cycle; only its length is used.
+	#[serde(default)]
+	cycles: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct VectorState {
+	a: u8,
+	b: u8,
+	c: u8,
+	d: u8,
+	e: u8,
+	f: u8,
+	h: u8,
+	l: u8,
+	pc: u16,
+	sp: u16,
+	ime: Option<u8>,
+	ram: Vec<(u16, u8)>,
+}
+
+impl VectorState {
+	fn registers(&self) -> Registers {
+		let mut registers = Registers::new()
+			.with_a(self.a)
+			.with_b(self.b)
+			.with_c(self.c)
+			.with_d(self.d)
+			.with_e(self.e)
+			.with_f(self.f)
+			.with_h(self.h)
+			.with_l(self.l)
+			.with_pc(self.pc)
+			.with_sp(self.sp);
+		if let Some(ime) = self.ime {
+			registers = registers.with_ime(ime != 0);
+		}
+		registers.memory.clone_from(&self.ram);
+		registers
+	}
+}
+
+/// A test vector whose result did not match.
+#[derive(Debug)]
+pub struct VectorFailure {
+	pub name: String,
+	pub differences: CompareResult,
+}
+
+#[derive(Debug, Default)]
+pub struct VectorResults {
+	pub total: usize,
+	pub failures: Vec<VectorFailure>,
+}
+
+/// Runs every test vector in a JSON file.
+///
+/// # Errors
+///
+/// Returns an error if the file is not an array of test vectors.
+pub fn check(json: &str) -> Result<VectorResults, serde_json::Error> {
+	let vectors: Vec<Vector> = serde_json::from_str(json)?;
+	let mut results = VectorResults {
+		total: vectors.len(),
+		failures: Vec::new(),
+	};
+	for vector in vectors {
+		let mut cpu_state = cpu::State::new(FlatMemory::default());
+		vector.initial.registers().configure(&mut cpu_state);
+		let start = cpu_state.cycles_elapsed;
+		cpu_state.tick();
+		let mut differences = vector.expected.registers().mismatches(&cpu_state);
+		let cycles = cpu_state.cycles_elapsed.wrapping_sub(start);
+		if !vector.cycles.is_empty() && cycles != vector.cycles.len() * 4 {
+			differences.push_condition(format!(
+				"Took {cycles} cycles, but expected {}",
+				vector.cycles.len() * 4
+			));
+		}
+		if !differences.is_empty() {
+			results.failures.push(VectorFailure {
+				name: vector.name,
+				differences,
+			});
+		}
+	}
+	Ok(results)
+}